@@ -0,0 +1,175 @@
+use crate::gfp;
+
+/// An allocation function recognized by the query, along with where its
+/// arguments live.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Allocator {
+    pub name: &'static str,
+    /// Index of the size argument, the element size for array allocators.
+    pub size_arg: usize,
+    /// Index of the element count argument of array allocators.
+    pub count_arg: Option<usize>,
+    /// Index of the gfp flags argument, None for allocators taking no flags
+    /// like the `osal_mem_alloc(size)` shims of vendor trees.
+    pub flags_arg: Option<usize>,
+    /// Whether the memory is always zeroed, whatever the flags.
+    pub zeroes: bool,
+    /// A note for the sites calling it, for entry points which behave
+    /// differently from the allocator they are usually reached through.
+    pub note: Option<&'static str>,
+}
+
+impl Allocator {
+    /// An allocator taking its size and flags as arguments.
+    pub const fn new(name: &'static str, size_arg: usize, flags_arg: usize) -> Self {
+        Allocator {
+            name,
+            size_arg,
+            count_arg: None,
+            flags_arg: Some(flags_arg),
+            zeroes: false,
+            note: None,
+        }
+    }
+
+    /// An allocator taking only a size, without flags.
+    pub const fn unflagged(name: &'static str, size_arg: usize) -> Self {
+        Allocator {
+            name,
+            size_arg,
+            count_arg: None,
+            flags_arg: None,
+            zeroes: false,
+            note: None,
+        }
+    }
+
+    /// An array allocator, taking an element count and size.
+    pub const fn array(
+        name: &'static str,
+        count_arg: usize,
+        size_arg: usize,
+        flags_arg: usize,
+    ) -> Self {
+        Allocator {
+            name,
+            size_arg,
+            count_arg: Some(count_arg),
+            flags_arg: Some(flags_arg),
+            zeroes: false,
+            note: None,
+        }
+    }
+
+    /// The same allocator, always zeroing the memory, like kzalloc.
+    pub const fn zeroing(self) -> Self {
+        Allocator {
+            zeroes: true,
+            ..self
+        }
+    }
+
+    /// The same allocator, with a note for its sites.
+    pub const fn noted(self, note: &'static str) -> Self {
+        Allocator {
+            note: Some(note),
+            ..self
+        }
+    }
+}
+
+/// The allocators matched by the built-in query. Only exact names are matched,
+/// wrappers with similar names (devm_kzalloc, sock_kmalloc, ...) have
+/// different signatures and are not picked up.
+pub const ALLOCATORS: &[Allocator] = &[
+    Allocator::new("kmalloc", 0, 1),
+    Allocator::new("kzalloc", 0, 1).zeroing(),
+    Allocator::new("kvmalloc", 0, 1),
+    Allocator::new("kvzalloc", 0, 1).zeroing(),
+    Allocator::new("kmalloc_node", 0, 1),
+    Allocator::new("kzalloc_node", 0, 1).zeroing(),
+    Allocator::new("kvmalloc_node", 0, 1),
+    Allocator::new("kvzalloc_node", 0, 1).zeroing(),
+    Allocator::array("kmalloc_array", 0, 1, 2),
+    Allocator::array("kcalloc", 0, 1, 2).zeroing(),
+    Allocator::array("kvmalloc_array", 0, 1, 2),
+    Allocator::array("kvcalloc", 0, 1, 2).zeroing(),
+    // what kmalloc lowers to, called directly by some code: __kmalloc skips
+    // the size class lookup kmalloc does at compile time for constant sizes,
+    // kmalloc_trace (5.17 and later) takes the cache kmalloc looked up
+    Allocator::new("__kmalloc", 0, 1).noted("via __kmalloc (no constant size fast path)"),
+    Allocator::new("kmalloc_trace", 2, 1).noted("via kmalloc_trace (the caller picks the cache)"),
+];
+
+pub fn lookup<'a>(allocators: &'a [Allocator], name: &str) -> Option<&'a Allocator> {
+    allocators.iter().find(|alloc| alloc.name == name)
+}
+
+/// Anchored regex matching exactly the names in an allocator table.
+pub fn name_regex(allocators: &[Allocator]) -> String {
+    let names: Vec<&str> = allocators.iter().map(|alloc| alloc.name).collect();
+    format!("^({})$", names.join("|"))
+}
+
+/// Whether an allocation site's memory starts out zeroed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zeroing {
+    /// A zeroing allocator like kzalloc, or flags including `__GFP_ZERO`.
+    Zeroed,
+    /// A plain allocator with literal flags lacking `__GFP_ZERO`, or one
+    /// taking no flags.
+    NotZeroed,
+    /// The flags come from a variable or call, or the allocator isn't in the
+    /// table.
+    Unknown,
+}
+
+impl Zeroing {
+    /// Classify a call to `allocator`, None when it isn't in the table, with
+    /// the flags argument `flags`, empty for allocators taking no flags.
+    pub fn classify(allocator: Option<&Allocator>, flags: &str) -> Self {
+        if let Some(alloc) = allocator.filter(|alloc| alloc.zeroes || alloc.flags_arg.is_none()) {
+            return match alloc.zeroes {
+                true => Zeroing::Zeroed,
+                false => Zeroing::NotZeroed,
+            };
+        }
+        match gfp::expand(flags) {
+            Some(bits) if bits.contains("__GFP_ZERO") => Zeroing::Zeroed,
+            Some(_) if allocator.is_some() => Zeroing::NotZeroed,
+            _ => Zeroing::Unknown,
+        }
+    }
+}
+
+impl std::fmt::Display for Zeroing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Zeroing::Zeroed => "zeroed",
+            Zeroing::NotZeroed => "not zeroed",
+            Zeroing::Unknown => "zeroing unknown",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn name_regex_matches_only_the_table() {
+        let re = regex::Regex::new(&name_regex(ALLOCATORS)).unwrap();
+        for allocator in ALLOCATORS {
+            assert!(re.is_match(allocator.name), "{}", allocator.name);
+        }
+        for lookalike in [
+            "sock_kmalloc",
+            "my_kmalloc_helper",
+            "devm_kzalloc",
+            "kmalloc_",
+            "xkcalloc",
+        ] {
+            assert!(!re.is_match(lookalike), "{lookalike}");
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use crate::files::{self, Selector};
+use crate::skipped::{self, Reason};
+
+/// Whether `path` names a source tarball, going by its extension.
+pub fn is_archive(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    [".tar", ".tar.gz", ".tgz", ".tar.xz", ".txz"]
+        .iter()
+        .any(|ext| name.ends_with(ext))
+}
+
+fn decompress(path: &Path) -> anyhow::Result<Box<dyn Read + Send>> {
+    let file = BufReader::new(File::open(path)?);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    Ok(if name.ends_with(".gz") || name.ends_with(".tgz") {
+        Box::new(flate2::read::GzDecoder::new(file))
+    } else if name.ends_with(".xz") || name.ends_with(".txz") {
+        Box::new(xz2::read::XzDecoder::new(file))
+    } else {
+        Box::new(file)
+    })
+}
+
+/// The number of C sources and headers in a tarball passing `selector`, the
+/// files `read_sources` would hand out. Only the entry headers are looked at,
+/// but a compressed archive is still decompressed in full.
+pub fn count_sources(path: &Path, selector: &Selector) -> anyhow::Result<usize> {
+    let mut archive = tar::Archive::new(decompress(path)?);
+    let mut count = 0;
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let entry_path = entry.path()?;
+        if files::is_source_file(&entry_path)
+            && selector.excludes_archive_dir(&entry_path).is_none()
+            && selector.matches_archive_entry(&entry_path)
+        {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Stream the C sources and headers out of a tarball, in archive order,
+/// calling `source` with the in-archive path and contents of each one passing
+/// `selector`. Nothing is written to disk. Stops early when `source` returns
+/// false.
+pub fn read_sources<F>(path: &Path, selector: &Selector, mut source: F) -> anyhow::Result<()>
+where
+    F: FnMut(PathBuf, std::io::Result<Vec<u8>>) -> bool,
+{
+    let mut archive = tar::Archive::new(decompress(path)?);
+    let mut excluded_dirs = HashSet::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let entry_path = entry.path()?.into_owned();
+        if !files::is_source_file(&entry_path) {
+            continue;
+        }
+        if let Some(dir) = selector.excludes_archive_dir(&entry_path) {
+            if excluded_dirs.insert(dir.clone()) {
+                skipped::record(Reason::ExcludedDir, &dir, None);
+            }
+            continue;
+        }
+        if !selector.matches_archive_entry(&entry_path) {
+            skipped::record(Reason::ExcludedByGlob, &entry_path, None);
+            continue;
+        }
+
+        let mut contents = Vec::with_capacity(entry.size() as usize);
+        let contents = entry.read_to_end(&mut contents).map(|_| contents);
+        if !source(entry_path, contents) {
+            break;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use git2::{BlameOptions, Oid, Repository};
+
+// set by `--blame`
+static BLAME: AtomicBool = AtomicBool::new(false);
+// whether a file which could not be blamed has been warned about
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+/// The commit which last changed a line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Blame {
+    /// The abbreviated commit hash.
+    pub commit: String,
+    /// The author date, `YYYY-MM-DD` in the author's timezone.
+    pub date: String,
+    /// The first line of the commit message.
+    pub subject: String,
+}
+
+impl fmt::Display for Blame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.commit, self.date, self.subject)
+    }
+}
+
+/// The blame of a whole file, looked up once for all of its sites.
+pub struct FileBlame {
+    /// The first and last line of each hunk and its commit, None for lines
+    /// which aren't committed yet.
+    hunks: Vec<(usize, usize, Option<Blame>)>,
+}
+
+impl FileBlame {
+    /// The commit which last changed a line, starting at 1. None for a line
+    /// which isn't committed yet.
+    pub fn line(&self, line: usize) -> Option<&Blame> {
+        self.hunks
+            .iter()
+            .find(|(first, last, _)| (*first..=*last).contains(&line))
+            .and_then(|(_, _, blame)| blame.as_ref())
+    }
+}
+
+pub fn enable() {
+    BLAME.store(true, Ordering::SeqCst);
+}
+
+pub fn enabled() -> bool {
+    BLAME.load(Ordering::SeqCst)
+}
+
+/// Blame a file with `content` as its contents in the work tree, so that
+/// lines changed since the last commit are told apart. None when the file
+/// isn't in a git work tree or was never committed, with a warning the first
+/// time.
+pub fn file(path: &Path, content: &[u8]) -> Option<FileBlame> {
+    match blame_file(path, content) {
+        Ok(blame) => Some(blame),
+        Err(e) => {
+            if !WARNED.swap(true, Ordering::SeqCst) {
+                eprintln!(
+                    "--blame: can't blame {} ({}), sites in files outside a git work tree \
+                     or never committed are printed without blame",
+                    path.display(),
+                    e.message()
+                );
+            }
+            None
+        }
+    }
+}
+
+fn blame_file(path: &Path, content: &[u8]) -> Result<FileBlame, git2::Error> {
+    let path = path
+        .canonicalize()
+        .map_err(|e| git2::Error::from_str(&e.to_string()))?;
+    let repo = Repository::discover(path.parent().unwrap_or(&path))?;
+    let relative = repo
+        .workdir()
+        .and_then(|workdir| path.strip_prefix(workdir).ok())
+        .ok_or_else(|| git2::Error::from_str("not in the repository's work tree"))?;
+    let committed = repo.blame_file(relative, Some(&mut BlameOptions::new()))?;
+    let blame = committed.blame_buffer(content)?;
+
+    let mut commits: HashMap<Oid, Option<Blame>> = HashMap::new();
+    let mut hunks = vec![];
+    for hunk in blame.iter() {
+        let oid = hunk.final_commit_id();
+        let commit = match commits.get(&oid) {
+            Some(commit) => commit.clone(),
+            None => {
+                let commit = match oid.is_zero() {
+                    true => None,
+                    false => Some(describe(&repo, oid)?),
+                };
+                commits.insert(oid, commit.clone());
+                commit
+            }
+        };
+        let first = hunk.final_start_line();
+        hunks.push((first, first + hunk.lines_in_hunk() - 1, commit));
+    }
+    Ok(FileBlame { hunks })
+}
+
+fn describe(repo: &Repository, oid: Oid) -> Result<Blame, git2::Error> {
+    let commit = repo.find_commit(oid)?;
+    let when = commit.author().when();
+    let hash = oid.to_string();
+    Ok(Blame {
+        commit: hash[..12].to_string(),
+        date: date(when.seconds() + i64::from(when.offset_minutes()) * 60),
+        subject: commit.summary().unwrap_or("").to_string(),
+    })
+}
+
+/// The `YYYY-MM-DD` date of a Unix timestamp.
+fn date(seconds: i64) -> String {
+    // days to a civil date, after Howard Hinnant's days_from_civil inverse
+    let days = seconds.div_euclid(86400) + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}")
+}
@@ -0,0 +1,512 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use tree_sitter as ts;
+use tree_sitter_c as ts_c;
+
+use crate::layout::Layout;
+use crate::output;
+use crate::pointee;
+use crate::query;
+use crate::scanner;
+use crate::usercopy::{self, Target};
+
+// set by `--track-bulk-writes`
+static TRACK: AtomicBool = AtomicBool::new(false);
+
+/// The bulk write functions, all taking the destination first and the
+/// length third.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Memcpy,
+    Memmove,
+    Memset,
+}
+
+impl Op {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "memcpy" => Some(Op::Memcpy),
+            "memmove" => Some(Op::Memmove),
+            "memset" => Some(Op::Memset),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Op::Memcpy => "memcpy",
+            Op::Memmove => "memmove",
+            Op::Memset => "memset",
+        }
+    }
+}
+
+/// The length argument of a bulk write, classified by how it compares with
+/// the bytes it writes into.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Length {
+    /// A `sizeof` of the destination itself, e.g. `sizeof(*p)` for the object
+    /// or `sizeof(p->buf)` for a field.
+    SizeofTarget,
+    /// A number literal.
+    Constant(usize),
+    /// Anything else, which may be larger than the destination.
+    Expression,
+}
+
+/// How a bulk write's length compares with the bytes from its destination to
+/// the end of the object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bound {
+    /// Exactly the size of the destination.
+    Exact,
+    /// A constant within the object.
+    Within,
+    /// A constant running past the end of the object by this many bytes.
+    Exceeds(usize),
+    /// Not a constant, or the object's size is unknown.
+    MayExceed,
+}
+
+impl fmt::Display for Bound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Bound::Exact => write!(f, "exactly its size"),
+            Bound::Within => write!(f, "within the object"),
+            Bound::Exceeds(by) => write!(f, "EXCEEDS the object by {by} bytes"),
+            Bound::MayExceed => write!(f, "may exceed the object"),
+        }
+    }
+}
+
+/// A `memcpy`, `memmove` or `memset` into one of the selected structs.
+pub struct BulkWrite {
+    pub path: PathBuf,
+    pub line: usize,
+    pub function: Option<String>,
+    pub op: Op,
+    /// The call, as written.
+    pub call: String,
+    pub target: Target,
+    /// The length argument, as written.
+    pub size: String,
+    pub length: Length,
+}
+
+impl BulkWrite {
+    /// Compare the write's length with the room left in the object from its
+    /// destination, which needs the struct's layout for anything but a
+    /// `sizeof` of the destination.
+    pub fn bound(&self, layout: Option<&Layout>) -> Bound {
+        let offset = match &self.target {
+            Target::Object => Some(0),
+            Target::Field(name) => layout.and_then(|layout| {
+                let member = layout.members.iter().find(|member| &member.name == name)?;
+                Some(member.offset)
+            }),
+        };
+        let room = offset.and_then(|offset| layout?.size?.checked_sub(offset));
+        match (&self.length, room) {
+            (Length::SizeofTarget, _) => Bound::Exact,
+            (&Length::Constant(n), Some(room)) if n > room => Bound::Exceeds(n - room),
+            (Length::Constant(_), Some(_)) => Bound::Within,
+            _ => Bound::MayExceed,
+        }
+    }
+}
+
+lazy_static! {
+    static ref CALL_QUERY: ts::Query = ts::Query::new(
+        ts_c::language(),
+        "(call_expression
+            function: (identifier) @call.function
+            (#match? @call.function \"^(memcpy|memmove|memset)$\")
+            arguments: (argument_list) @call.args
+        ) @call",
+    )
+    .unwrap();
+    // bulk writes by struct
+    static ref WRITES: Mutex<BTreeMap<String, Vec<BulkWrite>>> = Mutex::new(BTreeMap::new());
+}
+
+pub fn enable() {
+    TRACK.store(true, Ordering::SeqCst);
+}
+
+pub fn enabled() -> bool {
+    TRACK.load(Ordering::SeqCst)
+}
+
+/// Find the `memcpy`, `memmove` and `memset` calls in a parsed file whose
+/// destination resolves to one of the structs `selected` accepts, or to a
+/// field of one.
+pub fn find(root: ts::Node<'_>, content: &[u8], path: &Path, selected: impl Fn(&str) -> bool) {
+    let found = writes_in(root, content, path, selected);
+    if found.is_empty() {
+        return;
+    }
+    let mut writes = WRITES.lock().unwrap();
+    for (struct_name, write) in found {
+        writes.entry(struct_name).or_default().push(write);
+    }
+}
+
+/// The bulk writes [`find`] records, along with the struct each writes into.
+fn writes_in(
+    root: ts::Node<'_>,
+    content: &[u8],
+    path: &Path,
+    selected: impl Fn(&str) -> bool,
+) -> Vec<(String, BulkWrite)> {
+    let mut found = vec![];
+    for [call, function, args] in query::captures(
+        &CALL_QUERY,
+        ["call", "call.function", "call.args"],
+        root,
+        content,
+    ) {
+        let Some(op) = function.utf8_text(content).ok().and_then(Op::from_name) else {
+            continue;
+        };
+        let (Some(dst), Some(size)) = (query::argument(args, 0), query::argument(args, 2)) else {
+            continue;
+        };
+        let Some((struct_name, target)) = usercopy::object_part(dst, content) else {
+            continue;
+        };
+        if !selected(struct_name) {
+            continue;
+        }
+        found.push((
+            struct_name.to_string(),
+            BulkWrite {
+                path: path.to_path_buf(),
+                line: call.start_position().row + 1,
+                function: scanner::enclosing_function(call)
+                    .and_then(|def| scanner::function_name(def, content)),
+                op,
+                call: output::one_line(&content[call.byte_range()]),
+                length: length(size, struct_name, &target, content),
+                target,
+                size: output::one_line(&content[size.byte_range()]),
+            },
+        ));
+    }
+    found
+}
+
+/// Classify a length argument against the destination it is written to.
+fn length(size: ts::Node<'_>, struct_name: &str, target: &Target, content: &[u8]) -> Length {
+    let mut size = size;
+    while size.kind() == "parenthesized_expression" {
+        match size.named_child(0) {
+            Some(inner) => size = inner,
+            None => break,
+        }
+    }
+    match size.kind() {
+        "number_literal" => size
+            .utf8_text(content)
+            .ok()
+            .and_then(parse_number)
+            .map_or(Length::Expression, Length::Constant),
+        "sizeof_expression" if sizeof_target(size, struct_name, target, content) => {
+            Length::SizeofTarget
+        }
+        _ => Length::Expression,
+    }
+}
+
+/// Whether a `sizeof` is of the destination: the struct or a dereferenced
+/// pointer to it for the object, the same member for a field.
+fn sizeof_target(sizeof: ts::Node<'_>, struct_name: &str, target: &Target, content: &[u8]) -> bool {
+    if let Some(descriptor) = sizeof.child_by_field_name("type") {
+        return matches!(target, Target::Object)
+            && descriptor.child_by_field_name("declarator").is_none()
+            && descriptor
+                .child_by_field_name("type")
+                .and_then(|ty| pointee::struct_name(ty, content))
+                == Some(struct_name);
+    }
+    let Some(mut value) = sizeof.child_by_field_name("value") else {
+        return false;
+    };
+    while value.kind() == "parenthesized_expression" {
+        match value.named_child(0) {
+            Some(inner) => value = inner,
+            None => return false,
+        }
+    }
+    match target {
+        Target::Object => pointee::resolve(value, content) == Some((struct_name, 0)),
+        Target::Field(field) => usercopy::field_of(value, content)
+            .is_some_and(|(name, member)| name == struct_name && &member == field),
+    }
+}
+
+/// The value of a C integer literal, decimal, hex or octal with any suffix.
+fn parse_number(literal: &str) -> Option<usize> {
+    let digits = literal.trim_end_matches(['u', 'U', 'l', 'L']);
+    if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        return usize::from_str_radix(hex, 16).ok();
+    }
+    match digits.strip_prefix('0') {
+        Some(octal) if !octal.is_empty() => usize::from_str_radix(octal, 8).ok(),
+        _ => digits.parse().ok(),
+    }
+}
+
+/// A bulk write as the database records it.
+pub(crate) struct Row {
+    pub struct_name: String,
+    /// The path as it would have been printed.
+    pub path: String,
+    pub line: usize,
+    pub function: Option<String>,
+    /// `memcpy`, `memmove` or `memset`.
+    pub op: &'static str,
+    pub call: String,
+    /// The member written to, None for the whole object.
+    pub field: Option<String>,
+    /// The length argument as written.
+    pub size: String,
+    /// `exact`, `within`, `exceeds` or `may_exceed`.
+    pub bound: &'static str,
+    /// By how many bytes an `exceeds` write runs past the object.
+    pub exceeds_by: Option<usize>,
+}
+
+/// The bulk writes found, for the database, bounded with the layouts
+/// `layout` looks up.
+pub(crate) fn rows(layout: impl Fn(&str) -> Option<Layout>) -> Vec<Row> {
+    let mut rows = vec![];
+    for (struct_name, writes) in WRITES.lock().unwrap().iter() {
+        let layout = layout(struct_name);
+        for write in writes {
+            let (bound, exceeds_by) = match write.bound(layout.as_ref()) {
+                Bound::Exact => ("exact", None),
+                Bound::Within => ("within", None),
+                Bound::Exceeds(by) => ("exceeds", Some(by)),
+                Bound::MayExceed => ("may_exceed", None),
+            };
+            rows.push(Row {
+                struct_name: struct_name.clone(),
+                path: output::display_path(&write.path),
+                line: write.line,
+                function: write.function.clone(),
+                op: write.op.name(),
+                call: write.call.clone(),
+                field: match &write.target {
+                    Target::Object => None,
+                    Target::Field(name) => Some(name.clone()),
+                },
+                size: write.size.clone(),
+                bound,
+                exceeds_by,
+            });
+        }
+    }
+    rows.sort_by(|a, b| (&a.path, a.line).cmp(&(&b.path, b.line)));
+    rows
+}
+
+/// Print a section listing the bulk writes found into each struct, with how
+/// their length compares with the object. `layout` looks up the struct's
+/// members for the offsets of fields written to.
+pub fn print_report(layout: impl Fn(&str) -> Option<Layout>) {
+    for (struct_name, writes) in WRITES.lock().unwrap().iter() {
+        println!("======== Bulk writes to: struct {struct_name} ========\n");
+        let layout = layout(struct_name);
+        let mut writes: Vec<&BulkWrite> = writes.iter().collect();
+        writes.sort_by(|a, b| (&a.path, a.line).cmp(&(&b.path, b.line)));
+
+        for write in writes {
+            let target = usercopy::describe(&write.target, layout.as_ref());
+            let effect = match write.op {
+                Op::Memset => format!("sets {} bytes of {target}", write.size),
+                Op::Memcpy | Op::Memmove => format!("copies {} bytes into {target}", write.size),
+            };
+            let function = write
+                .function
+                .as_deref()
+                .map(|function| format!(" in {function}"))
+                .unwrap_or_default();
+            println!(
+                "{}:{}{function}: {}, {effect}, {}",
+                output::display_path(&write.path),
+                write.line,
+                write.call,
+                write.bound(layout.as_ref())
+            );
+        }
+        println!();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::Member;
+
+    const SOURCE: &str = "
+void foo_reset(struct foo *p, struct bar *q, const char *src, size_t n)
+{
+	memset(p, 0, sizeof(*p));
+	memset(p, 0, sizeof(struct foo));
+	memcpy(p->name, src, sizeof(p->name));
+	memcpy(&p->len, src, sizeof(p->name));
+	memcpy(p->name, src, 16);
+	memcpy(p->name, src, 0x20UL);
+	memmove(&p->len, src, 010);
+	memset(p, 0, n);
+	memcpy(q, src, 8);
+}
+";
+
+    /// `struct foo`, 32 bytes: `len` at 0-7 and `name` at 8-31.
+    fn foo() -> Layout {
+        let member = |name: &str, offset, size| Member {
+            decl: String::new(),
+            name: name.to_string(),
+            size,
+            offset,
+            depth: 1,
+        };
+        Layout {
+            members: vec![member("len", 0, 8), member("name", 8, 24)],
+            size: Some(32),
+        }
+    }
+
+    /// The writes into `struct foo` in `content`, in order.
+    fn writes(content: &str) -> Vec<BulkWrite> {
+        let mut parser = ts::Parser::new();
+        parser.set_language(ts_c::language()).unwrap();
+        let tree = parser.parse(content, None).unwrap();
+        let mut writes = writes_in(
+            tree.root_node(),
+            content.as_bytes(),
+            Path::new("foo.c"),
+            |name| name == "foo",
+        );
+        writes.sort_by_key(|(_, write)| write.line);
+        writes
+            .into_iter()
+            .map(|(struct_name, write)| {
+                assert_eq!(struct_name, "foo");
+                write
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lengths_are_classified_against_the_destination() {
+        let found: Vec<(Op, String, Length)> = writes(SOURCE)
+            .into_iter()
+            .map(|write| (write.op, write.size, write.length))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (Op::Memset, "sizeof(*p)".to_string(), Length::SizeofTarget),
+                (
+                    Op::Memset,
+                    "sizeof(struct foo)".to_string(),
+                    Length::SizeofTarget
+                ),
+                (
+                    Op::Memcpy,
+                    "sizeof(p->name)".to_string(),
+                    Length::SizeofTarget
+                ),
+                // the size of another field than the one written to
+                (
+                    Op::Memcpy,
+                    "sizeof(p->name)".to_string(),
+                    Length::Expression
+                ),
+                (Op::Memcpy, "16".to_string(), Length::Constant(16)),
+                (Op::Memcpy, "0x20UL".to_string(), Length::Constant(32)),
+                (Op::Memmove, "010".to_string(), Length::Constant(8)),
+                (Op::Memset, "n".to_string(), Length::Expression),
+            ]
+        );
+    }
+
+    #[test]
+    fn bounds_against_the_layout() {
+        let layout = foo();
+        let bounds: Vec<Bound> = writes(SOURCE)
+            .iter()
+            .map(|write| write.bound(Some(&layout)))
+            .collect();
+        assert_eq!(
+            bounds,
+            [
+                Bound::Exact,
+                Bound::Exact,
+                Bound::Exact,
+                Bound::MayExceed,
+                // 16 bytes from offset 8 of 32
+                Bound::Within,
+                Bound::Exceeds(8),
+                Bound::Within,
+                Bound::MayExceed,
+            ]
+        );
+    }
+
+    #[test]
+    fn bounds_without_a_layout() {
+        let bounds: Vec<Bound> = writes(SOURCE)
+            .iter()
+            .map(|write| write.bound(None))
+            .collect();
+        // only a sizeof of the destination is known to fit
+        assert_eq!(
+            bounds,
+            [
+                Bound::Exact,
+                Bound::Exact,
+                Bound::Exact,
+                Bound::MayExceed,
+                Bound::MayExceed,
+                Bound::MayExceed,
+                Bound::MayExceed,
+                Bound::MayExceed,
+            ]
+        );
+    }
+
+    #[test]
+    fn constant_writes_into_the_object() {
+        let layout = foo();
+        let bound = |content: &str| writes(content)[0].bound(Some(&layout));
+        let with = |len: &str| format!("void f(struct foo *p) {{ memset(p, 0, {len}); }}");
+        assert_eq!(bound(&with("32")), Bound::Within);
+        assert_eq!(bound(&with("33")), Bound::Exceeds(1));
+        assert_eq!(bound(&with("(64)")), Bound::Exceeds(32));
+    }
+
+    #[test]
+    fn number_literals() {
+        assert_eq!(parse_number("0"), Some(0));
+        assert_eq!(parse_number("128"), Some(128));
+        assert_eq!(parse_number("128u"), Some(128));
+        assert_eq!(parse_number("0x80"), Some(128));
+        assert_eq!(parse_number("0X80ULL"), Some(128));
+        assert_eq!(parse_number("0200"), Some(128));
+        assert_eq!(parse_number("0200L"), Some(128));
+        // not a valid octal digit
+        assert_eq!(parse_number("09"), None);
+        assert_eq!(parse_number("BUF_SIZE"), None);
+    }
+}
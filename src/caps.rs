@@ -0,0 +1,96 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tree_sitter as ts;
+
+// set by `--annotate-caps`
+static ANNOTATE_CAPS: AtomicBool = AtomicBool::new(false);
+
+/// Capability checks which don't name the capability they check.
+const IMPLIED: &[(&str, &str)] = &[
+    ("bpf_capable", "CAP_BPF"),
+    ("perfmon_capable", "CAP_PERFMON"),
+    ("checkpoint_restore_ns_capable", "CAP_CHECKPOINT_RESTORE"),
+];
+
+pub fn enable() {
+    ANNOTATE_CAPS.store(true, Ordering::SeqCst);
+}
+
+pub fn enabled() -> bool {
+    ANNOTATE_CAPS.load(Ordering::SeqCst)
+}
+
+/// The capabilities checked in a function before an allocation call, by
+/// `capable()`, `ns_capable()`, `sockopt_ns_capable()` and the other
+/// `*capable*` and `has_*capability*` helpers. This is a syntactic,
+/// function-local heuristic: a check on another path through the function is
+/// counted too, and a check in a caller is missed.
+pub fn guards(definition: ts::Node<'_>, call: ts::Node<'_>, content: &[u8]) -> Vec<String> {
+    let mut caps = vec![];
+    let Some(body) = definition.child_by_field_name("body") else {
+        return caps;
+    };
+    let mut stack = vec![body];
+    while let Some(node) = stack.pop() {
+        if node.start_byte() >= call.start_byte() {
+            continue;
+        }
+        let mut cursor = node.walk();
+        stack.extend(node.named_children(&mut cursor));
+        if node.kind() != "call_expression" {
+            continue;
+        }
+        let Some(name) = node
+            .child_by_field_name("function")
+            .and_then(|function| function.utf8_text(content).ok())
+        else {
+            continue;
+        };
+        if !is_capability_check(name) {
+            continue;
+        }
+
+        let mut named = node
+            .child_by_field_name("arguments")
+            .map(|args| capability_names(args, content))
+            .unwrap_or_default();
+        if named.is_empty() {
+            named.extend(
+                IMPLIED
+                    .iter()
+                    .filter(|&&(check, _)| check == name)
+                    .map(|&(_, cap)| cap.to_string()),
+            );
+        }
+        for cap in named {
+            if !caps.contains(&cap) {
+                caps.push(cap);
+            }
+        }
+    }
+    caps.sort();
+    caps
+}
+
+fn is_capability_check(name: &str) -> bool {
+    name.split('_').any(|part| part == "capable")
+        || (name.starts_with("has_") && name.ends_with("capability"))
+}
+
+/// The `CAP_*` constants in a call's arguments.
+fn capability_names(args: ts::Node<'_>, content: &[u8]) -> Vec<String> {
+    let mut names = vec![];
+    let mut stack = vec![args];
+    while let Some(node) = stack.pop() {
+        let mut cursor = node.walk();
+        stack.extend(node.named_children(&mut cursor));
+        if node.kind() == "identifier" {
+            if let Ok(name) = node.utf8_text(content) {
+                if name.starts_with("CAP_") {
+                    names.push(name.to_string());
+                }
+            }
+        }
+    }
+    names
+}
@@ -0,0 +1,142 @@
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::alloc;
+use crate::cli::ScanArgs;
+
+const MAGIC: &str = "kheap_sift checkpoint v1";
+
+/// A record of the files a scan has finished with, so an interrupted scan can
+/// be resumed where it left off. The file starts with a header identifying
+/// the options and file list of the scan, followed by one completed path per
+/// line.
+pub struct Checkpoint {
+    file: Mutex<File>,
+    completed: HashSet<PathBuf>,
+}
+
+impl Checkpoint {
+    /// Open the checkpoint at `path`, creating it if it doesn't exist yet. An
+    /// existing checkpoint is only resumed from if it was written by a scan
+    /// with the same options over the same files.
+    pub fn open(path: &Path, args: &ScanArgs, files: &[PathBuf]) -> anyhow::Result<Self> {
+        let header = format!(
+            "{MAGIC} config={:016x} files={:016x}",
+            config_hash(args),
+            files_hash(files)
+        );
+
+        let contents = match std::fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut completed = HashSet::new();
+        let mut lines = contents.split(|&b| b == b'\n');
+        match lines.next().filter(|line| !line.is_empty()) {
+            None => {
+                let mut file = File::create(path)?;
+                writeln!(file, "{header}")?;
+            }
+            Some(line) if line == header.as_bytes() => {
+                completed.extend(
+                    lines
+                        .filter(|line| !line.is_empty())
+                        .map(|line| PathBuf::from(OsStr::from_bytes(line))),
+                );
+            }
+            Some(line) if line.starts_with(MAGIC.as_bytes()) => anyhow::bail!(
+                "checkpoint {} was written by a scan with different options or files, \
+                 remove it to start over",
+                path.display()
+            ),
+            Some(_) => anyhow::bail!("{} is not a kheap_sift checkpoint", path.display()),
+        }
+
+        let file = OpenOptions::new().append(true).open(path)?;
+        Ok(Checkpoint {
+            file: Mutex::new(file),
+            completed,
+        })
+    }
+
+    /// The number of files a previous run finished with.
+    pub fn completed(&self) -> usize {
+        self.completed.len()
+    }
+
+    pub fn is_done(&self, path: &Path) -> bool {
+        self.completed.contains(path)
+    }
+
+    /// Record that `path` has been scanned. Written through immediately, so
+    /// it survives the process being killed.
+    pub fn complete(&self, path: &Path) -> anyhow::Result<()> {
+        let mut line = path.as_os_str().as_bytes().to_vec();
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&line)?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+/// FNV-1a, used instead of the std hasher as the hashes must stay the same
+/// across builds.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Hash everything that decides which sites a scan reports, so a checkpoint
+/// isn't resumed by a scan that would report different ones.
+fn config_hash(args: &ScanArgs) -> u64 {
+    let filter = &args.filter;
+    let config = format!(
+        "{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}",
+        (&args.dwarf.vmlinux_path, &args.dwarf.types_from),
+        filter.min_size,
+        filter.max_size,
+        filter.structs,
+        filter.struct_regex,
+        filter.exclude_struct,
+        filter.neighbors,
+        args.flags,
+        args.flags_exclude,
+        args.gfp,
+        args.gfp_include_unknown,
+        args.zeroing(),
+        args.only_elastic,
+        args.only_escaping,
+        args.size_unrelated(),
+        args.exclude_caps,
+        args.functions,
+        args.size_expr,
+        args.query,
+        args.extra_query,
+        args.allocator_config,
+        args.first_match,
+        args.no_dedup,
+        args.relative_paths,
+        alloc::ALLOCATORS,
+    );
+    fnv1a(config.as_bytes())
+}
+
+fn files_hash(files: &[PathBuf]) -> u64 {
+    let mut sorted: Vec<&PathBuf> = files.iter().collect();
+    sorted.sort();
+    let mut bytes = vec![];
+    for file in sorted {
+        bytes.extend_from_slice(file.as_os_str().as_bytes());
+        bytes.push(0);
+    }
+    fnv1a(&bytes)
+}
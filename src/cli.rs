@@ -0,0 +1,1593 @@
+use std::ffi::OsString;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+
+use clap::error::ErrorKind;
+use clap::ArgAction::{self, Append};
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use regex::Regex;
+
+use crate::alloc::Zeroing;
+use crate::gfp::GfpSpec;
+use crate::{alloc, archive, query};
+
+#[derive(Parser)]
+#[command(about = "A utility for finding Linux kernel heap objects of desired sizes.")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+// parsed once at startup, the size of the scan variant doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand)]
+pub enum Command {
+    /// Search a source tree for allocation sites of the selected structs.
+    #[command(allow_missing_positional = true)]
+    Scan(ScanArgs),
+    /// List the selected structs, without needing a source tree.
+    ListStructs(ListStructsArgs),
+    /// Print what is known about a single struct.
+    #[command(allow_missing_positional = true)]
+    Info(InfoArgs),
+}
+
+/// Where type information is loaded from, shared by every subcommand.
+#[derive(Args)]
+pub struct DwarfArgs {
+    /// The path to the vmlinux file, or a compressed kernel image (bzImage,
+    /// vmlinuz) to extract it from.
+    #[clap(
+        required_unless_present = "types_from",
+        help = "The path to the vmlinux file, or a compressed kernel image to extract it from. \
+                May be left out with --types-from."
+    )]
+    pub vmlinux_path: Option<PathBuf>,
+
+    /// Read the structs from BTF or a JSON dump of it instead of the DWARF.
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "Read the struct names, sizes and layouts from this file instead \
+                of the vmlinux DWARF: raw BTF like /sys/kernel/btf/vmlinux, an \
+                ELF with a .BTF section like pahole -J leaves, or the output \
+                of 'bpftool -j btf dump file'. Struct definition paths for \
+                --emit-tags and --resolve-symbols still need the vmlinux."
+    )]
+    pub types_from: Option<PathBuf>,
+
+    /// Fetch the debug info of a vmlinux without it from debuginfod.
+    #[clap(
+        long,
+        action,
+        help = "When the vmlinux has no DWARF, look its debug info up by \
+                build-id: in the debuginfod client cache, from the \
+                DEBUGINFOD_URLS servers, caching the download, and in \
+                /usr/lib/debug/.build-id. Fetching needs a build with the \
+                debuginfod feature."
+    )]
+    pub debuginfod: bool,
+}
+
+/// Which structs to work on, shared by the subcommands working on a set of
+/// structs so that they are always selected the same way.
+#[derive(Args)]
+pub struct StructFilterArgs {
+    /// The smallest struct size to select (inclusive).
+    #[clap(
+        long,
+        value_name = "BYTES",
+        help = "Only select structs of at least this many bytes. Defaults to 0."
+    )]
+    pub min_size: Option<usize>,
+
+    /// The largest struct size to select (inclusive).
+    #[clap(
+        long,
+        value_name = "BYTES",
+        help = "Only select structs of at most this many bytes. Defaults to no limit."
+    )]
+    pub max_size: Option<usize>,
+
+    /// Select every struct when nothing else narrows the selection down.
+    #[clap(
+        long,
+        action,
+        help = "Allow selecting every struct in the vmlinux. Without it, one of \
+                --min-size, --max-size, --struct, --struct-regex or \
+                --neighbors is required, as an unconstrained selection is \
+                enormous."
+    )]
+    pub all_structs: bool,
+
+    /// Only select the named struct, can be specified multiple times.
+    #[clap(
+        long = "struct",
+        value_name = "NAME",
+        action = Append,
+        help = "Only select the named struct, can be specified multiple \
+                times. The size bounds further filter the structs when given."
+    )]
+    pub structs: Vec<String>,
+
+    /// Only select structs whose names match this regex.
+    #[clap(
+        long,
+        value_name = "REGEX",
+        help = "Only select structs whose names match this regex, e.g. '^nft_'."
+    )]
+    pub struct_regex: Option<String>,
+
+    /// Skip structs whose names match this regex, takes precedence over
+    /// --struct and --struct-regex.
+    #[clap(
+        long,
+        value_name = "REGEX",
+        help = "Skip structs whose names match this regex, takes precedence \
+                over --struct and --struct-regex."
+    )]
+    pub exclude_struct: Option<String>,
+
+    /// Only select structs in the same kmalloc cache as the named struct.
+    #[clap(
+        long,
+        value_name = "STRUCT",
+        help = "Only select structs served from the same kmalloc cache as \
+                this struct, as spray or victim candidates next to it. The \
+                struct itself is included for reference, and its size and \
+                slack are printed to stderr."
+    )]
+    pub neighbors: Option<String>,
+}
+
+#[derive(Args)]
+pub struct ScanArgs {
+    #[command(flatten)]
+    pub dwarf: DwarfArgs,
+
+    /// The paths to the source code directories or files, separated by
+    /// colons, e.g. the kernel tree and an out-of-tree module.
+    #[clap(
+        value_delimiter = ':',
+        action = ArgAction::Set,
+        num_args = 1,
+        required = true,
+        help = "The path to the Linux source code directory, a single \
+                source file, or a .tar, .tar.gz or .tar.xz archive of the \
+                source. Several paths can be separated by colons, e.g. the \
+                kernel tree and an out-of-tree module."
+    )]
+    pub source_path: Vec<PathBuf>,
+
+    #[command(flatten)]
+    pub filter: StructFilterArgs,
+
+    /// Silence most output, only print struct names when allocation sites are
+    /// found.
+    #[clap(
+        long,
+        action,
+        help = "Silence most output, only print struct names when allocation \
+                sites are found."
+    )]
+    pub quiet: bool,
+
+    /// Browse the results in a terminal UI once the scan completes.
+    #[clap(
+        long,
+        action,
+        conflicts_with = "quiet",
+        help = "Browse the results in a terminal UI once the scan completes, \
+                listing the structs found on the left and their allocation \
+                sites on the right."
+    )]
+    pub tui: bool,
+
+    /// Print additional diagnostics to stderr.
+    #[clap(long, action, help = "Print additional diagnostics to stderr.")]
+    pub verbose: bool,
+
+    /// Regex filter on the allocation flags argument.
+    #[clap(long, help = "Regex filter on the allocation flags argument.")]
+    pub flags: Option<String>,
+
+    /// Regex on the allocation flags argument to drop sites by.
+    #[clap(
+        long,
+        value_name = "REGEX",
+        help = "Drop sites whose allocation flags match this regex, e.g. \
+                'GFP_ATOMIC'. Only applied to literal flag expressions, sites \
+                passing their flags in a variable are kept."
+    )]
+    pub flags_exclude: Option<String>,
+
+    /// Filter on what the allocation flags mean rather than how they are
+    /// written.
+    #[clap(
+        long,
+        value_name = "SPEC",
+        help = "Filter on what the allocation flags mean rather than how they \
+                are written. Comma separated terms which must all hold: a GFP \
+                flag whose bits must all be set, optionally written as \
+                'requires:FLAG', or one of the categories 'sleeping', 'atomic' \
+                and 'account', any of which may be negated with '!', e.g. \
+                'requires:GFP_KERNEL,!__GFP_HIGH'."
+    )]
+    pub gfp: Option<String>,
+
+    /// Report sites whose flags --gfp can't classify.
+    #[clap(
+        long,
+        action,
+        requires = "gfp",
+        help = "Report sites whose flags --gfp can't classify, e.g. ones \
+                passed in a variable, instead of dropping them."
+    )]
+    pub gfp_include_unknown: bool,
+
+    /// Only report sites whose memory starts out zeroed.
+    #[clap(
+        long,
+        action,
+        conflicts_with = "only_unzeroed",
+        help = "Only report sites whose memory starts out zeroed: kzalloc, \
+                kvzalloc, kcalloc and the like, or flags including __GFP_ZERO."
+    )]
+    pub only_zeroed: bool,
+
+    /// Only report sites whose memory isn't zeroed.
+    #[clap(
+        long,
+        action,
+        help = "Only report sites whose memory isn't zeroed: plain kmalloc \
+                and the like with literal flags lacking __GFP_ZERO. Sites \
+                passing their flags in a variable are dropped by both filters."
+    )]
+    pub only_unzeroed: bool,
+
+    /// Only report sites whose size isn't fixed at compile time.
+    #[clap(
+        long,
+        action,
+        help = "Only report elastic sites, whose size isn't fixed at compile \
+                time, like 'sizeof(*p) + len' or 'struct_size(p, entries, n)'. \
+                As these can grow a struct into a larger cache, structs \
+                smaller than --min-size are selected too."
+    )]
+    pub only_elastic: bool,
+
+    /// Only report sites whose object isn't freed in the same function.
+    #[clap(
+        long,
+        action,
+        help = "Only report sites whose object escapes the function \
+                allocating it, dropping temporary buffers which are kfree'd \
+                or kvfree'd again before it returns."
+    )]
+    pub only_escaping: bool,
+
+    /// Drop the sites whose size doesn't refer to the struct.
+    #[clap(
+        long,
+        action,
+        conflicts_with = "only_size_unrelated",
+        help = "Drop the sites whose size refers to neither the struct nor \
+                the variable it is stored in, like 'p = kmalloc(len, ...)', \
+                as their object may be in any cache."
+    )]
+    pub exclude_size_unrelated: bool,
+
+    /// Only report the sites whose size doesn't refer to the struct.
+    #[clap(
+        long,
+        action,
+        help = "Only report the sites whose size refers to neither the \
+                struct nor the variable it is stored in, possibly \
+                undersized allocations."
+    )]
+    pub only_size_unrelated: bool,
+
+    /// Drop sites guarded by any of these capabilities.
+    #[clap(
+        long,
+        value_name = "CAPS",
+        value_delimiter = ',',
+        help = "Drop sites whose function checks any of these capabilities \
+                before the allocation, e.g. 'CAP_SYS_ADMIN,CAP_NET_ADMIN', as \
+                unprivileged users can't reach them. Found the same way as \
+                for --annotate-caps."
+    )]
+    pub exclude_caps: Vec<String>,
+
+    /// Only report sites in functions whose names match this regex, can be
+    /// specified multiple times.
+    #[clap(
+        long = "function",
+        value_name = "REGEX",
+        action = Append,
+        help = "Only report sites in functions whose names match this regex, \
+                e.g. '_ioctl$' or '^nl80211_', can be specified multiple \
+                times to keep the sites matching any of them. --stats counts \
+                the sites dropped."
+    )]
+    pub functions: Vec<String>,
+
+    /// Regex filter on the allocation size argument.
+    #[clap(
+        long,
+        value_name = "REGEX",
+        help = "Regex filter on the allocation size expression, e.g. '\\+' \
+                for sites adding to a sizeof. For the array allocators it is \
+                matched against 'COUNT * SIZE'."
+    )]
+    pub size_expr: Option<String>,
+
+    /// Use the tree-sitter query in this file instead of the built-in one.
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "Use the tree-sitter query in this file to find allocation \
+                sites instead of the built-in one. It must have the captures \
+                @function.def, @struct.name, @declaration.declarator, \
+                @assignment.lvalue, @assignment.call, @assignment.function and \
+                @assignment.args, and may capture @size, @count and @flags to \
+                take those arguments from somewhere other than the allocator \
+                table. The built-in query's {declaration}, {assignment} and \
+                {allocators} placeholders are filled in."
+    )]
+    pub query: Option<PathBuf>,
+
+    /// Use the tree-sitter query in this file in addition to the built-in one,
+    /// can be specified multiple times.
+    #[clap(
+        long,
+        value_name = "PATH",
+        action = Append,
+        help = "Use the tree-sitter query in this file in addition to the \
+                built-in one, or the one given with --query. Can be specified \
+                multiple times, takes the same captures as --query."
+    )]
+    pub extra_query: Vec<PathBuf>,
+
+    /// Add allocators for the files matching a glob, from a TOML file.
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "Add allocators for the files matching a glob from this TOML \
+                file, for the allocation shims of vendor and out-of-tree \
+                code. Each '[allocators.\"drivers/vendor/**\"]' section has \
+                entries like 'osal_mem_alloc = { size = 0 }', giving the \
+                indices of the size argument and of the optional count and \
+                flags arguments, and 'zeroes = true' for allocators which \
+                zero the memory. --verbose prints the profile each file is \
+                scanned with."
+    )]
+    pub allocator_config: Option<PathBuf>,
+
+    /// Glob to exclude files based on, can be specified multiple times.
+    /// Relative patterns are matched against paths relative to the source
+    /// directory, absolute patterns against the full path.
+    #[clap(
+       long,
+       action=Append,
+       help = "Glob to exclude files based on, can be specified multiple times. \
+               Relative patterns (e.g. 'drivers/**') are matched against paths \
+               relative to the source directory, absolute patterns against the \
+               full path."
+    )]
+    pub exclude: Vec<String>,
+
+    /// Glob to include files based on, can be specified multiple times. When
+    /// given, only files matching one of them are scanned.
+    #[clap(
+       long,
+       action=Append,
+       help = "Glob to include files based on, can be specified multiple times. \
+               When given, only files matching at least one include glob and \
+               no exclude glob are scanned. Matched like --exclude."
+    )]
+    pub include: Vec<String>,
+
+    /// Skip directories with this name at any depth, can be specified
+    /// multiple times.
+    #[clap(
+        long,
+        value_name = "NAME",
+        action = Append,
+        help = "Skip every directory with this name at any depth, e.g. \
+                'selftests', can be specified multiple times."
+    )]
+    pub exclude_dir: Vec<String>,
+
+    /// Scan the kernel directories which are skipped by default.
+    #[clap(
+        long,
+        action,
+        help = "Scan the directories skipped by default: Documentation, \
+                tools, samples and scripts at the top of each source \
+                directory, and the arch/ trees other than the vmlinux's \
+                architecture."
+    )]
+    pub no_default_excludes: bool,
+
+    /// Print the effective configuration and exit.
+    #[clap(
+        long,
+        action,
+        help = "Print the effective configuration, including the directories \
+                skipped by default, and exit without scanning."
+    )]
+    pub print_config: bool,
+
+    /// Scan exactly the files listed in this file, one per line, instead of
+    /// searching the source directory.
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with = "files_from0",
+        help = "Scan exactly the files listed in this file, one per line, \
+                instead of searching the source directory. Use '-' for stdin, \
+                relative paths are resolved against the source directory."
+    )]
+    pub files_from: Option<PathBuf>,
+
+    /// Like --files-from, with the listed files separated by NUL bytes.
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "Like --files-from, with the listed files separated by NUL bytes."
+    )]
+    pub files_from0: Option<PathBuf>,
+
+    /// Scan every listed file regardless of its extension.
+    #[clap(
+        long,
+        action,
+        help = "Scan every file given with --files-from regardless of its \
+                extension, not just .c and .h files."
+    )]
+    pub no_ext_filter: bool,
+
+    /// Only scan files which appear in this JSON compilation database.
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "Only scan files which appear in this compile_commands.json, \
+                e.g. one written by scripts/clang-tools/gen_compile_commands.py, \
+                and note for each match whether its file is in the build."
+    )]
+    pub compile_commands: Option<PathBuf>,
+
+    /// Also scan headers under the include directories of the compilation
+    /// database.
+    #[clap(
+        long,
+        action,
+        requires = "compile_commands",
+        help = "With --compile-commands, also scan headers under the include \
+                directories the files are compiled with."
+    )]
+    pub compile_commands_headers: bool,
+
+    /// Only scan files which are probably built for this kernel .config.
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "Only scan files which are probably built for this kernel \
+                .config, judged from the obj-$(CONFIG_...) lines of the \
+                Makefiles in the source tree. Files the Makefiles don't \
+                clearly exclude are kept. The #if conditions guarding each \
+                site are evaluated against it too."
+    )]
+    pub kconfig: Option<PathBuf>,
+
+    /// Drop the sites whose #if conditions don't hold for --kconfig.
+    #[clap(
+        long,
+        action,
+        requires = "kconfig",
+        help = "Drop the sites whose #if, #ifdef and #ifndef conditions don't \
+                hold for the --kconfig .config, or can't be told from it as \
+                they depend on something other than config options."
+    )]
+    pub only_enabled: bool,
+
+    /// Record the files scanned so far in this file, and skip the files it
+    /// lists when it exists.
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "Record the files scanned so far in this file, so an interrupted \
+                scan run again with the same checkpoint picks up where it left \
+                off. Refuses to resume a checkpoint written with different \
+                options or files."
+    )]
+    pub checkpoint: Option<PathBuf>,
+
+    /// Only collect and filter the files and structs, then report what would
+    /// be scanned.
+    #[clap(
+        long,
+        value_name = "MODE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "count",
+        help = "Collect and filter the files and structs like a scan would, \
+                print how many there are and exit without parsing anything. \
+                With --dry-run=list the files are listed too."
+    )]
+    pub dry_run: Option<DryRun>,
+
+    /// Number of threads to scale up to.
+    #[clap(
+        long,
+        help = "Number of threads to scale up to, the default for --io-threads \
+                and --parse-threads."
+    )]
+    pub threads: Option<usize>,
+
+    /// Number of source files read concurrently.
+    #[clap(
+        long,
+        value_name = "N",
+        help = "Number of source files read concurrently, defaults to \
+                --threads. Worth raising on network filesystems."
+    )]
+    pub io_threads: Option<usize>,
+
+    /// Number of files parsed and queried concurrently.
+    #[clap(
+        long,
+        value_name = "N",
+        help = "Number of files parsed and queried concurrently, defaults to \
+                --threads."
+    )]
+    pub parse_threads: Option<usize>,
+
+    /// Show a progress bar on stderr while scanning.
+    #[clap(
+        long,
+        action,
+        help = "Show a progress bar on stderr while scanning, ignored when \
+                stderr is not a terminal."
+    )]
+    pub progress: bool,
+
+    /// Maximum number of seconds to spend parsing and querying a single file.
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Maximum number of seconds to spend parsing and querying a \
+                single file, files exceeding it are skipped and reported."
+    )]
+    pub file_timeout: Option<u64>,
+
+    /// Maximum number of file contents held in memory at once, defaults to the
+    /// I/O and parse thread counts combined.
+    #[clap(
+        long,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "Maximum number of file contents held in memory at once, \
+                defaults to --io-threads plus --parse-threads."
+    )]
+    pub max_buffered_files: Option<usize>,
+
+    /// Only report the first allocation site found for each struct.
+    #[clap(
+        long,
+        action,
+        help = "Only report the first allocation site found for each struct, \
+                the scan stops once every struct has one."
+    )]
+    pub first_match: bool,
+
+    /// Abort the whole scan as soon as one file fails to be processed.
+    #[clap(
+        long,
+        action,
+        help = "Abort the whole scan as soon as one file fails to be processed."
+    )]
+    pub fail_fast: bool,
+
+    /// Follow symbolic links while collecting source files.
+    #[clap(
+        long,
+        action,
+        help = "Follow symbolic links while collecting source files, files \
+                reachable through several paths are only scanned once."
+    )]
+    pub follow_symlinks: bool,
+
+    /// How many directories deep to search the source directories.
+    #[clap(
+        long,
+        value_name = "DEPTH",
+        help = "How many directories deep to search the source directories, \
+                defaults to unlimited, or 64 with --follow-symlinks."
+    )]
+    pub max_depth: Option<usize>,
+
+    /// Print every file which was skipped or failed, grouped by reason.
+    #[clap(
+        long,
+        action,
+        help = "Print every file which was skipped or failed to stderr once \
+                the scan completes, grouped by the reason: excluded by a glob, \
+                --compile-commands or --kconfig, already scanned according to \
+                --checkpoint, not mentioning any --struct, or failing to read, \
+                parse or finish within --file-timeout."
+    )]
+    pub report_skipped: bool,
+
+    /// Print statistics about the scan to stderr once it completes.
+    #[clap(
+        long,
+        action,
+        help = "Print statistics about the scan to stderr once it completes."
+    )]
+    pub stats: bool,
+
+    /// Report every match even when the same allocation site is found at
+    /// several paths.
+    #[clap(
+        long,
+        action,
+        help = "Report every match even when the same allocation site is \
+                found at several paths, e.g. copies of a header."
+    )]
+    pub no_dedup: bool,
+
+    /// Print paths relative to the source directory instead of as absolute
+    /// paths.
+    #[clap(
+        long,
+        action,
+        help = "Print paths relative to the source directory instead of as \
+                absolute paths."
+    )]
+    pub relative_paths: bool,
+
+    /// Print the matches grouped under a heading per kernel subsystem.
+    #[clap(
+        long,
+        value_enum,
+        conflicts_with_all = ["tui", "quiet"],
+        help = "Hold the matches back until the scan completes and print them \
+                grouped under a heading per kernel subsystem, its top-level \
+                directory like 'net/', or the top two under drivers/ and \
+                arch/ like 'drivers/gpu/'."
+    )]
+    pub group_by: Option<GroupBy>,
+
+    /// List where the structs with an allocation site are freed.
+    #[clap(
+        long,
+        action,
+        help = "After the scan, list the kfree, kvfree, kfree_rcu and \
+                kmem_cache_free calls freeing each struct an allocation site \
+                was found for, going by the declared or cast type of the \
+                pointer freed."
+    )]
+    pub track_frees: bool,
+
+    /// List where user-controlled data is written into the selected structs.
+    #[clap(
+        long,
+        action,
+        help = "After the scan, list the writes of user-controlled data into \
+                each selected struct and the field and offsets they land in: \
+                copy_from_user into an object or field, memdup_user results \
+                stored in a field, and memcpy from a buffer filled from user \
+                memory in the same function."
+    )]
+    pub track_user_writes: bool,
+
+    /// List where the selected structs are copied out to user memory.
+    #[clap(
+        long,
+        action,
+        help = "After the scan, list the copy_to_user calls copying from each \
+                selected struct or one of its fields, and the put_user calls \
+                storing one of its fields, as candidates for leaking its \
+                contents. Copies of a whole object note its padding holes."
+    )]
+    pub track_user_reads: bool,
+
+    /// List the memcpy, memmove and memset calls writing into the selected
+    /// structs.
+    #[clap(
+        long,
+        action,
+        help = "After the scan, list the memcpy, memmove and memset calls \
+                whose destination is a selected struct or one of its fields, \
+                with the length written and whether it exceeds or may exceed \
+                the object, any length other than a sizeof of the destination \
+                or a constant being flagged."
+    )]
+    pub track_bulk_writes: bool,
+
+    /// Also list allocator calls sized by a selected struct which the
+    /// allocation site query doesn't match.
+    #[clap(
+        long,
+        action,
+        conflicts_with = "quiet",
+        help = "After the scan, list the allocator calls with a \
+                'sizeof(struct X)' of a selected struct in their arguments \
+                which the allocation site query doesn't match, like results \
+                stored in a field or returned directly. These loose sites are \
+                lower confidence and are left out of ranking, focus, the TUI \
+                and the database."
+    )]
+    pub loose: bool,
+
+    /// List the selected structs without any sites after the scan.
+    #[clap(
+        long,
+        action,
+        help = "After the scan, list the selected structs which had no site \
+                reported, split into those named somewhere in the scanned \
+                sources, whose allocations the queries may be missing, and \
+                those never named in them."
+    )]
+    pub coverage: bool,
+
+    /// Note which sites are in ioctl handlers or functions bound in ops
+    /// tables.
+    #[clap(
+        long,
+        action,
+        help = "Note the sites in functions which look like ioctl handlers, by \
+                name or by the unlocked_ioctl signature, and in functions \
+                bound to a field of a file_operations or proto_ops table in \
+                the same file, e.g. '.write'."
+    )]
+    pub annotate_handlers: bool,
+
+    /// Note the offset and kind of each member in the printed layouts.
+    #[clap(
+        long,
+        action,
+        help = "Note the offset and kind of each member in the printed struct \
+                layouts, e.g. '/* off 16, fnptr */'. The kinds are ptr, \
+                fnptr, refcount, flex and len? for integers named like a \
+                length or count."
+    )]
+    pub annotate_members: bool,
+
+    /// Print every argument of each site's allocation call.
+    #[clap(
+        long,
+        action,
+        help = "Print every argument of each site's allocation call on an \
+                'Args:' line under its flags, numbered from 0, e.g. \
+                'Args: [0] struct_size(p, entries, n), [1] GFP_KERNEL'."
+    )]
+    pub show_args: bool,
+
+    /// Note the capabilities checked before each site.
+    #[clap(
+        long,
+        action,
+        help = "Note the capabilities a site's function checks before the \
+                allocation with capable(), ns_capable(), \
+                sockopt_ns_capable() and the like. The check is a syntactic \
+                heuristic within the function, checks in callers are missed."
+    )]
+    pub annotate_caps: bool,
+
+    /// List the .c files including the header of each site in one.
+    #[clap(
+        long,
+        action,
+        help = "For a site in a header, like a static inline function in \
+                include/linux, list the scanned .c files which #include the \
+                header directly, the first few by name and the rest counted. \
+                Includes through other headers aren't followed."
+    )]
+    pub resolve_includers: bool,
+
+    /// Print the symbol and offset of each site's function in the vmlinux.
+    #[clap(
+        long,
+        action,
+        help = "Look up each site's function in the vmlinux DWARF by name and \
+                file, and print its symbol, low_pc and the offset of the \
+                allocation's line from the line table, e.g. \
+                'foo_ioctl+0x4c', for kprobes and breakpoints. Functions only \
+                inlined into their callers or not in the vmlinux get a \
+                warning instead."
+    )]
+    pub resolve_symbols: bool,
+
+    /// Check each site's function against the target's symbols.
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "Check each site's function against a /proc/kallsyms capture \
+                or System.map of the target kernel, and print whether it is \
+                present, missing, or ambiguous as several static functions \
+                share its name. Captures taken with kptr_restrict set, with \
+                every address zero, are checked by name only."
+    )]
+    pub kallsyms: Option<PathBuf>,
+
+    /// Drop the sites whose function isn't in --kallsyms.
+    #[clap(
+        long,
+        action,
+        requires = "kallsyms",
+        help = "Drop the sites whose function has no symbol in --kallsyms. \
+                Ambiguous functions are kept."
+    )]
+    pub only_present: bool,
+
+    /// Print the commit which last changed each site's allocation line.
+    #[clap(
+        long,
+        action,
+        help = "When the source is a git work tree, print the commit which \
+                last changed each site's allocation line: its hash, author \
+                date and subject. Each file with a site is blamed once. \
+                Sites outside a work tree, like those in an archive, are \
+                printed without."
+    )]
+    pub blame: bool,
+
+    /// Write a ctags file of the functions with sites and their structs.
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "Write an exuberant-ctags format file to this path, tagging \
+                the function each site is in and the structs allocated, the \
+                latter at their definitions in the vmlinux DWARF when the \
+                file is in the source tree."
+    )]
+    pub emit_tags: Option<PathBuf>,
+
+    /// Write a drgn script inspecting the caches of the matched structs.
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "Write a self-contained drgn script to this path which, run \
+                against a live kernel, counts the allocated objects in the \
+                kmalloc caches each matched struct's sites use, and in a \
+                cache named after the struct if there is one. Its --walk \
+                option prints the objects cast to the struct."
+    )]
+    pub emit_drgn: Option<PathBuf>,
+
+    /// Write the functions with sites to focus fuzzing on.
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with = "quiet",
+        help = "Write the functions with sites to this path, each once with \
+                the structs it allocates, for a fuzzer to focus on. Each site \
+                weighs 1 plus its --rank-sites score, by the --rank-weights \
+                table if given."
+    )]
+    pub emit_focus: Option<PathBuf>,
+
+    /// The format of the --emit-focus file.
+    #[clap(
+        long,
+        value_enum,
+        default_value = "text",
+        requires = "emit_focus",
+        help = "The format of the --emit-focus file. 'text' is a line per \
+                function of its path, name and structs, tab separated. \
+                'syzkaller' is a focus_areas JSON fragment with an area per \
+                file and its functions, weighted by their sites, and a \
+                catch-all area of weight 1."
+    )]
+    pub focus_format: FocusFormat,
+
+    /// Print the matches sorted by a score of how promising the site is.
+    #[clap(
+        long,
+        action,
+        conflicts_with_all = ["tui", "quiet", "group_by"],
+        help = "Hold the matches back until the scan completes and print them \
+                highest scoring first, with the score's breakdown. A site \
+                scores for sleeping flags, unzeroed memory, being in an ioctl \
+                or ops table handler, function pointers in the struct, an \
+                elastic size or flexible array, and flags lacking \
+                __GFP_ACCOUNT."
+    )]
+    pub rank_sites: bool,
+
+    /// A JSON file overriding the weights of the --rank-sites score.
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "A JSON object overriding the weights --rank-sites and \
+                --emit-focus add up, e.g. '{\"handler\": 5, \"elastic\": 0}'. \
+                The keys are sleeping, unzeroed, handler, function_pointers, \
+                elastic and unaccounted."
+    )]
+    pub rank_weights: Option<PathBuf>,
+
+    /// A /proc/slabinfo snapshot to print each site's cache activity from.
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "A /proc/slabinfo capture from the target machine, SLUB or \
+                SLAB. Each site gets the active_objs/num_objs of the cache \
+                its struct is served from: the kmalloc cache of its size, the \
+                kmalloc-cg one for __GFP_ACCOUNT flags when the snapshot has \
+                it, or a cache named after a struct too large for kmalloc. \
+                Sites whose cache isn't in the snapshot get nothing."
+    )]
+    pub slabinfo: Option<PathBuf>,
+
+    /// The order to print the matches in.
+    #[clap(
+        long,
+        value_enum,
+        requires = "slabinfo",
+        conflicts_with_all = ["tui", "quiet", "group_by", "rank_sites"],
+        help = "Hold the matches back until the scan completes and print them \
+                in this order. 'slab-activity' puts the sites whose cache has \
+                the most active objects in the --slabinfo snapshot first, and \
+                those whose cache isn't in it last."
+    )]
+    pub sort: Option<SortBy>,
+
+    /// The format to report the matches in.
+    #[clap(
+        long,
+        value_enum,
+        default_value = "text",
+        help = "The format to report the matches in. 'text' prints them. \
+                'sqlite' adds the run, its structs and their sites to the \
+                --output database instead, along with any tracked user copies \
+                and bulk writes, creating it or updating its schema as \
+                needed, so that runs accumulate in one database."
+    )]
+    pub format: OutputFormat,
+
+    /// The database --format sqlite writes to.
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "The database --format sqlite writes to."
+    )]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct ListStructsArgs {
+    #[command(flatten)]
+    pub dwarf: DwarfArgs,
+
+    #[command(flatten)]
+    pub filter: StructFilterArgs,
+}
+
+#[derive(Args)]
+pub struct InfoArgs {
+    #[command(flatten)]
+    pub dwarf: DwarfArgs,
+
+    /// The name of the struct.
+    #[clap(help = "The name of the struct.")]
+    pub name: String,
+
+    /// A Linux source tree to count the struct's allocation sites in.
+    #[clap(
+        long,
+        value_name = "SOURCE_PATH",
+        help = "A Linux source tree to count the struct's allocation sites in."
+    )]
+    pub source: Option<PathBuf>,
+}
+
+/// How `--group-by` lays out the matches.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GroupBy {
+    /// Under a heading per kernel subsystem, like `net/` or `drivers/gpu/`.
+    Subsystem,
+}
+
+/// How the matches are reported.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Printed as they are found.
+    Text,
+    /// Written to a SQLite database once the scan completes.
+    Sqlite,
+}
+
+/// How `--emit-focus` writes the functions.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FocusFormat {
+    /// A line per function: path, name and the structs, tab separated.
+    Text,
+    /// A syzkaller `focus_areas` JSON fragment.
+    Syzkaller,
+}
+
+/// The order `--sort` prints the matches in.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SortBy {
+    /// By the active objects of the site's cache in the `--slabinfo`
+    /// snapshot, most first.
+    SlabActivity,
+}
+
+/// What `--dry-run` reports.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DryRun {
+    /// Only count the files and structs.
+    Count,
+    /// List every file that would be scanned as well.
+    List,
+}
+
+const MAX_THREADS: usize = 1000;
+
+impl Cli {
+    /// Parse the command line. Invocations without a subcommand predate them
+    /// and are still accepted as `scan` for now.
+    pub fn parse_args() -> Self {
+        let mut args: Vec<OsString> = std::env::args_os().collect();
+        if let Some(first) = args.get(1) {
+            let cmd = Cli::command();
+            let known = cmd.get_subcommands().any(|sub| first == sub.get_name())
+                || ["help", "-h", "--help", "-V", "--version"]
+                    .iter()
+                    .any(|arg| first == arg);
+            if !known {
+                eprintln!(
+                    "warning: running without a subcommand is deprecated, use `{} scan ...`",
+                    cmd.get_name()
+                );
+                args.insert(1, "scan".into());
+            }
+        }
+        Cli::parse_from(args)
+    }
+}
+
+/// Build an error against a subcommand, so the usage it prints is the
+/// subcommand's own.
+fn error(subcommand: &str, kind: ErrorKind, message: impl std::fmt::Display) -> clap::Error {
+    let mut cmd = Cli::command();
+    cmd.build();
+    match cmd.find_subcommand_mut(subcommand) {
+        Some(sub) => sub.error(kind, message),
+        None => cmd.error(kind, message),
+    }
+}
+
+impl DwarfArgs {
+    fn validate(&self, cmd: &str) -> Result<(), clap::Error> {
+        if let Some(vmlinux_path) = self.vmlinux_path.as_ref().filter(|path| !path.is_file()) {
+            return Err(error(
+                cmd,
+                ErrorKind::ValueValidation,
+                format!("vmlinux path '{}' is not a file", vmlinux_path.display()),
+            ));
+        }
+        if let Some(types_from) = self.types_from.as_ref().filter(|path| !path.is_file()) {
+            return Err(error(
+                cmd,
+                ErrorKind::ValueValidation,
+                format!("--types-from '{}' is not a file", types_from.display()),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl StructFilterArgs {
+    fn validate(&self, cmd: &str) -> Result<(), clap::Error> {
+        if let (Some(min), Some(max)) = (self.min_size, self.max_size) {
+            if min > max {
+                return Err(error(
+                    cmd,
+                    ErrorKind::ValueValidation,
+                    format!("--min-size ({min}) must not be larger than --max-size ({max})"),
+                ));
+            }
+        }
+
+        let constrained = self.min_size.is_some()
+            || self.max_size.is_some()
+            || !self.structs.is_empty()
+            || self.struct_regex.is_some()
+            || self.neighbors.is_some();
+        if !constrained && !self.all_structs {
+            return Err(error(
+                cmd,
+                ErrorKind::MissingRequiredArgument,
+                "no struct selection given, pass --min-size/--max-size, --struct, \
+                 --struct-regex or --neighbors\n\n  \
+                 tip: pass --all-structs to really select every struct",
+            ));
+        }
+
+        for (option, regex) in [
+            ("--struct-regex", &self.struct_regex),
+            ("--exclude-struct", &self.exclude_struct),
+        ] {
+            if let Some(regex) = regex {
+                if let Err(e) = Regex::new(regex) {
+                    return Err(error(
+                        cmd,
+                        ErrorKind::ValueValidation,
+                        format!("invalid {option} regex '{regex}':\n{e}"),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ScanArgs {
+    /// The zeroing asked for with --only-zeroed or --only-unzeroed.
+    pub fn zeroing(&self) -> Option<Zeroing> {
+        match (self.only_zeroed, self.only_unzeroed) {
+            (true, _) => Some(Zeroing::Zeroed),
+            (_, true) => Some(Zeroing::NotZeroed),
+            _ => None,
+        }
+    }
+
+    /// Whether the sites whose size doesn't refer to the struct are the only
+    /// ones kept, true, or dropped, false, with --only-size-unrelated or
+    /// --exclude-size-unrelated.
+    pub fn size_unrelated(&self) -> Option<bool> {
+        match (self.only_size_unrelated, self.exclude_size_unrelated) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        }
+    }
+
+    /// The effective --io-threads, falling back to --threads.
+    pub fn io_thread_count(&self) -> usize {
+        self.io_threads.or(self.threads).unwrap_or(1)
+    }
+
+    /// The effective --parse-threads, falling back to --threads.
+    pub fn parse_thread_count(&self) -> usize {
+        self.parse_threads.or(self.threads).unwrap_or(1)
+    }
+
+    /// The tarball being scanned, when the source path is one.
+    pub fn archive(&self) -> Option<&PathBuf> {
+        match self.source_path.as_slice() {
+            [path] if archive::is_archive(path) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// Check argument combinations clap can't validate on its own, so that bad
+    /// invocations fail upfront rather than partway through a scan.
+    pub fn validate(&self) -> Result<(), clap::Error> {
+        let cmd = "scan";
+        self.dwarf.validate(cmd)?;
+        self.filter.validate(cmd)?;
+
+        for source_path in &self.source_path {
+            if !source_path.is_dir() && !source_path.is_file() {
+                return Err(error(
+                    cmd,
+                    ErrorKind::ValueValidation,
+                    format!(
+                        "source path '{}' is not a directory or file\n\n  \
+                     tip: pass the root of the Linux source tree, or a single \
+                     source file",
+                        source_path.display()
+                    ),
+                ));
+            }
+        }
+
+        if let Some(db) = &self.compile_commands {
+            if !db.is_file() {
+                return Err(error(
+                    cmd,
+                    ErrorKind::ValueValidation,
+                    format!("compilation database '{}' does not exist", db.display()),
+                ));
+            }
+        }
+
+        if let Some(config) = &self.kconfig {
+            if !config.is_file() {
+                return Err(error(
+                    cmd,
+                    ErrorKind::ValueValidation,
+                    format!("kernel config '{}' does not exist", config.display()),
+                ));
+            }
+        }
+
+        if self.resolve_symbols && self.dwarf.vmlinux_path.is_none() {
+            return Err(error(
+                cmd,
+                ErrorKind::MissingRequiredArgument,
+                "--resolve-symbols needs the vmlinux's DWARF, pass it along with --types-from",
+            ));
+        }
+
+        match (self.format, &self.output) {
+            (OutputFormat::Sqlite, None) => {
+                return Err(error(
+                    cmd,
+                    ErrorKind::MissingRequiredArgument,
+                    "--format sqlite needs --output for the database to write to",
+                ));
+            }
+            (OutputFormat::Text, Some(_)) => {
+                return Err(error(
+                    cmd,
+                    ErrorKind::ArgumentConflict,
+                    "--output is only used by --format sqlite",
+                ));
+            }
+            _ => {}
+        }
+        if self.format == OutputFormat::Sqlite {
+            let printing = [
+                ("--tui", self.tui),
+                ("--quiet", self.quiet),
+                ("--group-by", self.group_by.is_some()),
+                ("--rank-sites", self.rank_sites),
+                ("--sort", self.sort.is_some()),
+            ];
+            if let Some((option, _)) = printing.iter().find(|(_, given)| *given) {
+                return Err(error(
+                    cmd,
+                    ErrorKind::ArgumentConflict,
+                    format!("{option} can't be used with --format sqlite, which prints no matches"),
+                ));
+            }
+        }
+
+        if self.source_path.len() > 1 && self.source_path.iter().any(|p| archive::is_archive(p)) {
+            return Err(error(
+                cmd,
+                ErrorKind::ArgumentConflict,
+                "a source archive can't be combined with other source paths",
+            ));
+        }
+        if self.archive().is_some() {
+            let needs_files = [
+                ("--files-from", self.files_from.is_some()),
+                ("--files-from0", self.files_from0.is_some()),
+                ("--compile-commands", self.compile_commands.is_some()),
+                ("--kconfig", self.kconfig.is_some()),
+                ("--checkpoint", self.checkpoint.is_some()),
+            ];
+            if let Some((option, _)) = needs_files.iter().find(|(_, given)| *given) {
+                return Err(error(
+                    cmd,
+                    ErrorKind::ArgumentConflict,
+                    format!("{option} can't be used with a source archive, extract it first"),
+                ));
+            }
+        }
+
+        for list in [&self.files_from, &self.files_from0].into_iter().flatten() {
+            if list != Path::new("-") && !list.is_file() {
+                return Err(error(
+                    cmd,
+                    ErrorKind::ValueValidation,
+                    format!("file list '{}' is not a file", list.display()),
+                ));
+            }
+        }
+
+        if self.tui && !std::io::stdout().is_terminal() {
+            return Err(error(
+                cmd,
+                ErrorKind::ArgumentConflict,
+                "--tui needs stdout to be a terminal, drop it to print the results",
+            ));
+        }
+
+        let regexes = [
+            ("--flags", &self.flags),
+            ("--flags-exclude", &self.flags_exclude),
+            ("--size-expr", &self.size_expr),
+        ];
+        let functions = self.functions.iter().map(|regex| ("--function", regex));
+        for (option, regex) in regexes
+            .into_iter()
+            .filter_map(|(option, regex)| Some((option, regex.as_ref()?)))
+            .chain(functions)
+        {
+            if let Err(e) = Regex::new(regex) {
+                return Err(error(
+                    cmd,
+                    ErrorKind::ValueValidation,
+                    format!("invalid {option} regex '{regex}':\n{e}"),
+                ));
+            }
+        }
+
+        if let Some(spec) = &self.gfp {
+            if let Err(e) = GfpSpec::parse(spec) {
+                return Err(error(
+                    cmd,
+                    ErrorKind::ValueValidation,
+                    format!("invalid --gfp spec '{spec}': {e}"),
+                ));
+            }
+        }
+
+        if let Err(e) = query::load(self.query.as_deref(), &self.extra_query, alloc::ALLOCATORS) {
+            return Err(error(cmd, ErrorKind::ValueValidation, format!("{e:#}")));
+        }
+
+        for name in &self.exclude_dir {
+            if name.is_empty() || name.contains('/') {
+                return Err(error(
+                    cmd,
+                    ErrorKind::ValueValidation,
+                    format!(
+                        "invalid --exclude-dir '{name}': expected a directory name, \
+                         use --exclude for paths and globs"
+                    ),
+                ));
+            }
+        }
+
+        for (option, globs) in [("--exclude", &self.exclude), ("--include", &self.include)] {
+            for glob in globs {
+                if let Err(e) = globset::Glob::new(glob) {
+                    return Err(error(
+                        cmd,
+                        ErrorKind::ValueValidation,
+                        format!("invalid {option} glob '{glob}': {e}"),
+                    ));
+                }
+            }
+        }
+
+        for (option, threads) in [
+            ("--threads", self.threads),
+            ("--io-threads", self.io_threads),
+            ("--parse-threads", self.parse_threads),
+        ] {
+            if let Some(threads) = threads {
+                if threads == 0 || threads > MAX_THREADS {
+                    return Err(error(
+                        cmd,
+                        ErrorKind::ValueValidation,
+                        format!("{option} must be between 1 and {MAX_THREADS}, got {threads}"),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ListStructsArgs {
+    pub fn validate(&self) -> Result<(), clap::Error> {
+        self.dwarf.validate("list-structs")?;
+        self.filter.validate("list-structs")
+    }
+}
+
+impl InfoArgs {
+    pub fn validate(&self) -> Result<(), clap::Error> {
+        self.dwarf.validate("info")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // any existing file and directory do, validation doesn't read them
+    const FILE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+    const DIR: &str = env!("CARGO_MANIFEST_DIR");
+
+    /// Parse and validate a scan of the manifest directory with `args`.
+    fn scan(args: &[&str]) -> Result<(), clap::Error> {
+        let argv = ["kheap_sift", "scan", "--types-from", FILE, DIR];
+        let cli = Cli::try_parse_from(argv.iter().chain(args))?;
+        let Command::Scan(scan) = cli.command else {
+            unreachable!("parsed a scan command line");
+        };
+        scan.validate()
+    }
+
+    /// Assert that the scan with `args` is rejected with `kind`, with an
+    /// error mentioning `needle`.
+    fn rejected(args: &[&str], kind: ErrorKind, needle: &str) {
+        let err = scan(args)
+            .err()
+            .unwrap_or_else(|| panic!("{args:?} was accepted"));
+        assert_eq!(err.kind(), kind, "{args:?}: {err}");
+        assert!(err.to_string().contains(needle), "{args:?}: {err}");
+    }
+
+    #[test]
+    fn accepts_a_valid_scan() {
+        scan(&["--min-size", "64", "--max-size", "128"]).unwrap();
+        scan(&["--all-structs", "--threads", "8", "--function", "_ioctl$"]).unwrap();
+    }
+
+    #[test]
+    fn rejects_struct_selection() {
+        rejected(
+            &["--min-size", "128", "--max-size", "64"],
+            ErrorKind::ValueValidation,
+            "--min-size (128) must not be larger than --max-size (64)",
+        );
+        rejected(&[], ErrorKind::MissingRequiredArgument, "--all-structs");
+        rejected(
+            &["--struct-regex", "foo("],
+            ErrorKind::ValueValidation,
+            "invalid --struct-regex regex",
+        );
+        rejected(
+            &["--all-structs", "--exclude-struct", "[a-"],
+            ErrorKind::ValueValidation,
+            "invalid --exclude-struct regex",
+        );
+    }
+
+    #[test]
+    fn rejects_missing_paths() {
+        let missing = concat!(env!("CARGO_MANIFEST_DIR"), "/does-not-exist");
+        for (args, needle) in [
+            (vec!["--compile-commands", missing], "compilation database"),
+            (vec!["--kconfig", missing], "kernel config"),
+            (vec!["--files-from", missing], "file list"),
+            (vec!["--files-from", DIR], "file list"),
+        ] {
+            let args: Vec<&str> = ["--all-structs"].into_iter().chain(args).collect();
+            rejected(&args, ErrorKind::ValueValidation, needle);
+        }
+
+        let argv = [
+            "kheap_sift",
+            "scan",
+            "--types-from",
+            FILE,
+            missing,
+            "--all-structs",
+        ];
+        let Command::Scan(args) = Cli::try_parse_from(argv).unwrap().command else {
+            unreachable!("parsed a scan command line");
+        };
+        let err = args.validate().err().unwrap();
+        assert!(
+            err.to_string().contains("is not a directory or file"),
+            "{err}"
+        );
+
+        let argv = [
+            "kheap_sift",
+            "scan",
+            "--types-from",
+            DIR,
+            DIR,
+            "--all-structs",
+        ];
+        let Command::Scan(args) = Cli::try_parse_from(argv).unwrap().command else {
+            unreachable!("parsed a scan command line");
+        };
+        let err = args.validate().err().unwrap();
+        assert!(err.to_string().contains("--types-from"), "{err}");
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        for (option, value, needle) in [
+            ("--flags", "GFP_(", "invalid --flags regex"),
+            ("--flags-exclude", "*", "invalid --flags-exclude regex"),
+            ("--size-expr", "sizeof(", "invalid --size-expr regex"),
+            ("--function", "ioctl(", "invalid --function regex"),
+            ("--gfp", "GFP_KERNEL,bogus", "invalid --gfp spec"),
+            ("--exclude-dir", "drivers/net", "invalid --exclude-dir"),
+            ("--exclude", "drivers/[", "invalid --exclude glob"),
+            ("--include", "{a,", "invalid --include glob"),
+        ] {
+            rejected(
+                &["--all-structs", option, value],
+                ErrorKind::ValueValidation,
+                needle,
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_thread_counts_out_of_range() {
+        for option in ["--threads", "--io-threads", "--parse-threads"] {
+            for count in ["0", "1001"] {
+                rejected(
+                    &["--all-structs", option, count],
+                    ErrorKind::ValueValidation,
+                    "must be between 1 and 1000",
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_output_combinations() {
+        rejected(
+            &["--all-structs", "--format", "sqlite"],
+            ErrorKind::MissingRequiredArgument,
+            "--format sqlite needs --output",
+        );
+        rejected(
+            &["--all-structs", "--output", "sites.db"],
+            ErrorKind::ArgumentConflict,
+            "--output is only used by --format sqlite",
+        );
+        for option in [
+            vec!["--quiet"],
+            vec!["--group-by", "subsystem"],
+            vec!["--rank-sites"],
+            vec!["--sort", "slab-activity", "--slabinfo", FILE],
+        ] {
+            let args: Vec<&str> = [
+                "--all-structs",
+                "--format",
+                "sqlite",
+                "--output",
+                "sites.db",
+            ]
+            .into_iter()
+            .chain(option)
+            .collect();
+            rejected(
+                &args,
+                ErrorKind::ArgumentConflict,
+                "can't be used with --format sqlite",
+            );
+        }
+        rejected(
+            &["--all-structs", "--resolve-symbols"],
+            ErrorKind::MissingRequiredArgument,
+            "--resolve-symbols needs the vmlinux",
+        );
+    }
+
+    #[test]
+    fn rejects_archive_combinations() {
+        // only the name makes it an archive
+        let dir = std::env::temp_dir().join(format!("kheap_sift-cli-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let archive = dir.join("linux.tar.gz");
+        std::fs::write(&archive, b"").unwrap();
+        let archive = archive.to_str().unwrap();
+
+        let validate = |args: &[&str]| {
+            let argv = ["kheap_sift", "scan", "--types-from", FILE, "--all-structs"];
+            let Command::Scan(scan) = Cli::try_parse_from(argv.iter().chain(args))
+                .unwrap()
+                .command
+            else {
+                unreachable!("parsed a scan command line");
+            };
+            scan.validate()
+        };
+        validate(&[archive]).unwrap();
+        let err = validate(&[&format!("{archive}:{DIR}")]).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::ArgumentConflict);
+        assert!(err.to_string().contains("can't be combined"), "{err}");
+        let err = validate(&[archive, "--kconfig", FILE]).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::ArgumentConflict);
+        assert!(
+            err.to_string()
+                .contains("--kconfig can't be used with a source archive"),
+            "{err}"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use serde::Deserialize;
+
+// set by `--compile-commands`, consulted when a match is reported
+static COMPILE_DB: OnceLock<CompileDb> = OnceLock::new();
+
+/// An entry of a JSON compilation database. clang tools write the command as
+/// a list of `arguments`, `gen_compile_commands.py` as a single `command`
+/// string.
+#[derive(Deserialize)]
+struct Entry {
+    directory: PathBuf,
+    file: PathBuf,
+    #[serde(default)]
+    command: Option<String>,
+    #[serde(default)]
+    arguments: Option<Vec<String>>,
+}
+
+/// The files built according to a compilation database, and the include
+/// directories they are built with.
+pub struct CompileDb {
+    files: HashSet<PathBuf>,
+    include_dirs: Vec<PathBuf>,
+    include_headers: bool,
+}
+
+impl CompileDb {
+    /// Load a compilation database. Relative `file` entries are resolved
+    /// against the entry's `directory`, and a file compiled by several
+    /// commands is only recorded once. When `include_headers` is set, headers
+    /// under any of the include directories are treated as part of the build.
+    pub fn load(path: &Path, include_headers: bool) -> anyhow::Result<Self> {
+        let entries: Vec<Entry> = serde_json::from_slice(&std::fs::read(path)?)
+            .map_err(|e| anyhow::anyhow!("failed to parse {}: {e}", path.display()))?;
+
+        let mut files = HashSet::new();
+        let mut include_dirs = HashSet::new();
+        for entry in entries {
+            files.insert(canonical(&entry.directory.join(&entry.file)));
+
+            let args = match (entry.arguments, entry.command) {
+                (Some(args), _) => args,
+                (None, Some(command)) => command.split_whitespace().map(String::from).collect(),
+                (None, None) => continue,
+            };
+            let mut args = args.iter();
+            while let Some(arg) = args.next() {
+                let dir = match arg.as_str() {
+                    "-I" | "-isystem" | "-iquote" | "-idirafter" => args.next().map(String::as_str),
+                    arg => arg.strip_prefix("-I"),
+                };
+                if let Some(dir) = dir.filter(|dir| !dir.is_empty()) {
+                    include_dirs.insert(canonical(&entry.directory.join(dir)));
+                }
+            }
+        }
+
+        Ok(CompileDb {
+            files,
+            include_dirs: include_dirs.into_iter().collect(),
+            include_headers,
+        })
+    }
+
+    /// Whether the file was compiled according to the database.
+    pub fn is_built(&self, path: &Path) -> bool {
+        self.files.contains(&canonical(path))
+    }
+
+    /// Whether the file should be scanned, it is built or is a header under
+    /// one of the include directories and headers were asked for.
+    pub fn selects(&self, path: &Path) -> bool {
+        if self.is_built(path) {
+            return true;
+        }
+        self.include_headers
+            && path.extension().is_some_and(|ext| ext == "h")
+            && self
+                .include_dirs
+                .iter()
+                .any(|dir| canonical(path).starts_with(dir))
+    }
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Use `db` to annotate reported matches with whether they are in the build.
+pub fn set(db: CompileDb) {
+    let _ = COMPILE_DB.set(db);
+}
+
+/// How the file of a match relates to the build, when a compilation database
+/// was given.
+pub fn build_status(path: &Path) -> Option<&'static str> {
+    let db = COMPILE_DB.get()?;
+    Some(if db.is_built(path) {
+        "in build"
+    } else {
+        "header, not compiled directly"
+    })
+}
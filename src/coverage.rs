@@ -0,0 +1,82 @@
+use std::collections::{BTreeSet, HashSet};
+use std::sync::{Mutex, OnceLock};
+
+use lazy_static::lazy_static;
+
+// the selected structs, set by `--coverage`
+static SELECTED: OnceLock<HashSet<String>> = OnceLock::new();
+
+lazy_static! {
+    // the selected structs named anywhere in a scanned file
+    static ref MENTIONED: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+    // the selected structs with a site reported
+    static ref COVERED: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+pub fn enable(selected: impl IntoIterator<Item = String>) {
+    let _ = SELECTED.set(selected.into_iter().collect());
+}
+
+pub fn enabled() -> bool {
+    SELECTED.get().is_some()
+}
+
+/// Note the selected structs a file names, given the struct prefilter's hits
+/// in it. Returns whether there were any.
+pub fn record_mentions<'a>(hits: impl Iterator<Item = &'a [u8]>) -> bool {
+    let Some(selected) = SELECTED.get() else {
+        return false;
+    };
+    let found: HashSet<&str> = hits
+        .filter_map(|hit| std::str::from_utf8(hit).ok())
+        .filter(|hit| selected.contains(*hit))
+        .collect();
+    if found.is_empty() {
+        return false;
+    }
+    let mut mentioned = MENTIONED.lock().unwrap();
+    mentioned.extend(found.into_iter().map(str::to_string));
+    true
+}
+
+pub fn record_site(struct_name: &str) {
+    if enabled() {
+        COVERED.lock().unwrap().insert(struct_name.to_string());
+    }
+}
+
+/// Print the selected structs which had no site reported, those never named
+/// in the scanned files apart from those named but never matched by the
+/// queries.
+pub fn print_report() {
+    let Some(selected) = SELECTED.get() else {
+        return;
+    };
+    let covered = COVERED.lock().unwrap();
+    let mentioned = MENTIONED.lock().unwrap();
+    let (named, unnamed): (BTreeSet<&str>, BTreeSet<&str>) = selected
+        .iter()
+        .filter(|name| !covered.contains(*name))
+        .map(String::as_str)
+        .partition(|name| mentioned.contains(*name));
+
+    println!("======== Coverage ========\n");
+    println!(
+        "{} of {} selected struct(s) have sites.\n",
+        covered.len(),
+        selected.len()
+    );
+    println!(
+        "Named in the sources, but no allocation matched ({}):",
+        named.len()
+    );
+    for name in &named {
+        println!("  struct {name}");
+    }
+    println!();
+    println!("Never named in the scanned sources ({}):", unnamed.len());
+    for name in &unnamed {
+        println!("  struct {name}");
+    }
+    println!();
+}
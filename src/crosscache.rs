@@ -0,0 +1,147 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+use crate::gfp;
+use crate::output;
+use crate::slab;
+
+/// Which of a size's two kmalloc caches a site's flags put the object in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Accounting {
+    /// No `__GFP_ACCOUNT`, the plain `kmalloc-N` cache.
+    Plain,
+    /// `__GFP_ACCOUNT`, the memcg `kmalloc-cg-N` cache.
+    Accounted,
+    /// The flags come from a variable or a call.
+    Unknown,
+}
+
+/// A reported allocation site and the flags it passes, as written.
+struct Site {
+    path: PathBuf,
+    line: usize,
+    flags: String,
+}
+
+lazy_static! {
+    static ref SITES: Mutex<BTreeMap<String, BTreeMap<Accounting, Vec<Site>>>> =
+        Mutex::new(BTreeMap::new());
+}
+
+/// Note a reported allocation site of `struct_name` and whether its flags are
+/// accounted.
+pub fn record(struct_name: &str, path: &Path, line: usize, flags: &str) {
+    let accounting = match gfp::expand(flags) {
+        Some(bits) if bits.contains("__GFP_ACCOUNT") => Accounting::Accounted,
+        Some(_) => Accounting::Plain,
+        None => Accounting::Unknown,
+    };
+    SITES
+        .lock()
+        .unwrap()
+        .entry(struct_name.to_string())
+        .or_default()
+        .entry(accounting)
+        .or_default()
+        .push(Site {
+            path: path.to_path_buf(),
+            line,
+            flags: output::one_line(flags.as_bytes()),
+        });
+}
+
+/// The kmalloc caches the recorded sites of each struct are served from, by
+/// struct name: the plain and accounted caches of its size per the sites'
+/// flags, both for flags that can't be classified. `size` looks up a
+/// struct's size; a struct without one, or too large for the kmalloc caches,
+/// has none.
+pub fn caches(
+    size: impl Fn(&str) -> Option<usize>,
+) -> BTreeMap<String, (Option<usize>, Vec<String>)> {
+    let sites = SITES.lock().unwrap();
+    let mut caches = BTreeMap::new();
+    for (struct_name, by_accounting) in sites.iter() {
+        let size = size(struct_name);
+        let mut names = vec![];
+        if let Some(size) = size.filter(|&size| slab::kmalloc_bucket(size).is_some()) {
+            let unknown = by_accounting.contains_key(&Accounting::Unknown);
+            if unknown || by_accounting.contains_key(&Accounting::Plain) {
+                names.push(slab::cache_name(size));
+            }
+            if unknown || by_accounting.contains_key(&Accounting::Accounted) {
+                names.push(slab::accounted_cache_name(size));
+            }
+        }
+        caches.insert(struct_name.clone(), (size, names));
+    }
+    caches
+}
+
+/// Print a section for each struct allocated both with and without
+/// `__GFP_ACCOUNT`, which lands it in two different caches, listing the sites
+/// on either side. Sites with flags that can't be classified are listed
+/// separately, for these structs and for those whose other sites all agree.
+/// `size` looks up a struct's size, structs too large for the kmalloc caches
+/// are left out.
+pub fn print_report(size: impl Fn(&str) -> Option<usize>) {
+    let sites = SITES.lock().unwrap();
+    let mut undetermined = vec![];
+    for (struct_name, by_accounting) in sites.iter() {
+        let Some(size) = size(struct_name).filter(|&size| slab::kmalloc_bucket(size).is_some())
+        else {
+            continue;
+        };
+        let (plain, accounted) = (slab::cache_name(size), slab::accounted_cache_name(size));
+        let unknown = by_accounting.get(&Accounting::Unknown);
+        let mixed = by_accounting.contains_key(&Accounting::Plain)
+            && by_accounting.contains_key(&Accounting::Accounted);
+        if !mixed {
+            if let Some(unknown) = unknown {
+                undetermined.push((struct_name, plain, accounted, unknown));
+            }
+            continue;
+        }
+
+        println!(
+            "======== Cross-cache: struct {struct_name} has sites in both {plain} and {accounted} ========\n"
+        );
+        for (accounting, cache) in [
+            (Accounting::Plain, &plain),
+            (Accounting::Accounted, &accounted),
+            (Accounting::Unknown, &"unknown accounting".to_string()),
+        ] {
+            let Some(sites) = by_accounting.get(&accounting) else {
+                continue;
+            };
+            println!("{cache}:");
+            print_sites(sites);
+        }
+        println!();
+    }
+
+    if undetermined.is_empty() {
+        return;
+    }
+    println!("======== Sites with unknown accounting ========\n");
+    for (struct_name, plain, accounted, sites) in undetermined {
+        println!("struct {struct_name}, {plain} or {accounted}:");
+        print_sites(sites);
+    }
+    println!();
+}
+
+fn print_sites(sites: &[Site]) {
+    let mut sites: Vec<&Site> = sites.iter().collect();
+    sites.sort_by(|a, b| (&a.path, a.line).cmp(&(&b.path, b.line)));
+    for site in sites {
+        println!(
+            "    {}:{} ({})",
+            output::display_path(&site.path),
+            site.line,
+            site.flags
+        );
+    }
+}
@@ -0,0 +1,118 @@
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+use object::Object;
+
+/// Where distributions install the debug info of their packages, by build-id.
+const DEBUG_FILE_DIRECTORY: &str = "/usr/lib/debug/.build-id";
+
+/// The GNU build-id of an ELF, as lowercase hex.
+pub fn build_id(elf: &[u8]) -> Option<String> {
+    let id = object::File::parse(elf).ok()?.build_id().ok()??;
+    Some(id.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    }))
+}
+
+/// The debuginfod client cache, the one elfutils' client uses:
+/// `$DEBUGINFOD_CACHE_PATH`, or `debuginfod_client` in the XDG cache
+/// directory.
+fn cache_dir() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("DEBUGINFOD_CACHE_PATH") {
+        return Some(PathBuf::from(path));
+    }
+    let cache = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(cache) => PathBuf::from(cache),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+    Some(cache.join("debuginfod_client"))
+}
+
+/// Find the debug info of the ELF `vmlinux` with `build_id` lacks: in the
+/// debuginfod cache, then from the `DEBUGINFOD_URLS` servers, caching the
+/// download, and last in the distribution's build-id directory. The error
+/// says what was tried and why each failed.
+pub fn find(vmlinux: &Path, build_id: &str) -> anyhow::Result<PathBuf> {
+    let cached = cache_dir().map(|dir| dir.join(build_id).join("debuginfo"));
+    if let Some(cached) = cached.as_ref().filter(|cached| cached.is_file()) {
+        eprintln!("Using the cached debuginfo {}", cached.display());
+        return Ok(cached.clone());
+    }
+
+    let fetch_error = match fetch(build_id, cached.as_deref()) {
+        Ok(path) => return Ok(path),
+        Err(e) => e,
+    };
+
+    let (prefix, rest) = build_id.split_at(2.min(build_id.len()));
+    let local = Path::new(DEBUG_FILE_DIRECTORY)
+        .join(prefix)
+        .join(format!("{rest}.debug"));
+    if local.is_file() {
+        eprintln!(
+            "debuginfod: {fetch_error}, using the local {} instead",
+            local.display()
+        );
+        return Ok(local);
+    }
+    anyhow::bail!(
+        "{} has no DWARF debug info and its build-id {build_id} couldn't be found: \
+         debuginfod: {fetch_error}; not in {}. Set DEBUGINFOD_URLS to a server with \
+         the kernel's debuginfo, install the distribution's kernel debug package, or \
+         pass the debug vmlinux instead",
+        vmlinux.display(),
+        local.display()
+    )
+}
+
+/// Download the debuginfo of `build_id` from the first `DEBUGINFOD_URLS`
+/// server which has it, into `cache`.
+#[cfg(feature = "debuginfod")]
+fn fetch(build_id: &str, cache: Option<&Path>) -> anyhow::Result<PathBuf> {
+    use std::time::Duration;
+
+    let Some(cache) = cache else {
+        anyhow::bail!("no cache directory, set DEBUGINFOD_CACHE_PATH or HOME");
+    };
+    let urls = std::env::var("DEBUGINFOD_URLS").unwrap_or_default();
+    if urls.split_whitespace().next().is_none() {
+        anyhow::bail!("DEBUGINFOD_URLS is not set");
+    }
+
+    let agent = ureq::AgentBuilder::new()
+        .timeout_connect(Duration::from_secs(10))
+        .build();
+    let mut errors = vec![];
+    for url in urls.split_whitespace() {
+        let url = format!("{}/buildid/{build_id}/debuginfo", url.trim_end_matches('/'));
+        eprintln!("Fetching {url}");
+        let response = match agent.get(&url).call() {
+            Ok(response) => response,
+            Err(e) => {
+                // ureq's errors already name the url
+                errors.push(e.to_string());
+                continue;
+            }
+        };
+        // downloaded next to its final path and renamed, so an interrupted
+        // download never looks cached
+        std::fs::create_dir_all(cache.parent().unwrap_or(cache))?;
+        let partial = cache.with_extension("partial");
+        let mut file = std::fs::File::create(&partial)?;
+        if let Err(e) = std::io::copy(&mut response.into_reader(), &mut file) {
+            let _ = std::fs::remove_file(&partial);
+            errors.push(format!("{url}: {e}"));
+            continue;
+        }
+        std::fs::rename(&partial, cache)?;
+        eprintln!("Cached the debuginfo in {}", cache.display());
+        return Ok(cache.to_path_buf());
+    }
+    anyhow::bail!("{}", errors.join(", "))
+}
+
+#[cfg(not(feature = "debuginfod"))]
+fn fetch(_build_id: &str, _cache: Option<&Path>) -> anyhow::Result<PathBuf> {
+    anyhow::bail!("kheap_sift was built without the debuginfod feature")
+}
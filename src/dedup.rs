@@ -0,0 +1,87 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+use crate::output;
+
+/// Where an allocation site was first reported, and the other places the same
+/// site was seen.
+struct SiteLocations {
+    struct_name: String,
+    first: (PathBuf, usize),
+    duplicates: Vec<(PathBuf, usize)>,
+}
+
+lazy_static! {
+    static ref SITES: Mutex<HashMap<u64, SiteLocations>> = Mutex::new(HashMap::new());
+}
+
+/// Identify an allocation site by its content rather than its path, so the
+/// same inline function in copies of a header reachable through different
+/// paths is recognized as one site. `call_offset` is the offset of the
+/// allocation within the function, distinguishing several sites in one
+/// function.
+pub fn site_key(
+    function_text: &[u8],
+    call_offset: usize,
+    struct_name: &str,
+    allocator: &str,
+) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    function_text.hash(&mut hasher);
+    call_offset.hash(&mut hasher);
+    struct_name.hash(&mut hasher);
+    allocator.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Record a site, returning true if it is the first time it has been seen and
+/// should be reported.
+pub fn first_sighting(key: u64, struct_name: &str, path: &Path, line: usize) -> bool {
+    let mut sites = SITES.lock().unwrap();
+    if let Some(site) = sites.get_mut(&key) {
+        site.duplicates.push((path.to_path_buf(), line));
+        return false;
+    }
+
+    sites.insert(
+        key,
+        SiteLocations {
+            struct_name: struct_name.to_string(),
+            first: (path.to_path_buf(), line),
+            duplicates: vec![],
+        },
+    );
+    true
+}
+
+/// List the sites which were reported once but found at several paths.
+pub fn print_duplicates() {
+    let sites = SITES.lock().unwrap();
+    let mut duplicated: Vec<&SiteLocations> = sites
+        .values()
+        .filter(|site| !site.duplicates.is_empty())
+        .collect();
+    if duplicated.is_empty() {
+        return;
+    }
+    duplicated.sort_by(|a, b| a.first.cmp(&b.first));
+
+    println!("======== Sites found at more than one path ========\n");
+    for site in duplicated {
+        let (path, line) = &site.first;
+        println!(
+            "struct {} at {}:{line}, also at:",
+            site.struct_name,
+            output::display_path(path)
+        );
+        for (path, line) in &site.duplicates {
+            println!("    {}:{line}", output::display_path(path));
+        }
+    }
+    println!();
+}
@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// The script, with `{structs}` replaced by a Python list of the matched
+/// structs, their sizes and the kmalloc caches their sites use.
+const SCRIPT: &str = r#"#!/usr/bin/env drgn
+# Generated by kheap_sift --emit-drgn.
+#
+# Run against a live kernel or a vmcore with
+#
+#     drgn {script} [--walk] [--limit N]
+#
+# For each struct with allocation sites, prints how many objects are allocated
+# in the kmalloc caches its sites use, and in a cache named after the struct if
+# the kernel has one. With --walk, also prints the allocated objects cast to the
+# struct. Objects in a kmalloc cache are whatever else of its size is allocated
+# there too, not only the struct.
+
+import argparse
+import sys
+
+from drgn.helpers.linux.slab import (
+    find_slab_cache,
+    slab_cache_for_each_allocated_object,
+)
+
+# (struct, size in bytes or None, kmalloc caches of its sites)
+STRUCTS = {structs}
+
+parser = argparse.ArgumentParser(
+    description="Inspect the slab caches of the structs kheap_sift matched"
+)
+parser.add_argument(
+    "--walk",
+    action="store_true",
+    help="print the allocated objects of each cache cast to the struct",
+)
+parser.add_argument(
+    "--limit",
+    type=int,
+    default=10,
+    metavar="N",
+    help="print at most N objects per cache with --walk (default: 10)",
+)
+args = parser.parse_args()
+
+
+def inspect(name, cache_name, dedicated):
+    cache = find_slab_cache(prog, cache_name)
+    if cache is None:
+        if not dedicated:
+            print(f"    {cache_name}: no such cache")
+        return
+    kind = "dedicated cache" if dedicated else "kmalloc cache"
+    try:
+        count = sum(1 for _ in slab_cache_for_each_allocated_object(cache, "void"))
+    except (LookupError, NotImplementedError, ValueError) as e:
+        print(f"    {cache_name} ({kind}): can't walk the cache: {e}")
+        return
+    size = cache.object_size.value_()
+    print(f"    {cache_name} ({kind}, {size} byte objects): {count} allocated")
+    if not args.walk:
+        return
+    try:
+        objects = slab_cache_for_each_allocated_object(cache, f"struct {name}")
+        for i, obj in enumerate(objects):
+            if i == args.limit:
+                print(f"        ... {count - i} more")
+                break
+            print(f"        {hex(obj.value_())}:")
+            print("        " + str(obj[0]).replace("\n", "\n        "))
+    except LookupError as e:
+        print(f"        can't cast to struct {name}: {e}")
+
+
+for name, size, caches in STRUCTS:
+    described = f"{size} bytes" if size is not None else "size unknown"
+    print(f"struct {name} ({described})")
+    if not caches:
+        print("    no kmalloc cache, too large or of an unknown size")
+    for cache_name in caches:
+        inspect(name, cache_name, dedicated=False)
+    inspect(name, name, dedicated=True)
+    sys.stdout.flush()
+"#;
+
+/// Write a self-contained drgn script to `out` which inspects the caches of
+/// `structs`, the struct names mapped to their sizes and the kmalloc caches
+/// their sites use. Returns the number of structs in the script.
+pub fn write(
+    out: &Path,
+    structs: &BTreeMap<String, (Option<usize>, Vec<String>)>,
+) -> anyhow::Result<usize> {
+    let mut list = String::from("[\n");
+    for (name, (size, caches)) in structs {
+        let size = size.map_or("None".to_string(), |size| size.to_string());
+        let caches: Vec<String> = caches.iter().map(|cache| python_string(cache)).collect();
+        list.push_str(&format!(
+            "    ({}, {size}, [{}]),\n",
+            python_string(name),
+            caches.join(", ")
+        ));
+    }
+    list.push(']');
+
+    let script_name = out
+        .file_name()
+        .map_or("script.py".into(), |name| name.to_string_lossy());
+    let script = SCRIPT
+        .replacen("{script}", &script_name, 1)
+        .replacen("{structs}", &list, 1);
+    std::fs::write(out, script)?;
+    Ok(structs.len())
+}
+
+/// A Python string literal of a name, which a JSON string of it is.
+fn python_string(name: &str) -> String {
+    serde_json::to_string(name).unwrap()
+}
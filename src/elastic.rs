@@ -0,0 +1,238 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use tree_sitter as ts;
+
+use crate::filter;
+use crate::output;
+
+/// Helpers computing the size of a struct with a trailing array, their last
+/// argument being the element count.
+const SIZE_HELPERS: &[&str] = &["struct_size", "flex_array_size"];
+
+/// Helpers whose first argument is the struct, or a pointer to it, that the
+/// size is computed for.
+const STRUCT_HELPERS: &[&str] = &["struct_size", "struct_size_t", "offsetof", "offsetofend"];
+
+/// The elastic sites of a struct, out of all its sites, and the variable
+/// components they were seen with.
+#[derive(Default)]
+struct Tally {
+    sites: usize,
+    elastic: usize,
+    components: Vec<String>,
+}
+
+lazy_static! {
+    // identifiers, and `struct name` type references as one token
+    static ref IDENTIFIER_OR_STRUCT: Regex =
+        Regex::new(r"\bstruct\s+[A-Za-z_][A-Za-z0-9_]*|[A-Za-z_][A-Za-z0-9_]*").unwrap();
+    static ref TALLIES: Mutex<BTreeMap<String, Tally>> = Mutex::new(BTreeMap::new());
+}
+
+/// How an allocation's size relates to the struct the result is stored as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sizing {
+    /// The size is the struct's, `sizeof(*p)` or `sizeof(struct foo)`.
+    SizeofConfirmed,
+    /// The size refers to the struct, but has a part which isn't fixed.
+    Elastic,
+    /// The size doesn't refer to the struct at all, like `kmalloc(len, ...)`,
+    /// so the object may be smaller than the struct or in another cache.
+    SizeUnrelated,
+}
+
+impl std::fmt::Display for Sizing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Sizing::SizeofConfirmed => "sizeof-confirmed",
+            Sizing::Elastic => "elastic",
+            Sizing::SizeUnrelated => "size-unrelated",
+        })
+    }
+}
+
+/// Classify an allocation's size arguments against the struct `struct_name`
+/// stored in the variable `variable`. The size refers to the struct when a
+/// `sizeof`, `struct_size()` or `offsetof()` in it names either.
+pub fn sizing(
+    size: ts::Node<'_>,
+    count: Option<ts::Node<'_>>,
+    struct_name: &str,
+    variable: &str,
+    content: &[u8],
+) -> Sizing {
+    let refers = [Some(size), count]
+        .into_iter()
+        .flatten()
+        .any(|arg| refers_to(arg, struct_name, variable, content));
+    match (refers, component(size, count, content)) {
+        (false, _) => Sizing::SizeUnrelated,
+        (true, None) => Sizing::SizeofConfirmed,
+        (true, Some(_)) => Sizing::Elastic,
+    }
+}
+
+/// Whether a `sizeof`, or a helper taking the struct or the variable as its
+/// first argument, in `expr` names the struct or the variable.
+fn refers_to(expr: ts::Node<'_>, struct_name: &str, variable: &str, content: &[u8]) -> bool {
+    let names = |node: ts::Node<'_>| {
+        let text = text(node, content);
+        IDENTIFIER_OR_STRUCT.find_iter(&text).any(|token| {
+            let token = token.as_str();
+            token == variable
+                || token
+                    .strip_prefix("struct")
+                    .is_some_and(|name| name.trim_start() == struct_name)
+        })
+    };
+    match expr.kind() {
+        "sizeof_expression" => return names(expr),
+        "call_expression" => {
+            let function = expr
+                .child_by_field_name("function")
+                .and_then(|function| function.utf8_text(content).ok());
+            let first = expr
+                .child_by_field_name("arguments")
+                .and_then(|args| args.named_child(0));
+            if let (Some(function), Some(first)) = (function, first) {
+                if STRUCT_HELPERS.contains(&function) && names(first) {
+                    return true;
+                }
+            }
+        }
+        _ => {}
+    }
+    let mut cursor = expr.walk();
+    let refers = expr
+        .named_children(&mut cursor)
+        .any(|child| refers_to(child, struct_name, variable, content));
+    refers
+}
+
+/// The part of an allocation's size which isn't fixed at compile time, like
+/// `+ len` for `sizeof(struct x) + len` or `* n` for `kmalloc_array(n, ...)`,
+/// or None for a fixed size. The attacker may get to choose the cache of an
+/// elastic site. Sizes built with `struct_size()` and the like are elastic by
+/// their element count.
+pub fn component(
+    size: ts::Node<'_>,
+    count: Option<ts::Node<'_>>,
+    content: &[u8],
+) -> Option<String> {
+    let mut parts = vec![];
+    if let Some(count) = count.filter(|&count| !is_fixed(count, content)) {
+        parts.push(format!("* {}", text(count, content)));
+    }
+    if let Some(part) = variable_part(size, content) {
+        parts.push(part);
+    }
+    match parts.is_empty() {
+        true => None,
+        false => Some(parts.join(" ")),
+    }
+}
+
+/// Count a reported site towards its struct's rollup.
+pub fn record(struct_name: &str, component: Option<&str>) {
+    let mut tallies = TALLIES.lock().unwrap();
+    let tally = tallies.entry(struct_name.to_string()).or_default();
+    tally.sites += 1;
+    if let Some(component) = component {
+        tally.elastic += 1;
+        if !tally.components.iter().any(|seen| seen == component) {
+            tally.components.push(component.to_string());
+        }
+    }
+}
+
+/// Print how many of each struct's sites are elastic, for the structs with
+/// any.
+pub fn print_rollup() {
+    let tallies = TALLIES.lock().unwrap();
+    let elastic: Vec<(&String, &Tally)> = tallies
+        .iter()
+        .filter(|(_, tally)| tally.elastic > 0)
+        .collect();
+    if elastic.is_empty() {
+        return;
+    }
+    println!("======== Elastic sites ========\n");
+    for (struct_name, tally) in elastic {
+        println!(
+            "struct {struct_name}: {} of {} sites elastic ({})",
+            tally.elastic,
+            tally.sites,
+            tally.components.join(", ")
+        );
+    }
+    println!();
+}
+
+fn variable_part(expr: ts::Node<'_>, content: &[u8]) -> Option<String> {
+    if is_fixed(expr, content) {
+        return None;
+    }
+    match expr.kind() {
+        "parenthesized_expression" => variable_part(expr.named_child(0)?, content),
+        "binary_expression" => {
+            let (left, right) = (
+                expr.child_by_field_name("left")?,
+                expr.child_by_field_name("right")?,
+            );
+            let operator = expr
+                .child_by_field_name("operator")?
+                .utf8_text(content)
+                .ok()?;
+            match (is_fixed(left, content), is_fixed(right, content)) {
+                (true, false) => Some(format!("{operator} {}", text(right, content))),
+                (false, true) if matches!(operator, "+" | "*") => {
+                    Some(format!("{operator} {}", text(left, content)))
+                }
+                _ => Some(text(expr, content)),
+            }
+        }
+        "call_expression" if is_size_helper(expr, content) => {
+            let args = expr.child_by_field_name("arguments")?;
+            let count = args.named_child(args.named_child_count().checked_sub(1)?)?;
+            Some(format!("+ {} elements", text(count, content)))
+        }
+        _ => Some(text(expr, content)),
+    }
+}
+
+/// Whether an expression is a compile time constant: sizeofs, number
+/// literals and upper case constants, combined with operators.
+fn is_fixed(expr: ts::Node<'_>, content: &[u8]) -> bool {
+    match expr.kind() {
+        "sizeof_expression" | "number_literal" | "char_literal" => true,
+        "identifier" => expr.utf8_text(content).is_ok_and(filter::is_literal),
+        "parenthesized_expression" | "binary_expression" | "unary_expression" => {
+            let mut cursor = expr.walk();
+            let fixed = expr
+                .named_children(&mut cursor)
+                .all(|child| is_fixed(child, content));
+            fixed
+        }
+        "cast_expression" => expr
+            .child_by_field_name("value")
+            .is_some_and(|value| is_fixed(value, content)),
+        "call_expression" if is_size_helper(expr, content) => expr
+            .child_by_field_name("arguments")
+            .and_then(|args| args.named_child(args.named_child_count().checked_sub(1)?))
+            .is_some_and(|count| is_fixed(count, content)),
+        _ => false,
+    }
+}
+
+fn is_size_helper(call: ts::Node<'_>, content: &[u8]) -> bool {
+    call.child_by_field_name("function")
+        .and_then(|function| function.utf8_text(content).ok())
+        .is_some_and(|name| SIZE_HELPERS.contains(&name))
+}
+
+fn text(node: ts::Node<'_>, content: &[u8]) -> String {
+    output::one_line(&content[node.byte_range()])
+}
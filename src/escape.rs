@@ -0,0 +1,153 @@
+use std::collections::HashSet;
+
+use tree_sitter as ts;
+
+use crate::frees::FREERS;
+use crate::pointee;
+use crate::query;
+use crate::scanner::Fate;
+
+/// Work out what becomes of an allocation within its function, going by the
+/// statements after the call: frees, returns and stores of the variable
+/// `assigned` it was assigned to, through `levels` dereferences. The checks
+/// are syntactic, a free on an error path doesn't count when the object is
+/// also returned or stored out of the function.
+pub fn fate(
+    definition: ts::Node<'_>,
+    call: ts::Node<'_>,
+    assigned: ts::Node<'_>,
+    levels: usize,
+    content: &[u8],
+) -> Fate {
+    let (Some(body), Ok(variable)) = (
+        definition.child_by_field_name("body"),
+        assigned.utf8_text(content),
+    ) else {
+        return Fate::Escapes {
+            returned: false,
+            stored_in_parameter: false,
+            stored_in_global: false,
+        };
+    };
+    let parameters = parameter_names(definition, content);
+    let locals = local_names(body, content);
+    let mut returned = false;
+    let mut stored_in_parameter = false;
+    let mut stored_in_global = false;
+    let mut freed = false;
+
+    // storing through `*pp` or `pp[i]` hands the object to whoever owns pp
+    let mut note_store = |root: &str| {
+        if parameters.contains(root) {
+            stored_in_parameter = true;
+        } else if !locals.contains(root) {
+            stored_in_global = true;
+        }
+    };
+    if levels > 0 {
+        note_store(variable);
+    }
+
+    let is_variable = |expr: ts::Node| pointee::variable(expr, content) == Some(variable);
+    let mut stack = vec![body];
+    while let Some(node) = stack.pop() {
+        let mut cursor = node.walk();
+        stack.extend(node.named_children(&mut cursor));
+        if node.end_byte() <= call.end_byte() {
+            continue;
+        }
+
+        match node.kind() {
+            "call_expression" => {
+                let name = node
+                    .child_by_field_name("function")
+                    .and_then(|function| function.utf8_text(content).ok());
+                let Some(freer) = FREERS.iter().find(|freer| Some(freer.name) == name) else {
+                    continue;
+                };
+                if node
+                    .child_by_field_name("arguments")
+                    .and_then(|args| query::argument(args, freer.ptr_arg))
+                    .is_some_and(is_variable)
+                {
+                    freed = true;
+                }
+            }
+            "return_statement" if node.named_child(0).is_some_and(is_variable) => {
+                returned = true;
+            }
+            "assignment_expression" => {
+                let (Some(left), Some(right)) = (
+                    node.child_by_field_name("left"),
+                    node.child_by_field_name("right"),
+                ) else {
+                    continue;
+                };
+                if left.kind() == "identifier" && left.utf8_text(content) == Ok(variable) {
+                    continue;
+                }
+                if is_variable(right) {
+                    if let Some(root) = root_identifier(left, content) {
+                        note_store(root);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    match freed && !returned && !stored_in_parameter && !stored_in_global {
+        true => Fate::FreedInFunction,
+        false => Fate::Escapes {
+            returned,
+            stored_in_parameter,
+            stored_in_global,
+        },
+    }
+}
+
+/// The variable an lvalue stores into, `ctx` for `ctx->buf[i]`.
+fn root_identifier<'a>(mut lvalue: ts::Node<'_>, content: &'a [u8]) -> Option<&'a str> {
+    loop {
+        lvalue = match lvalue.kind() {
+            "identifier" => return lvalue.utf8_text(content).ok(),
+            "field_expression" | "pointer_expression" | "subscript_expression" => {
+                lvalue.child_by_field_name("argument")?
+            }
+            "parenthesized_expression" => lvalue.named_child(0)?,
+            _ => return None,
+        };
+    }
+}
+
+fn parameter_names<'a>(definition: ts::Node<'_>, content: &'a [u8]) -> HashSet<&'a str> {
+    pointee::parameters(definition)
+        .into_iter()
+        .filter_map(|param| param.child_by_field_name("declarator"))
+        .flat_map(query::declared_identifiers)
+        .filter_map(|(id, _)| id.utf8_text(content).ok())
+        .collect()
+}
+
+/// The names declared anywhere in a function body.
+fn local_names<'a>(body: ts::Node<'_>, content: &'a [u8]) -> HashSet<&'a str> {
+    let mut names = HashSet::new();
+    let mut stack = vec![body];
+    while let Some(node) = stack.pop() {
+        let mut cursor = node.walk();
+        if node.kind() == "declaration" {
+            let declarators: Vec<ts::Node> = node
+                .children_by_field_name("declarator", &mut cursor)
+                .collect();
+            names.extend(
+                declarators
+                    .into_iter()
+                    .flat_map(query::declared_identifiers)
+                    .filter_map(|(id, _)| id.utf8_text(content).ok()),
+            );
+            continue;
+        }
+        stack.extend(node.named_children(&mut cursor));
+    }
+    names
+}
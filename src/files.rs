@@ -0,0 +1,558 @@
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::skipped::{self, Reason};
+use crate::stats::{self, STATS};
+
+/// Which files under the source directories are scanned.
+pub struct FilePolicy {
+    pub follow_symlinks: bool,
+    /// How many directories deep to descend below each root.
+    pub max_depth: Option<usize>,
+    /// When non-empty, only files matching one of these globs are scanned.
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    /// Directory names skipped at any depth.
+    pub exclude_dirs: Vec<String>,
+    /// The kernel directories skipped unless `--no-default-excludes` is given.
+    pub default_excludes: Option<DefaultExcludes>,
+}
+
+/// Top-level kernel directories which hold documentation, userspace tools
+/// and build scripts rather than kernel code.
+pub const DEFAULT_EXCLUDED_DIRS: &[&str] = &["Documentation", "tools", "samples", "scripts"];
+
+/// The directories skipped under each source root by default: those in
+/// `DEFAULT_EXCLUDED_DIRS`, and every `arch/` tree other than the one the
+/// vmlinux was built for.
+#[derive(Clone, Copy)]
+pub struct DefaultExcludes {
+    /// The `arch/` directory to keep. When the vmlinux's architecture isn't
+    /// known, nothing under `arch/` is skipped.
+    pub arch: Option<&'static str>,
+}
+
+impl DefaultExcludes {
+    /// Whether `dir`, a directory path relative to a source root, is skipped.
+    pub fn excludes(&self, dir: &Path) -> bool {
+        let mut components = dir.components().map(|c| c.as_os_str());
+        match (components.next(), components.next()) {
+            (Some(top), _) if DEFAULT_EXCLUDED_DIRS.iter().any(|name| top == *name) => true,
+            (Some(top), Some(arch)) if top == "arch" => self.arch.is_some_and(|keep| arch != keep),
+            _ => false,
+        }
+    }
+}
+
+/// A set of file globs. Relative patterns match paths relative to the source
+/// root, absolute patterns match the full path of the file.
+struct GlobFilter {
+    relative: globset::GlobSet,
+    absolute: globset::GlobSet,
+}
+
+impl GlobFilter {
+    fn new(patterns: &[String]) -> anyhow::Result<Self> {
+        let mut relative = globset::GlobSetBuilder::new();
+        let mut absolute = globset::GlobSetBuilder::new();
+        for pattern in patterns {
+            let glob = globset::Glob::new(pattern)?;
+            if Path::new(pattern).is_absolute() {
+                absolute.add(glob);
+            } else {
+                relative.add(glob);
+            }
+        }
+
+        Ok(GlobFilter {
+            relative: relative.build()?,
+            absolute: absolute.build()?,
+        })
+    }
+
+    fn is_match(&self, root: &Path, path: &Path) -> bool {
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        if self.relative.is_match(relative) {
+            return true;
+        }
+
+        !self.absolute.is_empty()
+            && std::path::absolute(path).is_ok_and(|full| self.absolute.is_match(full))
+    }
+}
+
+// how deep to walk when following symlinks and no --max-depth was given, in
+// case a link points somewhere enormous like /
+const FOLLOWED_MAX_DEPTH: usize = 64;
+
+/// The directory paths under `root` are relative to, `root` itself unless it
+/// is a single source file, in which case its name is matched against
+/// relative globs and printed by `--relative-paths`.
+pub fn root_dir(root: &Path) -> &Path {
+    match root.parent() {
+        Some(parent) if root.is_file() => parent,
+        _ => root,
+    }
+}
+
+/// The include and exclude globs of a policy, compiled once.
+pub struct Selector {
+    include: Option<GlobFilter>,
+    exclude: GlobFilter,
+    exclude_dirs: HashSet<OsString>,
+    default_excludes: Option<DefaultExcludes>,
+}
+
+impl Selector {
+    pub fn new(policy: &FilePolicy) -> anyhow::Result<Self> {
+        Ok(Selector {
+            include: (!policy.include.is_empty())
+                .then(|| GlobFilter::new(&policy.include))
+                .transpose()?,
+            exclude: GlobFilter::new(&policy.exclude)?,
+            exclude_dirs: policy.exclude_dirs.iter().map(OsString::from).collect(),
+            default_excludes: policy.default_excludes,
+        })
+    }
+
+    /// Whether the directory `dir`, found under `root`, is skipped along with
+    /// everything below it.
+    pub fn excludes_dir(&self, root: &Path, dir: &Path) -> bool {
+        dir.file_name()
+            .is_some_and(|name| self.exclude_dirs.contains(name))
+            || self
+                .default_excludes
+                .as_ref()
+                .is_some_and(|defaults| defaults.excludes(dir.strip_prefix(root).unwrap_or(dir)))
+    }
+
+    /// Whether `path`, found under `root`, passes the globs.
+    pub fn matches(&self, root: &Path, path: &Path) -> bool {
+        self.include
+            .as_ref()
+            .is_none_or(|include| include.is_match(root, path))
+            && !self.exclude.is_match(root, path)
+    }
+
+    /// Whether a path inside an archive passes the globs. Kernel archives keep
+    /// everything under a top-level directory like `linux-6.6.7/`, so relative
+    /// globs are matched both with and without it.
+    pub fn matches_archive_entry(&self, path: &Path) -> bool {
+        let mut components = path.components();
+        components.next();
+        let without_top = components.as_path();
+        let any = |filter: &GlobFilter| {
+            filter.is_match(Path::new(""), path)
+                || (!without_top.as_os_str().is_empty()
+                    && filter.is_match(Path::new(""), without_top))
+        };
+        self.include.as_ref().is_none_or(any) && !any(&self.exclude)
+    }
+
+    /// The outermost directory an archive entry is in which is skipped, if
+    /// any. The default excludes are matched with and without the archive's
+    /// top-level directory, like the globs.
+    pub fn excludes_archive_dir(&self, path: &Path) -> Option<PathBuf> {
+        let mut dirs: Vec<&Path> = path
+            .parent()?
+            .ancestors()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .collect();
+        dirs.reverse();
+        dirs.into_iter()
+            .find(|dir| {
+                let mut components = dir.components();
+                components.next();
+                let without_top = components.as_path();
+                dir.file_name()
+                    .is_some_and(|name| self.exclude_dirs.contains(name))
+                    || self.default_excludes.as_ref().is_some_and(|defaults| {
+                        defaults.excludes(dir)
+                            || (!without_top.as_os_str().is_empty()
+                                && defaults.excludes(without_top))
+                    })
+            })
+            .map(Path::to_path_buf)
+    }
+}
+
+pub fn is_source_file(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "c" || ext == "h")
+}
+
+/// Collect the C sources and headers under each of `roots` selected by
+/// `policy`, relative globs are matched against paths relative to the root
+/// the file was found under. A root may also be a single file, which is
+/// returned as long as it is a C source or header and isn't filtered out.
+/// Symlinks are only followed when asked to, then every directory is only
+/// descended into once so links can't create cycles, and the walk is limited
+/// to a maximum depth. Files reachable through more than one path, including
+/// from different roots, are only returned once.
+pub fn collect(roots: &[PathBuf], policy: &FilePolicy) -> anyhow::Result<Vec<PathBuf>> {
+    let selector = Selector::new(policy)?;
+    let max_depth = match (policy.max_depth, policy.follow_symlinks) {
+        (Some(depth), _) => depth,
+        (None, true) => FOLLOWED_MAX_DEPTH,
+        (None, false) => usize::MAX,
+    };
+
+    let mut seen = HashSet::new();
+    let mut visited_dirs = HashSet::new();
+    let mut files = vec![];
+    for root in roots {
+        files.extend(
+            walkdir::WalkDir::new(root)
+                .follow_links(policy.follow_symlinks)
+                .max_depth(max_depth)
+                .into_iter()
+                .filter_entry(|e| {
+                    if !e.file_type().is_dir() {
+                        return true;
+                    }
+                    if e.depth() > 0 && selector.excludes_dir(root_dir(root), e.path()) {
+                        skipped::record(Reason::ExcludedDir, e.path(), None);
+                        return false;
+                    }
+                    e.metadata()
+                        .map_or(true, |meta| visited_dirs.insert((meta.dev(), meta.ino())))
+                })
+                .filter_map(|e| e.ok())
+                .filter(|e| {
+                    if e.file_type().is_symlink() {
+                        stats::inc(&STATS.symlinks_skipped);
+                    }
+                    e.file_type().is_file() && is_source_file(e.path())
+                })
+                .map(|e| e.into_path())
+                .filter(|path| {
+                    let selected = selector.matches(root_dir(root), path);
+                    if !selected {
+                        skipped::record(Reason::ExcludedByGlob, path, None);
+                    }
+                    selected
+                })
+                .filter(|path| first_visit(&mut seen, path)),
+        );
+    }
+
+    Ok(files)
+}
+
+/// Record a file by its canonical path, returning false if it has already
+/// been seen through another path.
+fn first_visit(seen: &mut HashSet<PathBuf>, path: &Path) -> bool {
+    let canonical = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    if seen.insert(canonical) {
+        true
+    } else {
+        stats::inc(&STATS.duplicate_files);
+        false
+    }
+}
+
+/// Read the list of files to scan from `list`, or stdin when it is `-`.
+/// Entries are separated by newlines, or NUL bytes when `nul_separated`, and
+/// relative entries are resolved against the first of `roots` they exist
+/// under. Entries which don't exist are reported and skipped, as are ones
+/// without a C source or header extension when `ext_filter` is set.
+pub fn read_list(
+    list: &Path,
+    roots: &[PathBuf],
+    nul_separated: bool,
+    ext_filter: bool,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut contents = vec![];
+    if list == Path::new("-") {
+        std::io::stdin().lock().read_to_end(&mut contents)?;
+    } else {
+        contents = std::fs::read(list)?;
+    }
+
+    let separator = if nul_separated { b'\0' } else { b'\n' };
+    let mut seen = HashSet::new();
+    let mut files = vec![];
+    for entry in contents.split(|&b| b == separator) {
+        let entry = match entry {
+            [entry @ .., b'\r'] if !nul_separated => entry,
+            entry => entry,
+        };
+        if entry.is_empty() {
+            continue;
+        }
+
+        let entry = OsStr::from_bytes(entry);
+        let path = roots
+            .iter()
+            .map(|root| root.join(entry))
+            .find(|path| path.is_file())
+            .unwrap_or_else(|| roots[0].join(entry));
+        if !path.is_file() {
+            eprintln!(
+                "warning: listed file {} does not exist, skipping it",
+                path.display()
+            );
+            continue;
+        }
+        if ext_filter && !is_source_file(&path) {
+            continue;
+        }
+        if first_visit(&mut seen, &path) {
+            files.push(path);
+        }
+    }
+
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(include: &[&str], exclude: &[&str]) -> FilePolicy {
+        FilePolicy {
+            follow_symlinks: false,
+            max_depth: None,
+            include: include.iter().map(|glob| glob.to_string()).collect(),
+            exclude: exclude.iter().map(|glob| glob.to_string()).collect(),
+            exclude_dirs: vec![],
+            default_excludes: None,
+        }
+    }
+
+    fn fixture_tree() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tree")
+    }
+
+    /// The files of the fixture tree `collect` returns for `policy`,
+    /// relative to the tree.
+    fn collected(policy: &FilePolicy) -> Vec<String> {
+        collected_under(&fixture_tree(), &[fixture_tree()], policy)
+    }
+
+    /// The files `collect` returns from `roots`, relative to `base` and
+    /// sorted.
+    fn collected_under(base: &Path, roots: &[PathBuf], policy: &FilePolicy) -> Vec<String> {
+        let mut files: Vec<String> = collect(roots, policy)
+            .unwrap()
+            .iter()
+            .map(|path| path.strip_prefix(base).unwrap().display().to_string())
+            .collect();
+        files.sort();
+        files
+    }
+
+    /// A fresh tree under the temp directory with an empty file at each of
+    /// `files`, for the layouts the checked in fixtures can't hold, like
+    /// symlink loops.
+    fn temp_tree(name: &str, files: &[&str]) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("kheap_sift-files-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        for file in files {
+            let path = root.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn include_and_exclude_globs() {
+        let root = Path::new("/src/linux");
+        let matches = |selector: &Selector, path: &str| selector.matches(root, &root.join(path));
+
+        let all = Selector::new(&policy(&[], &[])).unwrap();
+        assert!(matches(&all, "net/core/sock.c"));
+
+        let net = Selector::new(&policy(&["net/**", "include/net/**"], &[])).unwrap();
+        assert!(matches(&net, "net/core/sock.c"));
+        assert!(matches(&net, "include/net/sock.h"));
+        assert!(!matches(&net, "drivers/net/tun.c"));
+        assert!(!matches(&net, "include/linux/skbuff.h"));
+
+        // an exclude wins over an include
+        let net_but_core = Selector::new(&policy(&["net/**"], &["net/core/**"])).unwrap();
+        assert!(matches(&net_but_core, "net/ipv4/tcp.c"));
+        assert!(!matches(&net_but_core, "net/core/sock.c"));
+
+        // absolute globs match the full path
+        let absolute = Selector::new(&policy(&["/src/linux/fs/**"], &[])).unwrap();
+        assert!(matches(&absolute, "fs/open.c"));
+        assert!(!matches(&absolute, "mm/slub.c"));
+    }
+
+    #[test]
+    fn include_globs_in_archives() {
+        let net = Selector::new(&policy(&["net/**"], &["**/*.h"])).unwrap();
+        // with and without the archive's top-level directory
+        assert!(net.matches_archive_entry(Path::new("linux-6.6/net/core/sock.c")));
+        assert!(net.matches_archive_entry(Path::new("net/core/sock.c")));
+        assert!(!net.matches_archive_entry(Path::new("linux-6.6/fs/open.c")));
+        assert!(!net.matches_archive_entry(Path::new("linux-6.6/net/core/sock.h")));
+    }
+
+    #[test]
+    fn invalid_glob_is_an_error() {
+        assert!(Selector::new(&policy(&["net/[core"], &[])).is_err());
+        assert!(Selector::new(&policy(&[], &["{a,b"])).is_err());
+    }
+
+    #[test]
+    fn collect_applies_globs_and_extensions() {
+        // net/core/Makefile is never a candidate
+        assert_eq!(
+            collected(&policy(&[], &[])),
+            [
+                "drivers/net/foo.c",
+                "fs/baz.c",
+                "include/linux/foo.h",
+                "net/core/bar.c",
+            ]
+        );
+        assert_eq!(
+            collected(&policy(&["drivers/**", "include/**"], &[])),
+            ["drivers/net/foo.c", "include/linux/foo.h"]
+        );
+        assert_eq!(
+            collected(&policy(&["**/*.c"], &["fs/**"])),
+            ["drivers/net/foo.c", "net/core/bar.c"]
+        );
+        assert!(collected(&policy(&["mm/**"], &[])).is_empty());
+    }
+
+    #[test]
+    fn max_depth_limits_the_walk() {
+        let depth = |max_depth| {
+            collected(&FilePolicy {
+                max_depth: Some(max_depth),
+                ..policy(&[], &[])
+            })
+        };
+        assert!(depth(1).is_empty());
+        assert_eq!(depth(2), ["fs/baz.c"]);
+        assert_eq!(
+            depth(3),
+            [
+                "drivers/net/foo.c",
+                "fs/baz.c",
+                "include/linux/foo.h",
+                "net/core/bar.c",
+            ]
+        );
+    }
+
+    #[test]
+    fn exclude_dirs_skip_a_name_at_any_depth() {
+        let excluding = |dirs: &[&str]| {
+            collected(&FilePolicy {
+                exclude_dirs: dirs.iter().map(|dir| dir.to_string()).collect(),
+                ..policy(&[], &[])
+            })
+        };
+        // both drivers/net and net
+        assert_eq!(excluding(&["net"]), ["fs/baz.c", "include/linux/foo.h"]);
+        assert_eq!(
+            excluding(&["core", "include"]),
+            ["drivers/net/foo.c", "fs/baz.c"]
+        );
+        // only whole names
+        assert_eq!(excluding(&["co"]).len(), 4);
+    }
+
+    #[test]
+    fn default_excludes_skip_docs_tools_and_other_arches() {
+        let root = temp_tree(
+            "defaults",
+            &[
+                "Documentation/foo.c",
+                "tools/foo.c",
+                "samples/foo.c",
+                "scripts/foo.c",
+                "arch/x86/foo.c",
+                "arch/arm64/foo.c",
+                "kernel/foo.c",
+                "kernel/tools/foo.c",
+            ],
+        );
+        let with = |default_excludes| {
+            collected_under(
+                &root,
+                std::slice::from_ref(&root),
+                &FilePolicy {
+                    default_excludes,
+                    ..policy(&[], &[])
+                },
+            )
+        };
+        let x86 = with(Some(DefaultExcludes { arch: Some("x86") }));
+        let any_arch = with(Some(DefaultExcludes { arch: None }));
+        let none = with(None);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        // only at the top of the tree
+        assert_eq!(
+            x86,
+            ["arch/x86/foo.c", "kernel/foo.c", "kernel/tools/foo.c"]
+        );
+        assert_eq!(
+            any_arch,
+            [
+                "arch/arm64/foo.c",
+                "arch/x86/foo.c",
+                "kernel/foo.c",
+                "kernel/tools/foo.c",
+            ]
+        );
+        assert_eq!(none.len(), 8);
+    }
+
+    #[test]
+    fn follow_symlinks_through_links_and_loops() {
+        let root = temp_tree("symlinks", &["a/x.c", "b/y.c"]);
+        // a loop back to the root, a second path to a/ and a file link
+        std::os::unix::fs::symlink("..", root.join("a/loop")).unwrap();
+        std::os::unix::fs::symlink("a", root.join("c")).unwrap();
+        std::os::unix::fs::symlink("b/y.c", root.join("z.c")).unwrap();
+        let with = |follow_symlinks| {
+            collected_under(
+                &root,
+                std::slice::from_ref(&root),
+                &FilePolicy {
+                    follow_symlinks,
+                    ..policy(&[], &[])
+                },
+            )
+        };
+        let not_followed = with(false);
+        let followed = with(true);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(not_followed, ["a/x.c", "b/y.c"]);
+        // the walk ends despite the loop, and each file is listed once
+        // through whichever path reached it first
+        assert_eq!(followed.len(), 2, "{followed:?}");
+        assert!(followed.iter().any(|path| path.ends_with("x.c")));
+        assert!(followed.iter().any(|path| path.ends_with("y.c")));
+    }
+
+    #[test]
+    fn collect_lists_a_file_once() {
+        let tree = fixture_tree();
+        // the same root twice, and a root inside another
+        let roots = [tree.clone(), tree.join("net"), tree.clone()];
+        assert_eq!(
+            collected_under(&tree, &roots, &policy(&[], &[])),
+            collected(&policy(&[], &[]))
+        );
+        // a file root already found under a directory root
+        let roots = [tree.join("fs"), tree.join("fs/baz.c")];
+        assert_eq!(
+            collected_under(&tree, &roots, &policy(&[], &[])),
+            ["fs/baz.c"]
+        );
+    }
+}
@@ -0,0 +1,183 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use tree_sitter as ts;
+
+use crate::alloc::Zeroing;
+use crate::cli::ScanArgs;
+use crate::elastic::Sizing;
+use crate::gfp::GfpSpec;
+use crate::query::QueryMatch;
+use crate::scanner::{self, Fate};
+
+lazy_static! {
+    static ref IDENTIFIER: Regex = Regex::new(r"[A-Za-z_][A-Za-z0-9_]*").unwrap();
+}
+
+/// The filters on the arguments of an allocation site, compiled once and
+/// shared by every worker.
+pub struct SiteFilter {
+    include: Option<Regex>,
+    exclude: Option<Regex>,
+    size_expr: Option<Regex>,
+    gfp: Option<GfpSpec>,
+    gfp_include_unknown: bool,
+    zeroing: Option<Zeroing>,
+    only_elastic: bool,
+    only_escaping: bool,
+    size_unrelated: Option<bool>,
+    exclude_caps: Vec<String>,
+    functions: Vec<Regex>,
+}
+
+/// The filter options a [`SiteFilter`] is built from, as given on the
+/// command line or in a `ScanConfig`. The defaults filter nothing.
+#[derive(Default)]
+pub struct SiteFilterOptions<'a> {
+    pub flags: Option<&'a str>,
+    pub flags_exclude: Option<&'a str>,
+    pub size_expr: Option<&'a str>,
+    pub gfp: Option<&'a str>,
+    /// Report the sites whose flags the GFP spec can't classify.
+    pub gfp_include_unknown: bool,
+    pub zeroing: Option<Zeroing>,
+    pub only_elastic: bool,
+    pub only_escaping: bool,
+    pub size_unrelated: Option<bool>,
+    pub exclude_caps: &'a [String],
+    pub functions: &'a [String],
+}
+
+impl SiteFilter {
+    pub fn new(args: &ScanArgs) -> anyhow::Result<Self> {
+        Self::build(&SiteFilterOptions {
+            flags: args.flags.as_deref(),
+            flags_exclude: args.flags_exclude.as_deref(),
+            size_expr: args.size_expr.as_deref(),
+            gfp: args.gfp.as_deref(),
+            gfp_include_unknown: args.gfp_include_unknown,
+            zeroing: args.zeroing(),
+            only_elastic: args.only_elastic,
+            only_escaping: args.only_escaping,
+            size_unrelated: args.size_unrelated(),
+            exclude_caps: &args.exclude_caps,
+            functions: &args.functions,
+        })
+    }
+
+    /// Compile the filters from the option values they are given as.
+    pub fn build(options: &SiteFilterOptions<'_>) -> anyhow::Result<Self> {
+        Ok(SiteFilter {
+            include: options.flags.map(Regex::new).transpose()?,
+            exclude: options.flags_exclude.map(Regex::new).transpose()?,
+            size_expr: options.size_expr.map(Regex::new).transpose()?,
+            gfp: options.gfp.map(GfpSpec::parse).transpose()?,
+            gfp_include_unknown: options.gfp_include_unknown,
+            zeroing: options.zeroing,
+            only_elastic: options.only_elastic,
+            only_escaping: options.only_escaping,
+            size_unrelated: options.size_unrelated,
+            exclude_caps: options.exclude_caps.to_vec(),
+            functions: options
+                .functions
+                .iter()
+                .map(|function| Regex::new(function))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Whether an allocation site is reported. The exclude regex is only applied to literal flag
+    /// expressions, a site passing its flags through a variable could be
+    /// called with anything so it isn't excluded based on the variable's name.
+    /// Sites whose flags the GFP spec can't classify are only reported when
+    /// asked for.
+    pub fn matches(&self, qm: &QueryMatch, content: &[u8]) -> bool {
+        if self.only_escaping && qm.fate == Fate::FreedInFunction {
+            return false;
+        }
+        if self
+            .size_unrelated
+            .is_some_and(|wanted| wanted != (qm.sizing == Sizing::SizeUnrelated))
+        {
+            return false;
+        }
+        self.matches_call(
+            qm.flags_text(content).unwrap_or(""),
+            &qm.size_expr(content),
+            qm.zeroing,
+            qm.elastic.is_some(),
+            &qm.caps,
+        )
+    }
+
+    /// Whether a `--loose` site is reported. It has no variable to follow, so
+    /// `--only-escaping` drops it, and its size names the struct, so
+    /// `--only-size-unrelated` does too.
+    pub fn matches_loose(
+        &self,
+        flags: &str,
+        size_expr: &str,
+        zeroing: Zeroing,
+        elastic: bool,
+        caps: &[String],
+    ) -> bool {
+        !self.only_escaping
+            && self.size_unrelated != Some(true)
+            && self.matches_call(flags, size_expr, zeroing, elastic, caps)
+    }
+
+    /// Whether a site in the function `definition` defines is reported, by
+    /// the `--function` regexes. Without any every site is, with some a site
+    /// whose function has no name to match is dropped.
+    pub fn matches_function(&self, definition: Option<ts::Node<'_>>, content: &[u8]) -> bool {
+        if self.functions.is_empty() {
+            return true;
+        }
+        definition
+            .and_then(|definition| scanner::function_name(definition, content))
+            .is_some_and(|name| self.functions.iter().any(|re| re.is_match(&name)))
+    }
+
+    /// The filters on the allocation call itself.
+    fn matches_call(
+        &self,
+        flags: &str,
+        size_expr: &str,
+        zeroing: Zeroing,
+        elastic: bool,
+        caps: &[String],
+    ) -> bool {
+        if self.zeroing.is_some_and(|wanted| wanted != zeroing) {
+            return false;
+        }
+        if self.only_elastic && !elastic {
+            return false;
+        }
+        if caps.iter().any(|cap| self.exclude_caps.contains(cap)) {
+            return false;
+        }
+        if self
+            .size_expr
+            .as_ref()
+            .is_some_and(|re| !re.is_match(size_expr))
+        {
+            return false;
+        }
+        if self.include.as_ref().is_some_and(|re| !re.is_match(flags)) {
+            return false;
+        }
+        if is_literal(flags) && self.exclude.as_ref().is_some_and(|re| re.is_match(flags)) {
+            return false;
+        }
+        self.gfp
+            .as_ref()
+            .is_none_or(|gfp| gfp.matches(flags).unwrap_or(self.gfp_include_unknown))
+    }
+}
+
+/// Whether a flags expression only combines constants like `GFP_KERNEL` and
+/// `__GFP_ZERO`, rather than taking them from a variable or call.
+pub fn is_literal(flags: &str) -> bool {
+    let mut identifiers = IDENTIFIER.find_iter(flags).peekable();
+    identifiers.peek().is_some()
+        && identifiers.all(|ident| !ident.as_str().chars().any(|c| c.is_ascii_lowercase()))
+}
@@ -0,0 +1,109 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use serde_json::json;
+use tree_sitter as ts;
+
+use crate::cli::FocusFormat;
+use crate::scanner;
+use crate::subsystem;
+
+// set by `--emit-focus`
+static EMIT_FOCUS: AtomicBool = AtomicBool::new(false);
+
+/// A function with sites, however many structs it allocates.
+#[derive(Default)]
+struct Function {
+    structs: BTreeSet<String>,
+    /// One per site plus the site's `--rank-sites` score.
+    weight: i64,
+}
+
+lazy_static! {
+    // by path relative to the scanned tree, then function name
+    static ref FUNCTIONS: Mutex<BTreeMap<String, BTreeMap<String, Function>>> =
+        Mutex::new(BTreeMap::new());
+}
+
+pub fn enable() {
+    EMIT_FOCUS.store(true, Ordering::SeqCst);
+}
+
+pub fn enabled() -> bool {
+    EMIT_FOCUS.load(Ordering::SeqCst)
+}
+
+/// Note a site of `struct_name` in the function `definition` and its score.
+pub fn record(
+    definition: ts::Node<'_>,
+    content: &[u8],
+    path: &Path,
+    struct_name: &str,
+    score: i64,
+) {
+    let Some(function) = scanner::function_name(definition, content) else {
+        return;
+    };
+    let path = subsystem::relative(path).display().to_string();
+    let mut functions = FUNCTIONS.lock().unwrap();
+    let entry = functions
+        .entry(path)
+        .or_default()
+        .entry(function)
+        .or_default();
+    entry.structs.insert(struct_name.to_string());
+    entry.weight += 1 + score.max(0);
+}
+
+/// Write the recorded functions to `out`. Returns the number of functions
+/// written.
+///
+/// The text format has a line per function, `path<TAB>function<TAB>structs`
+/// with the structs comma separated. The syzkaller format is a
+/// `focus_areas` fragment with an area per file, filtering on the file and
+/// its functions, weighted by the sum of their sites' weights, and a last
+/// area of weight 1 for the rest of the kernel.
+pub fn write(out: &Path, format: FocusFormat) -> anyhow::Result<usize> {
+    let functions = FUNCTIONS.lock().unwrap();
+    let count = functions.values().map(BTreeMap::len).sum();
+    let mut file = std::io::BufWriter::new(std::fs::File::create(out)?);
+    match format {
+        FocusFormat::Text => {
+            for (path, functions) in functions.iter() {
+                for (name, function) in functions {
+                    let structs: Vec<&str> = function.structs.iter().map(String::as_str).collect();
+                    writeln!(file, "{path}\t{name}\t{}", structs.join(","))?;
+                }
+            }
+        }
+        FocusFormat::Syzkaller => {
+            let mut areas: Vec<_> = functions
+                .iter()
+                .map(|(path, functions)| {
+                    let names: Vec<String> = functions
+                        .keys()
+                        .map(|name| format!("^{}$", regex::escape(name)))
+                        .collect();
+                    let weight: i64 = functions.values().map(|function| function.weight).sum();
+                    json!({
+                        "name": path,
+                        "filter": {
+                            "files": [format!("^{}$", regex::escape(path))],
+                            "functions": names,
+                        },
+                        "weight": weight as f64,
+                    })
+                })
+                .collect();
+            areas.push(json!({ "weight": 1.0 }));
+            serde_json::to_writer_pretty(&mut file, &json!({ "focus_areas": areas }))?;
+            writeln!(file)?;
+        }
+    }
+    file.flush()?;
+    Ok(count)
+}
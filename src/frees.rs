@@ -0,0 +1,213 @@
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use tree_sitter as ts;
+use tree_sitter_c as ts_c;
+
+use crate::output;
+use crate::pointee;
+use crate::query::{self, QueryMatch};
+use crate::scanner;
+
+/// A function releasing an object, along with which argument is the object.
+#[derive(Debug)]
+pub struct Freer {
+    pub name: &'static str,
+    pub ptr_arg: usize,
+    /// Whether the object is only freed after an RCU grace period.
+    pub rcu: bool,
+}
+
+pub const FREERS: &[Freer] = &[
+    Freer {
+        name: "kfree",
+        ptr_arg: 0,
+        rcu: false,
+    },
+    Freer {
+        name: "kvfree",
+        ptr_arg: 0,
+        rcu: false,
+    },
+    Freer {
+        name: "kfree_rcu",
+        ptr_arg: 0,
+        rcu: true,
+    },
+    Freer {
+        name: "kmem_cache_free",
+        ptr_arg: 1,
+        rcu: false,
+    },
+];
+
+// set by `--track-frees`
+static TRACK_FREES: AtomicBool = AtomicBool::new(false);
+
+/// A call freeing one of the selected structs.
+struct FreeSite {
+    path: PathBuf,
+    line: usize,
+    function: Option<String>,
+    /// The call as written, on one line.
+    call: String,
+    /// The variable freed, for pairing the call with an allocation.
+    variable: Option<String>,
+    freer: &'static Freer,
+}
+
+/// What is kept over a scan to print the free sites at the end.
+#[derive(Default)]
+struct Tracked {
+    // structs which had an allocation site reported
+    allocated: BTreeSet<String>,
+    // lines of the allocations by path, function and variable assigned
+    allocs: HashMap<(PathBuf, String, String), Vec<usize>>,
+    frees: HashMap<String, Vec<FreeSite>>,
+}
+
+lazy_static! {
+    static ref FREE_QUERY: ts::Query = {
+        let names: Vec<&str> = FREERS.iter().map(|freer| freer.name).collect();
+        let query = format!(
+            "(call_expression
+                function: (identifier) @free.function
+                (#match? @free.function \"^({})$\")
+                arguments: (argument_list) @free.args
+            ) @free.call",
+            names.join("|")
+        );
+        ts::Query::new(ts_c::language(), &query).unwrap()
+    };
+    static ref TRACKED: Mutex<Tracked> = Mutex::new(Tracked::default());
+}
+
+pub fn enable() {
+    TRACK_FREES.store(true, Ordering::SeqCst);
+}
+
+pub fn enabled() -> bool {
+    TRACK_FREES.load(Ordering::SeqCst)
+}
+
+/// Note a reported allocation site, so its struct gets a free sites section
+/// and frees of the same variable in the same function are paired with it.
+pub fn record_alloc(qm: &QueryMatch, content: &[u8], path: &Path) {
+    let Ok(struct_name) = qm.struct_name.utf8_text(content) else {
+        return;
+    };
+    let mut tracked = TRACKED.lock().unwrap();
+    tracked.allocated.insert(struct_name.to_string());
+
+    let function = scanner::function_name(qm.function_definition, content);
+    if let (Some(function), Ok(variable)) = (function, qm.assign_name.utf8_text(content)) {
+        let line = qm.assign_call.start_position().row + 1;
+        tracked
+            .allocs
+            .entry((path.to_path_buf(), function, variable.to_string()))
+            .or_default()
+            .push(line);
+    }
+}
+
+/// Find the calls in a parsed file freeing a pointer whose declared or cast
+/// type is one of the structs `selected` accepts.
+pub fn find(root: ts::Node<'_>, content: &[u8], path: &Path, selected: impl Fn(&str) -> bool) {
+    let mut found = vec![];
+    for [call, function, args] in query::captures(
+        &FREE_QUERY,
+        ["free.call", "free.function", "free.args"],
+        root,
+        content,
+    ) {
+        let Some(freer) = FREERS
+            .iter()
+            .find(|freer| function.utf8_text(content).ok() == Some(freer.name))
+        else {
+            continue;
+        };
+        let Some(ptr) = query::argument(args, freer.ptr_arg) else {
+            continue;
+        };
+        let Some(struct_name) = pointee::struct_pointee(ptr, content) else {
+            continue;
+        };
+        if !selected(struct_name) {
+            continue;
+        }
+
+        let definition = scanner::enclosing_function(call);
+        found.push((
+            struct_name.to_string(),
+            FreeSite {
+                path: path.to_path_buf(),
+                line: call.start_position().row + 1,
+                function: definition.and_then(|def| scanner::function_name(def, content)),
+                call: output::one_line(&content[call.byte_range()]),
+                variable: pointee::variable(ptr, content).map(str::to_string),
+                freer,
+            },
+        ));
+    }
+
+    if found.is_empty() {
+        return;
+    }
+    let mut tracked = TRACKED.lock().unwrap();
+    for (struct_name, site) in found {
+        tracked.frees.entry(struct_name).or_default().push(site);
+    }
+}
+
+/// Print a section with the free sites of every struct which had an
+/// allocation site reported.
+pub fn print_report() {
+    let tracked = TRACKED.lock().unwrap();
+    for struct_name in &tracked.allocated {
+        println!("======== Free sites for: struct {struct_name} ========\n");
+        let mut sites: Vec<&FreeSite> = tracked
+            .frees
+            .get(struct_name)
+            .map(|sites| sites.iter().collect())
+            .unwrap_or_default();
+        if sites.is_empty() {
+            println!("No free sites found\n");
+            continue;
+        }
+        sites.sort_by(|a, b| (&a.path, a.line).cmp(&(&b.path, b.line)));
+
+        for site in sites {
+            let mut notes = vec![];
+            if site.freer.rcu {
+                notes.push("RCU-delayed, reusable only after a grace period".to_string());
+            }
+            let paired = site.function.clone().zip(site.variable.clone()).and_then(
+                |(function, variable)| tracked.allocs.get(&(site.path.clone(), function, variable)),
+            );
+            if let Some(lines) = paired {
+                let lines: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+                notes.push(format!("frees the allocation at line {}", lines.join(", ")));
+            }
+
+            let function = site
+                .function
+                .as_deref()
+                .map(|function| format!(" in {function}"))
+                .unwrap_or_default();
+            let notes = match notes.is_empty() {
+                true => String::new(),
+                false => format!(" ({})", notes.join("; ")),
+            };
+            println!(
+                "{}:{}{function}: {}{notes}",
+                output::display_path(&site.path),
+                site.line,
+                site.call
+            );
+        }
+        println!();
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::BTreeSet;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    static ref IDENTIFIER: Regex = Regex::new(r"(~\s*)?([A-Za-z_][A-Za-z0-9_]*)").unwrap();
+}
+
+/// The composite GFP flags of include/linux/gfp_types.h, in terms of the
+/// `__GFP_*` bits they set. Flags which changed between releases follow
+/// recent kernels.
+const COMPOSITES: &[(&str, &str)] = &[
+    (
+        "__GFP_RECLAIM",
+        "__GFP_DIRECT_RECLAIM | __GFP_KSWAPD_RECLAIM",
+    ),
+    ("GFP_ATOMIC", "__GFP_HIGH | __GFP_KSWAPD_RECLAIM"),
+    ("GFP_KERNEL", "__GFP_RECLAIM | __GFP_IO | __GFP_FS"),
+    ("GFP_KERNEL_ACCOUNT", "GFP_KERNEL | __GFP_ACCOUNT"),
+    ("GFP_NOWAIT", "__GFP_KSWAPD_RECLAIM | __GFP_NOWARN"),
+    ("GFP_NOIO", "__GFP_RECLAIM"),
+    ("GFP_NOFS", "__GFP_RECLAIM | __GFP_IO"),
+    (
+        "GFP_USER",
+        "__GFP_RECLAIM | __GFP_IO | __GFP_FS | __GFP_HARDWALL",
+    ),
+    ("GFP_DMA", "__GFP_DMA"),
+    ("GFP_DMA32", "__GFP_DMA32"),
+    ("GFP_HIGHUSER", "GFP_USER | __GFP_HIGHMEM"),
+    (
+        "GFP_HIGHUSER_MOVABLE",
+        "GFP_HIGHUSER | __GFP_MOVABLE | __GFP_SKIP_KASAN",
+    ),
+    (
+        "GFP_TRANSHUGE_LIGHT",
+        "GFP_HIGHUSER_MOVABLE | __GFP_COMP | __GFP_NOMEMALLOC | __GFP_NOWARN & ~__GFP_RECLAIM",
+    ),
+    (
+        "GFP_TRANSHUGE",
+        "GFP_TRANSHUGE_LIGHT | __GFP_DIRECT_RECLAIM",
+    ),
+];
+
+/// Categories of allocation contexts a spec can name instead of flags.
+const CATEGORIES: &[&str] = &["sleeping", "atomic", "account"];
+
+/// Expand a flags expression into the `__GFP_*` bits it sets. Returns `None`
+/// when it can't be classified, because it takes flags from a variable or a
+/// call, or uses a composite flag that isn't in the table.
+pub fn expand(flags: &str) -> Option<BTreeSet<String>> {
+    let mut set = BTreeSet::new();
+    let mut cleared = BTreeSet::new();
+    for caps in IDENTIFIER.captures_iter(flags) {
+        let bits = expand_flag(&caps[2])?;
+        if caps.get(1).is_some() {
+            cleared.extend(bits);
+        } else {
+            set.extend(bits);
+        }
+    }
+    if set.is_empty() {
+        return None;
+    }
+    Some(set.difference(&cleared).cloned().collect())
+}
+
+/// An allocation context class of a site's flags. A site can be in several,
+/// like `GFP_KERNEL_ACCOUNT` being sleeping and accounted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum GfpContext {
+    /// May sleep in direct reclaim, GFP_KERNEL and friends.
+    Sleeping,
+    /// Can't sleep, GFP_ATOMIC and GFP_NOWAIT.
+    Atomic,
+    /// May sleep, but reclaim can't call into filesystems or do IO, GFP_NOFS
+    /// and GFP_NOIO.
+    FsConstrained,
+    /// Charged to the allocating task's memory cgroup, `__GFP_ACCOUNT` or
+    /// GFP_KERNEL_ACCOUNT.
+    Accounted,
+    /// Memory for userspace, GFP_USER and GFP_HIGHUSER.
+    User,
+}
+
+impl std::fmt::Display for GfpContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            GfpContext::Sleeping => "sleeping",
+            GfpContext::Atomic => "atomic",
+            GfpContext::FsConstrained => "fs-constrained",
+            GfpContext::Accounted => "accounted",
+            GfpContext::User => "user",
+        })
+    }
+}
+
+/// The context classes of a flags expression, by the bits it expands to.
+/// Returns `None` when the flags can't be expanded.
+pub fn contexts(flags: &str) -> Option<Vec<GfpContext>> {
+    let bits = expand(flags)?;
+    let sleeping = bits.contains("__GFP_DIRECT_RECLAIM");
+    let classes = [
+        (sleeping, GfpContext::Sleeping),
+        (!sleeping, GfpContext::Atomic),
+        (
+            sleeping && !(bits.contains("__GFP_FS") && bits.contains("__GFP_IO")),
+            GfpContext::FsConstrained,
+        ),
+        (bits.contains("__GFP_ACCOUNT"), GfpContext::Accounted),
+        (bits.contains("__GFP_HARDWALL"), GfpContext::User),
+    ];
+    Some(
+        classes
+            .into_iter()
+            .filter(|&(holds, _)| holds)
+            .map(|(_, class)| class)
+            .collect(),
+    )
+}
+
+fn expand_flag(flag: &str) -> Option<BTreeSet<String>> {
+    if let Some((_, definition)) = COMPOSITES.iter().find(|(name, _)| *name == flag) {
+        return expand(definition);
+    }
+    if flag.starts_with("__GFP_") {
+        return Some(BTreeSet::from([flag.to_string()]));
+    }
+    None
+}
+
+/// A term of a `--gfp` spec, which holds for a site's flags or not.
+enum Term {
+    /// Every bit of the flag is set.
+    Requires(BTreeSet<String>),
+    Category(&'static str),
+    Not(Box<Term>),
+}
+
+impl Term {
+    fn parse(term: &str) -> anyhow::Result<Self> {
+        if let Some(term) = term.strip_prefix('!') {
+            return Ok(Term::Not(Box::new(Term::parse(term)?)));
+        }
+        let term = term.strip_prefix("requires:").unwrap_or(term).trim();
+        if let Some(category) = CATEGORIES.iter().find(|category| **category == term) {
+            return Ok(Term::Category(category));
+        }
+        match expand_flag(term) {
+            Some(bits) => Ok(Term::Requires(bits)),
+            None => anyhow::bail!(
+                "unknown GFP flag or category '{term}', expected a __GFP_* flag, a \
+                 GFP_* flag from include/linux/gfp_types.h or one of: {}",
+                CATEGORIES.join(", ")
+            ),
+        }
+    }
+
+    fn holds(&self, bits: &BTreeSet<String>) -> bool {
+        match self {
+            Term::Requires(required) => required.is_subset(bits),
+            Term::Category("sleeping") => bits.contains("__GFP_DIRECT_RECLAIM"),
+            Term::Category("atomic") => !bits.contains("__GFP_DIRECT_RECLAIM"),
+            Term::Category("account") => bits.contains("__GFP_ACCOUNT"),
+            Term::Category(_) => unreachable!("categories are checked when parsed"),
+            Term::Not(term) => !term.holds(bits),
+        }
+    }
+}
+
+/// A `--gfp` spec, comma separated terms which must all hold for a site to be
+/// reported. A term is a flag the site's flags must include all the bits of,
+/// optionally prefixed with `requires:`, or a category, either of which may
+/// be negated with `!`. `requires:GFP_KERNEL,!__GFP_HIGH` selects sites which
+/// may sleep and do IO and FS reclaim but don't dip into the atomic reserves.
+pub struct GfpSpec {
+    terms: Vec<Term>,
+}
+
+impl GfpSpec {
+    pub fn parse(spec: &str) -> anyhow::Result<Self> {
+        let terms = spec
+            .split(',')
+            .filter(|term| !term.trim().is_empty())
+            .map(|term| Term::parse(term.trim()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if terms.is_empty() {
+            anyhow::bail!("empty GFP spec");
+        }
+        Ok(GfpSpec { terms })
+    }
+
+    /// Whether a site's flags satisfy the spec, `None` when they can't be
+    /// classified.
+    pub fn matches(&self, flags: &str) -> Option<bool> {
+        let bits = expand(flags)?;
+        Some(self.terms.iter().all(|term| term.holds(&bits)))
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use lazy_static::lazy_static;
+use tree_sitter as ts;
+use tree_sitter_c as ts_c;
+
+use crate::pointee;
+use crate::query;
+use crate::scanner;
+
+/// The ops structs whose initializers bind handlers reachable from userspace.
+pub const OPS_STRUCTS: &[&str] = &["file_operations", "proto_ops"];
+
+// set by `--annotate-handlers`
+static ANNOTATE_HANDLERS: AtomicBool = AtomicBool::new(false);
+
+/// A function bound to a field of an ops table, e.g. `.unlocked_ioctl` of
+/// `struct file_operations foo_fops`.
+#[derive(Debug, Clone)]
+pub struct Binding {
+    pub ops: String,
+    pub table: String,
+    pub field: String,
+}
+
+impl std::fmt::Display for Binding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "bound to .{} of {} {}", self.field, self.ops, self.table)
+    }
+}
+
+lazy_static! {
+    static ref OPS_QUERY: ts::Query = {
+        let query = format!(
+            "(declaration
+                type: (struct_specifier
+                    name: (type_identifier) @ops.type
+                    (#match? @ops.type \"^({})$\"))
+                declarator: (init_declarator
+                    declarator: (_) @ops.table
+                    value: (initializer_list) @ops.init)
+            )",
+            OPS_STRUCTS.join("|")
+        );
+        ts::Query::new(ts_c::language(), &query).unwrap()
+    };
+}
+
+pub fn enable() {
+    ANNOTATE_HANDLERS.store(true, Ordering::SeqCst);
+}
+
+pub fn enabled() -> bool {
+    ANNOTATE_HANDLERS.load(Ordering::SeqCst)
+}
+
+/// The ops table fields each function in a parsed file is bound to, found in
+/// the designated initializers of its `file_operations` and `proto_ops`
+/// tables, e.g. `.unlocked_ioctl = foo_ioctl`.
+pub fn bindings(root: ts::Node<'_>, content: &[u8]) -> HashMap<String, Vec<Binding>> {
+    let mut bindings: HashMap<String, Vec<Binding>> = HashMap::new();
+    for [ops, table, init] in query::captures(
+        &OPS_QUERY,
+        ["ops.type", "ops.table", "ops.init"],
+        root,
+        content,
+    ) {
+        let (Ok(ops), Some(table)) = (ops.utf8_text(content), declared_name(table, content)) else {
+            continue;
+        };
+
+        let mut init_cursor = init.walk();
+        for pair in init.named_children(&mut init_cursor) {
+            if pair.kind() != "initializer_pair" {
+                continue;
+            }
+            let field = pair
+                .child_by_field_name("designator")
+                .filter(|designator| designator.kind() == "field_designator")
+                .and_then(|designator| designator.named_child(0))
+                .and_then(|field| field.utf8_text(content).ok());
+            let function = pair
+                .child_by_field_name("value")
+                .and_then(|value| pointee::variable(value, content));
+            let (Some(field), Some(function)) = (field, function) else {
+                continue;
+            };
+            bindings
+                .entry(function.to_string())
+                .or_default()
+                .push(Binding {
+                    ops: ops.to_string(),
+                    table: table.to_string(),
+                    field: field.to_string(),
+                });
+        }
+    }
+    bindings
+}
+
+/// The notes for a site in the function `definition` defines: whether it
+/// looks like an ioctl handler and the ops table fields it is bound to.
+pub fn annotate(
+    definition: ts::Node<'_>,
+    content: &[u8],
+    bindings: &HashMap<String, Vec<Binding>>,
+) -> Vec<String> {
+    let mut notes = vec![];
+    if ioctl_like(definition, content) {
+        notes.push("ioctl handler".to_string());
+    }
+    let bound = scanner::function_name(definition, content).and_then(|name| bindings.get(&name));
+    notes.extend(bound.into_iter().flatten().map(Binding::to_string));
+    notes
+}
+
+/// Whether a function definition looks like an ioctl handler, going by its
+/// name or by the `long f(struct file *, unsigned int, unsigned long)`
+/// signature of `.unlocked_ioctl` and `.compat_ioctl`.
+pub fn ioctl_like(definition: ts::Node<'_>, content: &[u8]) -> bool {
+    if scanner::function_name(definition, content).is_some_and(|name| name.contains("ioctl")) {
+        return true;
+    }
+
+    let text = |node: Option<ts::Node>| {
+        node.and_then(|node| node.utf8_text(content).ok())
+            .map(|text| text.split_whitespace().collect::<Vec<_>>().join(" "))
+    };
+    if text(definition.child_by_field_name("type")).as_deref() != Some("long") {
+        return false;
+    }
+    let params = pointee::parameters(definition);
+    let [file, cmd, arg] = params.as_slice() else {
+        return false;
+    };
+    let file_type = file
+        .child_by_field_name("type")
+        .filter(|ty| ty.kind() == "struct_specifier")
+        .and_then(|ty| text(ty.child_by_field_name("name")));
+    let file_pointer = file
+        .child_by_field_name("declarator")
+        .is_some_and(|declarator| declarator.kind().contains("pointer_declarator"));
+    file_type.as_deref() == Some("file")
+        && file_pointer
+        && text(cmd.child_by_field_name("type")).as_deref() == Some("unsigned int")
+        && text(arg.child_by_field_name("type")).as_deref() == Some("unsigned long")
+}
+
+/// The identifier a declarator declares, looking through pointers.
+fn declared_name<'a>(mut declarator: ts::Node<'_>, content: &'a [u8]) -> Option<&'a str> {
+    while declarator.kind() != "identifier" {
+        declarator = declarator.child_by_field_name("declarator")?;
+    }
+    declarator.utf8_text(content).ok()
+}
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use lazy_static::lazy_static;
+use regex::bytes::Regex;
+
+use crate::output;
+
+// the `.c` files of the scan, set by `--resolve-includers`
+static SOURCES: OnceLock<Vec<PathBuf>> = OnceLock::new();
+// built from them on the first site in a header
+static INDEX: OnceLock<Index> = OnceLock::new();
+
+// the includers listed for a site, the rest are counted
+const SHOWN: usize = 5;
+
+lazy_static! {
+    static ref INCLUDE: Regex =
+        Regex::new(r#"(?m)^[ \t]*#[ \t]*include[ \t]*([<"])([^>"\n]+)[>"]"#).unwrap();
+    // many sites share a header, its includers are only looked up once
+    static ref INCLUDERS: Mutex<HashMap<PathBuf, Arc<Vec<PathBuf>>>> = Mutex::new(HashMap::new());
+}
+
+/// The direct `#include` lines of the scanned `.c` files.
+struct Index {
+    /// By the name in the include, `linux/foo.h` for `<linux/foo.h>`. Quoted
+    /// includes are here too, as the compiler falls back to the include path
+    /// for them.
+    by_name: HashMap<String, Vec<PathBuf>>,
+    /// Quoted includes by the path they resolve to next to their includer.
+    by_path: HashMap<PathBuf, Vec<PathBuf>>,
+}
+
+pub fn enable(files: &[PathBuf]) {
+    let sources = files
+        .iter()
+        .filter(|file| file.extension().is_some_and(|ext| ext == "c"))
+        .cloned()
+        .collect();
+    let _ = SOURCES.set(sources);
+}
+
+pub fn enabled() -> bool {
+    SOURCES.get().is_some()
+}
+
+/// The scanned `.c` files including the header at `path` directly, sorted,
+/// None unless enabled and `path` is a header. Includes through other
+/// headers aren't followed.
+pub fn of(path: &Path) -> Option<Arc<Vec<PathBuf>>> {
+    let sources = SOURCES.get()?;
+    if path.extension().is_none_or(|ext| ext != "h") {
+        return None;
+    }
+    let mut includers = INCLUDERS.lock().unwrap();
+    if let Some(found) = includers.get(path) {
+        return Some(Arc::clone(found));
+    }
+
+    let index = INDEX.get_or_init(|| Index::build(sources));
+    let normalized = normalize(path);
+    let mut found: Vec<PathBuf> = index
+        .by_path
+        .get(&normalized)
+        .into_iter()
+        .flatten()
+        .chain(
+            include_names(&normalized)
+                .filter_map(|name| index.by_name.get(name))
+                .flatten(),
+        )
+        .cloned()
+        .collect();
+    found.sort();
+    found.dedup();
+    let found = Arc::new(found);
+    includers.insert(path.to_path_buf(), Arc::clone(&found));
+    Some(found)
+}
+
+/// The line listing a header's includers, at most a few of them by name.
+pub fn describe(includers: &[PathBuf]) -> String {
+    if includers.is_empty() {
+        return "Included by: no scanned .c file".to_string();
+    }
+    let shown: Vec<String> = includers
+        .iter()
+        .take(SHOWN)
+        .map(|path| output::display_path(path))
+        .collect();
+    let mut line = format!(
+        "Included by {} .c file(s): {}",
+        includers.len(),
+        shown.join(", ")
+    );
+    if includers.len() > SHOWN {
+        line.push_str(&format!(", ... ({} more)", includers.len() - SHOWN));
+    }
+    line
+}
+
+impl Index {
+    fn build(sources: &[PathBuf]) -> Self {
+        let mut index = Index {
+            by_name: HashMap::new(),
+            by_path: HashMap::new(),
+        };
+        for source in sources {
+            // an unreadable file is reported by the scan itself
+            let Ok(content) = std::fs::read(source) else {
+                continue;
+            };
+            for caps in INCLUDE.captures_iter(&content) {
+                let name = String::from_utf8_lossy(&caps[2]).trim().to_string();
+                if &caps[1] == b"\"" {
+                    let dir = source.parent().unwrap_or(Path::new(""));
+                    index
+                        .by_path
+                        .entry(normalize(&dir.join(&name)))
+                        .or_default()
+                        .push(source.clone());
+                }
+                index.by_name.entry(name).or_default().push(source.clone());
+            }
+        }
+        index
+    }
+}
+
+/// The names a header can be included by through an include directory,
+/// `linux/foo.h` for `include/linux/foo.h`, and for `include/uapi/linux/foo.h`
+/// too.
+fn include_names(path: &Path) -> impl Iterator<Item = &str> {
+    let path = path.to_str().unwrap_or("");
+    path.match_indices("include/")
+        .filter(move |(idx, _)| *idx == 0 || path[..*idx].ends_with('/'))
+        .flat_map(move |(idx, matched)| {
+            let name = &path[idx + matched.len()..];
+            [Some(name), name.strip_prefix("uapi/")]
+        })
+        .flatten()
+}
+
+/// A path with its `.` and `..` components resolved without touching the
+/// filesystem, so `drivers/foo/../bar.h` and `drivers/bar.h` compare equal.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    normalized.push("..");
+                }
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
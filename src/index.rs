@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+use crate::cli::StructFilterArgs;
+use crate::slab;
+use crate::symbols;
+use crate::types::TypeSource;
+
+/// A named struct from the type information, with its byte size resolved once
+/// when the index is built.
+pub struct StructEntry {
+    pub byte_size: usize,
+}
+
+/// Where a struct is declared, from its `DW_AT_decl_file` and
+/// `DW_AT_decl_line`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DeclLocation {
+    pub file: PathBuf,
+    pub line: u64,
+}
+
+impl fmt::Display for DeclLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file.display(), self.line)
+    }
+}
+
+/// One distinct definition of a struct: the compilation units which agree on
+/// its size and where it is declared.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Definition {
+    pub byte_size: usize,
+    pub decl: Option<DeclLocation>,
+    /// The units it is defined in, indices into [`Definitions::units`], in
+    /// DWARF order.
+    pub units: Vec<u32>,
+}
+
+/// The struct definitions of every compilation unit, deduplicated by name,
+/// size and declaration while walking the DWARF. An allyesconfig vmlinux
+/// repeats most structs in thousands of units, this keeps one entry per
+/// distinct definition with the list of units it came from.
+#[derive(Debug, Default)]
+pub struct Definitions {
+    /// The compilation unit names, by index.
+    pub units: Vec<String>,
+    by_name: HashMap<String, Vec<Definition>>,
+}
+
+impl Definitions {
+    /// Record that `unit` defines `name` with this size and declaration.
+    pub fn record(&mut self, name: &str, byte_size: usize, decl: Option<DeclLocation>, unit: u32) {
+        // looked up by &str first, nearly every definition is a repeat and
+        // its name needn't be allocated again
+        let definitions = match self.by_name.get_mut(name) {
+            Some(definitions) => definitions,
+            None => self.by_name.entry(name.to_string()).or_default(),
+        };
+        match definitions
+            .iter_mut()
+            .find(|def| def.byte_size == byte_size && def.decl == decl)
+        {
+            Some(def) => {
+                if def.units.last() != Some(&unit) {
+                    def.units.push(unit);
+                }
+            }
+            None => definitions.push(Definition {
+                byte_size,
+                decl,
+                units: vec![unit],
+            }),
+        }
+    }
+
+    /// The distinct definitions of `name`, empty for a struct never defined.
+    pub fn of(&self, name: &str) -> &[Definition] {
+        self.by_name.get(name).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[Definition])> {
+        self.by_name
+            .iter()
+            .map(|(name, definitions)| (name.as_str(), definitions.as_slice()))
+    }
+
+    /// The number of distinct definitions across every struct.
+    pub fn len(&self) -> usize {
+        self.by_name.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+}
+
+/// Every named struct in the type information, deduplicated by name. Built in
+/// a single walk over it so the struct-side filters can all be applied to it
+/// without going back to the types.
+pub struct StructIndex {
+    entries: HashMap<String, StructEntry>,
+    /// The vmlinux the per-unit definitions are read from, once asked for.
+    vmlinux: Option<PathBuf>,
+    definitions: OnceLock<Result<Definitions, String>>,
+}
+
+impl StructIndex {
+    pub fn build(types: &dyn TypeSource) -> anyhow::Result<Self> {
+        // structs without a size are declarations only and never allocated
+        let entries = types
+            .sizes()?
+            .into_iter()
+            .map(|(name, byte_size)| (name, StructEntry { byte_size }))
+            .collect();
+
+        Ok(StructIndex {
+            entries,
+            vmlinux: types.vmlinux().map(Path::to_path_buf),
+            definitions: OnceLock::new(),
+        })
+    }
+
+    /// The per-unit definitions of every struct, read from the vmlinux DWARF
+    /// in one walk on the first call and shared by every later one. Fails
+    /// when the types aren't from a vmlinux, like `--types-from` BTF, which
+    /// keeps one definition per struct.
+    pub fn definitions(&self) -> anyhow::Result<&Definitions> {
+        let Some(vmlinux) = &self.vmlinux else {
+            anyhow::bail!("the per-unit struct definitions need the vmlinux DWARF");
+        };
+        self.definitions
+            .get_or_init(|| symbols::struct_definitions_by_unit(vmlinux).map_err(|e| e.to_string()))
+            .as_ref()
+            .map_err(|e| anyhow::anyhow!("{e}"))
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.entries.contains_key(name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&StructEntry> {
+        self.entries.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &StructEntry)> {
+        self.entries.iter()
+    }
+
+    /// Consume the index, keeping the sizes of only the structs accepted by
+    /// `keep`.
+    pub fn into_filtered<F>(self, mut keep: F) -> HashMap<String, usize>
+    where
+        F: FnMut(&str, &StructEntry) -> bool,
+    {
+        self.entries
+            .into_iter()
+            .filter(|(name, entry)| keep(name, entry))
+            .map(|(name, entry)| (name, entry.byte_size))
+            .collect()
+    }
+}
+
+/// The struct selection options compiled once, for testing every struct in
+/// the index against.
+pub struct StructFilter {
+    min_size: usize,
+    max_size: Option<usize>,
+    names: Vec<String>,
+    regex: Option<Regex>,
+    exclude: Option<Regex>,
+    // the sizes sharing the kmalloc cache of the --neighbors struct
+    bucket: Option<RangeInclusive<usize>>,
+}
+
+impl StructFilter {
+    /// Compile the filter options, failing if a struct asked for by name is
+    /// not in the index at all.
+    pub fn new(args: &StructFilterArgs, index: &StructIndex) -> anyhow::Result<Self> {
+        for name in &args.structs {
+            if !index.contains(name) {
+                anyhow::bail!("struct '{name}' was not found in the type information");
+            }
+        }
+
+        let bucket = match &args.neighbors {
+            Some(name) => {
+                let Some(target) = index.get(name) else {
+                    anyhow::bail!("struct '{name}' was not found in the type information");
+                };
+                let Some(bucket) = slab::bucket_range(target.byte_size) else {
+                    anyhow::bail!(
+                        "struct '{name}' is {} bytes, too large for the kmalloc caches",
+                        target.byte_size
+                    );
+                };
+                Some(bucket)
+            }
+            None => None,
+        };
+
+        Ok(StructFilter {
+            min_size: args.min_size.unwrap_or(0),
+            max_size: args.max_size,
+            names: args.structs.clone(),
+            regex: args.struct_regex.as_deref().map(Regex::new).transpose()?,
+            exclude: args.exclude_struct.as_deref().map(Regex::new).transpose()?,
+            bucket,
+        })
+    }
+
+    pub fn matches(&self, name: &str, entry: &StructEntry) -> bool {
+        let in_bounds = self.min_size <= entry.byte_size
+            && self.max_size.is_none_or(|max| entry.byte_size <= max);
+        in_bounds
+            && self
+                .bucket
+                .as_ref()
+                .is_none_or(|bucket| bucket.contains(&entry.byte_size))
+            && (self.names.is_empty() || self.names.iter().any(|n| n == name))
+            && self.regex.as_ref().is_none_or(|re| re.is_match(name))
+            && !self.exclude.as_ref().is_some_and(|re| re.is_match(name))
+    }
+
+    /// Let elastic sites grow structs into the selected sizes: a struct is
+    /// selected when its base size is at most the upper bound, or the top of
+    /// the --neighbors cache, as its allocations can be made larger.
+    pub fn elastic(mut self) -> Self {
+        self.min_size = 0;
+        if let Some(bucket) = &self.bucket {
+            self.max_size = Some(self.max_size.unwrap_or(usize::MAX).min(*bucket.end()));
+        }
+        self.bucket = None;
+        self
+    }
+
+    /// Whether the structs are picked by name patterns, which are easy to get
+    /// too broad or too narrow.
+    pub fn has_name_patterns(&self) -> bool {
+        self.regex.is_some() || self.exclude.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decl(file: &str, line: u64) -> Option<DeclLocation> {
+        Some(DeclLocation {
+            file: PathBuf::from(file),
+            line,
+        })
+    }
+
+    #[test]
+    fn definitions_dedupe_by_size_and_declaration() {
+        let mut definitions = Definitions::default();
+        definitions.record("foo", 8, decl("include/foo.h", 3), 0);
+        // the same struct seen twice in a unit is listed against it once
+        definitions.record("foo", 8, decl("include/foo.h", 3), 0);
+        definitions.record("foo", 8, decl("include/foo.h", 3), 1);
+        definitions.record("foo", 16, decl("include/foo.h", 3), 2);
+        definitions.record("foo", 8, decl("drivers/foo.c", 10), 3);
+        definitions.record("bar", 4, None, 1);
+
+        let foo = definitions.of("foo");
+        assert_eq!(foo.len(), 3);
+        assert_eq!((foo[0].byte_size, &foo[0].units), (8, &vec![0, 1]));
+        assert_eq!((foo[1].byte_size, &foo[1].units), (16, &vec![2]));
+        assert_eq!(foo[2].decl, decl("drivers/foo.c", 10));
+        assert_eq!(definitions.of("bar")[0].decl, None);
+        assert!(definitions.of("baz").is_empty());
+        assert_eq!(definitions.len(), 4);
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+use tree_sitter as ts;
+
+use crate::scanner;
+
+// set by `--kallsyms`
+static KALLSYMS: OnceLock<Kallsyms> = OnceLock::new();
+// set by `--only-present`
+static ONLY_PRESENT: AtomicBool = AtomicBool::new(false);
+
+/// A function symbol of the target kernel.
+#[derive(Debug, Clone)]
+struct Symbol {
+    address: u64,
+    /// The module it is in, None for the kernel image.
+    module: Option<String>,
+    /// Whether it is a GCC clone, like `foo.isra.0` or `foo.cold`, rather
+    /// than the function's own symbol.
+    clone: bool,
+}
+
+/// The function symbols of a `/proc/kallsyms` or System.map capture, by the
+/// name of the function they are for.
+#[derive(Debug, Clone, Default)]
+pub struct Kallsyms {
+    functions: HashMap<String, Vec<Symbol>>,
+    /// Every address is zero, as `/proc/kallsyms` reads with kptr_restrict
+    /// set, so only the names can be checked.
+    hidden_addresses: bool,
+}
+
+/// Whether a site's function exists on the target kernel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Presence {
+    /// It has exactly one symbol. The address is None when the capture's
+    /// addresses were hidden.
+    Present {
+        function: String,
+        address: Option<u64>,
+        module: Option<String>,
+    },
+    /// Several static functions share its name, in the kernel image or
+    /// different modules, so which one the site is in can't be told by name.
+    Ambiguous {
+        function: String,
+        locations: Vec<String>,
+    },
+    /// It has no symbol: not built for the target, or only inlined.
+    Missing(String),
+}
+
+impl fmt::Display for Presence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Presence::Present {
+                function,
+                address,
+                module,
+            } => {
+                write!(f, "{function} present")?;
+                if let Some(address) = address {
+                    write!(f, " at {address:#x}")?;
+                }
+                if let Some(module) = module {
+                    write!(f, " in [{module}]")?;
+                }
+                Ok(())
+            }
+            Presence::Ambiguous {
+                function,
+                locations,
+            } => write!(
+                f,
+                "{function} ambiguous, {} static functions have the name: {}",
+                locations.len(),
+                locations.join(", ")
+            ),
+            Presence::Missing(function) => write!(
+                f,
+                "warning: {function} missing, it isn't built for the target or only inlined"
+            ),
+        }
+    }
+}
+
+impl Presence {
+    pub fn is_missing(&self) -> bool {
+        matches!(self, Presence::Missing(_))
+    }
+}
+
+impl Kallsyms {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        let kallsyms = Kallsyms::parse(&text);
+        if kallsyms.functions.is_empty() {
+            anyhow::bail!("{} has no function symbols", path.display());
+        }
+        Ok(kallsyms)
+    }
+
+    /// Parse `/proc/kallsyms` or a System.map, lines of `address type name`
+    /// with a `[module]` after the name for module symbols. Only the text
+    /// symbols are kept, by the function name without any GCC clone suffix.
+    pub fn parse(text: &str) -> Self {
+        let mut functions: HashMap<String, Vec<Symbol>> = HashMap::new();
+        let mut hidden_addresses = true;
+        for line in text.lines() {
+            let mut fields = line.split_whitespace();
+            let (Some(address), Some(kind), Some(name)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            let Ok(address) = u64::from_str_radix(address, 16) else {
+                continue;
+            };
+            if !matches!(kind, "t" | "T" | "w" | "W") {
+                continue;
+            }
+            let module = fields
+                .next()
+                .and_then(|module| module.strip_prefix('['))
+                .and_then(|module| module.strip_suffix(']'))
+                .map(str::to_string);
+            hidden_addresses &= address == 0;
+            let (function, clone) = match name.split_once('.') {
+                Some((function, _)) => (function, true),
+                None => (name, false),
+            };
+            functions
+                .entry(function.to_string())
+                .or_default()
+                .push(Symbol {
+                    address,
+                    module,
+                    clone,
+                });
+        }
+        Kallsyms {
+            functions,
+            hidden_addresses,
+        }
+    }
+
+    /// Whether the capture's addresses were all zero.
+    pub fn hidden_addresses(&self) -> bool {
+        self.hidden_addresses
+    }
+
+    /// Look up a function by name. A function's clones don't make it
+    /// ambiguous, several symbols of its own name in the same place do.
+    pub fn lookup(&self, function: &str) -> Presence {
+        let Some(symbols) = self.functions.get(function) else {
+            return Presence::Missing(function.to_string());
+        };
+        // the function's symbols by module, its clones stand in for it
+        // where it has no symbol of its own
+        let mut places: BTreeMap<Option<&str>, Vec<&Symbol>> = BTreeMap::new();
+        for symbol in symbols {
+            places
+                .entry(symbol.module.as_deref())
+                .or_default()
+                .push(symbol);
+        }
+        let mut found = vec![];
+        for (module, symbols) in places {
+            let own: Vec<&Symbol> = symbols.iter().copied().filter(|s| !s.clone).collect();
+            match own.is_empty() {
+                true => found.push((module, symbols[0])),
+                false => found.extend(own.into_iter().map(|symbol| (module, symbol))),
+            }
+        }
+        if let [(module, symbol)] = found[..] {
+            return Presence::Present {
+                function: function.to_string(),
+                address: (!self.hidden_addresses).then_some(symbol.address),
+                module: module.map(str::to_string),
+            };
+        }
+        let locations = found
+            .iter()
+            .map(|(module, symbol)| {
+                let place = match module {
+                    Some(module) => format!("[{module}]"),
+                    None => "vmlinux".to_string(),
+                };
+                match self.hidden_addresses {
+                    true => place,
+                    false => format!("{place} {:#x}", symbol.address),
+                }
+            })
+            .collect();
+        Presence::Ambiguous {
+            function: function.to_string(),
+            locations,
+        }
+    }
+}
+
+pub fn enable(kallsyms: Kallsyms) {
+    let _ = KALLSYMS.set(kallsyms);
+}
+
+pub fn enable_only_present() {
+    ONLY_PRESENT.store(true, Ordering::SeqCst);
+}
+
+pub fn only_present() -> bool {
+    ONLY_PRESENT.load(Ordering::SeqCst)
+}
+
+/// Check the function a site is in against the loaded symbols.
+pub fn check(definition: ts::Node<'_>, content: &[u8]) -> Option<Presence> {
+    let kallsyms = KALLSYMS.get()?;
+    let function = scanner::function_name(definition, content)?;
+    Some(kallsyms.lookup(&function))
+}
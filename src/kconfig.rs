@@ -0,0 +1,318 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    // `CONFIG_FOO=y` lines of a .config, `# CONFIG_FOO is not set` lines are
+    // equivalent to the option being absent
+    static ref CONFIG_LINE: Regex = Regex::new(r"^(CONFIG_[A-Za-z0-9_]+)=(.*)$").unwrap();
+    // `obj-$(CONFIG_FOO) += foo.o bar/`, `foo-y := a.o b.o`, `foo-objs = ...`
+    static ref KBUILD_LINE: Regex =
+        Regex::new(r"^\s*([A-Za-z0-9_]+)-(\$\([^)]*\)|y|m|objs)\s*[:+?]?=\s*(.*)$").unwrap();
+    static ref CONFIG_NAME: Regex = Regex::new(r"CONFIG_[A-Za-z0-9_]+").unwrap();
+    static ref CONDITION_TOKEN: Regex = Regex::new(r"&&|\|\||[!()]|[A-Za-z0-9_]+").unwrap();
+    static ref CONDITION_IDENTIFIER: Regex = Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*$").unwrap();
+}
+
+/// The objects and directories a kbuild Makefile lists, and whether they are
+/// enabled.
+struct Mention {
+    /// The object or directory, without the `.o` or trailing `/`.
+    path: PathBuf,
+    /// The composite object it is part of, when it isn't added to one of the
+    /// kbuild lists of objects to build directly.
+    composite: Option<PathBuf>,
+    enabled: bool,
+}
+
+/// An approximation of the files built for a kernel .config, from the
+/// `obj-$(CONFIG_FOO)` lines of the Makefiles in the source tree. Anything
+/// the Makefiles don't clearly exclude, such as files they never mention or
+/// objects added under conditions other than a config option, is assumed to
+/// be built.
+pub struct BuiltFiles {
+    // for every object and directory mentioned, whether any mention enables it
+    enabled: HashMap<PathBuf, bool>,
+}
+
+impl BuiltFiles {
+    pub fn load(config: &Path, roots: &[PathBuf]) -> anyhow::Result<Self> {
+        let options = parse_config(&std::fs::read_to_string(config)?);
+
+        let mut mentions = vec![];
+        for root in roots {
+            for makefile in walkdir::WalkDir::new(root)
+                .into_iter()
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().is_file())
+                .filter(|e| e.file_name() == "Makefile" || e.file_name() == "Kbuild")
+            {
+                let Ok(contents) = std::fs::read(makefile.path()) else {
+                    continue;
+                };
+                let dir = makefile.path().parent().unwrap_or(root);
+                mentions.extend(parse_makefile(
+                    &String::from_utf8_lossy(&contents),
+                    dir,
+                    &options,
+                ));
+            }
+        }
+
+        // objects listed directly come first, the members of a composite
+        // object `foo-y += a.o` are then only enabled along with `foo.o`
+        let mut enabled: HashMap<PathBuf, bool> = HashMap::new();
+        let (direct, composite): (Vec<Mention>, Vec<Mention>) = mentions
+            .into_iter()
+            .partition(|mention| mention.composite.is_none());
+        for mention in direct {
+            *enabled.entry(mention.path).or_default() |= mention.enabled;
+        }
+        let mut members: HashMap<PathBuf, bool> = HashMap::new();
+        for mention in composite {
+            let parent_enabled = mention
+                .composite
+                .and_then(|parent| enabled.get(&parent).copied())
+                .unwrap_or(true);
+            *members.entry(mention.path).or_default() |= mention.enabled && parent_enabled;
+        }
+        for (path, member_enabled) in members {
+            *enabled.entry(path).or_default() |= member_enabled;
+        }
+
+        Ok(BuiltFiles { enabled })
+    }
+
+    /// Whether `path`, found under `root`, is probably built. Headers are
+    /// always kept, as any built file may include them.
+    pub fn contains(&self, path: &Path, root: &Path) -> bool {
+        if path.extension().is_some_and(|ext| ext == "h") {
+            return true;
+        }
+        if self.enabled.get(&path.with_extension("")) == Some(&false) {
+            return false;
+        }
+        !path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(root) && *dir != root)
+            .any(|dir| self.enabled.get(dir) == Some(&false))
+    }
+}
+
+/// The options set to `y` or `m` in a .config.
+fn parse_config(contents: &str) -> HashSet<String> {
+    parse_values(contents)
+        .into_iter()
+        .filter(|(_, value)| value == "y" || value == "m")
+        .map(|(option, _)| option)
+        .collect()
+}
+
+/// The value of every option set in a .config.
+fn parse_values(contents: &str) -> HashMap<String, String> {
+    contents
+        .lines()
+        .filter_map(|line| CONFIG_LINE.captures(line.trim()))
+        .map(|caps| (caps[1].to_string(), caps[2].to_string()))
+        .collect()
+}
+
+/// The options of a .config, for telling whether a preprocessor condition
+/// holds on the kernel it configures.
+pub struct Options {
+    values: HashMap<String, String>,
+}
+
+impl Options {
+    pub fn load(config: &Path) -> anyhow::Result<Self> {
+        Ok(Options {
+            values: parse_values(&std::fs::read_to_string(config)?),
+        })
+    }
+
+    /// Evaluate an `#if` condition, or the name of an `#ifdef`, the way the
+    /// preprocessor would with the generated autoconf.h: `CONFIG_FOO` is
+    /// defined as 1 when built in, `CONFIG_FOO_MODULE` when built as a
+    /// module. Understands `!`, `&&`, `||`, parentheses, `defined`, numbers
+    /// and the `IS_ENABLED()` family. None when the condition depends on
+    /// anything else, like `DEBUG` or a version comparison.
+    pub fn evaluate(&self, condition: &str) -> Option<bool> {
+        let tokens: Vec<&str> = CONDITION_TOKEN
+            .find_iter(condition)
+            .map(|token| token.as_str())
+            .collect();
+        // anything the tokens don't cover, like `>=`, isn't understood
+        let covered: usize = tokens.iter().map(|token| token.len()).sum();
+        if covered != condition.chars().filter(|c| !c.is_whitespace()).count() {
+            return None;
+        }
+        let mut parser = Condition {
+            options: self,
+            tokens: &tokens,
+            pos: 0,
+        };
+        let value = parser.or()?;
+        (parser.pos == tokens.len()).then_some(value)?
+    }
+
+    /// Whether a macro set from the config is defined, None for macros
+    /// which aren't.
+    fn defined(&self, name: &str) -> Option<bool> {
+        if !name.starts_with("CONFIG_") {
+            return None;
+        }
+        if let Some(option) = name.strip_suffix("_MODULE") {
+            if let Some(value) = self.values.get(option) {
+                return Some(value == "m");
+            }
+        }
+        Some(self.values.get(name).is_some_and(|value| value == "y"))
+    }
+
+    fn value(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+}
+
+/// A recursive descent over the tokens of a condition. Each step yields
+/// `Some(value)` for a value it can tell, `Some(None)` for one it can't, and
+/// None for a condition it doesn't understand.
+struct Condition<'a> {
+    options: &'a Options,
+    tokens: &'a [&'a str],
+    pos: usize,
+}
+
+impl<'a> Condition<'a> {
+    fn next(&mut self) -> Option<&'a str> {
+        let token = self.tokens.get(self.pos).copied();
+        self.pos += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn or(&mut self) -> Option<Option<bool>> {
+        let mut value = self.and()?;
+        while self.peek() == Some("||") {
+            self.pos += 1;
+            let rhs = self.and()?;
+            value = match (value, rhs) {
+                (Some(true), _) | (_, Some(true)) => Some(true),
+                (Some(false), Some(false)) => Some(false),
+                _ => None,
+            };
+        }
+        Some(value)
+    }
+
+    fn and(&mut self) -> Option<Option<bool>> {
+        let mut value = self.unary()?;
+        while self.peek() == Some("&&") {
+            self.pos += 1;
+            let rhs = self.unary()?;
+            value = match (value, rhs) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            };
+        }
+        Some(value)
+    }
+
+    fn unary(&mut self) -> Option<Option<bool>> {
+        if self.peek() == Some("!") {
+            self.pos += 1;
+            return Some(self.unary()?.map(|value| !value));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Option<Option<bool>> {
+        match self.next()? {
+            "(" => {
+                let value = self.or()?;
+                (self.next()? == ")").then_some(value)
+            }
+            "defined" => {
+                let parens = self.peek() == Some("(");
+                if parens {
+                    self.pos += 1;
+                }
+                let name = self.next()?;
+                if parens && self.next()? != ")" {
+                    return None;
+                }
+                Some(self.options.defined(name))
+            }
+            helper @ ("IS_ENABLED" | "IS_BUILTIN" | "IS_MODULE" | "IS_REACHABLE") => {
+                if self.next()? != "(" {
+                    return None;
+                }
+                let option = self.next()?;
+                if self.next()? != ")" || !option.starts_with("CONFIG_") {
+                    return None;
+                }
+                let value = self.options.value(option);
+                Some(Some(match helper {
+                    "IS_BUILTIN" => value == Some("y"),
+                    "IS_MODULE" => value == Some("m"),
+                    _ => matches!(value, Some("y" | "m")),
+                }))
+            }
+            token if token.chars().all(|c| c.is_ascii_digit()) => Some(Some(token != "0")),
+            // an undefined macro is 0 in an `#if`, a config one is 1 when set
+            token if CONDITION_IDENTIFIER.is_match(token) => Some(self.options.defined(token)),
+            _ => None,
+        }
+    }
+}
+
+/// Collect the objects and directories listed in a kbuild Makefile in `dir`.
+/// Lines continued with a backslash are joined and comments are dropped,
+/// conditionals are ignored so objects inside them count as unconditional.
+fn parse_makefile(contents: &str, dir: &Path, options: &HashSet<String>) -> Vec<Mention> {
+    let joined = contents.replace("\\\r\n", " ").replace("\\\n", " ");
+    let mut mentions = vec![];
+    for line in joined.lines() {
+        let line = line.split('#').next().unwrap_or("");
+        let Some(caps) = KBUILD_LINE.captures(line) else {
+            continue;
+        };
+
+        let condition = &caps[2];
+        let enabled = match CONFIG_NAME.find(condition) {
+            Some(option) => options.contains(option.as_str()),
+            // `-y`, `-m`, `-objs` or a condition we can't evaluate
+            None => true,
+        };
+        let composite = match &caps[1] {
+            "obj" | "lib" | "core" | "drivers" | "libs" | "net" | "virt" => None,
+            name => Some(dir.join(name)),
+        };
+
+        for word in caps[3].split_whitespace() {
+            let path = if let Some(object) = word.strip_suffix(".o") {
+                object
+            } else if let Some(subdir) = word.strip_suffix('/') {
+                subdir
+            } else {
+                continue;
+            };
+            if path.contains('$') {
+                continue;
+            }
+            mentions.push(Mention {
+                path: dir.join(path),
+                composite: composite.clone(),
+                enabled,
+            });
+        }
+    }
+    mentions
+}
@@ -0,0 +1,131 @@
+use std::io::Read;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+
+// the lz4 legacy frame format the kernel compresses images with, blocks
+// decompress to at most 8MiB each
+const LZ4_LEGACY_MAGIC: &[u8] = &[0x02, 0x21, 0x4c, 0x18];
+const LZ4_LEGACY_BLOCK: usize = 8 << 20;
+
+/// The compression formats an ELF may be embedded in a kernel image with,
+/// along with their magic bytes.
+const FORMATS: &[(&str, &[u8])] = &[
+    ("gzip", &[0x1f, 0x8b, 0x08]),
+    ("xz", &[0xfd, b'7', b'z', b'X', b'Z', 0x00]),
+    ("zstd", &[0x28, 0xb5, 0x2f, 0xfd]),
+    ("lz4", LZ4_LEGACY_MAGIC),
+];
+
+/// A vmlinux ELF decompressed out of a kernel image.
+pub struct Extracted {
+    pub format: &'static str,
+    pub offset: usize,
+    pub elf: Vec<u8>,
+}
+
+pub fn is_elf(bytes: &[u8]) -> bool {
+    bytes.starts_with(ELF_MAGIC)
+}
+
+/// Whether an ELF carries DWARF, going by whether a `.debug_info` section
+/// name appears in it. vmlinuz images are built from a stripped vmlinux, so
+/// this is rarely the case for an extracted one.
+pub fn has_debug_info(elf: &[u8]) -> bool {
+    elf.windows(b".debug_info".len())
+        .any(|window| window == b".debug_info")
+}
+
+/// Find the vmlinux ELF inside a compressed kernel image such as a bzImage,
+/// the way scripts/extract-vmlinux does: try decompressing at every
+/// occurrence of each format's magic bytes, and keep the first result which
+/// is an ELF.
+pub fn extract(image: &[u8]) -> Option<Extracted> {
+    for &(format, magic) in FORMATS {
+        let offsets = image
+            .windows(magic.len())
+            .enumerate()
+            .filter(|(_, window)| *window == magic)
+            .map(|(offset, _)| offset);
+        for offset in offsets {
+            let elf = decompress(format, &image[offset..]);
+            if is_elf(&elf) {
+                return Some(Extracted {
+                    format,
+                    offset,
+                    elf,
+                });
+            }
+        }
+    }
+    None
+}
+
+/// Decompress as much of `data` as possible. The compressed stream is
+/// followed by the rest of the image, so decoders failing on that trailing
+/// data is expected and whatever they produced until then is kept.
+fn decompress(format: &str, data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    match format {
+        "gzip" => {
+            let _ = flate2::read::GzDecoder::new(data).read_to_end(&mut out);
+        }
+        "xz" => {
+            let _ = xz2::read::XzDecoder::new(data).read_to_end(&mut out);
+        }
+        "zstd" => {
+            if let Ok(mut decoder) = zstd::stream::read::Decoder::new(data) {
+                let _ = decoder.read_to_end(&mut out);
+            }
+        }
+        "lz4" => decompress_lz4_legacy(&data[LZ4_LEGACY_MAGIC.len()..], &mut out),
+        _ => unreachable!("unknown compression format {format}"),
+    }
+    out
+}
+
+/// The legacy lz4 format is a sequence of blocks, each prefixed with its
+/// compressed size, until the data runs out or something which isn't a block
+/// follows.
+fn decompress_lz4_legacy(mut data: &[u8], out: &mut Vec<u8>) {
+    let mut block = vec![0; LZ4_LEGACY_BLOCK];
+    while data.len() >= 4 {
+        let size = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        if size == 0 || size > data.len() - 4 || data[..4] == *LZ4_LEGACY_MAGIC {
+            break;
+        }
+        match lz4_flex::block::decompress_into(&data[4..4 + size], &mut block) {
+            Ok(len) => out.extend_from_slice(&block[..len]),
+            Err(_) => break,
+        }
+        data = &data[4 + size..];
+    }
+}
+
+/// The `e_machine` field of an ELF header, in the ELF's own byte order.
+pub fn elf_machine(elf: &[u8]) -> Option<u16> {
+    if !is_elf(elf) || elf.len() < 20 {
+        return None;
+    }
+    let bytes = [elf[18], elf[19]];
+    match elf[5] {
+        1 => Some(u16::from_le_bytes(bytes)),
+        2 => Some(u16::from_be_bytes(bytes)),
+        _ => None,
+    }
+}
+
+/// The kernel's `arch/` directory for an ELF machine type.
+pub fn kernel_arch(machine: u16) -> Option<&'static str> {
+    Some(match machine {
+        3 | 62 => "x86",
+        8 => "mips",
+        20 | 21 => "powerpc",
+        22 => "s390",
+        40 => "arm",
+        43 => "sparc",
+        183 => "arm64",
+        243 => "riscv",
+        258 => "loongarch",
+        _ => return None,
+    })
+}
@@ -0,0 +1,346 @@
+use std::fmt;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    // a member line of dwat's verbose layout, `    int x;    /*    4 |    8 */`
+    static ref MEMBER_LINE: Regex =
+        Regex::new(r"^\s*(?P<decl>[^/]*?);\s*/\*\s*(?P<size>\d+)\s*\|\s*(?P<offset>\d+)\s*\*/")
+            .unwrap();
+    static ref IDENTIFIER: Regex = Regex::new(r"[A-Za-z_][A-Za-z0-9_]*").unwrap();
+    // the closing size comment, `    /* total size: 112 */`
+    static ref TOTAL_SIZE: Regex = Regex::new(r"/\*\s*total size:\s*(\d+)\s*\*/").unwrap();
+}
+
+/// Integer types a length or count member may be declared with.
+const INTEGER_TYPES: &[&str] = &[
+    "int",
+    "unsigned int",
+    "long",
+    "unsigned long",
+    "long int",
+    "long unsigned int",
+    "short",
+    "unsigned short",
+    "unsigned char",
+    "u8",
+    "u16",
+    "u32",
+    "u64",
+    "s8",
+    "s16",
+    "s32",
+    "s64",
+    "__u8",
+    "__u16",
+    "__u32",
+    "__u64",
+    "__s32",
+    "__s64",
+    "__le16",
+    "__le32",
+    "__le64",
+    "__be16",
+    "__be32",
+    "__be64",
+    "size_t",
+    "ssize_t",
+    "loff_t",
+    "atomic_t",
+    "atomic64_t",
+    "atomic_long_t",
+];
+
+/// A member of a struct, as printed in its verbose layout.
+pub struct Member {
+    /// The member's declaration, e.g. `void (*fn)(void *)`.
+    pub decl: String,
+    pub name: String,
+    pub size: usize,
+    pub offset: usize,
+    /// How deeply the member is nested, 1 for members of the struct itself
+    /// and more for members of nested anonymous structs and unions.
+    pub depth: usize,
+}
+
+/// How a struct is aligned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Alignment {
+    pub align: usize,
+    /// The alignment its most aligned member naturally has.
+    pub natural: usize,
+    /// Aligned less than `natural`, like a `__packed` struct, whose members
+    /// may sit at offsets their types wouldn't.
+    pub packed: bool,
+    /// Recorded by the type information rather than inferred.
+    pub recorded: bool,
+}
+
+impl fmt::Display for Alignment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "align {}", self.align)?;
+        if self.packed {
+            write!(f, ", packed (members need {})", self.natural)?;
+        }
+        Ok(())
+    }
+}
+
+/// The members of a struct, parsed from the output of `to_string_verbose`
+/// so that every consumer of member information works from the same
+/// offsets and sizes that are printed.
+pub struct Layout {
+    pub members: Vec<Member>,
+    /// The struct's size, from the total printed after its members.
+    pub size: Option<usize>,
+}
+
+impl Layout {
+    pub fn parse(verbose: &str) -> Self {
+        let mut members = vec![];
+        let mut size = None;
+        let mut depth: usize = 0;
+        for line in verbose.lines() {
+            if depth == 1 {
+                if let Some(caps) = TOTAL_SIZE.captures(line) {
+                    size = caps[1].parse().ok();
+                }
+            }
+            let code = line.split("/*").next().unwrap_or("");
+            if let Some(member) = Member::parse(line, depth) {
+                members.push(member);
+            }
+            depth += code.matches('{').count();
+            depth = depth.saturating_sub(code.matches('}').count());
+        }
+        Layout { members, size }
+    }
+
+    /// The members declared directly in the struct.
+    pub fn top_level(&self) -> impl Iterator<Item = &Member> {
+        self.members.iter().filter(|member| member.depth <= 1)
+    }
+
+    /// The struct's alignment, `recorded` when the type information has it
+    /// and otherwise inferred from the members, as DWARF rarely records it.
+    /// Members off their natural alignment, or a size that isn't a multiple
+    /// of the largest, give a packed struct away.
+    pub fn alignment(&self, recorded: Option<usize>) -> Alignment {
+        let natural = self
+            .top_level()
+            .map(Member::natural_alignment)
+            .max()
+            .unwrap_or(1);
+        let misaligned = self
+            .top_level()
+            .any(|member| !member.offset.is_multiple_of(member.natural_alignment()))
+            || self.size.is_some_and(|size| !size.is_multiple_of(natural));
+        let align = match recorded {
+            Some(align) => align,
+            None if misaligned => 1,
+            None => natural,
+        };
+        Alignment {
+            align,
+            natural,
+            packed: align < natural,
+            recorded: recorded.is_some(),
+        }
+    }
+
+    pub fn function_pointers(&self) -> Vec<&Member> {
+        self.members
+            .iter()
+            .filter(|member| member.is_function_pointer())
+            .collect()
+    }
+
+    pub fn refcounts(&self) -> Vec<&Member> {
+        self.members
+            .iter()
+            .filter(|member| member.is_refcount())
+            .collect()
+    }
+
+    /// The byte ranges no member covers, between the members and after the
+    /// last one.
+    pub fn holes(&self) -> Vec<std::ops::Range<usize>> {
+        let mut members: Vec<&Member> = self.top_level().collect();
+        members.sort_by_key(|member| member.offset);
+
+        let mut holes = vec![];
+        let mut covered = 0;
+        for member in members {
+            if member.offset > covered {
+                holes.push(covered..member.offset);
+            }
+            covered = covered.max(member.offset + member.size);
+        }
+        if let Some(size) = self.size.filter(|&size| size > covered) {
+            holes.push(covered..size);
+        }
+        holes
+    }
+
+    /// The trailing flexible array member, if the struct ends in one.
+    pub fn flex_array(&self) -> Option<&Member> {
+        self.top_level()
+            .last()
+            .filter(|member| member.is_flex_array())
+    }
+}
+
+/// Append the offset and kind of every member to its line of a verbose
+/// layout, e.g. `/* off 24, ptr */`, dimmed when `color` is set. The notes
+/// go after the existing size and offset comment. Members of a packed struct
+/// off their natural alignment are noted `unaligned`.
+pub fn annotate(verbose: &str, color: bool) -> String {
+    let mut out = String::with_capacity(verbose.len() * 2);
+    for line in verbose.lines() {
+        out.push_str(line);
+        if let Some(member) = Member::parse(line, 0) {
+            let mut notes: Vec<String> = vec![format!("off {}", member.offset)];
+            notes.extend(member.kind().map(str::to_string));
+            if !member.offset.is_multiple_of(member.natural_alignment()) {
+                notes.push("unaligned".to_string());
+            }
+            let note = format!("/* {} */", notes.join(", "));
+            match color {
+                true => out.push_str(&format!(" \x1b[2m{note}\x1b[0m")),
+                false => out.push_str(&format!(" {note}")),
+            }
+        }
+        out.push('\n');
+    }
+    if !verbose.ends_with('\n') {
+        out.pop();
+    }
+    out
+}
+
+/// Pick the declared name out of a member declaration, the identifier
+/// inside `(*name)` for function pointers, otherwise the last identifier
+/// before any array dimensions or bitfield width.
+fn member_name(decl: &str) -> String {
+    if let Some(start) = decl.find("(*") {
+        if let Some(name) = IDENTIFIER.find(&decl[start..]) {
+            return name.as_str().to_string();
+        }
+    }
+    let decl = decl.split(['[', ':']).next().unwrap_or(decl);
+    IDENTIFIER
+        .find_iter(decl)
+        .last()
+        .map(|name| name.as_str().to_string())
+        .unwrap_or_default()
+}
+
+impl Member {
+    /// Parse a member line of a verbose layout, None for other lines and the
+    /// closing lines of nested structs and unions.
+    fn parse(line: &str, depth: usize) -> Option<Self> {
+        let caps = MEMBER_LINE.captures(line)?;
+        let decl = caps["decl"].trim();
+        if decl.starts_with('}') {
+            return None;
+        }
+        Some(Member {
+            name: member_name(decl),
+            decl: decl.to_string(),
+            size: caps["size"].parse().unwrap_or(0),
+            offset: caps["offset"].parse().unwrap_or(0),
+            depth,
+        })
+    }
+
+    pub fn is_function_pointer(&self) -> bool {
+        self.decl.contains("(*")
+    }
+
+    pub fn is_pointer(&self) -> bool {
+        self.decl.contains('*')
+    }
+
+    pub fn is_flex_array(&self) -> bool {
+        self.decl.ends_with("[]") || self.decl.ends_with("[0]")
+    }
+
+    /// Whether the member is an integer named like a length or count, `len`,
+    /// `size`, `count` or `nr` on their own or as part of the name.
+    pub fn is_length_like(&self) -> bool {
+        let ty = self.type_name();
+        let integer =
+            !self.is_pointer() && INTEGER_TYPES.contains(&ty.trim_start_matches("const "));
+        integer
+            && self
+                .name
+                .split('_')
+                .any(|part| matches!(part, "len" | "length" | "size" | "count" | "cnt" | "nr"))
+    }
+
+    /// A short name for what kind of member this is, for the ones worth
+    /// pointing out: `fnptr`, `ptr`, `refcount`, `flex` or `len?`.
+    pub fn kind(&self) -> Option<&'static str> {
+        if self.is_function_pointer() {
+            Some("fnptr")
+        } else if self.is_flex_array() {
+            Some("flex")
+        } else if self.is_pointer() {
+            Some("ptr")
+        } else if self.is_refcount() {
+            Some("refcount")
+        } else if self.is_length_like() {
+            Some("len?")
+        } else {
+            None
+        }
+    }
+
+    pub fn is_refcount(&self) -> bool {
+        ["refcount_t", "struct kref", "struct percpu_ref"]
+            .iter()
+            .any(|ty| self.decl.starts_with(ty))
+    }
+
+    /// The type part of the declaration, without the name or array
+    /// dimensions.
+    fn type_name(&self) -> &str {
+        let decl = self.decl.split('[').next().unwrap_or(&self.decl);
+        decl.strip_suffix(self.name.as_str()).unwrap_or(decl).trim()
+    }
+
+    /// The alignment the member's type naturally has on x86_64. Known scalar
+    /// types are looked up, anything else is assumed to be aligned to the
+    /// largest power of two dividing both its size and offset, up to 8.
+    pub fn natural_alignment(&self) -> usize {
+        if self.is_pointer() {
+            return 8;
+        }
+        let ty = self.type_name();
+        let ty = ty
+            .trim_start_matches("const ")
+            .trim_start_matches("volatile ")
+            .trim_start_matches("unsigned ")
+            .trim_start_matches("signed ");
+        match ty {
+            "char" | "bool" | "_Bool" | "u8" | "s8" | "__u8" | "__s8" | "uint8_t" | "int8_t" => 1,
+            "short" | "short int" | "u16" | "s16" | "__u16" | "__s16" | "__le16" | "__be16"
+            | "uint16_t" | "int16_t" => 2,
+            "int" | "u32" | "s32" | "__u32" | "__s32" | "__le32" | "__be32" | "uint32_t"
+            | "int32_t" | "float" | "atomic_t" | "refcount_t" => 4,
+            "long" | "long int" | "long long" | "long long int" | "u64" | "s64" | "__u64"
+            | "__s64" | "__le64" | "__be64" | "uint64_t" | "int64_t" | "size_t" | "ssize_t"
+            | "loff_t" | "double" | "atomic64_t" | "atomic_long_t" => 8,
+            _ => {
+                let mut align = 8;
+                while align > 1
+                    && (!self.size.is_multiple_of(align) || !self.offset.is_multiple_of(align))
+                {
+                    align /= 2;
+                }
+                align
+            }
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! Find the allocation sites of Linux kernel structs by size, combining the
+//! struct layouts from a vmlinux's DWARF with a tree-sitter query over the
+//! kernel source.
+//!
+//! A [`Scanner`] is built from a [`ScanConfig`] naming the vmlinux, the source
+//! trees and which structs and sites to report, and yields a [`Match`] for
+//! every allocation site found. The kheap_sift binary is a command line
+//! interface over the same scanning code.
+
+pub mod alloc;
+pub mod scanner;
+
+pub use blame::Blame;
+pub use elastic::Sizing;
+pub use gfp::GfpContext;
+pub use layout::Alignment;
+pub use scanner::{load_dwarf, DwarfSource, Fate, Linkage, Match, ScanConfig, Scanner};
+pub use slabinfo::SlabActivity;
+
+// The modules below back the kheap_sift binary. They are public for its use
+// only and not part of the library API, so they may change in any release.
+#[doc(hidden)]
+pub mod archive;
+#[doc(hidden)]
+pub mod blame;
+#[doc(hidden)]
+pub mod bulk;
+#[doc(hidden)]
+pub mod caps;
+#[doc(hidden)]
+pub mod checkpoint;
+#[doc(hidden)]
+pub mod cli;
+#[doc(hidden)]
+pub mod compdb;
+#[doc(hidden)]
+pub mod coverage;
+#[doc(hidden)]
+pub mod crosscache;
+#[doc(hidden)]
+pub mod debuginfod;
+#[doc(hidden)]
+pub mod dedup;
+#[doc(hidden)]
+pub mod drgn;
+#[doc(hidden)]
+pub mod elastic;
+#[doc(hidden)]
+pub mod escape;
+#[doc(hidden)]
+pub mod files;
+#[doc(hidden)]
+pub mod filter;
+#[doc(hidden)]
+pub mod focus;
+#[doc(hidden)]
+pub mod frees;
+#[doc(hidden)]
+pub mod gfp;
+#[doc(hidden)]
+pub mod handlers;
+#[doc(hidden)]
+pub mod includers;
+#[doc(hidden)]
+pub mod index;
+#[doc(hidden)]
+pub mod kallsyms;
+#[doc(hidden)]
+pub mod kconfig;
+#[doc(hidden)]
+pub mod kimage;
+#[doc(hidden)]
+pub mod layout;
+#[doc(hidden)]
+pub mod loose;
+#[doc(hidden)]
+pub mod macros;
+#[doc(hidden)]
+pub mod output;
+#[doc(hidden)]
+pub mod pointee;
+#[doc(hidden)]
+pub mod preproc;
+#[doc(hidden)]
+pub mod profile;
+#[doc(hidden)]
+pub mod progress;
+#[doc(hidden)]
+pub mod query;
+#[doc(hidden)]
+pub mod rank;
+#[doc(hidden)]
+pub mod skipped;
+#[doc(hidden)]
+pub mod slab;
+#[doc(hidden)]
+pub mod slabinfo;
+#[doc(hidden)]
+pub mod sqlite;
+#[doc(hidden)]
+pub mod stats;
+#[doc(hidden)]
+pub mod subsystem;
+#[doc(hidden)]
+pub mod symbols;
+#[doc(hidden)]
+pub mod tags;
+#[doc(hidden)]
+pub mod tui;
+#[doc(hidden)]
+pub mod types;
+#[doc(hidden)]
+pub mod usercopy;
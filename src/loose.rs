@@ -0,0 +1,189 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use tree_sitter as ts;
+use tree_sitter_c as ts_c;
+
+use crate::alloc::{self, Allocator, Zeroing};
+use crate::filter::SiteFilter;
+use crate::query::{self, AllocQuery};
+use crate::{caps, elastic, output, scanner, slab};
+
+// set by `--loose`
+static LOOSE: AtomicBool = AtomicBool::new(false);
+
+// any call to an allocator, whatever its result is assigned to
+const LOOSE_QUERY: &str = "
+    (call_expression
+        function: (identifier) @function
+        (#match? @function \"{allocators}\")
+        arguments: (argument_list) @args
+    ) @call
+    ";
+
+/// A lower confidence site, an allocator call with a `sizeof(struct X)` in
+/// its arguments the precise query didn't match.
+struct Site {
+    path: PathBuf,
+    line: usize,
+    /// The status notes and the call, ready to print.
+    text: String,
+}
+
+lazy_static! {
+    // by struct, along with its size
+    static ref SITES: Mutex<BTreeMap<String, (usize, Vec<Site>)>> = Mutex::new(BTreeMap::new());
+}
+
+pub fn enable() {
+    LOOSE.store(true, Ordering::SeqCst);
+}
+
+pub fn enabled() -> bool {
+    LOOSE.load(Ordering::SeqCst)
+}
+
+/// Look for loose sites in a parsed file, skipping the calls any of the
+/// precise `queries` match, whether or not their filters let them through.
+/// `selected` gives the size of the structs in the selection.
+pub fn find(
+    root: ts::Node<'_>,
+    content: &[u8],
+    path: &Path,
+    queries: &[AllocQuery],
+    allocators: &[Allocator],
+    site_filter: &SiteFilter,
+    selected: impl Fn(&str) -> Option<usize>,
+) -> anyhow::Result<()> {
+    let mut cursor = ts::QueryCursor::new();
+    let mut precise = HashSet::new();
+    for query in queries {
+        for match_ in cursor.matches(&query.query, root, content) {
+            if let Some(qm) = query.extract(&match_, content)? {
+                precise.insert(qm.assign_call.start_byte());
+            }
+        }
+    }
+
+    let source = LOOSE_QUERY.replace("{allocators}", &alloc::name_regex(allocators));
+    let loose = ts::Query::new(ts_c::language(), &source)?;
+    for [call, function, args] in
+        query::captures(&loose, ["call", "function", "args"], root, content)
+    {
+        if precise.contains(&call.start_byte()) {
+            continue;
+        }
+        let Some(allocator) = alloc::lookup(allocators, function.utf8_text(content)?) else {
+            continue;
+        };
+        let Some(size) = query::argument(args, allocator.size_arg) else {
+            continue;
+        };
+        let flags = match allocator.flags_arg {
+            Some(idx) => match query::argument(args, idx) {
+                Some(flags) => flags.utf8_text(content)?,
+                None => continue,
+            },
+            None => "",
+        };
+        let count = allocator
+            .count_arg
+            .and_then(|idx| query::argument(args, idx));
+
+        let mut structs = vec![];
+        sizeof_structs(args, content, &mut structs);
+        structs.dedup();
+        let zeroing = Zeroing::classify(Some(allocator), flags);
+        let component = elastic::component(size, count, content);
+        // as `QueryMatch::size_expr` writes it, for the size filters
+        let size_text = String::from_utf8_lossy(&content[size.byte_range()]);
+        let size_expr = match count {
+            Some(count) => format!(
+                "{} * {size_text}",
+                String::from_utf8_lossy(&content[count.byte_range()])
+            ),
+            None => size_text.to_string(),
+        };
+        let definition = scanner::enclosing_function(call);
+        let caps = definition
+            .map(|definition| caps::guards(definition, call, content))
+            .unwrap_or_default();
+        if !site_filter.matches_loose(flags, &size_expr, zeroing, component.is_some(), &caps)
+            || !site_filter.matches_function(definition, content)
+        {
+            continue;
+        }
+
+        let line = call.start_position().row + 1;
+        let mut status = vec!["loose".to_string(), zeroing.to_string()];
+        status.extend(component.map(|component| format!("elastic: {component}")));
+        if let Some(function) = definition.and_then(|def| scanner::function_name(def, content)) {
+            status.push(format!("in {function}"));
+        }
+        let text = format!(
+            "{}:{line} ({}): {}",
+            output::display_path(path),
+            status.join(", "),
+            output::one_line(&content[call.byte_range()])
+        );
+        let mut sites = SITES.lock().unwrap();
+        for struct_name in &structs {
+            let Some(struct_size) = selected(struct_name) else {
+                continue;
+            };
+            sites
+                .entry(struct_name.clone())
+                .or_insert_with(|| (struct_size, vec![]))
+                .1
+                .push(Site {
+                    path: path.to_path_buf(),
+                    line,
+                    text: text.clone(),
+                });
+        }
+    }
+    Ok(())
+}
+
+/// The names of the structs taken the size of with `sizeof(struct X)` under
+/// `node`.
+fn sizeof_structs(node: ts::Node<'_>, content: &[u8], structs: &mut Vec<String>) {
+    if node.kind() == "sizeof_expression" {
+        let name = node
+            .child_by_field_name("type")
+            .and_then(|ty| ty.child_by_field_name("type"))
+            .filter(|ty| ty.kind() == "struct_specifier")
+            .and_then(|ty| ty.child_by_field_name("name"))
+            .and_then(|name| name.utf8_text(content).ok());
+        if let Some(name) = name {
+            structs.push(name.to_string());
+        }
+    }
+    let mut cursor = node.walk();
+    for child in node.named_children(&mut cursor) {
+        sizeof_structs(child, content, structs);
+    }
+}
+
+/// Print the loose sites found, by struct.
+pub fn print_report() {
+    let mut sites = SITES.lock().unwrap();
+    if sites.is_empty() {
+        return;
+    }
+    println!("======== Loose sites ========\n");
+    for (struct_name, (struct_size, sites)) in sites.iter_mut() {
+        sites.sort_by(|a, b| (&a.path, a.line).cmp(&(&b.path, b.line)));
+        println!(
+            "struct {struct_name} ({struct_size} bytes, {}):",
+            slab::cache_name(*struct_size)
+        );
+        for site in sites.iter() {
+            println!("  {}", site.text);
+        }
+        println!();
+    }
+}
@@ -0,0 +1,393 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::path::Path;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use tree_sitter as ts;
+use tree_sitter_c as ts_c;
+
+use crate::alloc::{self, Allocator};
+use crate::output;
+use crate::query;
+
+lazy_static! {
+    static ref DIRECTIVE: Regex = Regex::new(r"^\s*#\s*([a-z]+)\b\s*(.*)$").unwrap();
+    // a function-like macro has its parameter list right after the name
+    static ref DEFINE: Regex =
+        Regex::new(r"^([A-Za-z_][A-Za-z0-9_]*)(\(([^)]*)\))?(.*)$").unwrap();
+    static ref CALL: Regex = Regex::new(r"^([A-Za-z_][A-Za-z0-9_]*)\s*(\(.*\))$").unwrap();
+    static ref IDENTIFIER: Regex = Regex::new(r"[A-Za-z_][A-Za-z0-9_]*").unwrap();
+    static ref COMMENT: Regex = Regex::new(r"(?s)/\*.*?\*/|//.*$").unwrap();
+}
+
+/// A macro of a file whose replacement is a single call, an allocator
+/// wrapper if it is to an allocator, like
+/// `#define ALLOC_CTX() kzalloc(sizeof(struct ctx), GFP_KERNEL)`.
+#[derive(Debug, Clone)]
+struct Wrapper {
+    /// None for an object-like macro.
+    params: Option<Vec<String>>,
+    /// The replacement, comments dropped.
+    replacement: String,
+    /// The function the replacement calls, empty if it isn't a single call
+    /// or the macro isn't simple.
+    callee: String,
+    /// Defined inside an `#if`, `#ifdef` or `#ifndef` other than an include
+    /// guard.
+    conditional: bool,
+    /// Defined more than once in the file.
+    redefined: bool,
+}
+
+/// A use of an allocator wrapper macro, replaced by its expansion.
+#[derive(Debug, Clone)]
+pub struct Expansion {
+    /// Where the expansion is in the expanded contents.
+    pub range: Range<usize>,
+    /// The macro as used, like `ALLOC_CTX()`.
+    pub invocation: String,
+}
+
+/// Expand the uses of the file's allocator wrapper macros, so the allocation
+/// site queries see the allocator calls they stand for. Only macros whose
+/// replacement is one call to an allocator of `allocators` are expanded, ones
+/// wrapping another wrapper, defined conditionally or more than once are
+/// skipped, with a note when `verbose`. Each expansion is kept on the lines
+/// of its use, so line numbers stay those of the file. Returns None when the
+/// file has nothing to expand.
+pub fn expand(
+    content: &[u8],
+    allocators: &[Allocator],
+    path: &Path,
+    verbose: bool,
+) -> Option<(Vec<u8>, Vec<Expansion>)> {
+    if !content.windows(7).any(|window| window == b"#define") {
+        return None;
+    }
+    let mut wrappers = collect(&String::from_utf8_lossy(content));
+    // the macros calling an allocator directly, others calling one of them
+    // are a level too deep
+    let direct: HashSet<String> = wrappers
+        .iter()
+        .filter(|(_, wrapper)| alloc::lookup(allocators, &wrapper.callee).is_some())
+        .map(|(name, _)| name.clone())
+        .collect();
+    wrappers.retain(|name, wrapper| {
+        let reason = if direct.contains(name) {
+            None
+        } else if direct.contains(&wrapper.callee) {
+            Some(format!("it wraps the allocator wrapper {}", wrapper.callee))
+        } else {
+            return false;
+        };
+        let reason = reason
+            .or_else(|| {
+                wrapper
+                    .conditional
+                    .then(|| "it is defined conditionally".to_string())
+            })
+            .or_else(|| {
+                wrapper
+                    .redefined
+                    .then(|| "it is defined more than once".to_string())
+            });
+        if let Some(reason) = &reason {
+            if verbose {
+                eprintln!(
+                    "{}: not expanding {name}, {reason}",
+                    output::display_path(path)
+                );
+            }
+        }
+        reason.is_none()
+    });
+    if wrappers.is_empty() {
+        return None;
+    }
+
+    let mut parser = ts::Parser::new();
+    parser
+        .set_language(ts_c::language())
+        .expect("Error loading C grammar");
+    let tree = parser.parse(content, None)?;
+    let mut uses = vec![];
+    find_uses(tree.root_node(), content, &wrappers, &mut uses);
+    if uses.is_empty() {
+        return None;
+    }
+
+    let mut expanded = Vec::with_capacity(content.len());
+    let mut expansions = vec![];
+    let mut last = 0;
+    for (range, text) in uses {
+        expanded.extend_from_slice(&content[last..range.start]);
+        let start = expanded.len();
+        expanded.extend_from_slice(text.as_bytes());
+        expansions.push(Expansion {
+            range: start..expanded.len(),
+            invocation: output::one_line(&content[range.clone()]),
+        });
+        // the use's line breaks go after it, keeping the lines below in place
+        let lines = content[range.clone()]
+            .iter()
+            .filter(|&&b| b == b'\n')
+            .count();
+        expanded.extend(std::iter::repeat_n(b'\n', lines));
+        last = range.end;
+    }
+    expanded.extend_from_slice(&content[last..]);
+    Some((expanded, expansions))
+}
+
+/// The macros of a file whose replacement is a call, by name.
+fn collect(text: &str) -> HashMap<String, Wrapper> {
+    let mut wrappers: HashMap<String, Wrapper> = HashMap::new();
+    // whether each open conditional counts, include guards don't
+    let mut conditionals: Vec<bool> = vec![];
+    let mut guard: Option<String> = None;
+
+    let mut lines = text.lines();
+    while let Some(line) = lines.next() {
+        let mut line = line.to_string();
+        while line.ends_with('\\') {
+            line.pop();
+            match lines.next() {
+                Some(next) => line.push_str(next),
+                None => break,
+            }
+        }
+        let Some(caps) = DIRECTIVE.captures(&line) else {
+            continue;
+        };
+        let rest = caps[2].trim();
+        let pending_guard = guard.take();
+        match &caps[1] {
+            "if" | "ifdef" => conditionals.push(true),
+            "ifndef" => {
+                conditionals.push(true);
+                guard = Some(rest.to_string());
+            }
+            "endif" => {
+                conditionals.pop();
+            }
+            "define" => {
+                let Some(define) = DEFINE.captures(rest) else {
+                    continue;
+                };
+                let name = define[1].to_string();
+                if pending_guard.as_deref() == Some(name.as_str()) {
+                    if let Some(counts) = conditionals.last_mut() {
+                        *counts = false;
+                    }
+                    continue;
+                }
+                let params = define.get(3).map(|params| {
+                    params
+                        .as_str()
+                        .split(',')
+                        .map(|param| param.trim().to_string())
+                        .filter(|param| !param.is_empty())
+                        .collect::<Vec<_>>()
+                });
+                let replacement = COMMENT.replace_all(&define[4], "");
+                let replacement = unwrap_parens(replacement.trim());
+                let callee = match CALL.captures(replacement) {
+                    Some(call) if closes_at_end(&call[2]) => call[1].to_string(),
+                    _ => String::new(),
+                };
+                // stringizing, pasting and variadic macros aren't simple
+                let simple = !replacement.contains('#')
+                    && !params.iter().flatten().any(|param| param.contains("..."));
+                let wrapper = Wrapper {
+                    params,
+                    replacement: replacement.to_string(),
+                    callee: match simple {
+                        true => callee,
+                        false => String::new(),
+                    },
+                    conditional: conditionals.iter().any(|&counts| counts),
+                    redefined: false,
+                };
+                wrappers
+                    .entry(name)
+                    .and_modify(|existing| existing.redefined = true)
+                    .or_insert(wrapper);
+            }
+            _ => {}
+        }
+    }
+    wrappers
+}
+
+/// Strip parentheses around the whole of an expression, `(kmalloc(...))`.
+fn unwrap_parens(mut text: &str) -> &str {
+    while let Some(inner) = text.strip_prefix('(').and_then(|t| t.strip_suffix(')')) {
+        if !closes_at_end(text) {
+            break;
+        }
+        text = inner.trim();
+    }
+    text
+}
+
+/// Whether the parenthesis opening `text` is the one closing it.
+fn closes_at_end(text: &str) -> bool {
+    let mut depth = 0usize;
+    for (idx, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth = depth.saturating_sub(1);
+                if depth == 0 {
+                    return idx == text.len() - 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Collect the outermost uses of the wrappers under `node`, with their
+/// expansions, in order.
+fn find_uses(
+    node: ts::Node<'_>,
+    content: &[u8],
+    wrappers: &HashMap<String, Wrapper>,
+    uses: &mut Vec<(Range<usize>, String)>,
+) {
+    let name = |node: ts::Node<'_>| node.utf8_text(content).ok();
+    let expansion = match node.kind() {
+        // directives name macros rather than use them, `#define NAME`,
+        // `#ifdef NAME` and `defined(NAME)` stay as they are
+        "preproc_def"
+        | "preproc_function_def"
+        | "preproc_call"
+        | "preproc_include"
+        | "preproc_defined" => return,
+        "call_expression" => node
+            .child_by_field_name("function")
+            .filter(|function| function.kind() == "identifier")
+            .and_then(|function| wrappers.get(name(function)?))
+            .and_then(|wrapper| {
+                let params = wrapper.params.as_ref()?;
+                let args_node = node.child_by_field_name("arguments")?;
+                let args = query::split_arguments(&String::from_utf8_lossy(
+                    &content[args_node.byte_range()],
+                ));
+                (args.len() == params.len()).then(|| substitute(wrapper, params, &args))
+            }),
+        "identifier" => wrappers
+            .get(name(node).unwrap_or(""))
+            .filter(|wrapper| wrapper.params.is_none())
+            .map(|wrapper| wrapper.replacement.clone()),
+        _ => None,
+    };
+    if let Some(expansion) = expansion {
+        uses.push((node.byte_range(), expansion));
+        return;
+    }
+    // only the code between `#if` and `#endif` is searched, not the
+    // condition
+    let directive = node.kind().starts_with("preproc_");
+    for idx in 0..node.child_count() {
+        let skipped = directive
+            && matches!(
+                node.field_name_for_child(idx as u32),
+                Some("name" | "condition")
+            );
+        if let Some(child) = node.child(idx).filter(|_| !skipped) {
+            find_uses(child, content, wrappers, uses);
+        }
+    }
+}
+
+/// A function-like wrapper's replacement with its parameters replaced by
+/// the arguments of a use, on one line.
+fn substitute(wrapper: &Wrapper, params: &[String], args: &[String]) -> String {
+    IDENTIFIER
+        .replace_all(&wrapper.replacement, |caps: &regex::Captures| match params
+            .iter()
+            .position(|param| *param == caps[0])
+        {
+            Some(idx) => output::one_line(args[idx].as_bytes()),
+            None => caps[0].to_string(),
+        })
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expanded(content: &str) -> Option<(String, Vec<String>)> {
+        let (expanded, expansions) = expand(
+            content.as_bytes(),
+            alloc::ALLOCATORS,
+            Path::new("foo.c"),
+            false,
+        )?;
+        let invocations = expansions
+            .into_iter()
+            .map(|expansion| expansion.invocation)
+            .collect();
+        Some((String::from_utf8(expanded).unwrap(), invocations))
+    }
+
+    #[test]
+    fn object_like_macro_is_expanded_in_code_only() {
+        let content = "\
+#define NEW_FOO kzalloc(sizeof(struct foo), GFP_KERNEL)
+
+#ifdef NEW_FOO
+#define HAVE_NEW_FOO 1
+#endif
+#if defined(NEW_FOO) && NEW_FOO
+#endif
+
+struct foo *make_foo(void)
+{
+	struct foo *p;
+#ifndef NEW_FOO
+	return NULL;
+#endif
+	p = NEW_FOO;
+	return p;
+}
+";
+        let (expanded, invocations) = expanded(content).unwrap();
+        assert_eq!(invocations, ["NEW_FOO"]);
+        assert_eq!(
+            expanded,
+            content.replace(
+                "p = NEW_FOO;",
+                "p = kzalloc(sizeof(struct foo), GFP_KERNEL);"
+            )
+        );
+    }
+
+    #[test]
+    fn function_like_macro_is_expanded() {
+        let content = "\
+#define ALLOC_FOO(gfp) kzalloc(sizeof(struct foo), gfp)
+
+struct foo *make_foo(void)
+{
+	struct foo *p;
+	p = ALLOC_FOO(GFP_ATOMIC);
+	return p;
+}
+";
+        let (expanded, invocations) = expanded(content).unwrap();
+        assert_eq!(invocations, ["ALLOC_FOO(GFP_ATOMIC)"]);
+        assert!(expanded.contains("\tp = kzalloc(sizeof(struct foo), GFP_ATOMIC);\n"));
+        assert!(expanded.starts_with("#define ALLOC_FOO(gfp) kzalloc"));
+    }
+
+    #[test]
+    fn file_without_uses_is_left_alone() {
+        assert!(expanded("#define NEW_FOO kzalloc(sizeof(struct foo), GFP_KERNEL)\n").is_none());
+        assert!(expanded("struct foo *p = kzalloc(sizeof(*p), GFP_KERNEL);\n").is_none());
+    }
+}
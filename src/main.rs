@@ -1,452 +1,1857 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
 use std::io::IsTerminal;
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex, RwLock};
-
-use dwat::prelude::*;
+use std::ops::ControlFlow;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use tree_sitter as ts;
-use tree_sitter_c as ts_c;
-use ts::{Parser as TsParser, Query, QueryCursor};
 
-use clap::ArgAction::Append;
-use clap::Parser;
 use lazy_static::lazy_static;
-use memmap2::Mmap;
-use regex::Regex;
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
-use tokio::sync::Semaphore;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
 use tokio::task;
 
-#[derive(Parser)]
-struct CmdArgs {
-    /// The path to the vmlinux file.
-    #[clap(help = "The path to the vmlinux file.")]
-    vmlinux_path: PathBuf,
-
-    /// The path to the Linux source code directory.
-    #[clap(help = "The path to the Linux source code directory.")]
-    source_path: PathBuf,
-
-    /// The lower bound for struct sizes (exclusive).
-    #[clap(help = "The lower bound for struct sizes (exclusive).")]
-    lower_bound: usize,
-
-    /// The upper bound for struct sizes (inclusive).
-    #[clap(help = "The upper bound for struct sizes (inclusive).")]
-    upper_bound: usize,
-
-    /// Silence most output, only print struct names when allocation sites are
-    /// found.
-    #[clap(
-        long,
-        action,
-        help = "Silence most output, only print struct names when allocation \
-                sites are found."
-    )]
-    quiet: bool,
-
-    /// Regex filter on the allocation flags argument.
-    #[clap(long, help = "Regex filter on the allocation flags argument.")]
-    flags: Option<String>,
-
-    /// Glob to exclude files based on, can be specified multiple times.
-    #[clap(
-       long,
-       action=Append,
-       help = "Glob to exclude files based on, can be specified multiple times."
-    )]
-    exclude: Vec<String>,
-
-    /// Number of threads to scale up to.
-    #[clap(long, help = "Number of threads to scale up to.")]
-    threads: Option<usize>,
-}
+use kheap_sift::cli::{
+    Cli, Command, DryRun, DwarfArgs, GroupBy, InfoArgs, ListStructsArgs, OutputFormat, ScanArgs,
+    SortBy, StructFilterArgs,
+};
+use kheap_sift::elastic::Sizing;
+use kheap_sift::files::{DefaultExcludes, FilePolicy};
+use kheap_sift::filter::SiteFilter;
+use kheap_sift::index::{Definition, StructEntry, StructFilter, StructIndex};
+use kheap_sift::kallsyms::Presence;
+use kheap_sift::layout::Layout;
+use kheap_sift::profile::Profiles;
+use kheap_sift::progress::Progress;
+use kheap_sift::query::QueryMatch;
+use kheap_sift::scanner::{self, find_sites, Linkage};
+use kheap_sift::skipped::{self, Reason};
+use kheap_sift::stats::STATS;
+use kheap_sift::types::{self, DwarfTypes, TypeSource};
+use kheap_sift::{
+    archive, blame, bulk, caps, checkpoint, compdb, coverage, crosscache, dedup, drgn, elastic,
+    files, focus, frees, gfp, handlers, includers, kallsyms, kconfig, kimage, layout, loose,
+    macros, output, preproc, rank, slab, slabinfo, sqlite, stats, subsystem, symbols, tags, tui,
+    usercopy,
+};
+use kheap_sift::{DwarfSource, ScanConfig, Scanner};
 
 lazy_static! {
-    // Define a global static mutex for stdout
-    static ref STDOUT_MUTEX: Arc<Mutex<()>> = Arc::new(Mutex::new(()));
-
     // global static variable for quiet mode
     static ref QUIET_MODE: Arc<Mutex<bool>> = Arc::new(Mutex::new(false));
-}
 
-fn collect_src_files(dir: &PathBuf) -> Vec<PathBuf> {
-    walkdir::WalkDir::new(dir)
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .map(|e| e.into_path())
-        .filter(|path| {
-            path.is_file()
-                && path
-                    .extension()
-                    .map_or(false, |ext| ext == "c" || ext == "h")
-        })
-        .collect()
+    // global static variable for verbose mode
+    static ref VERBOSE_MODE: Arc<Mutex<bool>> = Arc::new(Mutex::new(false));
+
+    // global static variable for deduplicating sites by content
+    static ref DEDUP_MODE: Arc<Mutex<bool>> = Arc::new(Mutex::new(true));
+
+    // global static variable for annotating the members of printed layouts
+    static ref ANNOTATE_MEMBERS_MODE: Arc<Mutex<bool>> = Arc::new(Mutex::new(false));
+    static ref SHOW_ARGS_MODE: Arc<Mutex<bool>> = Arc::new(Mutex::new(false));
+
+    // global static variable for first match mode
+    static ref FIRST_MATCH_MODE: Arc<Mutex<bool>> = Arc::new(Mutex::new(false));
+
+    // struct names already printed in quiet mode
+    static ref QUIET_REPORTED: Arc<Mutex<HashSet<String>>> = Arc::new(Mutex::new(HashSet::new()));
+
+    // structs which have already had a site reported in first match mode
+    static ref FOUND_STRUCTS: Arc<Mutex<HashSet<String>>> = Arc::new(Mutex::new(HashSet::new()));
 }
 
+// set in first match mode once every struct has had a site reported
+static SCAN_DONE: AtomicBool = AtomicBool::new(false);
+
+// matches the names of the selected structs, set with --struct or --coverage
+static STRUCT_PREFILTER: OnceLock<Prefilter> = OnceLock::new();
+
+// how many blocks of output each parse thread can have queued for the writer
+const OUTPUT_QUEUE_PER_THREAD: usize = 4;
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let args = CmdArgs::parse();
+    let cli = Cli::parse_args();
+    let validated = match &cli.command {
+        Command::Scan(args) => args.validate(),
+        Command::ListStructs(args) => args.validate(),
+        Command::Info(args) => args.validate(),
+    };
+    if let Err(e) = validated {
+        e.exit();
+    }
+
+    match cli.command {
+        Command::Scan(args) => scan(args).await,
+        Command::ListStructs(args) => list_structs(args),
+        Command::Info(args) => info(args).await,
+    }
+}
 
-    let file = File::open(args.vmlinux_path).await?;
-    let mmap = unsafe { Mmap::map(&file) }?;
+/// Load the types from `--types-from` if given, the vmlinux DWARF otherwise.
+fn load_types(args: &DwarfArgs) -> anyhow::Result<Box<dyn TypeSource>> {
+    match (&args.types_from, &args.vmlinux_path) {
+        (Some(path), _) => types::load(path),
+        (None, Some(path)) => Ok(Box::new(
+            DwarfTypes::new(scanner::load_dwarf(path, args.debuginfod)?)?.with_vmlinux(path),
+        )),
+        (None, None) => unreachable!("clap requires one of them"),
+    }
+}
 
-    let dwarf = dwat::dwarf::OwnedDwarf::load(&*mmap)?;
-    let struct_map = dwarf.get_named_types_map::<dwat::Struct>()?;
+/// The kernel architecture the vmlinux was built for, going by its ELF
+/// header. None for compressed kernel images and unknown machine types.
+fn vmlinux_arch(path: &Path) -> Option<&'static str> {
+    let mut header = [0; 20];
+    std::io::Read::read_exact(&mut std::fs::File::open(path).ok()?, &mut header).ok()?;
+    kimage::kernel_arch(kimage::elf_machine(&header)?)
+}
 
-    let struct_map: HashMap<String, dwat::Struct> = {
-        struct_map
-            .into_iter()
-            .filter(|(_, struc)| {
-                if let Ok(bytesz) = struc.byte_size(&dwarf) {
-                    args.lower_bound < bytesz && bytesz <= args.upper_bound
-                } else {
-                    false
+/// Print the configuration a scan would run with, after defaults are applied.
+fn print_config(args: &ScanArgs, policy: &FilePolicy, structs: usize) {
+    let list = |items: &[String]| match items {
+        [] => "none".to_string(),
+        items => items.join(", "),
+    };
+    let paths: Vec<String> = args
+        .source_path
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect();
+
+    println!(
+        "vmlinux:             {}",
+        args.dwarf
+            .vmlinux_path
+            .as_ref()
+            .map_or("none".to_string(), |path| path.display().to_string())
+    );
+    if let Some(path) = &args.dwarf.types_from {
+        println!("types from:          {}", path.display());
+    }
+    println!("source paths:        {}", paths.join(", "));
+    println!(
+        "struct sizes:        {} to {}",
+        args.filter.min_size.unwrap_or(0),
+        args.filter
+            .max_size
+            .map_or("unlimited".to_string(), |max| max.to_string())
+    );
+    println!("structs selected:    {structs}");
+    println!("include globs:       {}", list(&policy.include));
+    println!("exclude globs:       {}", list(&policy.exclude));
+    println!("exclude dirs:        {}", list(&policy.exclude_dirs));
+    match &policy.default_excludes {
+        Some(defaults) => {
+            let mut dirs: Vec<String> = files::DEFAULT_EXCLUDED_DIRS
+                .iter()
+                .map(|dir| format!("{dir}/"))
+                .collect();
+            match defaults.arch {
+                Some(arch) => dirs.push(format!("arch/* except arch/{arch}/")),
+                None => dirs
+                    .push("nothing under arch/, the vmlinux's architecture is unknown".to_string()),
+            }
+            println!("default excludes:    {}", dirs.join(", "));
+        }
+        None => println!("default excludes:    disabled"),
+    }
+    println!("follow symlinks:     {}", policy.follow_symlinks);
+    println!(
+        "max depth:           {}",
+        policy
+            .max_depth
+            .map_or("unlimited".to_string(), |depth| depth.to_string())
+    );
+    println!("io threads:          {}", args.io_thread_count());
+    println!("parse threads:       {}", args.parse_thread_count());
+}
+
+/// Select the structs matching the filter options from the DWARF.
+fn select_structs(
+    types: &dyn TypeSource,
+    args: &StructFilterArgs,
+) -> anyhow::Result<(StructIndex, StructFilter)> {
+    let struct_index = StructIndex::build(types)?;
+    let filter = StructFilter::new(args, &struct_index)?;
+
+    // the target of --neighbors is shown for reference, so the neighbors'
+    // sizes can be compared with it
+    if let Some(entry) = args
+        .neighbors
+        .as_ref()
+        .and_then(|name| struct_index.get(name))
+    {
+        let size = entry.byte_size;
+        let bucket = slab::kmalloc_bucket(size).unwrap_or(size);
+        let neighbors = struct_index
+            .iter()
+            .filter(|(name, entry)| filter.matches(name, entry))
+            .count();
+        eprintln!(
+            "Neighbors of struct {}: {size} bytes in {}, {} bytes of slack, {neighbors} struct(s) selected",
+            args.neighbors.as_deref().unwrap_or_default(),
+            slab::cache_name(size),
+            bucket - size
+        );
+    }
+    Ok((struct_index, filter))
+}
+
+/// Print a table of the selected structs and the caches they fall in, sorted
+/// by size. Only the DWARF is needed.
+fn list_structs(args: ListStructsArgs) -> anyhow::Result<()> {
+    let types = load_types(&args.dwarf)?;
+    let (struct_index, filter) = select_structs(types.as_ref(), &args.filter)?;
+
+    let mut structs: Vec<(&String, &StructEntry)> = struct_index
+        .iter()
+        .filter(|(name, entry)| filter.matches(name, entry))
+        .collect();
+    structs.sort_by(|a, b| (a.1.byte_size, a.0).cmp(&(b.1.byte_size, b.0)));
+
+    if structs.is_empty() {
+        eprintln!("No structs match the given filters");
+        return Ok(());
+    }
+
+    let width = structs
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0);
+    let mut out = String::new();
+    writeln!(out, "{:<width$}  {:>6}  CACHE", "NAME", "SIZE")?;
+    for (name, entry) in &structs {
+        writeln!(
+            out,
+            "{name:<width$}  {:>6}  {}",
+            entry.byte_size,
+            slab::cache_name(entry.byte_size)
+        )?;
+    }
+    output::print(&out);
+    eprintln!("{} struct(s)", structs.len());
+    Ok(())
+}
+
+/// Print everything known about one struct from the DWARF, and how many
+/// allocation sites it has when a source tree is given.
+async fn info(args: InfoArgs) -> anyhow::Result<()> {
+    let types: Arc<dyn TypeSource> = load_types(&args.dwarf)?.into();
+    let struct_index = StructIndex::build(types.as_ref())?;
+    let Some(entry) = struct_index.get(&args.name) else {
+        anyhow::bail!("struct '{}' was not found in the vmlinux DWARF", args.name);
+    };
+
+    let verbose = types.layout(&args.name)?;
+    let layout = Layout::parse(&verbose);
+    let names = |members: Vec<&layout::Member>| -> String {
+        if members.is_empty() {
+            return "none".to_string();
+        }
+        let names: Vec<&str> = members.iter().map(|member| member.name.as_str()).collect();
+        names.join(", ")
+    };
+
+    let mut out = String::new();
+    writeln!(out, "struct {}", args.name)?;
+    writeln!(out, "Size:               {} bytes", entry.byte_size)?;
+    writeln!(
+        out,
+        "Cache:              {}",
+        slab::cache_name(entry.byte_size)
+    )?;
+    let alignment = layout.alignment(types.alignment(&args.name));
+    writeln!(
+        out,
+        "Alignment:          {} ({}){}",
+        alignment.align,
+        match alignment.recorded {
+            true => "DW_AT_alignment",
+            false => "inferred from members",
+        },
+        match alignment.packed {
+            true => format!(", packed, the members need {}", alignment.natural),
+            false => String::new(),
+        }
+    )?;
+    writeln!(
+        out,
+        "Function pointers:  {}",
+        names(layout.function_pointers())
+    )?;
+    writeln!(out, "Refcounts:          {}", names(layout.refcounts()))?;
+    writeln!(
+        out,
+        "Flexible array:     {}",
+        layout
+            .flex_array()
+            .map_or("none", |member| member.name.as_str())
+    )?;
+    // a vmlinux records a definition in every unit including the struct,
+    // they normally all agree but a config-dependent layout shows up here
+    if let Ok(definitions) = struct_index.definitions() {
+        let found = definitions.of(&args.name);
+        let describe = |def: &Definition| {
+            format!(
+                "{} bytes at {}, in {} unit(s)",
+                def.byte_size,
+                def.decl
+                    .as_ref()
+                    .map_or("an unknown location".to_string(), |decl| decl.to_string()),
+                def.units.len()
+            )
+        };
+        match found {
+            [] => writeln!(out, "Definitions:        none in the vmlinux DWARF")?,
+            [def] => writeln!(out, "Definition:         {}", describe(def))?,
+            found => {
+                let sizes: HashSet<usize> = found.iter().map(|def| def.byte_size).collect();
+                writeln!(
+                    out,
+                    "Definitions:        {} distinct{}",
+                    found.len(),
+                    match sizes.len() > 1 {
+                        true => ", their sizes differ between units",
+                        false => "",
+                    }
+                )?;
+                for def in found {
+                    writeln!(out, "  {}", describe(def))?;
                 }
-            })
-            .collect()
+            }
+        }
+    }
+    if let Some(source) = &args.source {
+        writeln!(
+            out,
+            "Allocation sites:   {}",
+            count_sites(Arc::clone(&types), &args.name, source)?
+        )?;
+    }
+    writeln!(out)?;
+    writeln!(out, "{verbose}")?;
+    output::print(&out);
+    Ok(())
+}
+
+/// Count the allocation sites of the struct `name` under `source` with the
+/// library's scanner, over the types `info` has already loaded. An array of
+/// pointers to the struct isn't an allocation of it and isn't counted.
+fn count_sites(types: Arc<dyn TypeSource>, name: &str, source: &Path) -> anyhow::Result<usize> {
+    let mut config = ScanConfig::new(DwarfSource::Types(types), vec![source.to_path_buf()]);
+    config.structs = vec![name.to_string()];
+    let scanner = Scanner::new(config)?;
+    let mut count = 0;
+    for site in scanner.scan()? {
+        if !site?.pointer_array {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+async fn scan(args: ScanArgs) -> anyhow::Result<()> {
+    let started = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let types: Arc<dyn TypeSource> = load_types(&args.dwarf)?.into();
+    let (struct_index, mut filter) = select_structs(types.as_ref(), &args.filter)?;
+    if args.only_elastic {
+        filter = filter.elastic();
+    }
+    let struct_map: HashMap<String, usize> =
+        struct_index.into_filtered(|name, entry| filter.matches(name, entry));
+
+    // name patterns are easy to get too broad or too narrow, show what they
+    // selected before committing to a scan
+    if filter.has_name_patterns() {
+        eprintln!("Selected {} struct(s)", struct_map.len());
+    }
+
+    // with only a few structs wanted, files which never mention any of them
+    // can be skipped without parsing. --coverage needs the structs each file
+    // names, which are the same regex's hits
+    if !args.filter.structs.is_empty() || args.coverage {
+        let _ = STRUCT_PREFILTER.set(Prefilter::new(
+            struct_map.keys(),
+            !args.filter.structs.is_empty(),
+        )?);
+    }
+
+    if args.report_skipped {
+        skipped::enable_listing();
+    }
+    if args.coverage {
+        coverage::enable(struct_map.keys().cloned());
+    }
+
+    let policy = FilePolicy {
+        follow_symlinks: args.follow_symlinks,
+        max_depth: args.max_depth,
+        include: args.include.clone(),
+        exclude: args.exclude.clone(),
+        exclude_dirs: args.exclude_dir.clone(),
+        default_excludes: (!args.no_default_excludes).then(|| DefaultExcludes {
+            arch: args
+                .dwarf
+                .vmlinux_path
+                .as_ref()
+                .or(args.dwarf.types_from.as_ref())
+                .and_then(|path| vmlinux_arch(path)),
+        }),
+    };
+    if args.print_config {
+        print_config(&args, &policy, struct_map.len());
+        return Ok(());
+    }
+    // an archive's files are streamed out of it while scanning, so there is no
+    // list of files to collect upfront
+    let archive = args.archive().cloned();
+    let mut files = match (&args.files_from, &args.files_from0) {
+        _ if archive.is_some() => vec![],
+        (Some(list), _) => files::read_list(list, &args.source_path, false, !args.no_ext_filter)?,
+        (_, Some(list)) => files::read_list(list, &args.source_path, true, !args.no_ext_filter)?,
+        (None, None) => files::collect(&args.source_path, &policy)?,
     };
 
-    let iter_files: Vec<PathBuf> = collect_src_files(&args.source_path);
-    let mut files: Vec<PathBuf> = vec![];
+    let symlinks_skipped = STATS.symlinks_skipped.load(Ordering::Relaxed);
+    if args.verbose && symlinks_skipped > 0 {
+        eprintln!(
+            "{symlinks_skipped} symlink(s) in the source directories were not followed, \
+             pass --follow-symlinks to scan through them"
+        );
+    }
 
-    let exclude_globs = &args.exclude;
-    if !exclude_globs.is_empty() {
-        let mut builder = globset::GlobSetBuilder::new();
-        for glob in exclude_globs {
-            builder.add(globset::Glob::new(glob)?);
+    if let Some(path) = &args.compile_commands {
+        let db = compdb::CompileDb::load(path, args.compile_commands_headers)?;
+        let before = files.len();
+        files.retain(|file| {
+            let selected = db.selects(file);
+            if !selected {
+                skipped::record(Reason::NotInCompileCommands, file, None);
+            }
+            selected
+        });
+        if args.verbose {
+            eprintln!(
+                "{} of {before} file(s) not in the compilation database were skipped",
+                before - files.len()
+            );
         }
+        compdb::set(db);
+    }
 
-        let set = builder.build()?;
+    if let Some(config) = &args.kconfig {
+        let roots: Vec<PathBuf> = args
+            .source_path
+            .iter()
+            .map(|root| files::root_dir(root).to_path_buf())
+            .collect();
+        let built = kconfig::BuiltFiles::load(config, &roots)?;
+        preproc::enable(kconfig::Options::load(config)?);
+        let before = files.len();
+        files.retain(|file| {
+            let selected = roots
+                .iter()
+                .filter(|root| file.starts_with(root))
+                .all(|root| built.contains(file, root));
+            if !selected {
+                skipped::record(Reason::NotBuiltByKconfig, file, None);
+            }
+            selected
+        });
+        if args.verbose {
+            eprintln!(
+                "{} of {before} file(s) not built by {} were skipped",
+                before - files.len(),
+                config.display()
+            );
+        }
+    }
 
-        // Filter files that do not match the exclusion pattern
-        for file in iter_files {
-            if !set.is_match(&file) {
-                files.push(file)
+    *QUIET_MODE.lock().unwrap() = args.quiet;
+    *VERBOSE_MODE.lock().unwrap() = args.verbose;
+    *FIRST_MATCH_MODE.lock().unwrap() = args.first_match;
+    *ANNOTATE_MEMBERS_MODE.lock().unwrap() = args.annotate_members;
+    *SHOW_ARGS_MODE.lock().unwrap() = args.show_args;
+    *DEDUP_MODE.lock().unwrap() = !args.no_dedup;
+    if args.tui {
+        tui::enable();
+    }
+    if args.track_frees {
+        frees::enable();
+    }
+    if args.loose {
+        loose::enable();
+    }
+    if args.annotate_handlers {
+        handlers::enable();
+    }
+    if args.annotate_caps {
+        caps::enable();
+    }
+    if args.blame {
+        blame::enable();
+    }
+    if args.resolve_includers {
+        includers::enable(&files);
+    }
+    if let Some(path) = &args.slabinfo {
+        slabinfo::enable(slabinfo::Slabinfo::load(path)?);
+    }
+    if args.sort == Some(SortBy::SlabActivity) {
+        slabinfo::enable_sort();
+    }
+    if args.resolve_symbols {
+        symbols::load(args.dwarf.vmlinux_path.as_ref().unwrap())?;
+    }
+    if let Some(path) = &args.kallsyms {
+        let kallsyms = kallsyms::Kallsyms::load(path)?;
+        if kallsyms.hidden_addresses() {
+            eprintln!(
+                "warning: every address in {} is zero, it was captured with \
+                 kptr_restrict set, the functions are checked by name only",
+                path.display()
+            );
+        }
+        kallsyms::enable(kallsyms);
+    }
+    if args.only_enabled {
+        preproc::enable_only_enabled();
+    }
+    if args.only_present {
+        kallsyms::enable_only_present();
+    }
+    if let Some(path) = &args.rank_weights {
+        rank::set_weights(rank::Weights::load(path)?);
+    }
+    if args.rank_sites {
+        rank::enable();
+    }
+    if args.emit_focus.is_some() {
+        focus::enable();
+    }
+    if args.track_user_writes {
+        usercopy::enable_writes();
+    }
+    if args.track_user_reads {
+        usercopy::enable_reads();
+    }
+    if args.track_bulk_writes {
+        bulk::enable();
+    }
+    if args.emit_tags.is_some() {
+        tags::enable();
+    }
+    if let Some(path) = args
+        .output
+        .as_ref()
+        .filter(|_| args.format == OutputFormat::Sqlite)
+    {
+        // opened upfront, so a database which can't be written to fails
+        // before the scan rather than after it
+        sqlite::open(path)?;
+        sqlite::enable();
+    }
+    let roots: Vec<PathBuf> = args
+        .source_path
+        .iter()
+        .map(|root| files::root_dir(root).to_path_buf())
+        .collect();
+    subsystem::set_roots(&roots);
+    if args.group_by == Some(GroupBy::Subsystem) {
+        subsystem::enable_grouping();
+    }
+    if args.relative_paths {
+        output::set_relative_roots(&roots);
+    }
+    if archive.is_some() {
+        output::set_archive_paths();
+    }
+
+    if let Some(mode) = args.dry_run {
+        let mut count = files.len();
+        if let Some(archive) = &archive {
+            archive::read_sources(archive, &files::Selector::new(&policy)?, |path, _| {
+                files.push(path);
+                true
+            })?;
+            count = files.len();
+        }
+        if mode == DryRun::List {
+            for file in &files {
+                println!("{}", output::display_path(file));
             }
         }
-    } else {
-        files = iter_files;
+        println!("{count} file(s) would be scanned");
+        println!("{} struct(s) selected", struct_map.len());
+        if args.report_skipped {
+            skipped::print_report();
+        }
+        return Ok(());
     }
 
-    let mut quiet_mode = QUIET_MODE.lock().unwrap();
-    *quiet_mode = args.quiet;
-    drop(quiet_mode);
+    let checkpoint = match &args.checkpoint {
+        Some(path) => {
+            let checkpoint = checkpoint::Checkpoint::open(path, &args, &files)?;
+            if checkpoint.completed() > 0 {
+                eprintln!(
+                    "Resuming from {}, {} of {} file(s) were already scanned",
+                    path.display(),
+                    checkpoint.completed(),
+                    files.len()
+                );
+                files.retain(|file| {
+                    let done = checkpoint.is_done(file);
+                    if done {
+                        skipped::record(Reason::AlreadyScanned, file, None);
+                    }
+                    !done
+                });
+            }
+            Some(Arc::new(checkpoint))
+        }
+        None => None,
+    };
 
-    if files.is_empty() {
-        println!("Exiting, no files to process");
+    if files.is_empty() && archive.is_none() {
+        match args.source_path.as_slice() {
+            _ if checkpoint.as_ref().is_some_and(|c| c.completed() > 0) => {
+                println!("Exiting, every file was already scanned according to the checkpoint")
+            }
+            [file] if file.is_file() => println!(
+                "Exiting, no files to process: {} is not a .c or .h file, or was filtered out",
+                output::display_path(file)
+            ),
+            _ => println!("Exiting, no files to process"),
+        }
         return Ok(());
     }
 
     let mut handles = vec![];
-    let threads = args.threads.unwrap_or(1);
-    if threads > 1000 {
-        panic!("The max number of threads allowed is 1000!");
-    }
-    let flimit_sem = Arc::new(Semaphore::new(threads));
+    let io_threads = if archive.is_some() {
+        1
+    } else {
+        args.io_thread_count()
+    };
+    let parse_threads = args.parse_thread_count();
+    let flimit_sem = Arc::new(Semaphore::new(parse_threads));
+    STATS.worker_limit.store(parse_threads, Ordering::SeqCst);
 
-    let shared_dwarf = Arc::new(RwLock::new(dwarf));
+    let struct_count = struct_map.len();
     let shared_struct_map = Arc::new(RwLock::new(struct_map));
 
-    for file in files {
+    let file_timeout = args.file_timeout.map(Duration::from_secs);
+    let site_filter = Arc::new(SiteFilter::new(&args)?);
+    let profiles = Arc::new(Profiles::load(
+        args.allocator_config.as_deref(),
+        args.query.as_deref(),
+        &args.extra_query,
+    )?);
+    let timed_out: Arc<Mutex<Vec<(PathBuf, Duration)>>> = Arc::new(Mutex::new(vec![]));
+    let failures: Arc<Mutex<Vec<(PathBuf, String)>>> = Arc::new(Mutex::new(vec![]));
+    let fail_fast = args.fail_fast;
+
+    let progress = if args.progress {
+        // an archive's files are only known once it has been read through, so
+        // it is counted up front for the bar to have a total
+        let total = match &archive {
+            Some(archive) => archive::count_sources(archive, &files::Selector::new(&policy)?)?,
+            None => files.len(),
+        };
+        Progress::start(total)
+    } else {
+        None
+    };
+
+    // Files are read by up to `io_threads` reads at once and handed to the
+    // workers over a bounded channel. Each buffer carries a permit from
+    // `buffer_sem` which is only released once its file has been processed,
+    // so at most `max_buffered` file contents are alive at any point
+    // regardless of how large the files are or how far the readers get ahead
+    // of the workers. By default that leaves room for every read and every
+    // parse to be in flight at once. Matches are printed by a single writer
+    // thread, which in turn blocks the workers when it falls behind.
+    let max_buffered = args
+        .max_buffered_files
+        .unwrap_or(io_threads + parse_threads);
+    STATS.buffer_limit.store(max_buffered, Ordering::SeqCst);
+    let buffer_sem = Arc::new(Semaphore::new(max_buffered));
+    let (buffer_tx, mut buffer_rx) = mpsc::channel::<FileBuffer>(max_buffered);
+    output::start_writer(parse_threads * OUTPUT_QUEUE_PER_THREAD);
+
+    if args.verbose {
+        eprintln!(
+            "Using {io_threads} I/O thread(s){}, {parse_threads} parse thread(s), \
+             {max_buffered} buffered file(s) and 1 output thread",
+            if archive.is_some() {
+                " (archives are read sequentially)"
+            } else {
+                ""
+            }
+        );
+    }
+
+    let reader = match archive {
+        // an archive can only be read sequentially, its entries are read on a
+        // blocking thread and bounded by the same buffer permits
+        Some(archive) => {
+            let selector = files::Selector::new(&policy)?;
+            let runtime = tokio::runtime::Handle::current();
+            task::spawn_blocking(move || {
+                let read = archive::read_sources(&archive, &selector, |path, contents| {
+                    if SCAN_DONE.load(Ordering::SeqCst) {
+                        return false;
+                    }
+                    let buffer_permit = runtime
+                        .block_on(buffer_sem.clone().acquire_owned())
+                        .unwrap();
+                    let buffer = FileBuffer::new(path, contents, buffer_permit);
+                    buffer_tx.blocking_send(buffer).is_ok()
+                });
+                if let Err(e) = read {
+                    eprintln!("error: failed to read {}: {e}", archive.display());
+                }
+            })
+        }
+        None => {
+            let io_sem = Arc::new(Semaphore::new(io_threads));
+            tokio::spawn(async move {
+                for path in files {
+                    if SCAN_DONE.load(Ordering::SeqCst) || buffer_tx.is_closed() {
+                        break;
+                    }
+                    let buffer_permit = buffer_sem.clone().acquire_owned().await.unwrap();
+                    let io_permit = io_sem.clone().acquire_owned().await.unwrap();
+                    let buffer_tx = buffer_tx.clone();
+                    tokio::spawn(async move {
+                        let contents = tokio::fs::read(&path).await;
+                        drop(io_permit);
+                        let buffer = FileBuffer::new(path, contents, buffer_permit);
+                        let _ = buffer_tx.send(buffer).await;
+                    });
+                }
+            })
+        }
+    };
+
+    while let Some(buffer) = buffer_rx.recv().await {
+        if SCAN_DONE.load(Ordering::SeqCst) {
+            break;
+        }
         let permit = flimit_sem.clone().acquire_owned().await.unwrap();
         let shared_struct_map = Arc::clone(&shared_struct_map);
-        let shared_dwarf = Arc::clone(&shared_dwarf);
-        let flags_regex_str = args.flags.clone();
+        let types = Arc::clone(&types);
+        let site_filter = Arc::clone(&site_filter);
+        let profiles = Arc::clone(&profiles);
+        let progress = progress.clone();
+        let timed_out = Arc::clone(&timed_out);
+        let failures = Arc::clone(&failures);
+        let checkpoint = checkpoint.clone();
+        let path = buffer.path.clone();
         let handle = tokio::spawn(async move {
-            read_and_process_file(file, shared_struct_map, shared_dwarf, flags_regex_str)
-                .await
-                .unwrap();
-            drop(permit);
+            let path = buffer.path.clone();
+            let res = process_file(
+                buffer,
+                permit,
+                shared_struct_map,
+                types,
+                site_filter,
+                profiles,
+                file_timeout,
+            )
+            .await;
+            // files which failed aren't checkpointed so a resumed scan
+            // retries them, ones which timed out would only time out again
+            let done = match res {
+                Ok(()) => true,
+                Err(e) if e.is::<scanner::FileTimedOut>() => {
+                    let elapsed = e.downcast_ref::<scanner::FileTimedOut>().unwrap().0;
+                    timed_out.lock().unwrap().push((path.clone(), elapsed));
+                    let detail = format!("{:.1}s", elapsed.as_secs_f64());
+                    skipped::record(Reason::Timeout, &path, Some(detail));
+                    true
+                }
+                Err(e) if fail_fast => panic!("{}: {e}", output::display_path(&path)),
+                Err(e) => {
+                    let reason = if e.is::<std::io::Error>() {
+                        Reason::ReadError
+                    } else {
+                        Reason::ParseError
+                    };
+                    skipped::record(reason, &path, Some(e.to_string()));
+                    failures.lock().unwrap().push((path.clone(), e.to_string()));
+                    false
+                }
+            };
+            if let Some(checkpoint) = checkpoint.filter(|_| done) {
+                if let Err(e) = checkpoint.complete(&path) {
+                    eprintln!("warning: failed to update the checkpoint: {e}");
+                }
+            }
+            stats::inc(&STATS.files_scanned);
+            if let Some(progress) = progress {
+                progress.inc();
+            }
         });
-        handles.push(handle);
+        handles.push((path, handle));
+    }
+    drop(buffer_rx);
+    reader.await.unwrap();
+
+    // Wait for all tasks to complete, a panicking task only fails its own file
+    for (path, handle) in handles {
+        match handle.await {
+            Ok(()) => {}
+            Err(e) if fail_fast => {
+                output::finish_writer();
+                panic::resume_unwind(e.into_panic())
+            }
+            Err(e) => {
+                skipped::record(Reason::ParseError, &path, Some(e.to_string()));
+                failures.lock().unwrap().push((path, e.to_string()))
+            }
+        }
+    }
+    output::finish_writer();
+
+    if let Some(progress) = progress {
+        progress.finish();
+    }
+
+    if args.tui {
+        tui::run()?;
+    }
+
+    if subsystem::grouping() {
+        subsystem::print_grouped();
+    }
+
+    if rank::enabled() {
+        rank::print_ranked();
+    }
+
+    if slabinfo::sorting() {
+        slabinfo::print_sorted();
     }
 
-    // Wait for all tasks to complete
-    for handle in handles {
-        handle.await.unwrap();
+    if !args.no_dedup && !args.quiet {
+        dedup::print_duplicates();
+    }
+
+    if !args.quiet {
+        let struct_map = shared_struct_map.read().unwrap();
+        elastic::print_rollup();
+        crosscache::print_report(|name| struct_map.get(name).copied());
+    }
+
+    if args.track_frees && !args.quiet {
+        frees::print_report();
+    }
+
+    if args.loose {
+        loose::print_report();
+    }
+
+    if args.coverage {
+        coverage::print_report();
+    }
+
+    if (args.track_user_writes || args.track_user_reads || args.track_bulk_writes) && !args.quiet {
+        let layout = |name: &str| Some(Layout::parse(&types.layout(name).ok()?));
+        if args.track_user_writes {
+            usercopy::print_writes(layout);
+        }
+        if args.track_user_reads {
+            usercopy::print_reads(layout);
+        }
+        if args.track_bulk_writes {
+            bulk::print_report(layout);
+        }
+    }
+
+    if let Some(path) = &args.emit_tags {
+        let count = tags::write(path, args.dwarf.vmlinux_path.as_deref(), &roots)?;
+        eprintln!("Wrote {count} tag(s) to {}", path.display());
+    }
+
+    if let Some(path) = &args.emit_focus {
+        let count = focus::write(path, args.focus_format)?;
+        eprintln!(
+            "Wrote {count} function(s) to focus on to {}",
+            path.display()
+        );
+    }
+
+    if let Some(path) = &args.emit_drgn {
+        let struct_map = shared_struct_map.read().unwrap();
+        let structs = crosscache::caches(|name| struct_map.get(name).copied());
+        let count = drgn::write(path, &structs)?;
+        eprintln!(
+            "Wrote a drgn script for {count} struct(s) to {}",
+            path.display()
+        );
+    }
+
+    if let Some(path) = args.output.as_ref().filter(|_| sqlite::enabled()) {
+        let run = sqlite::Run {
+            started,
+            vmlinux: args.dwarf.vmlinux_path.clone(),
+            types_from: args.dwarf.types_from.clone(),
+            source: args.source_path.clone(),
+            arguments: std::env::args().collect::<Vec<_>>().join(" "),
+        };
+        let layout = |name: &str| Some(Layout::parse(&types.layout(name).ok()?));
+        let (run_id, count) = sqlite::write(path, &run, layout)?;
+        eprintln!(
+            "Wrote {count} site(s) to {} as run {run_id}",
+            path.display()
+        );
+    }
+
+    if args.first_match && !args.quiet {
+        let mut found: Vec<String> = FOUND_STRUCTS.lock().unwrap().iter().cloned().collect();
+        found.sort();
+        println!(
+            "Found an allocation site for {}/{} structs: {}",
+            found.len(),
+            struct_count,
+            found.join(", ")
+        );
+        if SCAN_DONE.load(Ordering::SeqCst) {
+            println!("Every struct had a match, the scan terminated early");
+        }
+    }
+
+    let timed_out = timed_out.lock().unwrap();
+    if !timed_out.is_empty() {
+        eprintln!(
+            "Skipped {} file(s) exceeding the file timeout:",
+            timed_out.len()
+        );
+        for (path, elapsed) in timed_out.iter() {
+            eprintln!(
+                "    {} ({:.1}s)",
+                output::display_path(path),
+                elapsed.as_secs_f64()
+            );
+        }
+    }
+
+    if args.stats {
+        STATS.print(struct_count);
+    }
+
+    if args.report_skipped {
+        skipped::print_report();
+    }
+
+    let failures = failures.lock().unwrap();
+    if !failures.is_empty() {
+        eprintln!("Failed to process {} file(s):", failures.len());
+        for (path, error) in failures.iter() {
+            eprintln!("    {}: {error}", output::display_path(path));
+        }
+        anyhow::bail!("{} file(s) could not be processed", failures.len());
     }
 
     Ok(())
 }
 
-fn byte_offset_to_line_number(content: &Vec<u8>, byte_offset: usize) -> Option<usize> {
-    let mut line_number = 1;
-    let mut current_byte_index = 0;
+/// Get the 1-based line number of the byte at `byte_offset`. An offset equal to
+/// the content length refers to the end of the last line.
+fn byte_offset_to_line_number(content: &[u8], byte_offset: usize) -> anyhow::Result<usize> {
+    if byte_offset > content.len() {
+        anyhow::bail!(
+            "byte offset {byte_offset} is past the end of the content ({} bytes)",
+            content.len()
+        );
+    }
+    Ok(content[..byte_offset]
+        .iter()
+        .filter(|&&b| b == b'\n')
+        .count()
+        + 1)
+}
+
+/// Sort ranges by start and merge any which overlap or touch, so highlights can
+/// be inserted in a single forward pass.
+fn merge_ranges(ranges: &[std::ops::Range<usize>]) -> Vec<std::ops::Range<usize>> {
+    let mut sorted = ranges.to_vec();
+    sorted.sort_by_key(|range| range.start);
 
-    for char in content {
-        if current_byte_index >= byte_offset {
-            return Some(line_number);
+    let mut merged: Vec<std::ops::Range<usize>> = vec![];
+    for range in sorted {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
         }
-        if char == &b'\n' {
-            line_number += 1;
-        }
-        current_byte_index += 1;
     }
-    None
+    merged
 }
 
+/// Copy `content[base_range]` wrapping each of the highlight ranges in color
+/// escapes. Works on bytes so ranges don't need to fall on char boundaries.
 fn apply_highlight_ranges(
-    content: &mut String,
+    content: &[u8],
     base_range: &std::ops::Range<usize>,
-    highlight_ranges: &Vec<std::ops::Range<usize>>,
-) {
-    let mut added_bytes = 0;
-    for range in highlight_ranges {
-        let insert_start = (range.start - base_range.start) + added_bytes;
-        content.insert(insert_start, '\x1b');
-        content.insert(insert_start + 1, '[');
-        content.insert(insert_start + 2, '3');
-        content.insert(insert_start + 3, '1');
-        content.insert(insert_start + 4, 'm');
-
-        let insert_end = (range.end - base_range.start) + added_bytes + 5;
-        content.insert(insert_end, '\x1b');
-        content.insert(insert_end + 1, '[');
-        content.insert(insert_end + 2, '0');
-        content.insert(insert_end + 3, 'm');
-        added_bytes += 9;
+    highlight_ranges: &[std::ops::Range<usize>],
+) -> Vec<u8> {
+    let mut highlighted = Vec::with_capacity(base_range.len() + highlight_ranges.len() * 9);
+    let mut copied = base_range.start;
+    for range in merge_ranges(highlight_ranges) {
+        let start = range.start.clamp(copied, base_range.end);
+        let end = range.end.clamp(start, base_range.end);
+
+        highlighted.extend_from_slice(&content[copied..start]);
+        highlighted.extend_from_slice(b"\x1b[31m");
+        highlighted.extend_from_slice(&content[start..end]);
+        highlighted.extend_from_slice(b"\x1b[0m");
+        copied = end;
     }
+    highlighted.extend_from_slice(&content[copied..base_range.end]);
+    highlighted
+}
+
+/// What is known about a site beyond its query match, worked out by the
+/// caller as some of it is shared by the sites in a file.
+struct SiteNotes {
+    /// Notes for the status line, after the zeroing.
+    annotations: Vec<String>,
+    /// Whether the site is in a handler userspace can call.
+    reachable: bool,
+    /// The commit which last changed the allocation's line, for `--blame`.
+    blame: Option<String>,
+    /// Whether the site's function is on the target, for `--kallsyms`.
+    presence: Option<Presence>,
+    /// The `.c` files including the site's header, for
+    /// `--resolve-includers`.
+    includers: Option<Arc<Vec<PathBuf>>>,
 }
 
 fn display_match(
-    content: &Vec<u8>,
-    path: &PathBuf,
-    struct_: &dwat::Struct,
-    dwarf: &Arc<RwLock<dwat::dwarf::OwnedDwarf>>,
+    content: &[u8],
+    path: &Path,
+    struct_size: usize,
+    types: &dyn TypeSource,
     qm: &QueryMatch,
-) {
+    notes: &SiteNotes,
+) -> anyhow::Result<()> {
     let struct_name = qm.struct_name.utf8_text(content).unwrap();
+    let struct_str = types.layout(struct_name)?;
 
-    if *QUIET_MODE.lock().unwrap() {
-        println!("{struct_name}");
-        return;
-    }
-
-    let dwarf = dwarf.read().expect("failed to aqcuire dwarf rwlock");
-    let struct_str = struct_.to_string_verbose(&*dwarf, 1).unwrap();
-    drop(dwarf);
+    // the browser renders plain text, escape codes are only for the terminal
+    let color = std::io::stdout().is_terminal() && !tui::enabled();
 
     let decl_line_start =
-        byte_offset_to_line_number(content, qm.function_definition.byte_range().start).unwrap();
+        byte_offset_to_line_number(content, qm.function_definition.byte_range().start)?;
 
-    let mut match_ranges: Vec<std::ops::Range<usize>> = vec![];
-    match_ranges.push(qm.struct_name.byte_range());
-    match_ranges.push(qm.decl_name.byte_range());
-    match_ranges.push(qm.assign_name.byte_range());
-    match_ranges.push(qm.assign_func.byte_range());
-    match_ranges.push(qm.flags.byte_range());
+    let mut match_ranges: Vec<std::ops::Range<usize>> = vec![
+        qm.struct_name.byte_range(),
+        qm.decl_name.byte_range(),
+        qm.assign_name.byte_range(),
+        qm.assign_func.byte_range(),
+        qm.size.byte_range(),
+    ];
+    match_ranges.extend(qm.flags.map(|flags| flags.byte_range()));
 
     let base_range: std::ops::Range<usize> = qm.function_definition.byte_range();
+    let function_bytes = &content[base_range.clone()];
+    let mut function_src = String::from_utf8_lossy(function_bytes).to_string();
 
-    let mut function_src = qm
+    // find the line the body's opening brace is on, relative to the start of
+    // the function, definitions produced by macros or mis-parsed by
+    // tree-sitter may not have one, then only the matched lines are shown
+    let brace_line = qm
         .function_definition
-        .utf8_text(content)
-        .unwrap()
-        .to_string();
-
-    // find line index opening brace is on
-    let brace_location = function_src
-        .find('{')
-        .expect("no opening brace in function source?");
-    let end_decl_line =
-        byte_offset_to_line_number(&content[decl_line_start..].to_vec(), brace_location)
-            .expect("failed when looking for line number of opening brace")
-            - 1;
-
-    // determine which lines will be included, always include lines up to the opening brace
-    let mut included_lines: Vec<usize> = (0..end_decl_line).collect();
-    let mut seen: usize = 0;
-for (idx, line) in function_src.lines().enumerate() {
-        for range in &match_ranges {
-            if (seen..seen + line.len() + 1).contains(&(&range.start - &base_range.start))
-                && !included_lines.contains(&idx)
-            {
-                included_lines.push(idx);
-            }
-        }
-        seen += line.len() + 1;
+        .child_by_field_name("body")
+        .map(|body| body.start_byte() - base_range.start)
+        .filter(|&offset| function_bytes.get(offset) == Some(&b'{'))
+        .map(|offset| byte_offset_to_line_number(function_bytes, offset))
+        .transpose()?;
+
+    if brace_line.is_none() && *VERBOSE_MODE.lock().unwrap() {
+        eprintln!(
+            "warning: no opening brace in function at {}:{decl_line_start}",
+            output::display_path(path)
+        );
     }
 
-    // highlight captures
-    if std::io::stdout().is_terminal() {
-        apply_highlight_ranges(&mut function_src, &base_range, &match_ranges);
+    // determine which lines will be included, always include lines up to and
+    // including the opening brace
+    let mut included_lines: Vec<usize> = (0..brace_line.unwrap_or(0)).collect();
+    // add the lines holding the captures, counted by newlines in the raw bytes
+    // so they stay correct with CRLF line endings
+    for range in &match_ranges {
+        let line = byte_offset_to_line_number(function_bytes, range.start - base_range.start)?;
+        included_lines.push(line - 1);
     }
 
-    let lock = STDOUT_MUTEX.lock().expect("failed to acquire stdout lock");
+    // highlight captures
+    if color {
+        let highlighted = apply_highlight_ranges(content, &base_range, &match_ranges);
+        function_src = String::from_utf8_lossy(&highlighted).to_string();
+    }
 
-    println!("======== Found allocation site for: struct {struct_name} ========\n");
-    println!("{}", struct_str);
-    println!();
-    if std::io::stdout().is_terminal() {
-        println!(
-            "\x1b[1m{}\x1b[0m:{}",
-            path.to_str().unwrap(),
-            decl_line_start
-        );
+    // format the whole match up front so it is written out in one go
+    let display_path = output::display_path(path);
+    let mut out = String::new();
+    writeln!(
+        out,
+        "======== Found allocation site for: struct {struct_name} ========\n"
+    )?;
+    if *ANNOTATE_MEMBERS_MODE.lock().unwrap() {
+        writeln!(out, "{}", layout::annotate(&struct_str, color))?;
     } else {
-        println!("{}:{}", path.to_str().unwrap(), decl_line_start);
+        writeln!(out, "{}", struct_str)?;
+    }
+    let alignment = Layout::parse(&struct_str).alignment(types.alignment(struct_name));
+    writeln!(
+        out,
+        "size {struct_size}, {alignment} -> {}",
+        slab::cache_name(struct_size)
+    )?;
+    writeln!(out)?;
+    let mut status: Vec<String> = compdb::build_status(path)
+        .into_iter()
+        .map(str::to_string)
+        .collect();
+    status.push(qm.zeroing.to_string());
+    if let Some(component) = &qm.elastic {
+        status.push(format!("elastic: {component}"));
+    }
+    // an elastic size referring to the struct is told by the component alone
+    if qm.sizing != Sizing::Elastic {
+        status.push(qm.sizing.to_string());
+    }
+    status.extend_from_slice(&notes.annotations);
+    status.push(qm.fate.to_string());
+    let status = status.join(", ");
+    if color {
+        writeln!(
+            out,
+            "\x1b[1m{display_path}\x1b[0m:{decl_line_start} ({status})"
+        )?;
+    } else {
+        writeln!(out, "{display_path}:{decl_line_start} ({status})")?;
+    }
+    if let Some(function) = scanner::function_name(qm.function_definition, content) {
+        writeln!(out, "in function: {function}()")?;
+    }
+    let flags = qm.flags_text(content)?;
+    let contexts = gfp::contexts(flags).unwrap_or_default();
+    match (qm.flags, contexts.is_empty()) {
+        (None, _) => writeln!(
+            out,
+            "GFP: none, {} takes no flags",
+            qm.assign_func.utf8_text(content)?
+        )?,
+        (Some(_), true) => writeln!(
+            out,
+            "GFP: {} (context unknown)",
+            output::one_line(flags.as_bytes())
+        )?,
+        (Some(_), false) => writeln!(
+            out,
+            "GFP: {} ({})",
+            output::one_line(flags.as_bytes()),
+            contexts
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )?,
+    }
+    if *SHOW_ARGS_MODE.lock().unwrap() {
+        let args: Vec<String> = qm
+            .args
+            .iter()
+            .enumerate()
+            .map(|(idx, arg)| format!("[{idx}] {}", output::one_line(arg.as_bytes())))
+            .collect();
+        writeln!(out, "Args: {}", args.join(", "))?;
+    }
+    if let Some(includers) = &notes.includers {
+        writeln!(out, "{}", includers::describe(includers))?;
+    }
+    let line = qm.assign_call.start_position().row + 1;
+    if let Some(resolution) = symbols::resolve(qm.function_definition, content, path, line) {
+        writeln!(out, "Symbol: {resolution}")?;
+    }
+    if let Some(presence) = &notes.presence {
+        writeln!(out, "Kallsyms: {presence}")?;
+    }
+    if let Some(blame) = &notes.blame {
+        writeln!(out, "Blame: {blame}")?;
+    }
+    let activity = slabinfo::get()
+        .filter(|_| !qm.pointer_array)
+        .and_then(|slabinfo| {
+            let flags = qm.flags_text(content).ok()?;
+            slabinfo.lookup(struct_name, struct_size, flags)
+        });
+    if let Some(activity) = &activity {
+        writeln!(out, "Slab: {activity}")?;
+    }
+    let score = match rank::enabled() || focus::enabled() {
+        true => {
+            let layout = Layout::parse(&struct_str);
+            let flags = qm.flags_text(content)?;
+            let (score, breakdown) = rank::score(
+                flags,
+                qm.zeroing,
+                notes.reachable,
+                qm.elastic.is_some(),
+                &layout,
+            );
+            if rank::enabled() {
+                writeln!(out, "{breakdown}")?;
+            }
+            score
+        }
+        false => 0,
+    };
+    if focus::enabled() {
+        focus::record(qm.function_definition, content, path, struct_name, score);
     }
 
     let src_lines = function_src.lines().collect::<Vec<&str>>();
 
-    // add last line if it wasn't already included and it is a return
-    let src_lines_ct = function_src.lines().count() - 1;
-    if !included_lines.contains(&(src_lines_ct - 1))
-        && src_lines[src_lines_ct - 1].contains("return ")
-        && src_lines[src_lines_ct - 1].ends_with(';')
-    {
-        included_lines.push(src_lines_ct - 1);
-    }
-    // add last line if it wasn't already included
-    if !included_lines.contains(&src_lines_ct) {
-        included_lines.push(src_lines_ct);
+    // close the excerpt with the function's exit path and final brace
+    included_lines.extend(epilogue_lines(qm.function_definition, function_bytes)?);
+    if brace_line.is_none() {
+        included_lines.push(src_lines.len().saturating_sub(1));
     }
 
-    // set initially to max, so that the elipses won't print the first time through
-    let mut last_line = usize::MAX;
+    included_lines.sort_unstable();
+    included_lines.dedup();
+
+    // print elision markers only between gaps, the preamble always starts at
+    // the first line so nothing is elided before it
+    let mut last_line: Option<usize> = None;
     for line_idx in included_lines {
-        if last_line != usize::MAX && last_line + 1 != line_idx {
-            println!("...");
+        if last_line.is_some_and(|last| last + 1 != line_idx) {
+            writeln!(out, "...")?;
         }
-        println!("{}", src_lines[line_idx]);
-        last_line = line_idx;
+        writeln!(out, "{}", src_lines[line_idx])?;
+        last_line = Some(line_idx);
     }
-    println!();
+    writeln!(out)?;
 
-    drop(lock);
+    if tui::enabled() {
+        tui::record(tui::Site {
+            struct_name: struct_name.to_string(),
+            struct_size,
+            path: path.to_path_buf(),
+            line: byte_offset_to_line_number(content, qm.assign_call.start_byte())?,
+            text: out,
+        });
+    } else if sqlite::enabled() {
+        let site = sqlite::Site {
+            struct_name: struct_name.to_string(),
+            path: display_path,
+            line: byte_offset_to_line_number(content, qm.assign_call.start_byte())?,
+            allocator: qm.assign_func.utf8_text(content)?.to_string(),
+            flags: flags.to_string(),
+            contexts,
+            args: qm.args.clone(),
+            annotations: status,
+        };
+        sqlite::record(
+            site,
+            qm.function_definition,
+            content,
+            struct_size,
+            &struct_str,
+            alignment,
+        );
+    } else if subsystem::grouping() {
+        subsystem::group(&subsystem::of(path), out);
+    } else if rank::enabled() {
+        let line = byte_offset_to_line_number(content, qm.assign_call.start_byte())?;
+        rank::hold(score, path, line, out);
+    } else if slabinfo::sorting() {
+        let line = byte_offset_to_line_number(content, qm.assign_call.start_byte())?;
+        slabinfo::hold(activity.as_ref(), path, line, out);
+    } else {
+        output::print(&out);
+    }
+    Ok(())
 }
 
+/// Lines, relative to the start of the function, that end the excerpt: the
+/// last `return` or `goto` among the statements of the outermost block,
+/// looking through any label in front of it, and the body's closing brace.
+fn epilogue_lines(function: ts::Node, function_bytes: &[u8]) -> anyhow::Result<Vec<usize>> {
+    let Some(body) = function
+        .child_by_field_name("body")
+        .filter(|body| body.kind() == "compound_statement")
+    else {
+        return Ok(vec![]);
+    };
+    let base = function.start_byte();
+    let line_of = |offset: usize| -> anyhow::Result<usize> {
+        Ok(byte_offset_to_line_number(function_bytes, offset - base)? - 1)
+    };
+
+    let mut cursor = body.walk();
+    let exit = body
+        .named_children(&mut cursor)
+        .map(|mut stmt| {
+            while stmt.kind() == "labeled_statement" {
+                match stmt.named_child(stmt.named_child_count().saturating_sub(1)) {
+                    Some(inner) => stmt = inner,
+                    None => break,
+                }
+            }
+            stmt
+        })
+        .filter(|stmt| matches!(stmt.kind(), "return_statement" | "goto_statement"))
+        .last();
+
+    let mut lines = vec![];
+    if let Some(exit) = exit {
+        lines.extend(line_of(exit.start_byte())?..=line_of(exit.end_byte())?);
+    }
+    // the body is never empty, it at least holds its opening brace
+    lines.push(line_of(body.end_byte() - 1)?);
+    Ok(lines)
+}
+
+/// Returned when processing a file panicked, carries the panic message.
 #[derive(Debug)]
-struct QueryMatch<'a> {
-    function_definition: ts::Node<'a>,
-    struct_name: ts::Node<'a>,
-    decl_name: ts::Node<'a>,
-    assign_name: ts::Node<'a>,
-    _assign_call: ts::Node<'a>,
-    assign_func: ts::Node<'a>,
-    flags: ts::Node<'a>,
+struct FilePanicked(String);
+
+impl FilePanicked {
+    fn from_payload(payload: Box<dyn std::any::Any + Send>) -> Self {
+        let message = if let Some(message) = payload.downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "unknown panic payload".to_string()
+        };
+        FilePanicked(message)
+    }
+}
+
+impl std::fmt::Display for FilePanicked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "panicked: {}", self.0)
+    }
 }
 
-async fn process_file_content(
+impl std::error::Error for FilePanicked {}
+
+/// The names of the selected structs, to find which of them a file mentions
+/// before it is parsed.
+struct Prefilter {
+    regex: regex::bytes::Regex,
+    /// Skip the files naming none of them, for structs picked by --struct.
+    skip_unmentioned: bool,
+}
+
+impl Prefilter {
+    fn new<'a>(
+        names: impl IntoIterator<Item = &'a String>,
+        skip_unmentioned: bool,
+    ) -> anyhow::Result<Self> {
+        let names: Vec<String> = names.into_iter().map(|name| regex::escape(name)).collect();
+        // C identifiers are ASCII, the Unicode word boundary would only be
+        // slower. --coverage over every struct makes for a large alternation
+        let regex =
+            regex::bytes::RegexBuilder::new(&format!(r"(?-u:\b)(?:{})(?-u:\b)", names.join("|")))
+                .size_limit(256 << 20)
+                .build()?;
+        Ok(Prefilter {
+            regex,
+            skip_unmentioned,
+        })
+    }
+}
+
+fn process_file_content(
     path: PathBuf,
     content: Vec<u8>,
-    struct_map: Arc<RwLock<HashMap<String, dwat::Struct>>>,
-    dwarf: Arc<RwLock<dwat::dwarf::OwnedDwarf>>,
-    flags_regex_str: Option<String>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut parser = TsParser::new();
-
-    parser
-        .set_language(ts_c::language())
-        .expect("Error loading C grammar");
-
-    let parsed = parser
-        .parse(&content, None)
-        .expect("Parser returned no tree");
-    let root_node = parsed.root_node();
-
-    let query_str = "
-    (
-        function_definition
-        declarator: (_) @function.decl
-        body: (
-            compound_statement (
-                declaration type: (
-                    struct_specifier name: (
-                        type_identifier
-                    ) @struct.name
-                ) declarator: (
-                    pointer_declarator declarator: (
-                        identifier
-                    ) @declaration.name
-                )
-            )
-            (expression_statement (
-                assignment_expression
-                    left: (identifier) @assignment.name
-                    right: (
-                        (call_expression
-                            function: (identifier) @assignment.function
-                            (#match? @assignment.function \"k[mz]alloc\")
-                            arguments: (argument_list
-                                (_) @flags .
-                            )
-                        ) @assignment.call
-                    )
-                )
-            )
-            (#eq? @declaration.name @assignment.name)
-        )
-    ) @function.def
-    ";
+    struct_map: Arc<RwLock<HashMap<String, usize>>>,
+    types: Arc<dyn TypeSource>,
+    site_filter: Arc<SiteFilter>,
+    profiles: Arc<Profiles>,
+    timeout: Option<Duration>,
+) -> anyhow::Result<()> {
+    if let Some(prefilter) = STRUCT_PREFILTER.get() {
+        let mentioned = match coverage::enabled() {
+            true => coverage::record_mentions(
+                prefilter
+                    .regex
+                    .find_iter(&content)
+                    .map(|hit| hit.as_bytes()),
+            ),
+            false => prefilter.regex.is_match(&content),
+        };
+        if !mentioned && prefilter.skip_unmentioned {
+            skipped::record(Reason::PrefilterNoIdentifiers, &path, None);
+            return Ok(());
+        }
+    }
 
-    let query = Query::new(ts_c::language(), query_str).expect("Error parsing query");
-    let mut query_cursor = QueryCursor::new();
-    let matches = query_cursor.matches(&query, root_node, &content[..]);
+    let first_match = *FIRST_MATCH_MODE.lock().unwrap();
+    let dedup = *DEDUP_MODE.lock().unwrap();
+    let quiet = *QUIET_MODE.lock().unwrap();
+    let mut exports = None;
+    let mut bindings = None;
+    let mut file_blame = None;
 
-    for match_ in matches {
-        let captures = match_.captures;
-        let struct_name = captures
-            .get(2)
-            .unwrap()
-            .node
-            .utf8_text(&content)
-            .unwrap_or("")
-            .to_string();
+    let verbose = *VERBOSE_MODE.lock().unwrap();
+    let profile = profiles.of(&path)?;
+    if profiles.scoped() && verbose {
+        eprintln!(
+            "{}: allocator profile {}",
+            output::display_path(&path),
+            profile.name
+        );
+    }
+
+    // the uses of the file's allocator wrapper macros are scanned as the
+    // calls they expand to, its own contents are kept for blaming
+    let (content, original, expansions) =
+        match macros::expand(&content, profile.allocators, &path, verbose) {
+            Some((expanded, expansions)) => (expanded, Some(content), expansions),
+            None => (content, None, vec![]),
+        };
+
+    let tree = find_sites(&content, &profile.queries, &site_filter, timeout, |qm| {
+        if SCAN_DONE.load(Ordering::SeqCst) {
+            return Ok(ControlFlow::Break(()));
+        }
+
+        let struct_name = qm.struct_name.utf8_text(&content).unwrap_or("").to_string();
+        if first_match && FOUND_STRUCTS.lock().unwrap().contains(&struct_name) {
+            return Ok(ControlFlow::Continue(()));
+        }
 
         let struct_map = struct_map.read().unwrap();
-        if let Some(struct_) = struct_map.get(&struct_name) {
-            let mut flags_regex = Regex::new(".*")?;
-            if let Some(ref flags_regex_str) = flags_regex_str {
-                flags_regex = Regex::new(flags_regex_str)?;
+        let Some(&struct_size) = struct_map.get(&struct_name) else {
+            return Ok(ControlFlow::Continue(()));
+        };
+        // an array of pointers to the struct isn't an allocation of it, it
+        // is only shown alongside the struct's sites
+        if qm.pointer_array && (first_match || quiet) {
+            return Ok(ControlFlow::Continue(()));
+        }
+        if !site_filter.matches_function(Some(qm.function_definition), &content) {
+            stats::inc(&STATS.function_filtered);
+            return Ok(ControlFlow::Continue(()));
+        }
+
+        let presence = kallsyms::check(qm.function_definition, &content);
+        if kallsyms::only_present() && presence.as_ref().is_some_and(Presence::is_missing) {
+            return Ok(ControlFlow::Continue(()));
+        }
+        let guards = preproc::guards(qm.assign_call, &content);
+        let built = preproc::state(&guards);
+        if preproc::only_enabled() && built != Some(preproc::State::Enabled) {
+            return Ok(ControlFlow::Continue(()));
+        }
+
+        if dedup {
+            let function_range = qm.function_definition.byte_range();
+            let key = dedup::site_key(
+                &content[function_range.clone()],
+                qm.assign_call.start_byte() - function_range.start,
+                &struct_name,
+                qm.assign_func.utf8_text(&content)?,
+            );
+            let line = byte_offset_to_line_number(&content, function_range.start)?;
+            if !dedup::first_sighting(key, &struct_name, &path, line) {
+                return Ok(ControlFlow::Continue(()));
+            }
+        }
+
+        if first_match {
+            // another worker may have reported this struct since the check above
+            let mut found = FOUND_STRUCTS.lock().unwrap();
+            if !found.insert(struct_name.clone()) {
+                return Ok(ControlFlow::Continue(()));
             }
-            let flags = captures
-                .get(7)
-                .unwrap()
-                .node
-                .utf8_text(&content)
-                .unwrap_or("")
-                .to_string();
-            if flags_regex.find(&flags).is_none() {
-                continue;
+            if found.len() == struct_map.len() {
+                SCAN_DONE.store(true, Ordering::SeqCst);
             }
+        }
 
-            let qm = QueryMatch {
-                function_definition: captures.get(0).unwrap().node,
-                struct_name: captures.get(2).unwrap().node,
-                decl_name: captures.get(3).unwrap().node,
-                assign_name: captures.get(4).unwrap().node,
-                _assign_call: captures.get(5).unwrap().node,
-                assign_func: captures.get(6).unwrap().node,
-                flags: captures.get(7).unwrap().node,
-            };
+        stats::inc(&STATS.sites);
+        coverage::record_site(&struct_name);
+        subsystem::record_site(&subsystem::of(&path), &struct_name);
+        let line = byte_offset_to_line_number(&content, qm.assign_call.start_byte())?;
+        if !qm.pointer_array {
+            crosscache::record(&struct_name, &path, line, qm.flags_text(&content)?);
+            elastic::record(&struct_name, qm.elastic.as_deref());
+        }
+        if tags::enabled() {
+            tags::record(qm.function_definition, &content, &path, &struct_name);
+        }
+        if frees::enabled() && !qm.pointer_array {
+            frees::record_alloc(qm, &content, &path);
+        }
+        // quiet mode lists each struct with a site once, whichever file
+        // or worker finds it first
+        if quiet {
+            if QUIET_REPORTED.lock().unwrap().insert(struct_name.clone()) {
+                output::print(&format!("{struct_name}\n"));
+            }
+            return Ok(ControlFlow::Continue(()));
+        }
 
-            display_match(&content, &path, struct_, &dwarf, &qm);
+        // the exports are only looked for once the file has a site to show
+        let exports = exports.get_or_insert_with(|| scanner::exported_symbols(&content));
+        let linkage = Linkage::of(qm.function_definition, &content, exports);
+        let mut annotations = vec![linkage.to_string()];
+        if qm.pointer_array {
+            annotations.push(format!("pointer array of struct {struct_name} *"));
         }
-    }
+        annotations.extend(qm.note.map(str::to_string));
+        let call = qm.assign_call.start_byte();
+        if let Some(expansion) = expansions.iter().find(|e| e.range.contains(&call)) {
+            annotations.push(format!("via macro {}", expansion.invocation));
+        }
+        if !guards.is_empty() {
+            let guarded = format!("guarded by: {}", preproc::describe(&guards));
+            annotations.push(match built {
+                Some(state) => format!("{guarded} ({state})"),
+                None => guarded,
+            });
+        }
+        let mut reachable = false;
+        if handlers::enabled() || rank::enabled() || focus::enabled() {
+            let bindings = bindings.get_or_insert_with(|| {
+                let mut root = qm.function_definition;
+                while let Some(parent) = root.parent() {
+                    root = parent;
+                }
+                handlers::bindings(root, &content)
+            });
+            let notes = handlers::annotate(qm.function_definition, &content, bindings);
+            reachable = !notes.is_empty();
+            if handlers::enabled() {
+                annotations.extend(notes);
+            }
+        }
+        if caps::enabled() && !qm.caps.is_empty() {
+            annotations.push(format!("checks {} (heuristic)", qm.caps.join(", ")));
+        }
+        // the file is blamed once, for all of its sites
+        let blame = match blame::enabled() {
+            true => file_blame
+                .get_or_insert_with(|| blame::file(&path, original.as_deref().unwrap_or(&content)))
+                .as_ref()
+                .map(|file_blame| match file_blame.line(line) {
+                    Some(blame) => blame.to_string(),
+                    None => "not committed yet".to_string(),
+                }),
+            false => None,
+        };
+        let notes = SiteNotes {
+            annotations,
+            reachable,
+            blame,
+            presence,
+            includers: includers::of(&path),
+        };
+        display_match(&content, &path, struct_size, types.as_ref(), qm, &notes)?;
+        Ok(ControlFlow::Continue(()))
+    })?;
 
+    // the other uses of the selected structs are looked for in the same tree
+    let user_copies = usercopy::writes_enabled() || usercopy::reads_enabled();
+    let loose = loose::enabled();
+    if frees::enabled() || user_copies || bulk::enabled() || loose {
+        let struct_map = struct_map.read().unwrap();
+        let selected = |name: &str| struct_map.contains_key(name);
+        if frees::enabled() {
+            frees::find(tree.root_node(), &content, &path, selected);
+        }
+        if user_copies {
+            usercopy::find(tree.root_node(), &content, &path, selected);
+        }
+        if bulk::enabled() {
+            bulk::find(tree.root_node(), &content, &path, selected);
+        }
+        // loose sites are only listed, they aren't ranked or recorded
+        if loose {
+            loose::find(
+                tree.root_node(),
+                &content,
+                &path,
+                &profile.queries,
+                profile.allocators,
+                &site_filter,
+                |name| struct_map.get(name).copied(),
+            )?;
+        }
+    }
     Ok(())
 }
 
-async fn read_and_process_file(
+/// A file's contents as read by the reader stage. The permit bounds how many
+/// buffers exist at once and is released when the buffer is dropped.
+struct FileBuffer {
     path: PathBuf,
-    struct_map: Arc<RwLock<HashMap<String, dwat::Struct>>>,
-    dwarf: Arc<RwLock<dwat::dwarf::OwnedDwarf>>,
-    flags_regex_str: Option<String>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut file = File::open(path.clone()).await?;
-    let mut contents = vec![];
-    file.read_to_end(&mut contents).await?;
-
-    let struct_map = struct_map.clone();
-    let dwarf = dwarf.clone();
-    let _ = task::spawn_blocking(move || {
-        process_file_content(path, contents, struct_map, dwarf, flags_regex_str)
+    contents: std::io::Result<Vec<u8>>,
+    _permit: OwnedSemaphorePermit,
+    _held: stats::BufferGuard,
+}
+
+impl FileBuffer {
+    fn new(
+        path: PathBuf,
+        contents: std::io::Result<Vec<u8>>,
+        permit: OwnedSemaphorePermit,
+    ) -> Self {
+        let bytes = contents.as_ref().map_or(0, Vec::len);
+        FileBuffer {
+            path,
+            contents,
+            _permit: permit,
+            _held: stats::hold_buffer(bytes),
+        }
+    }
+}
+
+/// Process one file on the blocking thread pool. The worker permit is moved
+/// into the blocking closure so it is held for as long as the file is being
+/// parsed and queried, which is what actually bounds CPU use to `--threads`.
+async fn process_file(
+    buffer: FileBuffer,
+    permit: OwnedSemaphorePermit,
+    struct_map: Arc<RwLock<HashMap<String, usize>>>,
+    types: Arc<dyn TypeSource>,
+    site_filter: Arc<SiteFilter>,
+    profiles: Arc<Profiles>,
+    timeout: Option<Duration>,
+) -> anyhow::Result<()> {
+    let FileBuffer {
+        path,
+        contents,
+        _permit,
+        _held,
+    } = buffer;
+    let contents = contents?;
+
+    run_worker(permit, move || {
+        process_file_content(
+            path,
+            contents,
+            struct_map,
+            types,
+            site_filter,
+            profiles,
+            timeout,
+        )
+    })
+    .await
+}
+
+/// Run `work` on the blocking thread pool, holding the worker `permit` until
+/// it returns. A panic in `work` shouldn't take down the scan, it is returned
+/// as a [`FilePanicked`] error for the file being worked on.
+async fn run_worker(
+    permit: OwnedSemaphorePermit,
+    work: impl FnOnce() -> anyhow::Result<()> + Send + 'static,
+) -> anyhow::Result<()> {
+    task::spawn_blocking(move || {
+        let _permit = permit;
+        let _worker = stats::enter_worker();
+        panic::catch_unwind(AssertUnwindSafe(work))
+            .unwrap_or_else(|payload| Err(FilePanicked::from_payload(payload).into()))
     })
     .await?
-    .await;
-    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The highlighted copy of `content`, with the escapes shown as brackets.
+    fn highlight(content: &str, ranges: &[std::ops::Range<usize>]) -> String {
+        let highlighted = apply_highlight_ranges(content.as_bytes(), &(0..content.len()), ranges);
+        String::from_utf8(highlighted)
+            .unwrap()
+            .replace("\x1b[31m", "[")
+            .replace("\x1b[0m", "]")
+    }
+
+    #[test]
+    fn highlight_overlapping_ranges() {
+        assert_eq!(
+            highlight("p = kmalloc(n);", &[4..8, 6..11]),
+            "p = [kmalloc](n);"
+        );
+        // two captures of the same identifier
+        assert_eq!(
+            highlight("p = kmalloc(n);", &[0..1, 0..1]),
+            "[p] = kmalloc(n);"
+        );
+    }
+
+    #[test]
+    fn highlight_adjacent_ranges() {
+        assert_eq!(highlight("foo->bar", &[0..3, 3..5, 5..8]), "[foo->bar]");
+    }
+
+    #[test]
+    fn highlight_reversed_ranges() {
+        assert_eq!(
+            highlight("p = kmalloc(n);", &[12..13, 4..11, 0..1]),
+            "[p] = [kmalloc]([n]);"
+        );
+    }
+
+    #[test]
+    fn highlight_multibyte_content() {
+        // the ranges around and inside the multibyte chars don't need to
+        // fall on char boundaries, the bytes come out unchanged
+        let content = "/* Müller */ p = kmalloc(n);";
+        assert_eq!(
+            highlight(content, std::slice::from_ref(&(18..25))),
+            "/* Müller */ p = [kmalloc](n);"
+        );
+        let highlighted = apply_highlight_ranges(
+            content.as_bytes(),
+            &(0..content.len()),
+            std::slice::from_ref(&(4..5)),
+        );
+        assert_eq!(
+            highlighted,
+            b"/* M\x1b[31m\xc3\x1b[0m\xbcller */ p = kmalloc(n);"
+        );
+    }
+
+    #[test]
+    fn merge_unsorted_ranges() {
+        assert_eq!(merge_ranges(&[8..10, 0..2, 4..6]), [0..2, 4..6, 8..10]);
+    }
+
+    #[test]
+    fn merge_nested_ranges() {
+        assert_eq!(merge_ranges(&[2..4, 0..10, 5..6]), vec![0..10]);
+        assert_eq!(merge_ranges(&[3..5, 3..5]), vec![3..5]);
+    }
+
+    #[test]
+    fn merge_touching_ranges() {
+        assert_eq!(merge_ranges(&[3..6, 0..3, 7..9]), [0..6, 7..9]);
+        assert!(merge_ranges(&[]).is_empty());
+    }
+
+    #[test]
+    fn highlight_after_multibyte_comment() {
+        let content =
+            "int f(void)\n{\n\t/* Grüße, José */\n\tp = kzalloc(sizeof(*p), GFP_KERNEL);\n}\n";
+        let start = content.find("kzalloc").unwrap();
+        let ranges = [
+            start..start + "kzalloc".len(),
+            content.find("p =").unwrap()..start - 3,
+        ];
+        assert_eq!(
+            highlight(content, &ranges),
+            "int f(void)\n{\n\t/* Grüße, José */\n\t[p] = [kzalloc](sizeof(*p), GFP_KERNEL);\n}\n"
+        );
+    }
+
+    #[test]
+    fn line_number_of_first_and_last_byte() {
+        let content = b"int a;\nint b;\nint c;";
+        assert_eq!(byte_offset_to_line_number(content, 0).unwrap(), 1);
+        assert_eq!(byte_offset_to_line_number(content, 6).unwrap(), 1);
+        assert_eq!(byte_offset_to_line_number(content, 7).unwrap(), 2);
+        assert_eq!(
+            byte_offset_to_line_number(content, content.len() - 1).unwrap(),
+            3
+        );
+        // the end of a file without a trailing newline is on its last line
+        assert_eq!(
+            byte_offset_to_line_number(content, content.len()).unwrap(),
+            3
+        );
+    }
+
+    #[test]
+    fn line_number_past_trailing_newline() {
+        let content = b"int a;\nint b;\n";
+        assert_eq!(
+            byte_offset_to_line_number(content, content.len() - 1).unwrap(),
+            2
+        );
+        assert_eq!(
+            byte_offset_to_line_number(content, content.len()).unwrap(),
+            3
+        );
+        assert!(byte_offset_to_line_number(content, content.len() + 1).is_err());
+    }
+
+    #[test]
+    fn line_number_in_empty_file() {
+        assert_eq!(byte_offset_to_line_number(b"", 0).unwrap(), 1);
+        assert!(byte_offset_to_line_number(b"", 1).is_err());
+    }
+
+    #[test]
+    fn count_sites_again_in_the_same_process() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let types: Arc<dyn TypeSource> = types::load(&fixtures.join("types.json")).unwrap().into();
+        let tree = fixtures.join("tree");
+        assert_eq!(count_sites(Arc::clone(&types), "bar", &tree).unwrap(), 2);
+        assert_eq!(count_sites(Arc::clone(&types), "bar", &tree).unwrap(), 2);
+        assert_eq!(
+            count_sites(types, "bar", &tree.join("drivers/net/foo.c")).unwrap(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn panicking_worker_fails_with_its_message() {
+        let sem = Arc::new(Semaphore::new(1));
+        let permit = Arc::clone(&sem).acquire_owned().await.unwrap();
+        let err = run_worker(permit, || panic!("deliberate panic"))
+            .await
+            .unwrap_err();
+        let panicked = err.downcast_ref::<FilePanicked>().expect("a FilePanicked");
+        assert_eq!(panicked.to_string(), "panicked: deliberate panic");
+        // the permit is released despite the panic, for the next file
+        assert_eq!(sem.available_permits(), 1);
+
+        let permit = Arc::clone(&sem).acquire_owned().await.unwrap();
+        let name = String::from("foo.c");
+        let err = run_worker(permit, move || panic!("bad input in {name}"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "panicked: bad input in foo.c");
+    }
+
+    #[tokio::test]
+    async fn worker_result_is_passed_through() {
+        let sem = Arc::new(Semaphore::new(1));
+        let permit = Arc::clone(&sem).acquire_owned().await.unwrap();
+        assert!(run_worker(permit, || Ok(())).await.is_ok());
+
+        let permit = Arc::clone(&sem).acquire_owned().await.unwrap();
+        let err = run_worker(permit, || anyhow::bail!("unreadable"))
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<FilePanicked>().is_none());
+        assert_eq!(err.to_string(), "unreadable");
+    }
+
+    #[test]
+    fn highlight_clamps_to_the_base_range() {
+        let content = b"int x; p = kmalloc(n); int y;";
+        let highlighted = apply_highlight_ranges(content, &(7..22), &[0..9, 20..29]);
+        assert_eq!(
+            highlighted,
+            b"\x1b[31mp \x1b[0m= kmalloc(n\x1b[31m);\x1b[0m"
+        );
+    }
 }
@@ -0,0 +1,154 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
+use std::thread::JoinHandle;
+
+use crate::progress;
+
+// serializes everything written to the terminal by the workers
+static STDOUT_LOCK: Mutex<()> = Mutex::new(());
+
+// set by `--relative-paths` to the source directories paths are printed under
+static RELATIVE_ROOTS: OnceLock<Vec<PathBuf>> = OnceLock::new();
+
+/// Lock stdout for exclusive use. The lock guards no data, so if a thread
+/// panicked while holding it the poisoning is ignored rather than taking
+/// every other writer down with it.
+pub fn lock_stdout() -> MutexGuard<'static, ()> {
+    STDOUT_LOCK.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The thread all scan output is written from, fed over a bounded channel.
+struct Writer {
+    tx: SyncSender<String>,
+    thread: JoinHandle<()>,
+}
+
+// set while a scan runs, see `start_writer`
+static WRITER: Mutex<Option<Writer>> = Mutex::new(None);
+
+/// Hand everything printed from here on to a single writer thread, so workers
+/// never wait on the terminal while holding a parse thread. At most
+/// `capacity` blocks are queued, beyond that printing blocks the workers
+/// until the writer catches up.
+pub fn start_writer(capacity: usize) {
+    let (tx, rx) = mpsc::sync_channel::<String>(capacity);
+    let thread = std::thread::spawn(move || {
+        for text in rx {
+            write(&text);
+        }
+    });
+    *WRITER.lock().unwrap_or_else(PoisonError::into_inner) = Some(Writer { tx, thread });
+}
+
+/// Wait for the writer thread to print everything queued, printing directly
+/// again afterwards.
+pub fn finish_writer() {
+    let writer = WRITER.lock().unwrap_or_else(PoisonError::into_inner).take();
+    if let Some(Writer { tx, thread }) = writer {
+        drop(tx);
+        let _ = thread.join();
+    }
+}
+
+/// Print a fully formatted block of output, through the writer thread when
+/// one is running.
+pub fn print(text: &str) {
+    let tx = WRITER
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_ref()
+        .map(|writer| writer.tx.clone());
+    match tx {
+        Some(tx) if tx.send(text.to_string()).is_ok() => {}
+        _ => write(text),
+    }
+}
+
+/// Write a block of output to stdout in a single locked write, so that it
+/// never interleaves with the progress bar or other output.
+fn write(text: &str) {
+    let _lock = lock_stdout();
+    progress::clear();
+
+    let mut stdout = std::io::stdout().lock();
+    let _ = stdout.write_all(text.as_bytes());
+    let _ = stdout.flush();
+}
+
+// set when scanning an archive, whose paths are printed as they are in it
+static ARCHIVE_PATHS: AtomicBool = AtomicBool::new(false);
+
+/// Print paths as given, they are paths inside an archive rather than on
+/// disk.
+pub fn set_archive_paths() {
+    ARCHIVE_PATHS.store(true, Ordering::SeqCst);
+}
+
+/// Print paths relative to the source directory under `roots` they are in
+/// rather than as absolute paths.
+pub fn set_relative_roots(roots: &[PathBuf]) {
+    let _ = RELATIVE_ROOTS.set(roots.to_vec());
+}
+
+/// Format a path for output. Paths are absolute unless `--relative-paths` was
+/// given, and never fail to print, bytes which aren't valid UTF-8 are
+/// replaced. With several source directories, relative paths are prefixed by
+/// the name of the directory they are in so they stay unambiguous.
+pub fn display_path(path: &Path) -> String {
+    if ARCHIVE_PATHS.load(Ordering::SeqCst) {
+        return path.display().to_string();
+    }
+    if let Some(roots) = RELATIVE_ROOTS.get() {
+        // the innermost root wins when one source directory is nested in another
+        let relative = roots
+            .iter()
+            .filter_map(|root| Some((root, path.strip_prefix(root).ok()?)))
+            .min_by_key(|(_, relative)| relative.components().count());
+        if let Some((root, relative)) = relative {
+            if roots.len() == 1 {
+                return relative.display().to_string();
+            }
+            let name = std::path::absolute(root)
+                .ok()
+                .and_then(|root| root.file_name().map(|name| name.to_owned()))
+                .unwrap_or_default();
+            return Path::new(&name).join(relative).display().to_string();
+        }
+    }
+    std::path::absolute(path)
+        .as_deref()
+        .unwrap_or(path)
+        .display()
+        .to_string()
+}
+
+/// Collapse the whitespace in a snippet of source, so a call spanning several
+/// lines prints on one.
+pub fn one_line(source: &[u8]) -> String {
+    let source = String::from_utf8_lossy(source);
+    source.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn poisoned_stdout_lock_still_prints() {
+        // a worker panicking halfway through printing a match
+        let worker = std::thread::spawn(|| {
+            let _lock = lock_stdout();
+            panic!("a match failed to print");
+        });
+        assert!(worker.join().is_err());
+        assert!(STDOUT_LOCK.is_poisoned());
+
+        // later output still takes the lock and is written, on this thread
+        // as no writer thread is running
+        print("after the panic\n");
+        drop(lock_stdout());
+    }
+}
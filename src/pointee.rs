@@ -0,0 +1,154 @@
+use tree_sitter as ts;
+
+use crate::query;
+
+/// The struct an expression's type is built on and the number of pointer
+/// levels on top of it, going by a cast around the expression or the
+/// declarations in scope at it: `p` for `struct foo *p` is `(foo, 1)`, `&p`
+/// is `(foo, 2)` and `(struct foo *)data` is `(foo, 1)`. Member accesses and
+/// typedefs aren't resolved.
+pub fn resolve<'a>(expr: ts::Node<'_>, content: &'a [u8]) -> Option<(&'a str, usize)> {
+    match expr.kind() {
+        "parenthesized_expression" => resolve(expr.named_child(0)?, content),
+        "cast_expression" => {
+            let descriptor = expr.child_by_field_name("type")?;
+            let name = struct_name(descriptor.child_by_field_name("type")?, content)?;
+            let mut levels = 0;
+            let mut declarator = descriptor.child_by_field_name("declarator");
+            while let Some(node) = declarator {
+                if node.kind() == "abstract_pointer_declarator" {
+                    levels += 1;
+                }
+                declarator = node.child_by_field_name("declarator");
+            }
+            Some((name, levels))
+        }
+        "pointer_expression" => {
+            let (name, levels) = resolve(expr.child_by_field_name("argument")?, content)?;
+            match expr
+                .child_by_field_name("operator")?
+                .utf8_text(content)
+                .ok()?
+            {
+                "&" => Some((name, levels + 1)),
+                _ => Some((name, levels.checked_sub(1)?)),
+            }
+        }
+        "subscript_expression" => {
+            let (name, levels) = resolve(expr.child_by_field_name("argument")?, content)?;
+            Some((name, levels.checked_sub(1)?))
+        }
+        "identifier" => declared_type(expr, content),
+        _ => None,
+    }
+}
+
+/// The struct an expression points to, when it is a pointer to one.
+pub fn struct_pointee<'a>(expr: ts::Node<'_>, content: &'a [u8]) -> Option<&'a str> {
+    resolve(expr, content)
+        .filter(|&(_, levels)| levels == 1)
+        .map(|(name, _)| name)
+}
+
+/// The variable an expression refers to once casts and parentheses around it
+/// are looked through, e.g. `p` for `(void *)p`.
+pub fn variable<'a>(mut expr: ts::Node<'_>, content: &'a [u8]) -> Option<&'a str> {
+    loop {
+        match expr.kind() {
+            "identifier" => return expr.utf8_text(content).ok(),
+            "parenthesized_expression" => expr = expr.named_child(0)?,
+            "cast_expression" => expr = expr.child_by_field_name("value")?,
+            _ => return None,
+        }
+    }
+}
+
+/// Look up the type of the variable `identifier` names in the innermost scope
+/// declaring it: the enclosing blocks, a `for` initializer, the function's
+/// parameters and finally the file scope.
+fn declared_type<'a>(identifier: ts::Node<'_>, content: &'a [u8]) -> Option<(&'a str, usize)> {
+    let name = identifier.utf8_text(content).ok()?;
+    let mut scope = identifier.parent();
+    while let Some(node) = scope {
+        // a declaration of the name which isn't a struct shadows any outer one
+        let declared = match node.kind() {
+            "compound_statement" | "translation_unit" => {
+                let mut cursor = node.walk();
+                let declarations: Vec<ts::Node> = node
+                    .named_children(&mut cursor)
+                    .filter(|child| {
+                        child.kind() == "declaration"
+                            && child.start_byte() < identifier.start_byte()
+                    })
+                    .collect();
+                declarations
+                    .into_iter()
+                    .rev()
+                    .find_map(|decl| declares(decl, name, content))
+            }
+            "for_statement" => node
+                .child_by_field_name("initializer")
+                .filter(|init| init.kind() == "declaration")
+                .and_then(|decl| declares(decl, name, content)),
+            "function_definition" => parameters(node)
+                .into_iter()
+                .find_map(|param| declares(param, name, content)),
+            _ => None,
+        };
+        if let Some(declared) = declared {
+            return declared;
+        }
+        scope = node.parent();
+    }
+    None
+}
+
+/// If `decl` declares `name`, the struct type it declares it with, which is
+/// None when the type isn't a struct.
+fn declares<'a>(
+    decl: ts::Node<'_>,
+    name: &str,
+    content: &'a [u8],
+) -> Option<Option<(&'a str, usize)>> {
+    let mut cursor = decl.walk();
+    let declarators: Vec<ts::Node> = decl
+        .children_by_field_name("declarator", &mut cursor)
+        .collect();
+    let levels = declarators
+        .into_iter()
+        .flat_map(query::declared_identifiers)
+        .find(|(id, _)| id.utf8_text(content).ok() == Some(name))
+        .map(|(_, levels)| levels)?;
+    let struct_name = decl
+        .child_by_field_name("type")
+        .and_then(|ty| struct_name(ty, content));
+    Some(struct_name.map(|name| (name, levels)))
+}
+
+/// The parameter declarations of a function definition.
+pub(crate) fn parameters(definition: ts::Node<'_>) -> Vec<ts::Node<'_>> {
+    let mut node = definition.child_by_field_name("declarator");
+    while let Some(declarator) = node {
+        if declarator.kind() == "function_declarator" {
+            let Some(params) = declarator.child_by_field_name("parameters") else {
+                break;
+            };
+            let mut cursor = params.walk();
+            let params = params
+                .named_children(&mut cursor)
+                .filter(|param| param.kind() == "parameter_declaration")
+                .collect();
+            return params;
+        }
+        node = declarator.child_by_field_name("declarator");
+    }
+    vec![]
+}
+
+/// The name of a `struct NAME` type specifier.
+pub(crate) fn struct_name<'a>(ty: ts::Node<'_>, content: &'a [u8]) -> Option<&'a str> {
+    if ty.kind() != "struct_specifier" {
+        return None;
+    }
+    ty.child_by_field_name("name")?.utf8_text(content).ok()
+}
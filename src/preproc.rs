@@ -0,0 +1,153 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+use tree_sitter as ts;
+
+use crate::kconfig::Options;
+use crate::output;
+
+// set by `--kconfig`
+static OPTIONS: OnceLock<Options> = OnceLock::new();
+// set by `--only-enabled`
+static ONLY_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether a site's preprocessor conditions hold for the `--kconfig` .config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Enabled,
+    Disabled,
+    /// A condition depends on something other than the config.
+    Unknown,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            State::Enabled => "enabled",
+            State::Disabled => "disabled",
+            State::Unknown => "unknown",
+        })
+    }
+}
+
+pub fn enable(options: Options) {
+    let _ = OPTIONS.set(options);
+}
+
+pub fn enable_only_enabled() {
+    ONLY_ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn only_enabled() -> bool {
+    ONLY_ENABLED.load(Ordering::SeqCst)
+}
+
+/// The conditions of the `#if`, `#ifdef` and `#ifndef` blocks `node` is in,
+/// outermost first, negated for their `#else` branches. A node in an `#elif`
+/// branch is guarded by its condition and the negations of those before it.
+pub fn guards(node: ts::Node<'_>, content: &[u8]) -> Vec<String> {
+    let mut guards = vec![];
+    let mut child = node;
+    while let Some(parent) = child.parent() {
+        let in_alternative = parent
+            .child_by_field_name("alternative")
+            .is_some_and(|alternative| alternative.id() == child.id());
+        if let Some(condition) = condition(parent, content) {
+            guards.push(match in_alternative {
+                true => negate(&condition),
+                false => condition,
+            });
+        }
+        child = parent;
+    }
+    guards.reverse();
+    guards
+}
+
+/// The condition of a conditional block, `NAME` or `!NAME` for `#ifdef` and
+/// `#ifndef`.
+fn condition(node: ts::Node<'_>, content: &[u8]) -> Option<String> {
+    match node.kind() {
+        "preproc_if" | "preproc_elif" => {
+            let condition = node.child_by_field_name("condition")?;
+            Some(output::one_line(&content[condition.byte_range()]))
+        }
+        "preproc_ifdef" | "preproc_elifdef" => {
+            let name = node.child_by_field_name("name")?.utf8_text(content).ok()?;
+            let directive = node.child(0)?.utf8_text(content).ok()?;
+            Some(
+                match directive.trim_start_matches('#').trim().ends_with("ndef") {
+                    true => format!("!{name}"),
+                    false => name.to_string(),
+                },
+            )
+        }
+        _ => None,
+    }
+}
+
+/// The negation of a condition, for the `#else` branch of its block.
+fn negate(condition: &str) -> String {
+    match condition.strip_prefix('!') {
+        Some(negated) if is_simple(negated) => negated.to_string(),
+        _ if is_simple(condition) => format!("!{condition}"),
+        _ => format!("!({condition})"),
+    }
+}
+
+/// Whether a condition is a lone name, `defined NAME` or `defined(NAME)`.
+fn is_simple(condition: &str) -> bool {
+    condition
+        .strip_prefix("defined")
+        .unwrap_or(condition)
+        .trim()
+        .trim_start_matches('(')
+        .trim_end_matches(')')
+        .trim()
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// The conditions guarding a site, joined into one.
+pub fn describe(guards: &[String]) -> String {
+    let parts: Vec<String> = guards
+        .iter()
+        .map(|guard| match guards.len() > 1 && has_top_level_or(guard) {
+            true => format!("({guard})"),
+            false => guard.clone(),
+        })
+        .collect();
+    parts.join(" && ")
+}
+
+/// Whether a condition has a `||` outside of any parentheses.
+fn has_top_level_or(condition: &str) -> bool {
+    let mut depth = 0usize;
+    let mut previous = ' ';
+    for c in condition.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            '|' if previous == '|' && depth == 0 => return true,
+            _ => {}
+        }
+        previous = c;
+    }
+    false
+}
+
+/// Whether a site guarded by `guards` is built with the `--kconfig` .config,
+/// None without one.
+pub fn state(guards: &[String]) -> Option<State> {
+    let options = OPTIONS.get()?;
+    let mut state = State::Enabled;
+    for guard in guards {
+        match options.evaluate(guard) {
+            Some(false) => return Some(State::Disabled),
+            Some(true) => {}
+            None => state = State::Unknown,
+        }
+    }
+    Some(state)
+}
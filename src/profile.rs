@@ -0,0 +1,197 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::alloc::{self, Allocator};
+use crate::query::{self, AllocQuery};
+use crate::subsystem;
+
+/// An `--allocator-config` file. Each section of `allocators` is keyed by a
+/// glob over paths relative to the kernel tree:
+///
+/// ```toml
+/// [allocators."drivers/vendor/**"]
+/// osal_mem_alloc = { size = 0 }
+/// osal_mem_calloc = { count = 0, size = 1, zeroes = true }
+/// vendor_kmalloc = { size = 1, flags = 2 }
+/// ```
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default)]
+    allocators: BTreeMap<String, BTreeMap<String, Entry>>,
+}
+
+/// An allocator of a config section, by the indices of its arguments.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    size: usize,
+    count: Option<usize>,
+    /// Left out for allocators taking no flags.
+    flags: Option<usize>,
+    #[serde(default)]
+    zeroes: bool,
+}
+
+/// The allocators added for the files matching a glob.
+struct Section {
+    glob: String,
+    matcher: globset::GlobMatcher,
+    allocators: Vec<Allocator>,
+}
+
+/// The allocator table a file is scanned with, and the queries built for it.
+pub struct Profile {
+    /// `built-in`, followed by the globs of the sections adding to it.
+    pub name: String,
+    pub allocators: &'static [Allocator],
+    pub queries: Vec<AllocQuery>,
+}
+
+/// The allocator profiles of a scan. A file matching no section is scanned
+/// with the built-in table, one matching some with the built-in table plus
+/// their allocators. The queries are built once per distinct set of sections.
+pub struct Profiles {
+    sections: Vec<Section>,
+    query: Option<PathBuf>,
+    extra: Vec<PathBuf>,
+    built: Mutex<HashMap<Vec<usize>, Arc<Profile>>>,
+}
+
+impl Profiles {
+    /// Load the sections of the `--allocator-config` file if there is one,
+    /// and build the built-in profile from `query` and `extra` like
+    /// `query::load`.
+    pub fn load(
+        config: Option<&Path>,
+        query: Option<&Path>,
+        extra: &[PathBuf],
+    ) -> anyhow::Result<Self> {
+        let sections = match config {
+            Some(path) => {
+                load_sections(path).with_context(|| format!("failed to load {}", path.display()))?
+            }
+            None => vec![],
+        };
+        let profiles = Profiles {
+            sections,
+            query: query.map(Path::to_path_buf),
+            extra: extra.to_vec(),
+            built: Mutex::new(HashMap::new()),
+        };
+        profiles.build(vec![])?;
+        Ok(profiles)
+    }
+
+    /// Whether any allocators are scoped to some of the files.
+    pub fn scoped(&self) -> bool {
+        !self.sections.is_empty()
+    }
+
+    /// The profile of a file in the scan.
+    pub fn of(&self, path: &Path) -> anyhow::Result<Arc<Profile>> {
+        let relative = subsystem::relative(path);
+        let matching = self
+            .sections
+            .iter()
+            .enumerate()
+            .filter(|(_, section)| section.matcher.is_match(relative))
+            .map(|(idx, _)| idx)
+            .collect();
+        self.build(matching)
+    }
+
+    fn build(&self, sections: Vec<usize>) -> anyhow::Result<Arc<Profile>> {
+        let mut built = self.built.lock().unwrap();
+        if let Some(profile) = built.get(&sections) {
+            return Ok(Arc::clone(profile));
+        }
+
+        // an allocator in several of the sections takes the definition of
+        // the one with the longest glob, as the most specific, and any of
+        // them replaces a built-in allocator of the same name
+        let mut scoped: Vec<&Section> = sections.iter().map(|&idx| &self.sections[idx]).collect();
+        scoped.sort_by_key(|section| section.glob.len());
+        let mut table: Vec<Allocator> = alloc::ALLOCATORS.to_vec();
+        for allocator in scoped.iter().flat_map(|section| &section.allocators) {
+            table.retain(|existing| existing.name != allocator.name);
+            table.push(*allocator);
+        }
+        let name = match scoped.is_empty() {
+            true => "built-in".to_string(),
+            false => {
+                let globs: Vec<&str> = scoped.iter().map(|section| section.glob.as_str()).collect();
+                format!("built-in + {}", globs.join(" + "))
+            }
+        };
+
+        // a profile lives for the rest of the scan, the queries hold on to
+        // its table
+        let allocators: &'static [Allocator] = match scoped.is_empty() {
+            true => alloc::ALLOCATORS,
+            false => Box::leak(table.into_boxed_slice()),
+        };
+        let profile = Arc::new(Profile {
+            name,
+            allocators,
+            queries: query::load(self.query.as_deref(), &self.extra, allocators)?,
+        });
+        built.insert(sections, Arc::clone(&profile));
+        Ok(profile)
+    }
+}
+
+fn load_sections(path: &Path) -> anyhow::Result<Vec<Section>> {
+    let config: Config = toml::from_str(&std::fs::read_to_string(path)?)?;
+    let mut sections = vec![];
+    for (glob, entries) in config.allocators {
+        let matcher = globset::Glob::new(&glob)
+            .with_context(|| format!("invalid glob '{glob}'"))?
+            .compile_matcher();
+        let mut allocators = vec![];
+        for (name, entry) in entries {
+            if !is_identifier(&name) {
+                anyhow::bail!("'{name}' in [allocators.\"{glob}\"] isn't a function name");
+            }
+            if entry.count == Some(entry.size)
+                || entry
+                    .flags
+                    .is_some_and(|flags| flags == entry.size || entry.count == Some(flags))
+            {
+                anyhow::bail!("{name} in [allocators.\"{glob}\"] uses an argument twice");
+            }
+            let name: &'static str = Box::leak(name.into_boxed_str());
+            let allocator = match (entry.count, entry.flags) {
+                (Some(count), Some(flags)) => Allocator::array(name, count, entry.size, flags),
+                (None, Some(flags)) => Allocator::new(name, entry.size, flags),
+                (count, None) => Allocator {
+                    count_arg: count,
+                    ..Allocator::unflagged(name, entry.size)
+                },
+            };
+            allocators.push(match entry.zeroes {
+                true => allocator.zeroing(),
+                false => allocator,
+            });
+        }
+        sections.push(Section {
+            glob,
+            matcher,
+            allocators,
+        });
+    }
+    Ok(sections)
+}
+
+/// Whether a name can go in the query's allocator regex as is.
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
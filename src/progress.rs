@@ -0,0 +1,129 @@
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::output;
+
+const BAR_WIDTH: usize = 30;
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+// set while a bar may be on screen, so match output knows to clear it first
+static BAR_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Progress bar over the set of files being scanned, rendered on stderr.
+pub struct Progress {
+    total: usize,
+    done: AtomicUsize,
+    start: Instant,
+    finished: AtomicBool,
+}
+
+impl Progress {
+    /// Create a progress tracker for `total` files and start redrawing it in
+    /// the background. Returns None when stderr is not a terminal.
+    pub fn start(total: usize) -> Option<Arc<Self>> {
+        if !std::io::stderr().is_terminal() {
+            return None;
+        }
+
+        let progress = Arc::new(Progress {
+            total,
+            done: AtomicUsize::new(0),
+            start: Instant::now(),
+            finished: AtomicBool::new(false),
+        });
+        BAR_ACTIVE.store(true, Ordering::SeqCst);
+
+        let bar = Arc::clone(&progress);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REDRAW_INTERVAL);
+            while !bar.finished.load(Ordering::SeqCst) {
+                interval.tick().await;
+                bar.draw();
+            }
+        });
+
+        Some(progress)
+    }
+
+    /// Record that one more file has been processed.
+    pub fn inc(&self) {
+        self.done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Stop redrawing and remove the bar from the terminal.
+    pub fn finish(&self) {
+        let _lock = output::lock_stdout();
+        self.finished.store(true, Ordering::SeqCst);
+        BAR_ACTIVE.store(false, Ordering::SeqCst);
+        erase();
+    }
+
+    fn draw(&self) {
+        // hold the stdout lock so the bar is never drawn in the middle of a
+        // match being printed
+        let _lock = output::lock_stdout();
+        if self.finished.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let done = self.done.load(Ordering::Relaxed);
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            done as f64 / elapsed
+        } else {
+            0.0
+        };
+        // with no total, or more files done than counted, there is nothing
+        // left to estimate
+        let eta = if self.total > 0 && rate > 0.0 {
+            format_duration(self.total.saturating_sub(done) as f64 / rate)
+        } else {
+            "--".to_string()
+        };
+
+        let filled = (done * BAR_WIDTH)
+            .checked_div(self.total)
+            .unwrap_or(0)
+            .min(BAR_WIDTH);
+        let bar = format!(
+            "{}{}",
+            "#".repeat(filled),
+            " ".repeat(BAR_WIDTH.saturating_sub(filled))
+        );
+
+        let mut stderr = std::io::stderr().lock();
+        let _ = write!(
+            stderr,
+            "\r\x1b[2K[{bar}] {done}/{} files  {rate:.1} files/s  ETA {eta}",
+            self.total
+        );
+        let _ = stderr.flush();
+    }
+}
+
+/// Erase the progress bar line if one may be on screen. Callers must hold the
+/// stdout lock, the bar is redrawn on the next tick.
+pub fn clear() {
+    if BAR_ACTIVE.load(Ordering::SeqCst) {
+        erase();
+    }
+}
+
+fn erase() {
+    let mut stderr = std::io::stderr().lock();
+    let _ = write!(stderr, "\r\x1b[2K");
+    let _ = stderr.flush();
+}
+
+fn format_duration(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    if secs >= 3600 {
+        format!("{}h{:02}m", secs / 3600, (secs % 3600) / 60)
+    } else if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{secs}s")
+    }
+}
@@ -0,0 +1,925 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context};
+use tree_sitter as ts;
+use tree_sitter_c as ts_c;
+use ts::Query;
+
+use crate::alloc::{self, Allocator, Zeroing};
+use crate::elastic::Sizing;
+use crate::scanner::Fate;
+use crate::{caps, elastic, escape};
+
+// `{allocators}` is replaced with an anchored regex over the allocator table.
+// The declared variable is matched either as a local declaration or as a
+// parameter, the latter for output-parameter constructors assigning through
+// `*pp`. Whether the declarator and the assignment's left hand side refer to
+// the same variable is checked in `AllocQuery::extract`, since that depends
+// on the levels of indirection on either side. Children aren't anchored, so
+// qualifiers, attributes and declspecs around the type or declarator don't
+// stop a match. Assignments directly in the body of a loop are matched too,
+// for tables of struct pointers filled in element by element.
+//
+// Definitions made by a macro, `SYSCALL_DEFINE2(name, int, fd, ...) { ... }`,
+// don't parse as one: tree-sitter takes the macro for a call missing its `;`
+// and the body for a block of its own, which is then the `@function.def`.
+const ALLOC_QUERY: &str = "
+    (
+        function_definition
+        declarator: (_) @function.decl
+        body: (
+            compound_statement
+            (declaration {declaration})
+            {assignment}
+        )
+    ) @function.def
+
+    (
+        function_definition
+        declarator: (_) @function.decl
+        body: (
+            compound_statement
+            (declaration {declaration})
+            [
+                (for_statement body: [{assignment} (compound_statement {assignment})])
+                (while_statement body: [{assignment} (compound_statement {assignment})])
+            ]
+        )
+    ) @function.def
+
+    (
+        function_definition
+        declarator: [
+            (function_declarator parameters: (
+                parameter_list (parameter_declaration {declaration})
+            ))
+            (pointer_declarator declarator: (function_declarator parameters: (
+                parameter_list (parameter_declaration {declaration})
+            )))
+        ] @function.decl
+        body: (compound_statement {assignment})
+    ) @function.def
+
+    (
+        (expression_statement (call_expression
+            function: (identifier) @function.macro
+            (#match? @function.macro \"^[A-Z][A-Z0-9_]*DEFINE[0-9]*$\")
+        )) @function.decl
+        .
+        (
+            compound_statement
+            (declaration {declaration})
+            {assignment}
+        ) @function.def
+    )
+    ";
+
+const DECLARATION: &str = "
+    type: (
+        struct_specifier name: (
+            type_identifier
+        ) @struct.name
+    )
+    declarator: (_) @declaration.declarator
+    ";
+
+const ASSIGNMENT: &str = "
+    (expression_statement (
+        assignment_expression
+            left: (_) @assignment.lvalue
+            right: (
+                (call_expression
+                    function: (identifier) @assignment.function
+                    (#match? @assignment.function \"{allocators}\")
+                    arguments: (argument_list) @assignment.args
+                ) @assignment.call
+            )
+        )
+    )
+    ";
+
+#[derive(Debug)]
+pub struct QueryMatch<'a> {
+    pub function_definition: ts::Node<'a>,
+    pub struct_name: ts::Node<'a>,
+    pub decl_name: ts::Node<'a>,
+    pub assign_name: ts::Node<'a>,
+    pub assign_call: ts::Node<'a>,
+    pub assign_func: ts::Node<'a>,
+    pub size: ts::Node<'a>,
+    pub count: Option<ts::Node<'a>>,
+    /// None for allocators taking no flags.
+    pub flags: Option<ts::Node<'a>>,
+    pub zeroing: Zeroing,
+    /// The part of the size which isn't fixed at compile time, for elastic
+    /// sites.
+    pub elastic: Option<String>,
+    pub fate: Fate,
+    /// The capabilities checked in the function before the allocation.
+    pub caps: Vec<String>,
+    /// The allocation is of an array of pointers to the struct, like
+    /// `slots = kcalloc(n, sizeof(*slots), ...)` for `struct foo **slots`,
+    /// rather than of the struct itself.
+    pub pointer_array: bool,
+    /// The allocator's note, for entry points like `__kmalloc`.
+    pub note: Option<&'static str>,
+    /// Whether the size refers to the struct.
+    pub sizing: Sizing,
+    /// Every argument of the call as written, see `split_arguments`.
+    pub args: Vec<String>,
+}
+
+impl QueryMatch<'_> {
+    /// The flags argument as written, empty for allocators taking no flags.
+    pub fn flags_text<'c>(&self, content: &'c [u8]) -> anyhow::Result<&'c str> {
+        match self.flags {
+            Some(flags) => Ok(flags.utf8_text(content)?),
+            None => Ok(""),
+        }
+    }
+
+    /// The expression for the number of bytes allocated, as written. For the
+    /// array allocators it is the element count times the element size.
+    pub fn size_expr(&self, content: &[u8]) -> String {
+        let size = String::from_utf8_lossy(&content[self.size.byte_range()]);
+        match self.count {
+            Some(count) => format!(
+                "{} * {size}",
+                String::from_utf8_lossy(&content[count.byte_range()])
+            ),
+            None => size.to_string(),
+        }
+    }
+}
+
+/// The captures every allocation site query must have. `size`, `count` and
+/// `flags` captures are optional, and take the place of the allocator table's
+/// argument positions when present.
+pub const REQUIRED_CAPTURES: &[&str] = &[
+    "function.def",
+    "struct.name",
+    "declaration.declarator",
+    "assignment.lvalue",
+    "assignment.call",
+    "assignment.function",
+    "assignment.args",
+];
+
+/// The allocation site query, along with the indices of its captures which are
+/// looked up by name so that matches don't depend on the order the captures
+/// happen to be returned in.
+pub struct AllocQuery {
+    pub query: Query,
+    allocators: &'static [Allocator],
+    function_def: u32,
+    struct_name: u32,
+    decl_declarator: u32,
+    assign_lvalue: u32,
+    assign_call: u32,
+    assign_func: u32,
+    assign_args: u32,
+    size: Option<u32>,
+    count: Option<u32>,
+    flags: Option<u32>,
+}
+
+impl AllocQuery {
+    /// Build the built-in query matching calls to the allocators in
+    /// `allocators`.
+    pub fn new(allocators: &'static [Allocator]) -> anyhow::Result<Self> {
+        Self::from_source(ALLOC_QUERY, allocators)
+    }
+
+    /// Build a query from its source, e.g. one loaded with `--query`. The
+    /// `{declaration}`, `{assignment}` and `{allocators}` placeholders of the
+    /// built-in query are filled in, so a custom query can reuse its parts.
+    /// Fails naming every required capture the query lacks.
+    pub fn from_source(source: &str, allocators: &'static [Allocator]) -> anyhow::Result<Self> {
+        let query_str = source
+            .replace("{declaration}", DECLARATION)
+            .replace("{assignment}", ASSIGNMENT)
+            .replace("{allocators}", &alloc::name_regex(allocators));
+        let query = Query::new(ts_c::language(), &query_str)?;
+
+        let missing: Vec<String> = REQUIRED_CAPTURES
+            .iter()
+            .filter(|name| query.capture_index_for_name(name).is_none())
+            .map(|name| format!("@{name}"))
+            .collect();
+        if !missing.is_empty() {
+            anyhow::bail!("query is missing the captures {}", missing.join(", "));
+        }
+        let capture = |name: &str| query.capture_index_for_name(name);
+
+        Ok(AllocQuery {
+            function_def: capture("function.def").unwrap(),
+            struct_name: capture("struct.name").unwrap(),
+            decl_declarator: capture("declaration.declarator").unwrap(),
+            assign_lvalue: capture("assignment.lvalue").unwrap(),
+            assign_call: capture("assignment.call").unwrap(),
+            assign_func: capture("assignment.function").unwrap(),
+            assign_args: capture("assignment.args").unwrap(),
+            size: capture("size"),
+            count: capture("count"),
+            flags: capture("flags"),
+            query,
+            allocators,
+        })
+    }
+
+    /// Pull the named captures out of a match of this query. Returns None when
+    /// the assignment isn't to the declared variable, or the call's arguments
+    /// don't fit the allocator's signature.
+    pub fn extract<'tree>(
+        &self,
+        match_: &ts::QueryMatch<'_, 'tree>,
+        content: &[u8],
+    ) -> anyhow::Result<Option<QueryMatch<'tree>>> {
+        let mut function_definition = None;
+        let mut struct_name = None;
+        let mut decl_declarator = None;
+        let mut assign_lvalue = None;
+        let mut assign_call = None;
+        let mut assign_func = None;
+        let mut assign_args = None;
+        let mut size = None;
+        let mut count = None;
+        let mut flags = None;
+
+        for capture in match_.captures {
+            let node = Some(capture.node);
+            match capture.index {
+                i if i == self.function_def => function_definition = node,
+                i if i == self.struct_name => struct_name = node,
+                i if i == self.decl_declarator => decl_declarator = node,
+                i if i == self.assign_lvalue => assign_lvalue = node,
+                i if i == self.assign_call => assign_call = node,
+                i if i == self.assign_func => assign_func = node,
+                i if i == self.assign_args => assign_args = node,
+                i if Some(i) == self.size => size = node,
+                i if Some(i) == self.count => count = node,
+                i if Some(i) == self.flags => flags = node,
+                _ => {}
+            }
+        }
+
+        let missing = |name: &str| anyhow!("query match has no @{name} capture");
+        let assign_func = assign_func.ok_or_else(|| missing("assignment.function"))?;
+        let assign_args = assign_args.ok_or_else(|| missing("assignment.args"))?;
+        let decl_declarator = decl_declarator.ok_or_else(|| missing("declaration.declarator"))?;
+        let assign_lvalue = assign_lvalue.ok_or_else(|| missing("assignment.lvalue"))?;
+
+        // the allocation must be stored in a struct pointer, so the declared
+        // variable has one more level of indirection than the left hand side
+        // dereferences, `p` for `struct foo *p`, `*pp` or `pp[i]` for
+        // `struct foo **pp` and `tbl[i]` for `struct foo *tbl[N]`. With more
+        // levels left, it is stored in a pointer to struct pointers, `slots`
+        // for `struct foo **slots`, and allocates an array of them.
+        let Some((assign_name, assign_levels)) = assigned_identifier(assign_lvalue, content) else {
+            return Ok(None);
+        };
+        let assigned = assign_name.utf8_text(content)?;
+        let Some((decl_name, decl_levels)) = declared_identifiers(decl_declarator)
+            .into_iter()
+            .find(|(name, levels)| {
+                *levels > assign_levels && name.utf8_text(content).ok() == Some(assigned)
+            })
+        else {
+            return Ok(None);
+        };
+        let pointer_array = decl_levels > assign_levels + 1;
+
+        // arguments the query captures itself take precedence, the rest come
+        // from the allocator's signature
+        let (size, count, flags) = match (size, flags) {
+            (Some(size), Some(flags)) => (size, count, Some(flags)),
+            _ => {
+                let Some(allocator) =
+                    alloc::lookup(self.allocators, assign_func.utf8_text(content)?)
+                else {
+                    return Ok(None);
+                };
+                let flags = match (flags, allocator.flags_arg) {
+                    (Some(flags), _) => Some(flags),
+                    (None, Some(idx)) => match argument(assign_args, idx) {
+                        Some(flags) => Some(flags),
+                        None => return Ok(None),
+                    },
+                    (None, None) => None,
+                };
+                let Some(size) = size.or_else(|| argument(assign_args, allocator.size_arg)) else {
+                    return Ok(None);
+                };
+                let count = match (count, allocator.count_arg) {
+                    (Some(count), _) => Some(count),
+                    (None, Some(idx)) => match argument(assign_args, idx) {
+                        Some(count) => Some(count),
+                        None => return Ok(None),
+                    },
+                    (None, None) => None,
+                };
+                (size, count, flags)
+            }
+        };
+
+        let allocator = alloc::lookup(self.allocators, assign_func.utf8_text(content)?);
+        let flags_text = match flags {
+            Some(flags) => flags.utf8_text(content)?,
+            None => "",
+        };
+        let zeroing = Zeroing::classify(allocator, flags_text);
+        let elastic = elastic::component(size, count, content);
+        let struct_name = struct_name.ok_or_else(|| missing("struct.name"))?;
+        let sizing = elastic::sizing(
+            size,
+            count,
+            struct_name.utf8_text(content)?,
+            assigned,
+            content,
+        );
+        let function_definition = function_definition.ok_or_else(|| missing("function.def"))?;
+        let assign_call = assign_call.ok_or_else(|| missing("assignment.call"))?;
+        let fate = escape::fate(
+            function_definition,
+            assign_call,
+            assign_name,
+            assign_levels,
+            content,
+        );
+        let caps = caps::guards(function_definition, assign_call, content);
+        let args = split_arguments(&String::from_utf8_lossy(&content[assign_args.byte_range()]));
+
+        Ok(Some(QueryMatch {
+            function_definition,
+            struct_name,
+            decl_name,
+            assign_name,
+            assign_call,
+            assign_func,
+            size,
+            count,
+            flags,
+            zeroing,
+            elastic,
+            fate,
+            caps,
+            pointer_array,
+            note: allocator.and_then(|allocator| allocator.note),
+            sizing,
+            args,
+        }))
+    }
+}
+
+/// Compile the queries a scan looks for allocation sites with: the one in
+/// `query`, or the built-in one, followed by those in `extra`.
+pub fn load(
+    query: Option<&Path>,
+    extra: &[PathBuf],
+    allocators: &'static [Allocator],
+) -> anyhow::Result<Vec<AllocQuery>> {
+    let from_file = |path: &Path| {
+        std::fs::read_to_string(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|source| AllocQuery::from_source(&source, allocators))
+            .with_context(|| format!("failed to load query {}", path.display()))
+    };
+
+    let mut queries = vec![match query {
+        Some(path) => from_file(path)?,
+        None => AllocQuery::new(allocators)?,
+    }];
+    for path in extra {
+        queries.push(from_file(path)?);
+    }
+    Ok(queries)
+}
+
+/// The nodes of the captures `names` in each match of `query` under `root`,
+/// in the order of `names`. Matches missing any of them are skipped.
+pub(crate) fn captures<'tree, const N: usize>(
+    query: &Query,
+    names: [&str; N],
+    root: ts::Node<'tree>,
+    content: &[u8],
+) -> Vec<[ts::Node<'tree>; N]> {
+    let indices = names.map(|name| {
+        query
+            .capture_index_for_name(name)
+            .unwrap_or_else(|| panic!("the query has no @{name}"))
+    });
+    let mut cursor = ts::QueryCursor::new();
+    cursor
+        .matches(query, root, content)
+        .filter_map(|match_| {
+            let nodes: Vec<ts::Node<'tree>> = indices
+                .iter()
+                .map(|&idx| {
+                    match_
+                        .captures
+                        .iter()
+                        .find(|c| c.index == idx)
+                        .map(|c| c.node)
+                })
+                .collect::<Option<_>>()?;
+            nodes.try_into().ok()
+        })
+        .collect()
+}
+
+/// Get the `idx`th argument of an argument_list node, skipping comments.
+pub(crate) fn argument(args: ts::Node<'_>, idx: usize) -> Option<ts::Node<'_>> {
+    let mut cursor = args.walk();
+    let arg = args
+        .named_children(&mut cursor)
+        .filter(|arg| arg.kind() != "comment")
+        .nth(idx);
+    arg
+}
+
+/// Split the text of an argument list, parentheses included, into the
+/// arguments' texts. Commas only separate arguments outside of any
+/// parentheses, brackets or braces, and outside of string and character
+/// literals, so `min(a, b)` in a macro invocation stays one argument.
+/// Comments are dropped and each argument is trimmed, the rest is verbatim.
+/// Unbalanced closing brackets, as in a list mangled by the preprocessor, are
+/// taken as text rather than ending the list.
+pub fn split_arguments(text: &str) -> Vec<String> {
+    let text = text.trim();
+    let text = match text
+        .strip_prefix('(')
+        .and_then(|text| text.strip_suffix(')'))
+    {
+        Some(inner) => inner,
+        None => text,
+    };
+
+    let mut args = vec![];
+    let mut arg = String::new();
+    let mut depth = 0usize;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' | '\'' => {
+                arg.push(c);
+                while let Some(next) = chars.next() {
+                    arg.push(next);
+                    if next == '\\' {
+                        arg.extend(chars.next());
+                    } else if next == c {
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut last = ' ';
+                for next in chars.by_ref() {
+                    if last == '*' && next == '/' {
+                        break;
+                    }
+                    last = next;
+                }
+                arg.push(' ');
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                while chars.next_if(|&next| next != '\n').is_some() {}
+            }
+            '(' | '[' | '{' => {
+                depth += 1;
+                arg.push(c);
+            }
+            ')' | ']' | '}' => {
+                depth = depth.saturating_sub(1);
+                arg.push(c);
+            }
+            ',' if depth == 0 => args.push(std::mem::take(&mut arg)),
+            _ => arg.push(c),
+        }
+    }
+    args.push(arg);
+
+    let args: Vec<String> = args.iter().map(|arg| arg.trim().to_string()).collect();
+    // an empty list has no arguments rather than one empty one
+    match args[..] {
+        [ref only] if only.is_empty() => vec![],
+        _ => args,
+    }
+}
+
+/// Find the identifiers a declarator may declare, along with the number of
+/// pointer and array levels wrapped around each.
+///
+/// Kernel annotations like `__rcu` or `__ro_after_init` are macros unknown to
+/// tree-sitter, so `struct foo *p __ro_after_init` parses with the annotation
+/// as the declared identifier and `p` inside an ERROR node. Identifiers in
+/// ERROR nodes along the declarator are returned as candidates as well.
+pub(crate) fn declared_identifiers(declarator: ts::Node<'_>) -> Vec<(ts::Node<'_>, usize)> {
+    let mut candidates = vec![];
+    let mut levels = 0;
+    let mut node = declarator;
+    loop {
+        match node.kind() {
+            "identifier" => {
+                candidates.push((node, levels));
+                break;
+            }
+            "pointer_declarator" | "array_declarator" => levels += 1,
+            "init_declarator" | "parenthesized_declarator" | "attributed_declarator" => {}
+            _ => break,
+        }
+        let mut cursor = node.walk();
+        let errors: Vec<ts::Node> = node
+            .children(&mut cursor)
+            .filter(|child| child.is_error())
+            .collect();
+        for error in errors {
+            candidates.extend(error_identifiers(error).into_iter().map(|id| (id, levels)));
+        }
+
+        match node
+            .child_by_field_name("declarator")
+            .or_else(|| node.named_child(0))
+        {
+            Some(inner) => node = inner,
+            None => break,
+        }
+    }
+
+    // an annotation after the declared name can also push the name into an
+    // ERROR following the declarator
+    let mut sibling = declarator.next_sibling();
+    while let Some(error) = sibling.filter(|sibling| sibling.is_error()) {
+        candidates.extend(error_identifiers(error).into_iter().map(|id| (id, levels)));
+        sibling = error.next_sibling();
+    }
+    candidates
+}
+
+/// Identifiers held directly in an ERROR node.
+fn error_identifiers(error: ts::Node<'_>) -> Vec<ts::Node<'_>> {
+    let mut cursor = error.walk();
+    let identifiers = error
+        .named_children(&mut cursor)
+        .filter(|child| child.kind() == "identifier")
+        .collect();
+    identifiers
+}
+
+/// Find the variable an assignment's left hand side stores through, along
+/// with the number of dereferences and subscripts applied to it.
+fn assigned_identifier<'a>(
+    mut lvalue: ts::Node<'a>,
+    content: &[u8],
+) -> Option<(ts::Node<'a>, usize)> {
+    let mut levels = 0;
+    loop {
+        match lvalue.kind() {
+            "identifier" => return Some((lvalue, levels)),
+            "pointer_expression" => {
+                let operator = lvalue.child_by_field_name("operator")?;
+                if operator.utf8_text(content).ok()? != "*" {
+                    return None;
+                }
+                levels += 1;
+            }
+            "subscript_expression" => levels += 1,
+            "parenthesized_expression" => {}
+            _ => return None,
+        }
+        lvalue = lvalue
+            .child_by_field_name("argument")
+            .or_else(|| lvalue.named_child(0))?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SITE: &str = "
+struct foo *make_foo(int n)
+{
+	struct foo *p;
+	p = kmalloc(sizeof(*p), GFP_KERNEL);
+	return p;
+}
+";
+
+    // the built-in query's captures in another order, with captures of its
+    // own mixed in which extract has no use for
+    const REORDERED: &str = "
+    (function_definition
+        body: (compound_statement
+            (declaration
+                type: (struct_specifier name: (type_identifier) @struct.name) @extra.type
+                declarator: (_) @declaration.declarator)
+            (expression_statement
+                (assignment_expression
+                    left: (_) @assignment.lvalue
+                    right: (call_expression
+                        function: (identifier) @assignment.function
+                        (#match? @assignment.function \"{allocators}\")
+                        arguments: (argument_list) @assignment.args) @assignment.call)))
+        @extra.body) @function.def
+    ";
+
+    /// The declared variable, struct, allocator and flags of each site
+    /// `query` finds in `content`.
+    fn sites(query: &AllocQuery, content: &str) -> Vec<[String; 4]> {
+        let mut parser = ts::Parser::new();
+        parser.set_language(ts_c::language()).unwrap();
+        let tree = parser.parse(content, None).unwrap();
+        let content = content.as_bytes();
+        let text = |node: ts::Node<'_>| node.utf8_text(content).unwrap().to_string();
+
+        let mut cursor = ts::QueryCursor::new();
+        let mut sites: Vec<[String; 4]> = cursor
+            .matches(&query.query, tree.root_node(), content)
+            .filter_map(|match_| query.extract(&match_, content).unwrap())
+            .map(|qm| {
+                [
+                    text(qm.decl_name),
+                    text(qm.struct_name),
+                    text(qm.assign_func),
+                    qm.flags_text(content).unwrap().to_string(),
+                ]
+            })
+            .collect();
+        // a site can be matched by more than one of the patterns
+        sites.dedup();
+        sites
+    }
+
+    fn site(var: &str, struct_name: &str, func: &str, flags: &str) -> [String; 4] {
+        [var, struct_name, func, flags].map(str::to_string)
+    }
+
+    #[test]
+    fn builtin_query_extracts_site() {
+        let query = AllocQuery::new(alloc::ALLOCATORS).unwrap();
+        assert_eq!(
+            sites(&query, SITE),
+            [site("p", "foo", "kmalloc", "GFP_KERNEL")]
+        );
+    }
+
+    #[test]
+    fn macro_defined_function_is_searched() {
+        let query = AllocQuery::new(alloc::ALLOCATORS).unwrap();
+        let content = "
+SYSCALL_DEFINE2(foo_create, int, fd, unsigned int, flags)
+{
+	struct foo *p;
+	p = kzalloc(sizeof(*p), GFP_KERNEL);
+	return foo_install(p, fd);
+}
+";
+        assert_eq!(
+            sites(&query, content),
+            [site("p", "foo", "kzalloc", "GFP_KERNEL")]
+        );
+    }
+
+    #[test]
+    fn qualified_and_annotated_declarators() {
+        let query = AllocQuery::new(alloc::ALLOCATORS).unwrap();
+        for declaration in [
+            "struct foo __rcu *p;",
+            "struct foo __percpu *p;",
+            "struct foo * const p;",
+            "const struct foo *p;",
+            "struct foo *p __ro_after_init;",
+            "struct foo *p __maybe_unused;",
+        ] {
+            let content = format!(
+                "
+struct foo *make_foo(void)
+{{
+	{declaration}
+	p = kzalloc(sizeof(*p), GFP_KERNEL);
+	return p;
+}}
+"
+            );
+            assert_eq!(
+                sites(&query, &content),
+                [site("p", "foo", "kzalloc", "GFP_KERNEL")],
+                "{declaration}"
+            );
+        }
+    }
+
+    #[test]
+    fn annotated_functions() {
+        let query = AllocQuery::new(alloc::ALLOCATORS).unwrap();
+        for signature in [
+            "static struct foo *make_foo(struct bar *b) __must_hold(&b->lock)",
+            "static struct foo *make_foo(struct bar *b)\n\t__acquires(&b->lock)",
+            "static __always_inline struct foo *make_foo(struct bar *b)",
+            "struct foo * __init make_foo(struct bar *b)",
+        ] {
+            let content = format!(
+                "
+{signature}
+{{
+	struct foo *p;
+	p = kmalloc(sizeof(*p), GFP_ATOMIC);
+	return p;
+}}
+"
+            );
+            assert_eq!(
+                sites(&query, &content),
+                [site("p", "foo", "kmalloc", "GFP_ATOMIC")],
+                "{signature}"
+            );
+        }
+    }
+
+    #[test]
+    fn size_argument_is_captured_as_written() {
+        let query = AllocQuery::new(alloc::ALLOCATORS).unwrap();
+        let sizes = |call: &str| {
+            let content = format!("void f(size_t n)\n{{\n\tstruct foo *p;\n\tp = {call};\n}}\n");
+            let mut parser = ts::Parser::new();
+            parser.set_language(ts_c::language()).unwrap();
+            let tree = parser.parse(&content, None).unwrap();
+            let content = content.as_bytes();
+            let mut cursor = ts::QueryCursor::new();
+            let mut sizes: Vec<String> = cursor
+                .matches(&query.query, tree.root_node(), content)
+                .filter_map(|match_| query.extract(&match_, content).unwrap())
+                .map(|qm| qm.size_expr(content))
+                .collect();
+            sizes.dedup();
+            sizes
+        };
+        assert_eq!(
+            sizes("kmalloc(sizeof(struct foo), GFP_KERNEL)"),
+            ["sizeof(struct foo)"]
+        );
+        assert_eq!(sizes("kmalloc(sizeof(*p), GFP_KERNEL)"), ["sizeof(*p)"]);
+        assert_eq!(
+            sizes("kzalloc(sizeof(*p) + n, GFP_KERNEL)"),
+            ["sizeof(*p) + n"]
+        );
+        assert_eq!(
+            sizes("kcalloc(n, sizeof(*p), GFP_KERNEL)"),
+            ["n * sizeof(*p)"]
+        );
+    }
+
+    #[test]
+    fn allocator_lookalikes_are_not_sites() {
+        let query = AllocQuery::new(alloc::ALLOCATORS).unwrap();
+        let content = "
+struct foo *sock_foo(struct sock *sk)
+{
+	struct foo *p;
+	p = sock_kmalloc(sk, sizeof(*p), GFP_KERNEL);
+	return p;
+}
+
+struct foo *wrapped_foo(void)
+{
+	struct foo *p;
+	p = my_kmalloc_helper(sizeof(*p), GFP_KERNEL);
+	return p;
+}
+";
+        assert_eq!(sites(&query, content), Vec::<[String; 4]>::new());
+    }
+
+    #[test]
+    fn reordered_and_extra_captures_extract_the_same_site() {
+        let query = AllocQuery::from_source(REORDERED, alloc::ALLOCATORS).unwrap();
+        let builtin = AllocQuery::new(alloc::ALLOCATORS).unwrap();
+        assert_ne!(
+            query.query.capture_index_for_name("function.def"),
+            builtin.query.capture_index_for_name("function.def")
+        );
+        assert_eq!(
+            sites(&query, SITE),
+            [site("p", "foo", "kmalloc", "GFP_KERNEL")]
+        );
+    }
+
+    #[test]
+    fn captured_arguments_replace_the_allocator_table() {
+        // an allocator the table doesn't know, its size and flags are
+        // captured by the query instead
+        let source = REORDERED
+            .replace(
+                "(argument_list) @assignment.args",
+                "(argument_list (_) @size (_) @flags) @assignment.args",
+            )
+            .replace("{allocators}", "^pool_alloc$");
+        let query = AllocQuery::from_source(&source, alloc::ALLOCATORS).unwrap();
+        let content = SITE.replace("kmalloc(", "pool_alloc(");
+        assert_eq!(
+            sites(&query, &content),
+            [site("p", "foo", "pool_alloc", "GFP_KERNEL")]
+        );
+    }
+
+    #[test]
+    fn missing_captures_are_named() {
+        let source = REORDERED.replace("@struct.name", "@name");
+        let err = AllocQuery::from_source(&source, alloc::ALLOCATORS)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "query is missing the captures @struct.name"
+        );
+    }
+
+    #[test]
+    fn split_arguments_at_top_level_commas() {
+        assert_eq!(
+            split_arguments("(sizeof(*p), GFP_KERNEL)"),
+            ["sizeof(*p)", "GFP_KERNEL"]
+        );
+        // nested parentheses, brackets and braces keep their commas
+        assert_eq!(
+            split_arguments("(struct_size(p, items, min(a, b)), tbl[i, j], (struct s){1, 2})"),
+            [
+                "struct_size(p, items, min(a, b))",
+                "tbl[i, j]",
+                "(struct s){1, 2}"
+            ]
+        );
+        // an unbalanced closer is kept as text
+        assert_eq!(split_arguments("(a), b)"), ["a)", "b"]);
+    }
+
+    #[test]
+    fn split_arguments_skips_commas_in_literals() {
+        assert_eq!(
+            split_arguments(r#"("a, b", ',', "q\", x")"#),
+            [r#""a, b""#, "','", r#""q\", x""#]
+        );
+        // an escaped quote doesn't end the literal
+        assert_eq!(split_arguments(r"('\'', n)"), [r"'\''", "n"]);
+    }
+
+    #[test]
+    fn split_arguments_drops_comments() {
+        assert_eq!(
+            split_arguments("(n /* count, not bytes */, GFP_KERNEL)"),
+            ["n", "GFP_KERNEL"]
+        );
+        assert_eq!(
+            split_arguments("(n, // a, b\n GFP_KERNEL)"),
+            ["n", "GFP_KERNEL"]
+        );
+        assert_eq!(split_arguments("(a/*x*/b)"), ["a b"]);
+    }
+
+    #[test]
+    fn split_arguments_trims_whitespace() {
+        assert_eq!(
+            split_arguments("  (\n\tsize ,\n\tGFP_KERNEL\t\n)  "),
+            ["size", "GFP_KERNEL"]
+        );
+        // without the parentheses too
+        assert_eq!(split_arguments(" a ,b "), ["a", "b"]);
+    }
+
+    #[test]
+    fn split_arguments_of_empty_lists() {
+        assert!(split_arguments("()").is_empty());
+        assert!(split_arguments("(  )").is_empty());
+        assert!(split_arguments("").is_empty());
+        // an empty argument in a longer list is kept
+        assert_eq!(split_arguments("(a, , b)"), ["a", "", "b"]);
+        assert_eq!(split_arguments("(a,)"), ["a", ""]);
+    }
+
+    #[test]
+    fn captures_in_the_order_asked_for() {
+        let query = Query::new(
+            ts_c::language(),
+            "(call_expression
+                function: (identifier) @function
+                arguments: (argument_list . (identifier)? @first)) @call",
+        )
+        .unwrap();
+        let content = "void f(void) { g(a); h(1); }";
+        let mut parser = ts::Parser::new();
+        parser.set_language(ts_c::language()).unwrap();
+        let tree = parser.parse(content, None).unwrap();
+        let texts: Vec<[&str; 3]> = captures(
+            &query,
+            ["first", "function", "call"],
+            tree.root_node(),
+            content.as_bytes(),
+        )
+        .iter()
+        .map(|nodes| nodes.map(|node| node.utf8_text(content.as_bytes()).unwrap()))
+        .collect();
+        // h(1) has no @first and is skipped
+        assert_eq!(texts, [["a", "g", "g(a)"]]);
+    }
+}
@@ -0,0 +1,167 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+use lazy_static::lazy_static;
+use serde::Deserialize;
+
+use crate::alloc::Zeroing;
+use crate::gfp;
+use crate::layout::Layout;
+
+// set by `--rank-sites`, matches are held back and printed by score once the
+// scan completes
+static RANK: AtomicBool = AtomicBool::new(false);
+// set by `--rank-weights`, the defaults otherwise
+static WEIGHTS: OnceLock<Weights> = OnceLock::new();
+
+/// How much each property of a site adds to its score, for `--rank-sites`
+/// and the `--emit-focus` weights. Read from the JSON object given with
+/// `--rank-weights`, keys left out keep their default.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Weights {
+    /// The flags allow sleeping, so the allocation can be made from a syscall
+    /// rather than only from interrupt context.
+    pub sleeping: i64,
+    /// The memory isn't zeroed, so stale data survives in the object.
+    pub unzeroed: i64,
+    /// The allocation is in an ioctl handler or a function bound in an ops
+    /// table, so userspace can reach it directly.
+    pub handler: i64,
+    /// The struct has function pointers to overwrite.
+    pub function_pointers: i64,
+    /// The site's size isn't fixed or the struct ends in a flexible array, so
+    /// its allocations can be sized into other caches.
+    pub elastic: i64,
+    /// The flags lack `__GFP_ACCOUNT`, so the object shares the generic cache
+    /// with everything else of its size.
+    pub unaccounted: i64,
+}
+
+impl Default for Weights {
+    fn default() -> Self {
+        Weights {
+            sleeping: 2,
+            unzeroed: 2,
+            handler: 3,
+            function_pointers: 3,
+            elastic: 1,
+            unaccounted: 1,
+        }
+    }
+}
+
+impl Weights {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        serde_json::from_slice(&std::fs::read(path)?)
+            .map_err(|e| anyhow::anyhow!("failed to parse {}: {e}", path.display()))
+    }
+}
+
+/// A site waiting to be printed in score order.
+struct Ranked {
+    score: i64,
+    path: PathBuf,
+    line: usize,
+    text: String,
+}
+
+lazy_static! {
+    static ref RANKED: Mutex<Vec<Ranked>> = Mutex::new(vec![]);
+}
+
+pub fn enable() {
+    RANK.store(true, Ordering::SeqCst);
+}
+
+pub fn enabled() -> bool {
+    RANK.load(Ordering::SeqCst)
+}
+
+pub fn set_weights(weights: Weights) {
+    let _ = WEIGHTS.set(weights);
+}
+
+/// Score a site, returning the score and a line explaining it with the
+/// weight of every property which holds. `reachable` is whether it is in a
+/// handler userspace can call, `elastic` whether its size isn't fixed.
+pub fn score(
+    flags: &str,
+    zeroing: Zeroing,
+    reachable: bool,
+    elastic: bool,
+    layout: &Layout,
+) -> (i64, String) {
+    let weights = WEIGHTS.get().cloned().unwrap_or_default();
+    let bits = gfp::expand(flags);
+    let components = [
+        (
+            "sleeping",
+            weights.sleeping,
+            bits.as_ref()
+                .is_some_and(|bits| bits.contains("__GFP_DIRECT_RECLAIM")),
+        ),
+        (
+            "not zeroed",
+            weights.unzeroed,
+            zeroing == Zeroing::NotZeroed,
+        ),
+        ("handler", weights.handler, reachable),
+        (
+            "function pointers",
+            weights.function_pointers,
+            !layout.function_pointers().is_empty(),
+        ),
+        (
+            "elastic",
+            weights.elastic,
+            elastic || layout.flex_array().is_some(),
+        ),
+        (
+            "unaccounted",
+            weights.unaccounted,
+            bits.as_ref()
+                .is_some_and(|bits| !bits.contains("__GFP_ACCOUNT")),
+        ),
+    ];
+
+    let held: Vec<(&str, i64)> = components
+        .into_iter()
+        .filter(|&(_, _, holds)| holds)
+        .map(|(name, weight, _)| (name, weight))
+        .collect();
+    let score = held.iter().map(|(_, weight)| weight).sum();
+    let breakdown: Vec<String> = held
+        .iter()
+        .map(|(name, weight)| format!("{name} {weight:+}"))
+        .collect();
+    let breakdown = match breakdown.is_empty() {
+        true => "nothing".to_string(),
+        false => breakdown.join(", "),
+    };
+    (score, format!("Score: {score} ({breakdown})"))
+}
+
+/// Hold back a formatted match until `print_ranked`.
+pub fn hold(score: i64, path: &Path, line: usize, text: String) {
+    RANKED.lock().unwrap().push(Ranked {
+        score,
+        path: path.to_path_buf(),
+        line,
+        text,
+    });
+}
+
+/// Print the matches held back by `hold`, highest score first.
+pub fn print_ranked() {
+    let mut ranked = RANKED.lock().unwrap();
+    ranked.sort_by(|a, b| {
+        b.score
+            .cmp(&a.score)
+            .then_with(|| (&a.path, a.line).cmp(&(&b.path, b.line)))
+    });
+    for site in ranked.iter() {
+        print!("{}", site.text);
+    }
+}
@@ -0,0 +1,749 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use dwat::dwarf::OwnedDwarf;
+use lazy_static::lazy_static;
+use memmap2::Mmap;
+use tree_sitter as ts;
+use tree_sitter_c as ts_c;
+
+use crate::alloc::{self, Allocator, Zeroing};
+use crate::blame::{self, Blame};
+use crate::cli::StructFilterArgs;
+use crate::debuginfod;
+use crate::elastic::Sizing;
+use crate::files::{self, FilePolicy};
+use crate::filter::{SiteFilter, SiteFilterOptions};
+use crate::gfp::{self, GfpContext};
+use crate::index::{StructFilter, StructIndex};
+use crate::kimage;
+use crate::layout::{Alignment, Layout};
+use crate::query::{self, AllocQuery, QueryMatch};
+use crate::slab;
+use crate::slabinfo::{SlabActivity, Slabinfo};
+use crate::subsystem;
+use crate::types::{self, DwarfTypes, TypeSource};
+
+lazy_static! {
+    // `EXPORT_SYMBOL(name)` and its _GPL and _NS variants
+    static ref EXPORT_SYMBOL: regex::bytes::Regex =
+        regex::bytes::Regex::new(r"\bEXPORT_SYMBOL\w*\s*\(\s*([A-Za-z_][A-Za-z0-9_]*)").unwrap();
+}
+
+/// Where the type information for a scan comes from.
+pub enum DwarfSource {
+    /// A vmlinux with debug info, or a compressed kernel image to extract one
+    /// from.
+    Path(PathBuf),
+    /// DWARF the caller has already loaded.
+    Loaded(OwnedDwarf),
+    /// Types the caller has already loaded from any source, shared with the
+    /// scanner rather than loaded again.
+    Types(Arc<dyn TypeSource>),
+    /// BTF, an ELF with a `.BTF` section or a bpftool JSON dump of BTF, for
+    /// when there is no vmlinux with debug info.
+    TypesFrom(PathBuf),
+}
+
+/// What a [`Scanner`] scans and which allocation sites it reports. Built with
+/// [`ScanConfig::new`], which selects every struct until size bounds, names
+/// or a regex set on it narrow the selection.
+#[non_exhaustive]
+pub struct ScanConfig {
+    pub dwarf: DwarfSource,
+    /// Source directories or single files to scan.
+    pub roots: Vec<PathBuf>,
+    /// The smallest struct size selected (inclusive).
+    pub min_size: usize,
+    /// The largest struct size selected (inclusive), unlimited when None.
+    pub max_size: Option<usize>,
+    /// Only select these structs, by name. Every struct in the size bounds is
+    /// selected when empty.
+    pub structs: Vec<String>,
+    /// Only select structs whose names match this regex.
+    pub struct_regex: Option<String>,
+    /// Only report sites whose flags argument matches this regex.
+    pub flags: Option<String>,
+    /// Drop sites whose literal flags argument matches this regex.
+    pub flags_exclude: Option<String>,
+    /// Only report sites whose size expression matches this regex.
+    pub size_expr: Option<String>,
+    /// A GFP spec, as taken by `--gfp`, the flags must satisfy.
+    pub gfp: Option<String>,
+    /// Only report sites whose memory starts out zeroed, or not zeroed.
+    /// Sites which can't be classified are dropped when set.
+    pub zeroing: Option<Zeroing>,
+    /// Only report sites whose size isn't fixed, like `sizeof(*p) + len`.
+    /// Structs smaller than `min_size` are selected too, as these sites can
+    /// grow them into the size bounds.
+    pub only_elastic: bool,
+    /// Only report sites whose object isn't freed again in the same
+    /// function.
+    pub only_escaping: bool,
+    /// Only report the sites whose size doesn't refer to the struct, true,
+    /// or drop them, false.
+    pub size_unrelated: Option<bool>,
+    /// Drop sites whose function checks any of these capabilities before
+    /// the allocation, e.g. `CAP_SYS_ADMIN`.
+    pub exclude_caps: Vec<String>,
+    /// Only report sites in functions whose names match any of these
+    /// regexes, e.g. `_ioctl$`.
+    pub functions: Vec<String>,
+    /// Blame the allocation line of each match, for sources in a git work
+    /// tree.
+    pub blame: bool,
+    /// A `/proc/slabinfo` snapshot to look up the activity of each match's
+    /// cache in.
+    pub slabinfo: Option<PathBuf>,
+    /// The allocation functions looked for.
+    pub allocators: &'static [Allocator],
+    /// Look the debug info of a vmlinux without DWARF up by build-id, in the
+    /// debuginfod client cache, from the `DEBUGINFOD_URLS` servers and in
+    /// `/usr/lib/debug/.build-id`.
+    pub debuginfod: bool,
+}
+
+impl ScanConfig {
+    pub fn new(dwarf: DwarfSource, roots: Vec<PathBuf>) -> Self {
+        ScanConfig {
+            dwarf,
+            roots,
+            min_size: 0,
+            max_size: None,
+            structs: vec![],
+            struct_regex: None,
+            flags: None,
+            flags_exclude: None,
+            size_expr: None,
+            gfp: None,
+            zeroing: None,
+            only_elastic: false,
+            only_escaping: false,
+            size_unrelated: None,
+            exclude_caps: vec![],
+            functions: vec![],
+            blame: false,
+            slabinfo: None,
+            allocators: alloc::ALLOCATORS,
+            debuginfod: false,
+        }
+    }
+}
+
+/// An allocation site of one of the selected structs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Match {
+    pub struct_name: String,
+    pub struct_size: usize,
+    /// The kmalloc cache the struct is served from, e.g. `kmalloc-192`.
+    pub cache: String,
+    /// The struct's alignment, and whether it is packed.
+    pub alignment: Alignment,
+    pub path: PathBuf,
+    /// The line of the allocation call, starting at 1.
+    pub line: usize,
+    /// The allocation function called, e.g. `kzalloc`.
+    pub allocator: String,
+    /// The flags argument as written.
+    pub flags: String,
+    /// The allocation context classes of the flags, empty when they can't
+    /// be expanded.
+    pub contexts: Vec<GfpContext>,
+    /// The size argument as written, `COUNT * SIZE` for array allocators.
+    pub size_expr: String,
+    /// Every argument of the call as written, comments dropped.
+    pub args: Vec<String>,
+    /// The function the allocation happens in.
+    pub function: String,
+    /// Whether the allocated memory starts out zeroed.
+    pub zeroing: Zeroing,
+    /// The part of the size which isn't fixed at compile time, e.g. `+ len`
+    /// for `sizeof(*p) + len`, None for a fixed size.
+    pub elastic: Option<String>,
+    /// Whether the size refers to the struct.
+    pub sizing: Sizing,
+    /// The kernel subsystem the file is in, like `net/` or `drivers/gpu/`.
+    pub subsystem: String,
+    /// Whether the function the allocation happens in can be called from
+    /// other files.
+    pub linkage: Linkage,
+    /// Whether the object is freed again in the same function or escapes it.
+    pub fate: Fate,
+    /// The capabilities checked in the function before the allocation, e.g.
+    /// `CAP_NET_ADMIN`, going by a syntactic look at the function.
+    pub caps: Vec<String>,
+    /// The commit which last changed the allocation line, when asked for
+    /// with `ScanConfig::blame` and the file is in a git work tree.
+    pub blame: Option<Blame>,
+    /// The object counts of the match's cache, when `ScanConfig::slabinfo`
+    /// is set and the snapshot has the cache.
+    pub slab: Option<SlabActivity>,
+    /// The allocation is of an array of pointers to the struct, like
+    /// `slots = kcalloc(n, sizeof(*slots), ...)`, not of the struct.
+    pub pointer_array: bool,
+}
+
+/// What becomes of an allocated object in the function allocating it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fate {
+    /// Freed again in the function, like a temporary buffer.
+    FreedInFunction,
+    /// Not freed in the function, and whether it is handed out of it by
+    /// returning it or storing it through a parameter or into a global.
+    Escapes {
+        returned: bool,
+        stored_in_parameter: bool,
+        stored_in_global: bool,
+    },
+}
+
+impl std::fmt::Display for Fate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Fate::Escapes {
+            returned,
+            stored_in_parameter,
+            stored_in_global,
+        } = *self
+        else {
+            return f.write_str("freed-in-function");
+        };
+        let notes: Vec<&str> = [
+            (returned, "returned"),
+            (stored_in_parameter, "stored in a parameter"),
+            (stored_in_global, "stored in a global"),
+        ]
+        .into_iter()
+        .filter(|&(holds, _)| holds)
+        .map(|(_, note)| note)
+        .collect();
+        match notes.is_empty() {
+            true => f.write_str("escapes"),
+            false => write!(f, "escapes: {}", notes.join(", ")),
+        }
+    }
+}
+
+/// Whether a function can be called from outside its file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Linkage {
+    /// Declared `static`, only callable from its own file.
+    Static,
+    /// Callable from the rest of the kernel image, but not exported to
+    /// modules.
+    Global,
+    /// Exported with `EXPORT_SYMBOL` or one of its variants in the same file.
+    Exported,
+}
+
+impl Linkage {
+    /// The linkage of the function `definition` defines, `exports` being the
+    /// symbols exported by its file.
+    #[doc(hidden)]
+    pub fn of(definition: ts::Node<'_>, content: &[u8], exports: &HashSet<String>) -> Self {
+        if function_name(definition, content).is_some_and(|name| exports.contains(&name)) {
+            return Linkage::Exported;
+        }
+        let mut cursor = definition.walk();
+        let is_static = definition
+            .children(&mut cursor)
+            .filter(|child| child.kind() == "storage_class_specifier")
+            .any(|child| child.utf8_text(content) == Ok("static"));
+        match is_static {
+            true => Linkage::Static,
+            false => Linkage::Global,
+        }
+    }
+}
+
+impl std::fmt::Display for Linkage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Linkage::Static => "static",
+            Linkage::Global => "global",
+            Linkage::Exported => "exported",
+        })
+    }
+}
+
+/// The symbols a file exports with `EXPORT_SYMBOL` and its variants.
+#[doc(hidden)]
+pub fn exported_symbols(content: &[u8]) -> HashSet<String> {
+    EXPORT_SYMBOL
+        .captures_iter(content)
+        .map(|caps| String::from_utf8_lossy(&caps[1]).into_owned())
+        .collect()
+}
+
+/// Finds allocation sites of the structs selected by a [`ScanConfig`].
+pub struct Scanner {
+    roots: Vec<PathBuf>,
+    // the directories the roots are, or are in for single files
+    root_dirs: Vec<PathBuf>,
+    structs: HashMap<String, usize>,
+    query: AllocQuery,
+    site_filter: SiteFilter,
+    blame: bool,
+    slabinfo: Option<Slabinfo>,
+    types: Arc<dyn TypeSource>,
+    // worked out from the layout on a struct's first match
+    alignments: Mutex<HashMap<String, Alignment>>,
+}
+
+impl Scanner {
+    /// Load the DWARF if needed, select the structs and compile the filters.
+    pub fn new(config: ScanConfig) -> anyhow::Result<Self> {
+        let types: Arc<dyn TypeSource> = match config.dwarf {
+            DwarfSource::Path(path) => Arc::new(
+                DwarfTypes::new(load_dwarf(&path, config.debuginfod)?)?.with_vmlinux(&path),
+            ),
+            DwarfSource::Loaded(dwarf) => Arc::new(DwarfTypes::new(dwarf)?),
+            DwarfSource::TypesFrom(path) => types::load(&path)?.into(),
+            DwarfSource::Types(types) => types,
+        };
+
+        let filter_args = StructFilterArgs {
+            min_size: Some(config.min_size),
+            max_size: config.max_size,
+            all_structs: true,
+            structs: config.structs,
+            struct_regex: config.struct_regex,
+            exclude_struct: None,
+            neighbors: None,
+        };
+        let index = StructIndex::build(types.as_ref())?;
+        let mut filter = StructFilter::new(&filter_args, &index)?;
+        if config.only_elastic {
+            filter = filter.elastic();
+        }
+        let structs = index
+            .iter()
+            .filter(|(name, entry)| filter.matches(name, entry))
+            .map(|(name, entry)| (name.clone(), entry.byte_size))
+            .collect();
+
+        let root_dirs = config
+            .roots
+            .iter()
+            .map(|root| files::root_dir(root).to_path_buf())
+            .collect();
+        Ok(Scanner {
+            roots: config.roots,
+            root_dirs,
+            structs,
+            query: AllocQuery::new(config.allocators)?,
+            site_filter: SiteFilter::build(&SiteFilterOptions {
+                flags: config.flags.as_deref(),
+                flags_exclude: config.flags_exclude.as_deref(),
+                size_expr: config.size_expr.as_deref(),
+                gfp: config.gfp.as_deref(),
+                zeroing: config.zeroing,
+                only_elastic: config.only_elastic,
+                only_escaping: config.only_escaping,
+                size_unrelated: config.size_unrelated,
+                exclude_caps: &config.exclude_caps,
+                functions: &config.functions,
+                ..SiteFilterOptions::default()
+            })?,
+            blame: config.blame,
+            slabinfo: config
+                .slabinfo
+                .map(|path| Slabinfo::load(&path))
+                .transpose()?,
+            types,
+            alignments: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The selected structs and their sizes, in no particular order.
+    pub fn structs(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.structs
+            .iter()
+            .map(|(name, &size)| (name.as_str(), size))
+    }
+
+    /// Scan every C source and header under the roots, one file at a time.
+    /// Files which can't be read or parsed are yielded as errors, and the
+    /// scan carries on with the next one.
+    pub fn scan(&self) -> anyhow::Result<impl Iterator<Item = anyhow::Result<Match>> + '_> {
+        let policy = FilePolicy {
+            follow_symlinks: false,
+            max_depth: None,
+            include: vec![],
+            exclude: vec![],
+            exclude_dirs: vec![],
+            default_excludes: None,
+        };
+        let files = files::collect(&self.roots, &policy)?;
+        Ok(files.into_iter().flat_map(move |path| {
+            let matches = std::fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|content| self.scan_source(&path, &content));
+            match matches {
+                Ok(matches) => matches.into_iter().map(Ok).collect(),
+                Err(e) => vec![Err(e.context(path.display().to_string()))],
+            }
+        }))
+    }
+
+    fn alignment(&self, struct_name: &str) -> anyhow::Result<Alignment> {
+        let mut alignments = self.alignments.lock().unwrap();
+        if let Some(&alignment) = alignments.get(struct_name) {
+            return Ok(alignment);
+        }
+        let layout = Layout::parse(&self.types.layout(struct_name)?);
+        let alignment = layout.alignment(self.types.alignment(struct_name));
+        alignments.insert(struct_name.to_string(), alignment);
+        Ok(alignment)
+    }
+
+    /// Find the allocation sites in the contents of a single file, `path` is
+    /// only used to label the matches, and to blame them when asked to.
+    pub fn scan_source(&self, path: &Path, content: &[u8]) -> anyhow::Result<Vec<Match>> {
+        let mut matches = vec![];
+        let subsystem = subsystem::label(path, &self.root_dirs);
+        let exports = exported_symbols(content);
+        let mut file_blame = None;
+        find_sites(
+            content,
+            std::slice::from_ref(&self.query),
+            &self.site_filter,
+            None,
+            |qm| {
+                let struct_name = qm.struct_name.utf8_text(content)?;
+                if !self
+                    .site_filter
+                    .matches_function(Some(qm.function_definition), content)
+                {
+                    return Ok(ControlFlow::Continue(()));
+                }
+                if let Some(&struct_size) = self.structs.get(struct_name) {
+                    let line = qm.assign_call.start_position().row + 1;
+                    let blame = match self.blame {
+                        true => file_blame
+                            .get_or_insert_with(|| blame::file(path, content))
+                            .as_ref()
+                            .and_then(|file_blame| file_blame.line(line).cloned()),
+                        false => None,
+                    };
+                    let flags = qm.flags_text(content)?;
+                    let slab = self
+                        .slabinfo
+                        .as_ref()
+                        .filter(|_| !qm.pointer_array)
+                        .and_then(|slabinfo| slabinfo.lookup(struct_name, struct_size, flags));
+                    matches.push(Match {
+                        struct_name: struct_name.to_string(),
+                        struct_size,
+                        cache: slab::cache_name(struct_size),
+                        alignment: self.alignment(struct_name)?,
+                        path: path.to_path_buf(),
+                        line,
+                        allocator: qm.assign_func.utf8_text(content)?.to_string(),
+                        flags: flags.to_string(),
+                        contexts: gfp::contexts(flags).unwrap_or_default(),
+                        size_expr: qm.size_expr(content),
+                        args: qm.args.clone(),
+                        function: function_name(qm.function_definition, content)
+                            .unwrap_or_default(),
+                        zeroing: qm.zeroing,
+                        elastic: qm.elastic.clone(),
+                        sizing: qm.sizing,
+                        subsystem: subsystem.clone(),
+                        linkage: Linkage::of(qm.function_definition, content, &exports),
+                        fate: qm.fate,
+                        caps: qm.caps.clone(),
+                        blame,
+                        slab,
+                        pointer_array: qm.pointer_array,
+                    });
+                }
+                Ok(ControlFlow::Continue(()))
+            },
+        )?;
+        Ok(matches)
+    }
+}
+
+/// Load the DWARF from a vmlinux, or from the vmlinux inside a compressed
+/// kernel image (bzImage, vmlinuz) if it still has its debug info. With
+/// `use_debuginfod`, a vmlinux without it has its debug info looked up by
+/// build-id instead.
+pub fn load_dwarf(path: &Path, use_debuginfod: bool) -> anyhow::Result<OwnedDwarf> {
+    let file = std::fs::File::open(path)?;
+    let mmap = unsafe { Mmap::map(&file) }?;
+    if kimage::is_elf(&mmap) {
+        if let Some(debuginfo) = debuginfo(path, &mmap, use_debuginfod)? {
+            return load_dwarf(&debuginfo, use_debuginfod);
+        }
+        return Ok(OwnedDwarf::load(&mmap)?);
+    }
+
+    // not an ELF, try it as a compressed kernel image (bzImage, vmlinuz)
+    let Some(extracted) = kimage::extract(&mmap) else {
+        anyhow::bail!(
+            "{} is neither an ELF nor a compressed kernel image containing one",
+            path.display()
+        );
+    };
+    eprintln!(
+        "Extracted a {}-compressed vmlinux ({} bytes) from {} at offset {:#x}",
+        extracted.format,
+        extracted.elf.len(),
+        path.display(),
+        extracted.offset
+    );
+    if let Some(debuginfo) = debuginfo(path, &extracted.elf, use_debuginfod)? {
+        return load_dwarf(&debuginfo, use_debuginfod);
+    }
+    if !kimage::has_debug_info(&extracted.elf) {
+        anyhow::bail!(
+            "the vmlinux extracted from {} has no DWARF debug info (.debug_info), \
+             compressed kernel images are built from a stripped vmlinux. Pass the \
+             vmlinux from the kernel build, or the distribution's debug vmlinux \
+             (e.g. /usr/lib/debug/boot/vmlinux-<version>) instead",
+            path.display()
+        );
+    }
+    Ok(OwnedDwarf::load(&extracted.elf)?)
+}
+
+/// The separate debug info to load instead of a vmlinux without DWARF, when
+/// asked to with `use_debuginfod`.
+fn debuginfo(path: &Path, elf: &[u8], use_debuginfod: bool) -> anyhow::Result<Option<PathBuf>> {
+    if !use_debuginfod || kimage::has_debug_info(elf) {
+        return Ok(None);
+    }
+    let Some(build_id) = debuginfod::build_id(elf) else {
+        anyhow::bail!(
+            "{} has no DWARF debug info, and no build-id to look it up by",
+            path.display()
+        );
+    };
+    debuginfod::find(path, &build_id).map(Some)
+}
+
+/// Returned when scanning a file exceeds its timeout, carries the time spent
+/// on the file before it was abandoned.
+#[derive(Debug)]
+pub struct FileTimedOut(pub Duration);
+
+impl std::fmt::Display for FileTimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "file timed out after {:.1}s", self.0.as_secs_f64())
+    }
+}
+
+impl std::error::Error for FileTimedOut {}
+
+/// Parse `content` and call `site` with every match of `queries` passing
+/// `site_filter`, until it breaks. A site matched by several of the queries
+/// is only passed on once. Shared by the library and the binary, which
+/// layers its own reporting on top. Returns the parsed tree, for looking for
+/// more than allocation sites without parsing the file again.
+#[doc(hidden)]
+pub fn find_sites<F>(
+    content: &[u8],
+    queries: &[AllocQuery],
+    site_filter: &SiteFilter,
+    timeout: Option<Duration>,
+    mut site: F,
+) -> anyhow::Result<ts::Tree>
+where
+    F: FnMut(&QueryMatch) -> anyhow::Result<ControlFlow<()>>,
+{
+    let start = Instant::now();
+    let mut parser = ts::Parser::new();
+
+    parser
+        .set_language(ts_c::language())
+        .expect("Error loading C grammar");
+
+    if let Some(timeout) = timeout {
+        parser.set_timeout_micros(timeout.as_micros() as u64);
+    }
+
+    // the parser only gives up without a tree when the timeout expired
+    let parsed = match parser.parse(content, None) {
+        Some(parsed) => parsed,
+        None if timeout.is_some() => return Err(FileTimedOut(start.elapsed()).into()),
+        None => panic!("Parser returned no tree"),
+    };
+
+    let mut seen = HashSet::new();
+    let mut query_cursor = ts::QueryCursor::new();
+    'queries: for query in queries {
+        for match_ in query_cursor.matches(&query.query, parsed.root_node(), content) {
+            if let Some(timeout) = timeout {
+                if start.elapsed() > timeout {
+                    return Err(FileTimedOut(start.elapsed()).into());
+                }
+            }
+
+            let Some(qm) = query.extract(&match_, content)? else {
+                continue;
+            };
+            if !site_filter.matches(&qm, content) {
+                continue;
+            }
+            if queries.len() > 1
+                && !seen.insert((qm.assign_call.start_byte(), qm.struct_name.start_byte()))
+            {
+                continue;
+            }
+            if site(&qm)?.is_break() {
+                break 'queries;
+            }
+        }
+    }
+    Ok(parsed)
+}
+
+/// The function definition a node is inside of.
+pub(crate) fn enclosing_function(node: ts::Node<'_>) -> Option<ts::Node<'_>> {
+    let mut parent = node.parent();
+    while let Some(node) = parent.filter(|node| node.kind() != "function_definition") {
+        parent = node.parent();
+    }
+    parent
+}
+
+/// The name of the function a function definition node defines.
+pub fn function_name(definition: ts::Node<'_>, content: &[u8]) -> Option<String> {
+    let node = function_identifier(definition, content)?;
+    Some(node.utf8_text(content).ok()?.to_string())
+}
+
+/// The identifier naming the function a function definition node defines.
+/// The declarator is followed through pointers, parentheses and attributes,
+/// so `void (*get_handler(int x))(int)` is named `get_handler`.
+///
+/// An attribute macro between the return type and the name, as in
+/// `static int noinline_for_stack foo(void)`, makes tree-sitter take the name
+/// for the type and the parameter list for a parenthesized declarator, the
+/// name is then the type. An `__attribute__((...))` between the `*` of a
+/// pointer return and the name is taken for the name, with the real name and
+/// its parameters left as a call after the parameter list, the name is then
+/// the call's. For the body of a definition made by a macro,
+/// `SYSCALL_DEFINE1(foo, int, fd) { ... }`, it is the macro's first argument.
+pub(crate) fn function_identifier<'tree>(
+    definition: ts::Node<'tree>,
+    content: &[u8],
+) -> Option<ts::Node<'tree>> {
+    if definition.kind() == "compound_statement" {
+        let call = definition
+            .prev_named_sibling()
+            .filter(|stmt| stmt.kind() == "expression_statement")?
+            .named_child(0)
+            .filter(|call| call.kind() == "call_expression")?;
+        return query::argument(call.child_by_field_name("arguments")?, 0)
+            .filter(|name| name.kind() == "identifier");
+    }
+    let declarator = definition.child_by_field_name("declarator")?;
+    let mut node = declarator;
+    let mut parameters = false;
+    while node.kind() != "identifier" {
+        if node.kind() == "function_declarator" {
+            parameters = true;
+            let attribute = node
+                .child_by_field_name("declarator")
+                .is_some_and(|name| name.utf8_text(content).ok() == Some("__attribute__"));
+            if attribute {
+                let mut cursor = node.walk();
+                let call = node
+                    .named_children(&mut cursor)
+                    .find(|child| child.kind() == "call_expression");
+                return call
+                    .and_then(|call| call.child_by_field_name("function"))
+                    .filter(|function| function.kind() == "identifier");
+            }
+        }
+        node = match node.kind() {
+            "parenthesized_declarator" | "attributed_declarator" => node.named_child(0)?,
+            _ => node.child_by_field_name("declarator")?,
+        };
+    }
+    if parameters {
+        return Some(node);
+    }
+    definition
+        .child_by_field_name("type")
+        .filter(|ty| ty.kind() == "type_identifier")
+        .filter(|_| declarator.kind() == "parenthesized_declarator")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The name `function_name` gives the function defined by `signature`.
+    fn name(signature: &str) -> Option<String> {
+        let content = format!("{signature}\n{{\n\treturn 0;\n}}\n");
+        let mut parser = ts::Parser::new();
+        parser.set_language(ts_c::language()).unwrap();
+        let tree = parser.parse(&content, None).unwrap();
+        let root = tree.root_node();
+        let mut cursor = root.walk();
+        let definition = root
+            .named_children(&mut cursor)
+            .find(|node| matches!(node.kind(), "function_definition" | "compound_statement"))
+            .unwrap();
+        function_name(definition, content.as_bytes())
+    }
+
+    #[test]
+    fn function_names_across_declarators() {
+        for (signature, expected) in [
+            (
+                "int foo_ioctl(struct file *f, unsigned int cmd)",
+                "foo_ioctl",
+            ),
+            ("static struct foo *foo_alloc(gfp_t gfp)", "foo_alloc"),
+            ("struct foo **foo_table(void)", "foo_table"),
+            ("static struct foo *(foo_create)(int n)", "foo_create"),
+            ("void (*get_handler(int x))(int)", "get_handler"),
+            (
+                "static noinline_for_stack int foo_stack(int n)",
+                "foo_stack",
+            ),
+            (
+                "static __always_inline void *foo_inline(void)",
+                "foo_inline",
+            ),
+            (
+                "static __attribute__((noinline)) struct foo *foo_noinline(int n)",
+                "foo_noinline",
+            ),
+            (
+                "struct foo *foo_cold(int n) __attribute__((cold))",
+                "foo_cold",
+            ),
+            (
+                "static struct foo *\n__attribute__((noinline))\nfoo_split(int n)",
+                "foo_split",
+            ),
+            (
+                "static struct foo *__attribute__((noinline)) foo_inner(int n)",
+                "foo_inner",
+            ),
+            (
+                "int foo_locked(struct bar *b) __must_hold(&b->lock)",
+                "foo_locked",
+            ),
+            (
+                "int foo_unused(struct bar *b __maybe_unused, int n)",
+                "foo_unused",
+            ),
+            ("int foo_old(n, p)\n\tint n;\n\tchar *p;", "foo_old"),
+            (
+                "SYSCALL_DEFINE3(foo_create, int, fd, unsigned int, flags, size_t, len)",
+                "foo_create",
+            ),
+        ] {
+            assert_eq!(name(signature).as_deref(), Some(expected), "{signature}");
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::output;
+
+// how many skipped paths `--report-skipped` lists, past that they are only
+// counted
+const LIST_LIMIT: usize = 10_000;
+
+/// Why a file produced nothing. Directories skipped as a whole are recorded
+/// once rather than per file.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Reason {
+    ExcludedDir,
+    ExcludedByGlob,
+    NotInCompileCommands,
+    NotBuiltByKconfig,
+    AlreadyScanned,
+    PrefilterNoIdentifiers,
+    ReadError,
+    ParseError,
+    Timeout,
+}
+
+impl Reason {
+    const ALL: [Reason; 9] = [
+        Reason::ExcludedDir,
+        Reason::ExcludedByGlob,
+        Reason::NotInCompileCommands,
+        Reason::NotBuiltByKconfig,
+        Reason::AlreadyScanned,
+        Reason::PrefilterNoIdentifiers,
+        Reason::ReadError,
+        Reason::ParseError,
+        Reason::Timeout,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            Reason::ExcludedDir => "excluded-dir",
+            Reason::ExcludedByGlob => "excluded-by-glob",
+            Reason::NotInCompileCommands => "not-in-compile-commands",
+            Reason::NotBuiltByKconfig => "not-built-by-kconfig",
+            Reason::AlreadyScanned => "already-scanned",
+            Reason::PrefilterNoIdentifiers => "prefilter-no-identifiers",
+            Reason::ReadError => "read-error",
+            Reason::ParseError => "parse-error",
+            Reason::Timeout => "timeout",
+        }
+    }
+}
+
+// counted for every skipped file, so the totals are always available
+static COUNTS: [AtomicUsize; Reason::ALL.len()] =
+    [const { AtomicUsize::new(0) }; Reason::ALL.len()];
+
+// set by `--report-skipped`, the paths are only kept when asked for
+static LISTING: AtomicBool = AtomicBool::new(false);
+static LISTED: Mutex<Vec<(Reason, PathBuf, Option<String>)>> = Mutex::new(vec![]);
+
+/// Keep the skipped files for `print_report` rather than only counting them.
+pub fn enable_listing() {
+    LISTING.store(true, Ordering::SeqCst);
+}
+
+/// Record that `path` was skipped, with an optional detail like the error
+/// reading it.
+pub fn record(reason: Reason, path: &Path, detail: Option<String>) {
+    COUNTS[reason as usize].fetch_add(1, Ordering::Relaxed);
+    if LISTING.load(Ordering::Relaxed) {
+        let mut listed = LISTED.lock().unwrap();
+        if listed.len() < LIST_LIMIT {
+            listed.push((reason, path.to_path_buf(), detail));
+        }
+    }
+}
+
+fn count(reason: Reason) -> usize {
+    COUNTS[reason as usize].load(Ordering::Relaxed)
+}
+
+pub fn total() -> usize {
+    Reason::ALL.iter().map(|&reason| count(reason)).sum()
+}
+
+/// The non-zero counts by reason, e.g. `3 excluded-by-glob, 1 timeout`.
+pub fn summary() -> String {
+    let counts: Vec<String> = Reason::ALL
+        .iter()
+        .filter(|&&reason| count(reason) > 0)
+        .map(|&reason| format!("{} {}", count(reason), reason.label()))
+        .collect();
+    counts.join(", ")
+}
+
+/// Print every skipped file to stderr, grouped by reason.
+pub fn print_report() {
+    let total = total();
+    if total == 0 {
+        eprintln!("No files were skipped");
+        return;
+    }
+    eprintln!("Skipped {total} path(s): {}", summary());
+
+    let mut listed = std::mem::take(&mut *LISTED.lock().unwrap());
+    listed.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+    for reason in Reason::ALL {
+        let mut paths = listed.iter().filter(|(r, ..)| *r == reason).peekable();
+        if paths.peek().is_none() {
+            continue;
+        }
+        eprintln!("  {} ({}):", reason.label(), count(reason));
+        for (_, path, detail) in paths {
+            match detail {
+                Some(detail) => eprintln!("    {} ({detail})", output::display_path(path)),
+                None => eprintln!("    {}", output::display_path(path)),
+            }
+        }
+    }
+    if total > listed.len() {
+        eprintln!(
+            "  ... {} more not listed, only the first {LIST_LIMIT} are kept",
+            total - listed.len()
+        );
+    }
+}
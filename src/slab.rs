@@ -0,0 +1,46 @@
+use std::ops::RangeInclusive;
+
+/// Sizes of the generic kmalloc caches, as on x86_64 with SLUB.
+const KMALLOC_SIZES: [usize; 13] = [
+    8, 16, 32, 64, 96, 128, 192, 256, 512, 1024, 2048, 4096, 8192,
+];
+
+/// The size of the kmalloc cache an object of `size` bytes is served from, or
+/// None if it is too large for the caches and comes from the page allocator.
+pub fn kmalloc_bucket(size: usize) -> Option<usize> {
+    KMALLOC_SIZES.iter().copied().find(|&bucket| size <= bucket)
+}
+
+/// The object sizes served from the same kmalloc cache as an object of
+/// `size` bytes, None if it comes from the page allocator.
+pub fn bucket_range(size: usize) -> Option<RangeInclusive<usize>> {
+    let bucket = kmalloc_bucket(size)?;
+    let smaller = KMALLOC_SIZES
+        .iter()
+        .copied()
+        .take_while(|&smaller| smaller < bucket)
+        .last()
+        .unwrap_or(0);
+    Some(smaller + 1..=bucket)
+}
+
+/// The name of the kmalloc cache an object of `size` bytes is served from,
+/// following the kernel's naming, e.g. `kmalloc-192` or `kmalloc-1k`.
+pub fn cache_name(size: usize) -> String {
+    match kmalloc_bucket(size) {
+        Some(bucket) if bucket >= 1024 => format!("kmalloc-{}k", bucket / 1024),
+        Some(bucket) => format!("kmalloc-{bucket}"),
+        None => "page allocator".to_string(),
+    }
+}
+
+/// The name of the kmalloc cache a `__GFP_ACCOUNT` allocation of `size` bytes
+/// is served from, the memcg accounted counterpart of `cache_name`, e.g.
+/// `kmalloc-cg-192`.
+pub fn accounted_cache_name(size: usize) -> String {
+    match kmalloc_bucket(size) {
+        Some(bucket) if bucket >= 1024 => format!("kmalloc-cg-{}k", bucket / 1024),
+        Some(bucket) => format!("kmalloc-cg-{bucket}"),
+        None => "page allocator".to_string(),
+    }
+}
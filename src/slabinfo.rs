@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+use lazy_static::lazy_static;
+
+use crate::gfp;
+use crate::slab;
+
+// set by `--slabinfo`
+static SLABINFO: OnceLock<Slabinfo> = OnceLock::new();
+// set by `--sort slab-activity`, matches are held back and printed by how
+// many objects their cache has once the scan completes
+static SORT: AtomicBool = AtomicBool::new(false);
+
+/// The object counts of the caches in a `/proc/slabinfo` snapshot.
+#[derive(Debug, Clone, Default)]
+pub struct Slabinfo {
+    /// active_objs and num_objs by cache name.
+    caches: HashMap<String, (usize, usize)>,
+}
+
+/// How busy the cache a site's objects come from was in the snapshot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlabActivity {
+    /// The cache looked up, e.g. `kmalloc-192`, or the struct's own cache.
+    pub cache: String,
+    pub active_objs: usize,
+    pub num_objs: usize,
+    /// The site's flags are accounted but the snapshot has no `kmalloc-cg-*`
+    /// cache of its size, as on kernels merging them into the plain ones or
+    /// predating them, so these are the plain cache's counts.
+    pub merged: bool,
+}
+
+impl fmt::Display for SlabActivity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}/{} objects active",
+            self.cache, self.active_objs, self.num_objs
+        )?;
+        if self.merged {
+            write!(f, " (no cg cache in the snapshot, the plain one's counts)")?;
+        }
+        Ok(())
+    }
+}
+
+/// A site waiting to be printed by the activity of its cache.
+struct Held {
+    active_objs: Option<usize>,
+    path: PathBuf,
+    line: usize,
+    text: String,
+}
+
+lazy_static! {
+    static ref HELD: Mutex<Vec<Held>> = Mutex::new(vec![]);
+}
+
+impl Slabinfo {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        let slabinfo = Slabinfo::parse(&text);
+        if slabinfo.caches.is_empty() {
+            anyhow::bail!("{} has no slabinfo cache lines", path.display());
+        }
+        Ok(slabinfo)
+    }
+
+    /// Parse `/proc/slabinfo`. SLUB and SLAB both start a cache's line with
+    /// its name, active_objs and num_objs, whatever columns follow, so lines
+    /// which don't, like the version and `#` headers, are skipped.
+    pub fn parse(text: &str) -> Self {
+        let mut caches = HashMap::new();
+        for line in text.lines() {
+            if line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let (Some(name), Some(active), Some(num)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            if let (Ok(active), Ok(num)) = (active.parse(), num.parse()) {
+                caches.insert(name.to_string(), (active, num));
+            }
+        }
+        Slabinfo { caches }
+    }
+
+    /// The activity of the cache an allocation of `struct_name` with `flags`
+    /// comes from: the kmalloc cache of its size, accounted or not by the
+    /// flags, or a cache named after the struct when it is too large for
+    /// kmalloc. None when the snapshot doesn't have the cache.
+    pub fn lookup(&self, struct_name: &str, size: usize, flags: &str) -> Option<SlabActivity> {
+        let activity = |cache: String, merged| {
+            let &(active_objs, num_objs) = self.caches.get(&cache)?;
+            Some(SlabActivity {
+                cache,
+                active_objs,
+                num_objs,
+                merged,
+            })
+        };
+        if slab::kmalloc_bucket(size).is_none() {
+            return activity(struct_name.to_string(), false);
+        }
+        let accounted = gfp::expand(flags).is_some_and(|bits| bits.contains("__GFP_ACCOUNT"));
+        if accounted {
+            let cg = slab::accounted_cache_name(size);
+            if self.caches.contains_key(&cg) {
+                return activity(cg, false);
+            }
+        }
+        activity(slab::cache_name(size), accounted)
+    }
+}
+
+pub fn enable(slabinfo: Slabinfo) {
+    let _ = SLABINFO.set(slabinfo);
+}
+
+pub fn get() -> Option<&'static Slabinfo> {
+    SLABINFO.get()
+}
+
+pub fn enable_sort() {
+    SORT.store(true, Ordering::SeqCst);
+}
+
+pub fn sorting() -> bool {
+    SORT.load(Ordering::SeqCst)
+}
+
+/// Hold back a formatted match until `print_sorted`.
+pub fn hold(activity: Option<&SlabActivity>, path: &Path, line: usize, text: String) {
+    HELD.lock().unwrap().push(Held {
+        active_objs: activity.map(|activity| activity.active_objs),
+        path: path.to_path_buf(),
+        line,
+        text,
+    });
+}
+
+/// Print the matches held back by `hold`, those in the caches with the most
+/// active objects first and those whose cache wasn't in the snapshot last.
+pub fn print_sorted() {
+    let mut held = HELD.lock().unwrap();
+    held.sort_by(|a, b| {
+        b.active_objs
+            .cmp(&a.active_objs)
+            .then_with(|| (&a.path, a.line).cmp(&(&b.path, b.line)))
+    });
+    for site in held.iter() {
+        print!("{}", site.text);
+    }
+}
@@ -0,0 +1,378 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use tree_sitter as ts;
+
+use crate::bulk;
+use crate::gfp::GfpContext;
+use crate::layout::{Alignment, Layout};
+use crate::scanner;
+use crate::slab;
+use crate::usercopy;
+
+// set by `--format sqlite`
+static SQLITE: AtomicBool = AtomicBool::new(false);
+
+/// The schema, one step per version: a database at version N has had the
+/// first N steps applied. New versions are appended, never edited, so
+/// databases written by older releases are brought up to date on open.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE runs (
+        id INTEGER PRIMARY KEY,
+        started INTEGER NOT NULL,
+        finished INTEGER NOT NULL,
+        version TEXT NOT NULL,
+        vmlinux TEXT,
+        types_from TEXT,
+        source TEXT NOT NULL,
+        arguments TEXT NOT NULL
+    );
+    CREATE TABLE structs (
+        id INTEGER PRIMARY KEY,
+        run_id INTEGER NOT NULL REFERENCES runs(id) ON DELETE CASCADE,
+        name TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        bucket TEXT NOT NULL,
+        members INTEGER NOT NULL,
+        pointers INTEGER NOT NULL,
+        function_pointers INTEGER NOT NULL,
+        refcounts INTEGER NOT NULL,
+        flex_array TEXT,
+        UNIQUE (run_id, name)
+    );
+    CREATE TABLE sites (
+        id INTEGER PRIMARY KEY,
+        run_id INTEGER NOT NULL REFERENCES runs(id) ON DELETE CASCADE,
+        struct_id INTEGER NOT NULL REFERENCES structs(id) ON DELETE CASCADE,
+        path TEXT NOT NULL,
+        line INTEGER NOT NULL,
+        function TEXT,
+        allocator TEXT NOT NULL,
+        flags TEXT NOT NULL,
+        annotations TEXT NOT NULL
+    );
+    CREATE INDEX sites_struct ON sites (struct_id);
+    CREATE INDEX sites_run ON sites (run_id);
+",
+    // a JSON array of the flags' context classes, like '["sleeping", "accounted"]'
+    "ALTER TABLE sites ADD COLUMN contexts TEXT NOT NULL DEFAULT '[]';",
+    // a JSON array of the call's arguments as written
+    "ALTER TABLE sites ADD COLUMN args TEXT NOT NULL DEFAULT '[]';",
+    // the struct's alignment and whether it is packed, NULL in older runs
+    "
+    ALTER TABLE structs ADD COLUMN align INTEGER;
+    ALTER TABLE structs ADD COLUMN packed INTEGER;
+",
+    // the copies between user memory and the selected structs, by name as
+    // their structs needn't have sites
+    "
+    CREATE TABLE user_writes (
+        id INTEGER PRIMARY KEY,
+        run_id INTEGER NOT NULL REFERENCES runs(id) ON DELETE CASCADE,
+        struct_name TEXT NOT NULL,
+        path TEXT NOT NULL,
+        line INTEGER NOT NULL,
+        function TEXT,
+        call TEXT NOT NULL,
+        field TEXT,
+        access TEXT NOT NULL,
+        length TEXT
+    );
+    CREATE TABLE user_reads (
+        id INTEGER PRIMARY KEY,
+        run_id INTEGER NOT NULL REFERENCES runs(id) ON DELETE CASCADE,
+        struct_name TEXT NOT NULL,
+        path TEXT NOT NULL,
+        line INTEGER NOT NULL,
+        function TEXT,
+        call TEXT NOT NULL,
+        field TEXT,
+        access TEXT NOT NULL,
+        length TEXT
+    );
+    CREATE INDEX user_writes_run ON user_writes (run_id);
+    CREATE INDEX user_reads_run ON user_reads (run_id);
+",
+    // 6: the bulk writes of --track-bulk-writes
+    "
+    CREATE TABLE bulk_writes (
+        id INTEGER PRIMARY KEY,
+        run_id INTEGER NOT NULL REFERENCES runs(id) ON DELETE CASCADE,
+        struct_name TEXT NOT NULL,
+        path TEXT NOT NULL,
+        line INTEGER NOT NULL,
+        function TEXT,
+        op TEXT NOT NULL,
+        call TEXT NOT NULL,
+        field TEXT,
+        size TEXT NOT NULL,
+        bound TEXT NOT NULL,
+        exceeds_by INTEGER
+    );
+    CREATE INDEX bulk_writes_run ON bulk_writes (run_id);
+",
+];
+
+/// What a run was asked to scan, for the `runs` table.
+pub struct Run {
+    /// When the scan started, in seconds since the epoch.
+    pub started: u64,
+    pub vmlinux: Option<PathBuf>,
+    pub types_from: Option<PathBuf>,
+    pub source: Vec<PathBuf>,
+    /// The command line, space separated.
+    pub arguments: String,
+}
+
+/// An allocation site, for the `sites` table.
+pub struct Site {
+    pub struct_name: String,
+    /// The path as it would have been printed.
+    pub path: String,
+    /// The line of the allocation call.
+    pub line: usize,
+    pub allocator: String,
+    pub flags: String,
+    /// The context classes of the flags.
+    pub contexts: Vec<GfpContext>,
+    /// The call's arguments.
+    pub args: Vec<String>,
+    /// The notes of its status line, comma separated.
+    pub annotations: String,
+}
+
+/// A struct with sites, summarized from its layout for the `structs` table.
+struct Struct {
+    size: usize,
+    members: usize,
+    pointers: usize,
+    function_pointers: usize,
+    refcounts: usize,
+    flex_array: Option<String>,
+    alignment: Alignment,
+}
+
+lazy_static! {
+    static ref STRUCTS: Mutex<BTreeMap<String, Struct>> = Mutex::new(BTreeMap::new());
+    // along with the name of the function they are in
+    static ref SITES: Mutex<Vec<(Site, Option<String>)>> = Mutex::new(vec![]);
+}
+
+pub fn enable() {
+    SQLITE.store(true, Ordering::SeqCst);
+}
+
+pub fn enabled() -> bool {
+    SQLITE.load(Ordering::SeqCst)
+}
+
+/// Buffer a site in the function `definition` until `write`. Its struct's
+/// layout is summarized the first time one of its sites is recorded.
+pub fn record(
+    site: Site,
+    definition: ts::Node<'_>,
+    content: &[u8],
+    struct_size: usize,
+    struct_layout: &str,
+    alignment: Alignment,
+) {
+    STRUCTS
+        .lock()
+        .unwrap()
+        .entry(site.struct_name.clone())
+        .or_insert_with(|| {
+            let layout = Layout::parse(struct_layout);
+            Struct {
+                size: struct_size,
+                members: layout.top_level().count(),
+                pointers: layout
+                    .members
+                    .iter()
+                    .filter(|member| member.is_pointer() && !member.is_function_pointer())
+                    .count(),
+                function_pointers: layout.function_pointers().len(),
+                refcounts: layout.refcounts().len(),
+                flex_array: layout.flex_array().map(|member| member.name.clone()),
+                alignment,
+            }
+        });
+    let function = scanner::function_name(definition, content);
+    SITES.lock().unwrap().push((site, function));
+}
+
+/// Open the database at `path`, creating it or migrating it to the current
+/// schema.
+pub fn open(path: &Path) -> anyhow::Result<Connection> {
+    let mut db = Connection::open(path)?;
+    db.pragma_update(None, "foreign_keys", true)?;
+    let tx = db.transaction()?;
+    tx.execute_batch("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)")?;
+    let version: Option<usize> = tx
+        .query_row("SELECT version FROM schema_version", [], |row| row.get(0))
+        .optional()?;
+    let version = version.unwrap_or(0);
+    if version > MIGRATIONS.len() {
+        anyhow::bail!(
+            "{} has schema version {version}, newer than the {} this kheap_sift knows, \
+             it was written by a later release",
+            path.display(),
+            MIGRATIONS.len()
+        );
+    }
+    for migration in &MIGRATIONS[version..] {
+        tx.execute_batch(migration)?;
+    }
+    tx.execute("DELETE FROM schema_version", [])?;
+    tx.execute(
+        "INSERT INTO schema_version (version) VALUES (?1)",
+        [MIGRATIONS.len()],
+    )?;
+    tx.commit()?;
+    Ok(db)
+}
+
+/// Write the recorded structs and sites to the database at `path` as a new
+/// run, in one transaction, along with the user copies of whichever
+/// directions are tracked and the bulk writes if they are. `layout` looks up
+/// a struct's members to bound the bulk writes with. Returns the run's id and
+/// the number of sites.
+pub fn write(
+    path: &Path,
+    run: &Run,
+    layout: impl Fn(&str) -> Option<Layout>,
+) -> anyhow::Result<(i64, usize)> {
+    let mut db = open(path)?;
+    let tx = db.transaction()?;
+    let run_id = insert_run(&tx, run)?;
+
+    let structs = STRUCTS.lock().unwrap();
+    let mut struct_ids = BTreeMap::new();
+    {
+        let mut insert = tx.prepare(
+            "INSERT INTO structs (run_id, name, size, bucket, members, pointers, \
+             function_pointers, refcounts, flex_array, align, packed) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+        )?;
+        for (name, s) in structs.iter() {
+            insert.execute(params![
+                run_id,
+                name,
+                s.size,
+                slab::cache_name(s.size),
+                s.members,
+                s.pointers,
+                s.function_pointers,
+                s.refcounts,
+                s.flex_array,
+                s.alignment.align,
+                s.alignment.packed,
+            ])?;
+            struct_ids.insert(name.as_str(), tx.last_insert_rowid());
+        }
+    }
+
+    let sites = SITES.lock().unwrap();
+    {
+        let mut insert = tx.prepare(
+            "INSERT INTO sites (run_id, struct_id, path, line, function, allocator, flags, \
+             contexts, args, annotations) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        )?;
+        for (site, function) in sites.iter() {
+            let contexts: Vec<String> = site.contexts.iter().map(ToString::to_string).collect();
+            insert.execute(params![
+                run_id,
+                struct_ids[site.struct_name.as_str()],
+                site.path,
+                site.line,
+                function,
+                site.allocator,
+                site.flags,
+                serde_json::to_string(&contexts)?,
+                serde_json::to_string(&site.args)?,
+                site.annotations,
+            ])?;
+        }
+    }
+
+    let mut copies = vec![];
+    if usercopy::writes_enabled() {
+        copies.push(("user_writes", usercopy::write_rows()));
+    }
+    if usercopy::reads_enabled() {
+        copies.push(("user_reads", usercopy::read_rows()));
+    }
+    for (table, rows) in copies {
+        let mut insert = tx.prepare(&format!(
+            "INSERT INTO {table} (run_id, struct_name, path, line, function, call, field, \
+             access, length) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)"
+        ))?;
+        for row in rows {
+            insert.execute(params![
+                run_id,
+                row.struct_name,
+                row.path,
+                row.line,
+                row.function,
+                row.call,
+                row.field,
+                row.access,
+                row.length,
+            ])?;
+        }
+    }
+    if bulk::enabled() {
+        let mut insert = tx.prepare(
+            "INSERT INTO bulk_writes (run_id, struct_name, path, line, function, op, call, \
+             field, size, bound, exceeds_by) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+        )?;
+        for row in bulk::rows(layout) {
+            insert.execute(params![
+                run_id,
+                row.struct_name,
+                row.path,
+                row.line,
+                row.function,
+                row.op,
+                row.call,
+                row.field,
+                row.size,
+                row.bound,
+                row.exceeds_by,
+            ])?;
+        }
+    }
+    tx.commit()?;
+    Ok((run_id, sites.len()))
+}
+
+fn insert_run(tx: &Transaction, run: &Run) -> anyhow::Result<i64> {
+    let finished = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    let source: Vec<String> = run
+        .source
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect();
+    tx.execute(
+        "INSERT INTO runs (started, finished, version, vmlinux, types_from, source, arguments) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            run.started,
+            finished,
+            env!("CARGO_PKG_VERSION"),
+            run.vmlinux.as_ref().map(|path| path.display().to_string()),
+            run.types_from
+                .as_ref()
+                .map(|path| path.display().to_string()),
+            source.join(":"),
+            run.arguments,
+        ],
+    )?;
+    Ok(tx.last_insert_rowid())
+}
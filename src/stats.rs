@@ -0,0 +1,137 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{skipped, subsystem};
+
+/// Counters collected over a scan, printed at the end with `--stats`.
+pub struct Stats {
+    pub files_scanned: AtomicUsize,
+    pub duplicate_files: AtomicUsize,
+    /// Symlinks seen while collecting files without `--follow-symlinks`.
+    pub symlinks_skipped: AtomicUsize,
+    pub sites: AtomicUsize,
+    /// Sites of the selected structs dropped by `--function`.
+    pub function_filtered: AtomicUsize,
+    /// Configured `--threads`, which `workers` must never exceed.
+    pub worker_limit: AtomicUsize,
+    pub workers: AtomicUsize,
+    pub peak_workers: AtomicUsize,
+    /// Configured `--max-buffered-files`, which `buffered_files` must never
+    /// exceed.
+    pub buffer_limit: AtomicUsize,
+    pub buffered_files: AtomicUsize,
+    pub peak_buffered_files: AtomicUsize,
+    pub buffered_bytes: AtomicUsize,
+    pub peak_buffered_bytes: AtomicUsize,
+}
+
+pub static STATS: Stats = Stats {
+    files_scanned: AtomicUsize::new(0),
+    duplicate_files: AtomicUsize::new(0),
+    symlinks_skipped: AtomicUsize::new(0),
+    sites: AtomicUsize::new(0),
+    function_filtered: AtomicUsize::new(0),
+    worker_limit: AtomicUsize::new(usize::MAX),
+    workers: AtomicUsize::new(0),
+    peak_workers: AtomicUsize::new(0),
+    buffer_limit: AtomicUsize::new(usize::MAX),
+    buffered_files: AtomicUsize::new(0),
+    peak_buffered_files: AtomicUsize::new(0),
+    buffered_bytes: AtomicUsize::new(0),
+    peak_buffered_bytes: AtomicUsize::new(0),
+};
+
+pub fn inc(counter: &AtomicUsize) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Marks a file as being processed on a worker thread until dropped.
+pub struct WorkerGuard;
+
+/// Count a worker as running, tracking the peak number running at once. In
+/// debug builds this asserts the worker limit is respected.
+pub fn enter_worker() -> WorkerGuard {
+    let running = STATS.workers.fetch_add(1, Ordering::SeqCst) + 1;
+    STATS.peak_workers.fetch_max(running, Ordering::SeqCst);
+    debug_assert!(
+        running <= STATS.worker_limit.load(Ordering::SeqCst),
+        "{running} workers running, limit is {}",
+        STATS.worker_limit.load(Ordering::SeqCst)
+    );
+    WorkerGuard
+}
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        STATS.workers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Marks a file's contents as held in memory until dropped.
+pub struct BufferGuard {
+    bytes: usize,
+}
+
+/// Count a file buffer of `bytes` as alive, tracking the peak number of them
+/// and of their bytes at once. In debug builds this asserts the buffer limit
+/// is respected.
+pub fn hold_buffer(bytes: usize) -> BufferGuard {
+    let held = STATS.buffered_files.fetch_add(1, Ordering::SeqCst) + 1;
+    STATS.peak_buffered_files.fetch_max(held, Ordering::SeqCst);
+    let held_bytes = STATS.buffered_bytes.fetch_add(bytes, Ordering::SeqCst) + bytes;
+    STATS
+        .peak_buffered_bytes
+        .fetch_max(held_bytes, Ordering::SeqCst);
+    debug_assert!(
+        held <= STATS.buffer_limit.load(Ordering::SeqCst),
+        "{held} file buffers alive, limit is {}",
+        STATS.buffer_limit.load(Ordering::SeqCst)
+    );
+    BufferGuard { bytes }
+}
+
+impl Drop for BufferGuard {
+    fn drop(&mut self) {
+        STATS.buffered_files.fetch_sub(1, Ordering::SeqCst);
+        STATS.buffered_bytes.fetch_sub(self.bytes, Ordering::SeqCst);
+    }
+}
+
+impl Stats {
+    pub fn print(&self, structs: usize) {
+        let get = |counter: &AtomicUsize| counter.load(Ordering::Relaxed);
+        eprintln!("Structs in size range:      {structs}");
+        eprintln!("Files scanned:              {}", get(&self.files_scanned));
+        eprintln!("Duplicate files skipped:    {}", get(&self.duplicate_files));
+        eprintln!(
+            "Symlinks not followed:      {}",
+            get(&self.symlinks_skipped)
+        );
+        match skipped::total() {
+            0 => eprintln!("Paths skipped:              0"),
+            total => eprintln!(
+                "Paths skipped:              {total} ({})",
+                skipped::summary()
+            ),
+        }
+        eprintln!("Allocation sites reported:  {}", get(&self.sites));
+        if get(&self.function_filtered) > 0 {
+            eprintln!(
+                "Dropped by --function:      {}",
+                get(&self.function_filtered)
+            );
+        }
+        if get(&self.sites) > 0 {
+            eprintln!("Sites by subsystem:");
+            subsystem::print_table();
+        }
+        eprintln!("Peak concurrent workers:    {}", get(&self.peak_workers));
+        eprintln!(
+            "Peak buffered files:        {}",
+            get(&self.peak_buffered_files)
+        );
+        eprintln!(
+            "Peak buffered bytes:        {}",
+            get(&self.peak_buffered_bytes)
+        );
+    }
+}
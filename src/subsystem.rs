@@ -0,0 +1,174 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+use lazy_static::lazy_static;
+
+// the top-level directories of a kernel tree, telling an archive's paths
+// which start with the tree apart from ones under a directory holding it
+const KERNEL_DIRS: &[&str] = &[
+    "Documentation",
+    "arch",
+    "block",
+    "certs",
+    "crypto",
+    "drivers",
+    "fs",
+    "include",
+    "init",
+    "io_uring",
+    "ipc",
+    "kernel",
+    "lib",
+    "mm",
+    "net",
+    "rust",
+    "samples",
+    "scripts",
+    "security",
+    "sound",
+    "tools",
+    "usr",
+    "virt",
+];
+
+// directories whose subdirectories are subsystems of their own
+const SPLIT_DIRS: &[&str] = &["arch", "drivers"];
+
+// the source directories paths are labeled relative to
+static ROOTS: OnceLock<Vec<PathBuf>> = OnceLock::new();
+
+// set by `--group-by subsystem`, matches are held back and printed by
+// subsystem once the scan completes
+static GROUPING: AtomicBool = AtomicBool::new(false);
+
+/// The sites reported in a subsystem.
+#[derive(Default)]
+struct Tally {
+    sites: usize,
+    structs: BTreeSet<String>,
+}
+
+lazy_static! {
+    static ref TALLIES: Mutex<BTreeMap<String, Tally>> = Mutex::new(BTreeMap::new());
+    static ref GROUPED: Mutex<BTreeMap<String, Vec<String>>> = Mutex::new(BTreeMap::new());
+}
+
+/// The subsystem a source file belongs to, going by its path relative to the
+/// innermost of `roots` it is in: its top-level directory like `net/`, or the
+/// top two under `drivers/` and `arch/` like `drivers/gpu/`. A path under
+/// none of the roots is one inside an archive, and is taken relative to the
+/// kernel tree in it. Files at the top of a tree are labeled `./`.
+pub fn label(path: &Path, roots: &[PathBuf]) -> String {
+    let dirs: Vec<String> = relative_to(path, roots)
+        .parent()
+        .into_iter()
+        .flat_map(Path::components)
+        .filter_map(|component| match component {
+            Component::Normal(dir) => Some(dir.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect();
+    match dirs.as_slice() {
+        [] => "./".to_string(),
+        [top, sub, ..] if SPLIT_DIRS.contains(&top.as_str()) => format!("{top}/{sub}/"),
+        [top, ..] => format!("{top}/"),
+    }
+}
+
+/// A path relative to the innermost of `roots` it is in, or to the kernel
+/// tree inside an archive for a path under none of them.
+fn relative_to<'a>(path: &'a Path, roots: &[PathBuf]) -> &'a Path {
+    roots
+        .iter()
+        .filter_map(|root| path.strip_prefix(root).ok())
+        .min_by_key(|relative| relative.components().count())
+        .unwrap_or_else(|| {
+            let mut components = path.components();
+            match components.next() {
+                Some(Component::Normal(top))
+                    if !KERNEL_DIRS.iter().any(|dir| OsStr::new(dir) == top) =>
+                {
+                    components.as_path()
+                }
+                _ => path,
+            }
+        })
+}
+
+/// A path in the scan relative to the top of its kernel tree, e.g.
+/// `drivers/foo/bar.c`.
+pub fn relative(path: &Path) -> &Path {
+    relative_to(path, ROOTS.get().map_or(&[], Vec::as_slice))
+}
+
+/// Label the scan's paths relative to these source directories.
+pub fn set_roots(roots: &[PathBuf]) {
+    let _ = ROOTS.set(roots.to_vec());
+}
+
+/// The subsystem of a path in the scan, see `label`.
+pub fn of(path: &Path) -> String {
+    label(path, ROOTS.get().map_or(&[], Vec::as_slice))
+}
+
+/// Count a reported site towards its subsystem.
+pub fn record_site(subsystem: &str, struct_name: &str) {
+    let mut tallies = TALLIES.lock().unwrap();
+    let tally = tallies.entry(subsystem.to_string()).or_default();
+    tally.sites += 1;
+    tally.structs.insert(struct_name.to_string());
+}
+
+pub fn enable_grouping() {
+    GROUPING.store(true, Ordering::SeqCst);
+}
+
+pub fn grouping() -> bool {
+    GROUPING.load(Ordering::SeqCst)
+}
+
+/// Hold back a formatted match until `print_grouped`.
+pub fn group(subsystem: &str, text: String) {
+    GROUPED
+        .lock()
+        .unwrap()
+        .entry(subsystem.to_string())
+        .or_default()
+        .push(text);
+}
+
+/// Print the matches held back by `group`, under a heading per subsystem.
+pub fn print_grouped() {
+    let grouped = GROUPED.lock().unwrap();
+    let tallies = TALLIES.lock().unwrap();
+    for (subsystem, matches) in grouped.iter() {
+        let structs = tallies
+            .get(subsystem)
+            .map_or(0, |tally| tally.structs.len());
+        println!(
+            "######## {subsystem}: {} site(s) across {structs} struct(s) ########\n",
+            matches.len()
+        );
+        for text in matches {
+            print!("{text}");
+        }
+    }
+}
+
+/// Print the sites and structs per subsystem to stderr, busiest first.
+pub fn print_table() {
+    let tallies = TALLIES.lock().unwrap();
+    let mut rows: Vec<(&String, &Tally)> = tallies.iter().collect();
+    rows.sort_by(|a, b| b.1.sites.cmp(&a.1.sites).then(a.0.cmp(b.0)));
+    let width = rows.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    for (subsystem, tally) in rows {
+        eprintln!(
+            "    {subsystem:<width$}  {} site(s) across {} struct(s)",
+            tally.sites,
+            tally.structs.len()
+        );
+    }
+}
@@ -0,0 +1,469 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use gimli::{AttributeValue, EndianSlice, RunTimeEndian};
+use memmap2::Mmap;
+use object::{Object, ObjectSection, ObjectSymbol, SymbolKind};
+use tree_sitter as ts;
+
+use crate::index::{DeclLocation, Definitions};
+use crate::kimage;
+use crate::scanner;
+
+type Reader = EndianSlice<'static, RunTimeEndian>;
+
+// set by `--resolve-symbols`, built once before the scan
+static INDEX: OnceLock<SymbolIndex> = OnceLock::new();
+
+/// A function definition in the vmlinux DWARF.
+struct Function {
+    /// The file it is defined in, an index into `SymbolIndex::files`.
+    file: usize,
+    /// The address range of its out-of-line copy, None when it only exists
+    /// inlined into its callers.
+    low_pc: Option<u64>,
+    high_pc: Option<u64>,
+    /// The compilation unit it is in, an index into `SymbolIndex::units`.
+    unit: usize,
+}
+
+/// The functions in the vmlinux DWARF by name, along with the ELF function
+/// symbols by address for the names GCC gives its clones, like
+/// `foo.isra.0`.
+struct SymbolIndex {
+    dwarf: gimli::Dwarf<Reader>,
+    units: Vec<gimli::UnitHeader<Reader>>,
+    files: Vec<PathBuf>,
+    functions: HashMap<String, Vec<Function>>,
+    symbols: HashMap<u64, Vec<String>>,
+}
+
+/// Where a site's function is in the running kernel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    /// The function has a symbol of its own. `offset` is that of the first
+    /// instruction for the site's line, when the line table has it.
+    Found {
+        symbol: String,
+        low_pc: u64,
+        offset: Option<u64>,
+    },
+    /// The function is in the DWARF, but only inlined into its callers.
+    Inlined(String),
+    /// The function isn't in the DWARF, it was discarded or not built.
+    Missing(String),
+}
+
+impl fmt::Display for Resolution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Resolution::Found {
+                symbol,
+                low_pc,
+                offset: Some(offset),
+            } => write!(f, "{symbol}+{offset:#x} (low_pc {low_pc:#x})"),
+            Resolution::Found {
+                symbol,
+                low_pc,
+                offset: None,
+            } => write!(
+                f,
+                "{symbol} (low_pc {low_pc:#x}), the line isn't in the line table"
+            ),
+            Resolution::Inlined(function) => write!(
+                f,
+                "warning: {function} was inlined into its callers, it has no symbol"
+            ),
+            Resolution::Missing(function) => write!(
+                f,
+                "warning: {function} isn't in the vmlinux, it was discarded or not built"
+            ),
+        }
+    }
+}
+
+/// Index the functions in the vmlinux for `resolve`. The vmlinux is mapped
+/// for the rest of the run.
+pub fn load(path: &Path) -> anyhow::Result<()> {
+    let index = SymbolIndex::build(map_elf(path, "--resolve-symbols")?)?;
+    let _ = INDEX.set(index);
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    INDEX.get().is_some()
+}
+
+/// Look up the function a site at `line` of `path` is in, by its name and
+/// the file the DWARF says it is declared in.
+pub fn resolve(
+    definition: ts::Node<'_>,
+    content: &[u8],
+    path: &Path,
+    line: usize,
+) -> Option<Resolution> {
+    let index = INDEX.get()?;
+    let function = scanner::function_name(definition, content)?;
+    Some(index.resolve(function, path, line))
+}
+
+/// The files and lines the vmlinux DWARF says the named structs are defined
+/// at, for those it has a definition of.
+pub fn struct_definitions(
+    vmlinux: &Path,
+    names: &HashSet<String>,
+) -> anyhow::Result<HashMap<String, (PathBuf, u64)>> {
+    let object = object::File::parse(map_elf(vmlinux, "--emit-tags")?)?;
+    let dwarf = load_dwarf(&object)?;
+    let mut definitions = HashMap::new();
+    let mut headers = dwarf.units();
+    while let Some(header) = headers.next()? {
+        let unit = dwarf.unit(header)?;
+        let mut entries = unit.entries();
+        while let Some((_, entry)) = entries.next_dfs()? {
+            if entry.tag() != gimli::DW_TAG_structure_type
+                || entry.attr(gimli::DW_AT_declaration)?.is_some()
+            {
+                continue;
+            }
+            let Some(name) = entry.attr_value(gimli::DW_AT_name)? else {
+                continue;
+            };
+            let name = dwarf.attr_string(&unit, name)?.to_string_lossy();
+            if !names.contains(name.as_ref()) || definitions.contains_key(name.as_ref()) {
+                continue;
+            }
+            let (Some(AttributeValue::FileIndex(file)), Some(line)) = (
+                entry.attr_value(gimli::DW_AT_decl_file)?,
+                entry
+                    .attr_value(gimli::DW_AT_decl_line)?
+                    .and_then(|line| line.udata_value()),
+            ) else {
+                continue;
+            };
+            let file = unit.line_program.as_ref().and_then(|program| {
+                let header = program.header();
+                file_path(&dwarf, &unit, header, header.file(file)?)
+            });
+            if let Some(file) = file {
+                definitions.insert(name.into_owned(), (file, line));
+            }
+        }
+        if definitions.len() == names.len() {
+            break;
+        }
+    }
+    Ok(definitions)
+}
+
+/// The alignments the vmlinux DWARF records for structs, by name, for those
+/// declared with one, like `__aligned(64)` or `__packed`.
+pub fn struct_alignments(vmlinux: &Path) -> anyhow::Result<HashMap<String, usize>> {
+    let object = object::File::parse(map_elf(vmlinux, "DW_AT_alignment")?)?;
+    let dwarf = load_dwarf(&object)?;
+    let mut alignments = HashMap::new();
+    let mut headers = dwarf.units();
+    while let Some(header) = headers.next()? {
+        let unit = dwarf.unit(header)?;
+        let mut entries = unit.entries();
+        while let Some((_, entry)) = entries.next_dfs()? {
+            if entry.tag() != gimli::DW_TAG_structure_type {
+                continue;
+            }
+            let (Some(name), Some(alignment)) = (
+                entry.attr_value(gimli::DW_AT_name)?,
+                entry
+                    .attr_value(gimli::DW_AT_alignment)?
+                    .and_then(|alignment| alignment.udata_value()),
+            ) else {
+                continue;
+            };
+            let name = dwarf.attr_string(&unit, name)?.to_string_lossy();
+            alignments
+                .entry(name.into_owned())
+                .or_insert(alignment as usize);
+        }
+    }
+    Ok(alignments)
+}
+
+/// The struct definitions of every compilation unit in the vmlinux DWARF,
+/// deduplicated as they are walked by name, byte size and declaration.
+pub fn struct_definitions_by_unit(vmlinux: &Path) -> anyhow::Result<Definitions> {
+    let object = object::File::parse(map_elf(vmlinux, "the per-unit struct definitions")?)?;
+    let dwarf = load_dwarf(&object)?;
+    let mut definitions = Definitions::default();
+    let mut headers = dwarf.units();
+    while let Some(header) = headers.next()? {
+        let unit = dwarf.unit(header)?;
+        let index = definitions.units.len() as u32;
+        definitions.units.push(
+            unit.name
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        );
+        // a unit's structs are declared in a handful of files, each path is
+        // only built once
+        let mut files: HashMap<u64, Option<PathBuf>> = HashMap::new();
+        let mut entries = unit.entries();
+        while let Some((_, entry)) = entries.next_dfs()? {
+            if entry.tag() != gimli::DW_TAG_structure_type
+                || entry.attr(gimli::DW_AT_declaration)?.is_some()
+            {
+                continue;
+            }
+            let (Some(name), Some(byte_size)) = (
+                entry.attr_value(gimli::DW_AT_name)?,
+                entry
+                    .attr_value(gimli::DW_AT_byte_size)?
+                    .and_then(|size| size.udata_value()),
+            ) else {
+                continue;
+            };
+            let name = dwarf.attr_string(&unit, name)?.to_string_lossy();
+            let decl = match (
+                entry.attr_value(gimli::DW_AT_decl_file)?,
+                entry
+                    .attr_value(gimli::DW_AT_decl_line)?
+                    .and_then(|line| line.udata_value()),
+            ) {
+                (Some(AttributeValue::FileIndex(file)), Some(line)) => files
+                    .entry(file)
+                    .or_insert_with(|| {
+                        let program = unit.line_program.as_ref()?;
+                        let header = program.header();
+                        file_path(&dwarf, &unit, header, header.file(file)?)
+                    })
+                    .clone()
+                    .map(|file| DeclLocation { file, line }),
+                _ => None,
+            };
+            definitions.record(&name, byte_size as usize, decl, index);
+        }
+    }
+    Ok(definitions)
+}
+
+/// Map a vmlinux for the rest of the run, failing for anything but an ELF as
+/// `option` needs the DWARF in one.
+fn map_elf(path: &Path, option: &str) -> anyhow::Result<&'static [u8]> {
+    let file = std::fs::File::open(path)?;
+    let mmap: &'static Mmap = Box::leak(Box::new(unsafe { Mmap::map(&file) }?));
+    if !kimage::is_elf(mmap) {
+        anyhow::bail!(
+            "{option} needs the vmlinux ELF, {} isn't one",
+            path.display()
+        );
+    }
+    Ok(mmap)
+}
+
+/// Load the DWARF sections of an ELF, decompressing any compressed ones into
+/// memory kept for the run.
+fn load_dwarf(object: &object::File<'static>) -> anyhow::Result<gimli::Dwarf<Reader>> {
+    let endian = match object.is_little_endian() {
+        true => RunTimeEndian::Little,
+        false => RunTimeEndian::Big,
+    };
+    gimli::Dwarf::load(|id| -> anyhow::Result<Reader> {
+        let data = match object.section_by_name(id.name()) {
+            Some(section) => section.uncompressed_data()?,
+            None => Cow::Borrowed(&[][..]),
+        };
+        let data: &'static [u8] = match data {
+            Cow::Borrowed(data) => data,
+            Cow::Owned(data) => Box::leak(data.into_boxed_slice()),
+        };
+        Ok(EndianSlice::new(data, endian))
+    })
+}
+
+impl SymbolIndex {
+    fn build(elf: &'static [u8]) -> anyhow::Result<Self> {
+        let object = object::File::parse(elf)?;
+        let dwarf = load_dwarf(&object)?;
+
+        let mut symbols: HashMap<u64, Vec<String>> = HashMap::new();
+        for symbol in object.symbols() {
+            if symbol.kind() != SymbolKind::Text {
+                continue;
+            }
+            if let Ok(name) = symbol.name() {
+                symbols
+                    .entry(symbol.address())
+                    .or_default()
+                    .push(name.to_string());
+            }
+        }
+
+        let mut index = SymbolIndex {
+            dwarf,
+            units: vec![],
+            files: vec![],
+            functions: HashMap::new(),
+            symbols,
+        };
+        let mut file_ids: HashMap<PathBuf, usize> = HashMap::new();
+        let mut headers = index.dwarf.units();
+        while let Some(header) = headers.next()? {
+            let unit = index.dwarf.unit(header)?;
+            let unit_id = index.units.len();
+            index.units.push(header);
+
+            let mut entries = unit.entries();
+            while let Some((_, entry)) = entries.next_dfs()? {
+                if entry.tag() != gimli::DW_TAG_subprogram
+                    || entry.attr(gimli::DW_AT_declaration)?.is_some()
+                {
+                    continue;
+                }
+                // out-of-line copies of inline functions, and definitions of
+                // functions declared earlier, take their name and file from
+                // the entry they refer to
+                let origin = match entry
+                    .attr_value(gimli::DW_AT_abstract_origin)?
+                    .or(entry.attr_value(gimli::DW_AT_specification)?)
+                {
+                    Some(AttributeValue::UnitRef(offset)) => Some(unit.entry(offset)?),
+                    _ => None,
+                };
+                let attr = |name| -> gimli::Result<Option<AttributeValue<Reader>>> {
+                    match (entry.attr_value(name)?, &origin) {
+                        (Some(value), _) => Ok(Some(value)),
+                        (None, Some(origin)) => origin.attr_value(name),
+                        (None, None) => Ok(None),
+                    }
+                };
+
+                let Some(name) = attr(gimli::DW_AT_name)? else {
+                    continue;
+                };
+                let name = index.dwarf.attr_string(&unit, name)?;
+                let Some(AttributeValue::FileIndex(file)) = attr(gimli::DW_AT_decl_file)? else {
+                    continue;
+                };
+                let Some(file) = unit.line_program.as_ref().and_then(|program| {
+                    let header = program.header();
+                    file_path(&index.dwarf, &unit, header, header.file(file)?)
+                }) else {
+                    continue;
+                };
+                let next_id = file_ids.len();
+                let file = *file_ids.entry(file.clone()).or_insert_with(|| {
+                    index.files.push(file);
+                    next_id
+                });
+
+                let low_pc = match entry.attr_value(gimli::DW_AT_low_pc)? {
+                    Some(value) => index.dwarf.attr_address(&unit, value)?,
+                    None => None,
+                };
+                let high_pc = match (low_pc, entry.attr_value(gimli::DW_AT_high_pc)?) {
+                    (_, Some(AttributeValue::Addr(high_pc))) => Some(high_pc),
+                    (Some(low_pc), Some(size)) => size.udata_value().map(|size| low_pc + size),
+                    _ => None,
+                };
+
+                // a header's inline functions are in every unit including
+                // it, one entry per file is kept, an out-of-line copy if any
+                let functions = index
+                    .functions
+                    .entry(name.to_string_lossy().into_owned())
+                    .or_default();
+                let function = Function {
+                    file,
+                    low_pc,
+                    high_pc,
+                    unit: unit_id,
+                };
+                match functions.iter_mut().find(|seen| seen.file == file) {
+                    Some(seen) if seen.low_pc.is_none() && low_pc.is_some() => *seen = function,
+                    Some(_) => {}
+                    None => functions.push(function),
+                }
+            }
+        }
+        Ok(index)
+    }
+
+    fn resolve(&self, function: String, path: &Path, line: usize) -> Resolution {
+        let relative = crate::subsystem::relative(path);
+        let Some(definition) = self.functions.get(&function).and_then(|functions| {
+            functions
+                .iter()
+                .find(|candidate| self.files[candidate.file].ends_with(relative))
+        }) else {
+            return Resolution::Missing(function);
+        };
+        let Some(low_pc) = definition.low_pc else {
+            return Resolution::Inlined(function);
+        };
+
+        // a clone like foo.isra.0 is the only symbol at its address
+        let names = self.symbols.get(&low_pc).map_or(&[][..], Vec::as_slice);
+        let symbol = names
+            .iter()
+            .find(|name| **name == function)
+            .or_else(|| names.iter().find(|name| name.starts_with(&function)))
+            .or(names.first())
+            .cloned()
+            .unwrap_or(function);
+        Resolution::Found {
+            symbol,
+            low_pc,
+            offset: self
+                .line_address(definition, relative, line)
+                .map(|address| address - low_pc),
+        }
+    }
+
+    /// The lowest address the line table gives for a line of a function.
+    fn line_address(&self, function: &Function, path: &Path, line: usize) -> Option<u64> {
+        let range = function.low_pc?..function.high_pc?;
+        let unit = self.dwarf.unit(self.units[function.unit]).ok()?;
+        let mut rows = unit.line_program.clone()?.rows();
+        let mut lowest: Option<u64> = None;
+        while let Ok(Some((header, row))) = rows.next_row() {
+            if row.end_sequence()
+                || !row.is_stmt()
+                || row.line().map(|line| line.get()) != Some(line as u64)
+                || !range.contains(&row.address())
+            {
+                continue;
+            }
+            let in_file = row
+                .file(header)
+                .and_then(|file| file_path(&self.dwarf, &unit, header, file))
+                .is_some_and(|file| file.ends_with(path));
+            if in_file {
+                lowest = Some(lowest.map_or(row.address(), |lowest| lowest.min(row.address())));
+            }
+        }
+        lowest
+    }
+}
+
+/// The path of a file in a line program, joined to its directory.
+fn file_path(
+    dwarf: &gimli::Dwarf<Reader>,
+    unit: &gimli::Unit<Reader>,
+    header: &gimli::LineProgramHeader<Reader>,
+    file: &gimli::FileEntry<Reader>,
+) -> Option<PathBuf> {
+    let name = dwarf.attr_string(unit, file.path_name()).ok()?;
+    let mut path = match file.directory(header) {
+        Some(directory) => PathBuf::from(
+            dwarf
+                .attr_string(unit, directory)
+                .ok()?
+                .to_string_lossy()
+                .into_owned(),
+        ),
+        None => PathBuf::new(),
+    };
+    path.push(name.to_string_lossy().as_ref());
+    Some(path)
+}
@@ -0,0 +1,144 @@
+use std::collections::{BTreeSet, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use tree_sitter as ts;
+
+use crate::scanner;
+use crate::symbols;
+
+// set by `--emit-tags`
+static EMIT_TAGS: AtomicBool = AtomicBool::new(false);
+
+/// An entry of an exuberant-ctags file, ordered the way its line sorts.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Tag {
+    name: String,
+    path: String,
+    /// The line defining the name, escaped for a `/^...$/` search.
+    pattern: String,
+    /// `f` for a function, `s` for a struct.
+    kind: char,
+}
+
+lazy_static! {
+    // the functions with sites, one entry however many sites each has
+    static ref TAGS: Mutex<BTreeSet<Tag>> = Mutex::new(BTreeSet::new());
+    // the structs with sites, tagged at their definitions once the scan is done
+    static ref STRUCTS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+pub fn enable() {
+    EMIT_TAGS.store(true, Ordering::SeqCst);
+}
+
+pub fn enabled() -> bool {
+    EMIT_TAGS.load(Ordering::SeqCst)
+}
+
+/// Record the function a site is in and the struct it allocates.
+pub fn record(definition: ts::Node<'_>, content: &[u8], path: &Path, struct_name: &str) {
+    if let Some(identifier) = scanner::function_identifier(definition, content) {
+        if let Ok(name) = identifier.utf8_text(content) {
+            let start = content[..identifier.start_byte()]
+                .iter()
+                .rposition(|&b| b == b'\n')
+                .map_or(0, |newline| newline + 1);
+            let end = content[start..]
+                .iter()
+                .position(|&b| b == b'\n')
+                .map_or(content.len(), |newline| start + newline);
+            TAGS.lock().unwrap().insert(Tag {
+                name: name.to_string(),
+                path: path.display().to_string(),
+                pattern: pattern(&String::from_utf8_lossy(&content[start..end])),
+                kind: 'f',
+            });
+        }
+    }
+    STRUCTS.lock().unwrap().insert(struct_name.to_string());
+}
+
+/// Write the recorded functions to a tags file at `out`, along with the
+/// structs at the definitions the vmlinux DWARF gives, for those found
+/// under `roots`, when there is a vmlinux. Returns the number of tags
+/// written.
+pub fn write(out: &Path, vmlinux: Option<&Path>, roots: &[PathBuf]) -> anyhow::Result<usize> {
+    let mut tags = std::mem::take(&mut *TAGS.lock().unwrap());
+    let structs = STRUCTS.lock().unwrap();
+    let definitions = match vmlinux {
+        Some(vmlinux) => symbols::struct_definitions(vmlinux, &structs),
+        None => Err(anyhow::anyhow!("they need the vmlinux DWARF")),
+    };
+    match definitions {
+        Ok(definitions) => {
+            for (name, (file, line)) in definitions {
+                let Some(path) = source_file(&file, roots) else {
+                    continue;
+                };
+                let Some(text) = std::fs::read(&path).ok().and_then(|content| {
+                    let line = content
+                        .split(|&b| b == b'\n')
+                        .nth(line.checked_sub(1)? as usize)?;
+                    Some(String::from_utf8_lossy(line).into_owned())
+                }) else {
+                    continue;
+                };
+                tags.insert(Tag {
+                    name,
+                    path: path.display().to_string(),
+                    pattern: pattern(&text),
+                    kind: 's',
+                });
+            }
+        }
+        Err(e) => eprintln!("--emit-tags: the structs are left out, {e}"),
+    }
+
+    let mut file = std::io::BufWriter::new(std::fs::File::create(out)?);
+    writeln!(
+        file,
+        "!_TAG_FILE_FORMAT\t2\t/extended format; --format=1 will not append ;\" to lines/"
+    )?;
+    writeln!(
+        file,
+        "!_TAG_FILE_SORTED\t1\t/0=unsorted, 1=sorted, 2=foldcase/"
+    )?;
+    writeln!(file, "!_TAG_PROGRAM_NAME\tkheap_sift\t//")?;
+    for tag in &tags {
+        writeln!(
+            file,
+            "{}\t{}\t/^{}$/;\"\t{}",
+            tag.name, tag.path, tag.pattern, tag.kind
+        )?;
+    }
+    file.flush()?;
+    Ok(tags.len())
+}
+
+/// Escape a line for a ctags search pattern, whose delimiter is `/`.
+fn pattern(line: &str) -> String {
+    line.trim_end_matches('\r')
+        .replace('\\', "\\\\")
+        .replace('/', "\\/")
+}
+
+/// Where a file named in the DWARF is in the scanned trees. The DWARF names
+/// files as they were in the build directory, so the path is tried as is,
+/// then under each root with fewer and fewer leading directories.
+fn source_file(file: &Path, roots: &[PathBuf]) -> Option<PathBuf> {
+    if file.is_absolute() && file.is_file() {
+        return Some(file.to_path_buf());
+    }
+    let components: Vec<_> = file.components().collect();
+    (0..components.len()).find_map(|skip| {
+        let suffix: PathBuf = components[skip..].iter().collect();
+        roots
+            .iter()
+            .map(|root| root.join(&suffix))
+            .find(|path| path.is_file())
+    })
+}
@@ -0,0 +1,280 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::slab;
+
+// set by `--tui`, matches are collected for the browser instead of printed
+static TUI_MODE: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref SITES: Mutex<Vec<Site>> = Mutex::new(vec![]);
+}
+
+/// A reported allocation site, kept for the browser.
+pub struct Site {
+    pub struct_name: String,
+    pub struct_size: usize,
+    pub path: PathBuf,
+    /// The line of the allocation call, for opening the file at.
+    pub line: usize,
+    /// The match as it would have been printed.
+    pub text: String,
+}
+
+pub fn enable() {
+    TUI_MODE.store(true, Ordering::SeqCst);
+}
+
+pub fn enabled() -> bool {
+    TUI_MODE.load(Ordering::SeqCst)
+}
+
+pub fn record(site: Site) {
+    SITES.lock().unwrap().push(site);
+}
+
+/// A struct with allocation sites, as listed in the left pane.
+struct StructSites {
+    name: String,
+    size: usize,
+    sites: Vec<Site>,
+}
+
+struct Browser {
+    structs: Vec<StructSites>,
+    /// Indices into `structs` of those matching the filter.
+    visible: Vec<usize>,
+    list: ListState,
+    filter: String,
+    editing_filter: bool,
+    site: usize,
+    scroll: u16,
+}
+
+impl Browser {
+    fn new(sites: Vec<Site>) -> Self {
+        let mut by_name: BTreeMap<String, StructSites> = BTreeMap::new();
+        for site in sites {
+            by_name
+                .entry(site.struct_name.clone())
+                .or_insert_with(|| StructSites {
+                    name: site.struct_name.clone(),
+                    size: site.struct_size,
+                    sites: vec![],
+                })
+                .sites
+                .push(site);
+        }
+        let mut structs: Vec<StructSites> = by_name.into_values().collect();
+        structs.sort_by(|a, b| (a.size, &a.name).cmp(&(b.size, &b.name)));
+        for entry in &mut structs {
+            entry
+                .sites
+                .sort_by(|a, b| (&a.path, a.line).cmp(&(&b.path, b.line)));
+        }
+
+        let mut browser = Browser {
+            visible: (0..structs.len()).collect(),
+            structs,
+            list: ListState::default(),
+            filter: String::new(),
+            editing_filter: false,
+            site: 0,
+            scroll: 0,
+        };
+        browser
+            .list
+            .select((!browser.visible.is_empty()).then_some(0));
+        browser
+    }
+
+    fn selected(&self) -> Option<&StructSites> {
+        let idx = self.visible.get(self.list.selected()?)?;
+        self.structs.get(*idx)
+    }
+
+    fn selected_site(&self) -> Option<&Site> {
+        self.selected()?.sites.get(self.site)
+    }
+
+    fn apply_filter(&mut self) {
+        self.visible = (0..self.structs.len())
+            .filter(|&idx| self.structs[idx].name.contains(&self.filter))
+            .collect();
+        self.list.select((!self.visible.is_empty()).then_some(0));
+        self.site = 0;
+        self.scroll = 0;
+    }
+
+    fn move_selection(&mut self, forward: bool) {
+        if self.visible.is_empty() {
+            return;
+        }
+        let current = self.list.selected().unwrap_or(0);
+        let next = if forward {
+            (current + 1).min(self.visible.len() - 1)
+        } else {
+            current.saturating_sub(1)
+        };
+        self.list.select(Some(next));
+        self.site = 0;
+        self.scroll = 0;
+    }
+
+    fn move_site(&mut self, forward: bool) {
+        let Some(count) = self.selected().map(|entry| entry.sites.len()) else {
+            return;
+        };
+        self.site = if forward {
+            (self.site + 1) % count
+        } else {
+            (self.site + count - 1) % count
+        };
+        self.scroll = 0;
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, status] =
+            Layout::vertical([Constraint::Min(1), Constraint::Length(1)]).areas(frame.area());
+        let [left, right] =
+            Layout::horizontal([Constraint::Percentage(35), Constraint::Percentage(65)])
+                .areas(main);
+
+        let items: Vec<ListItem> = self
+            .visible
+            .iter()
+            .map(|&idx| {
+                let entry = &self.structs[idx];
+                ListItem::new(format!(
+                    "{} {} {} ({})",
+                    entry.name,
+                    entry.size,
+                    slab::cache_name(entry.size),
+                    entry.sites.len()
+                ))
+            })
+            .collect();
+        let list = List::new(items)
+            .block(Block::default().borders(Borders::ALL).title("Structs"))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, left, &mut self.list);
+
+        let (title, text) = match (self.selected(), self.selected_site()) {
+            (Some(entry), Some(site)) => (
+                format!("Site {}/{}", self.site + 1, entry.sites.len()),
+                site.text.as_str(),
+            ),
+            _ => ("Sites".to_string(), ""),
+        };
+        let detail = Paragraph::new(text)
+            .block(Block::default().borders(Borders::ALL).title(title))
+            .scroll((self.scroll, 0));
+        frame.render_widget(detail, right);
+
+        let status_line = if self.editing_filter {
+            format!("filter: {}_", self.filter)
+        } else {
+            "j/k struct  n/N site  J/K scroll  / filter  e open in $EDITOR  q quit".to_string()
+        };
+        frame.render_widget(Line::from(status_line), status);
+    }
+}
+
+/// Open the collected sites in the results browser, until it is quit.
+pub fn run() -> anyhow::Result<()> {
+    let sites = std::mem::take(&mut *SITES.lock().unwrap());
+    if sites.is_empty() {
+        println!("No allocation sites found");
+        return Ok(());
+    }
+
+    let mut browser = Browser::new(sites);
+    let mut terminal = ratatui::init();
+    let result = browse(&mut terminal, &mut browser);
+    ratatui::restore();
+    result
+}
+
+fn browse(terminal: &mut DefaultTerminal, browser: &mut Browser) -> anyhow::Result<()> {
+    loop {
+        terminal.draw(|frame| browser.draw(frame))?;
+
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+
+        if browser.editing_filter {
+            match key.code {
+                KeyCode::Enter => browser.editing_filter = false,
+                KeyCode::Esc => {
+                    browser.editing_filter = false;
+                    browser.filter.clear();
+                    browser.apply_filter();
+                }
+                KeyCode::Backspace => {
+                    browser.filter.pop();
+                    browser.apply_filter();
+                }
+                KeyCode::Char(c) => {
+                    browser.filter.push(c);
+                    browser.apply_filter();
+                }
+                _ => {}
+            }
+            continue;
+        }
+
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+            KeyCode::Char('j') | KeyCode::Down => browser.move_selection(true),
+            KeyCode::Char('k') | KeyCode::Up => browser.move_selection(false),
+            KeyCode::Char('n') | KeyCode::Tab => browser.move_site(true),
+            KeyCode::Char('N') | KeyCode::BackTab => browser.move_site(false),
+            KeyCode::Char('J') | KeyCode::PageDown => {
+                browser.scroll = browser.scroll.saturating_add(10)
+            }
+            KeyCode::Char('K') | KeyCode::PageUp => {
+                browser.scroll = browser.scroll.saturating_sub(10)
+            }
+            KeyCode::Char('/') => browser.editing_filter = true,
+            KeyCode::Char('e') | KeyCode::Enter => {
+                if let Some(site) = browser.selected_site() {
+                    let (path, line) = (site.path.clone(), site.line);
+                    ratatui::restore();
+                    let edited = open_in_editor(&path, line);
+                    *terminal = ratatui::init();
+                    edited?;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Open `path` at `line` in `$EDITOR`, falling back to vi.
+fn open_in_editor(path: &std::path::Path, line: usize) -> anyhow::Result<()> {
+    let editor = std::env::var("EDITOR").unwrap_or_else(|_| "vi".to_string());
+    let mut words = editor.split_whitespace();
+    let Some(program) = words.next() else {
+        anyhow::bail!("$EDITOR is empty");
+    };
+    std::process::Command::new(program)
+        .args(words)
+        .arg(format!("+{line}"))
+        .arg(path)
+        .status()?;
+    Ok(())
+}
@@ -0,0 +1,592 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use dwat::dwarf::OwnedDwarf;
+use dwat::prelude::*;
+use memmap2::Mmap;
+use object::{Object, ObjectSection};
+use serde_json::Value;
+
+use crate::{kimage, symbols};
+
+/// Where struct names, sizes and layouts come from. The scan only needs
+/// these, so a vmlinux's DWARF, a BTF blob and a JSON dump of one are
+/// interchangeable to it.
+pub trait TypeSource: Send + Sync {
+    /// Every named struct with a size, declarations only are left out.
+    fn sizes(&self) -> anyhow::Result<HashMap<String, usize>>;
+
+    /// A struct's definition with the size and offset of each member, in the
+    /// format `Layout::parse` reads.
+    fn layout(&self, name: &str) -> anyhow::Result<String>;
+
+    /// What the types were read from, e.g. `DWARF`.
+    fn kind(&self) -> &'static str;
+
+    /// The alignment recorded for a struct, DWARF's `DW_AT_alignment` for
+    /// one declared `__aligned()` or `__packed`. None when there is none and
+    /// it has to be inferred from the members.
+    fn alignment(&self, _name: &str) -> Option<usize> {
+        None
+    }
+
+    /// The vmlinux the types were read from, for what only its DWARF has.
+    fn vmlinux(&self) -> Option<&Path> {
+        None
+    }
+}
+
+/// The types in a vmlinux's DWARF.
+pub struct DwarfTypes {
+    dwarf: OwnedDwarf,
+    structs: HashMap<String, dwat::Struct>,
+    /// The vmlinux the DWARF was loaded from, read again for the alignments
+    /// dwat doesn't give.
+    vmlinux: Option<PathBuf>,
+    alignments: OnceLock<HashMap<String, usize>>,
+}
+
+impl DwarfTypes {
+    pub fn new(dwarf: OwnedDwarf) -> anyhow::Result<Self> {
+        let structs = dwarf.get_named_types_map::<dwat::Struct>()?;
+        Ok(DwarfTypes {
+            dwarf,
+            structs,
+            vmlinux: None,
+            alignments: OnceLock::new(),
+        })
+    }
+
+    /// Look the recorded alignments up in the vmlinux at `path`, on the
+    /// first struct asked about. A compressed image or one whose debug info
+    /// is elsewhere has none to give, its alignments are inferred.
+    pub fn with_vmlinux(self, path: &Path) -> Self {
+        DwarfTypes {
+            vmlinux: Some(path.to_path_buf()),
+            ..self
+        }
+    }
+}
+
+impl TypeSource for DwarfTypes {
+    fn sizes(&self) -> anyhow::Result<HashMap<String, usize>> {
+        Ok(self
+            .structs
+            .iter()
+            .filter_map(|(name, struc)| Some((name.clone(), struc.byte_size(&self.dwarf).ok()?)))
+            .collect())
+    }
+
+    fn layout(&self, name: &str) -> anyhow::Result<String> {
+        let Some(struc) = self.structs.get(name) else {
+            anyhow::bail!("struct '{name}' is not in the DWARF");
+        };
+        Ok(struc.to_string_verbose(&self.dwarf, 1)?)
+    }
+
+    fn kind(&self) -> &'static str {
+        "DWARF"
+    }
+
+    fn alignment(&self, name: &str) -> Option<usize> {
+        let vmlinux = self.vmlinux.as_ref()?;
+        self.alignments
+            .get_or_init(|| symbols::struct_alignments(vmlinux).unwrap_or_default())
+            .get(name)
+            .copied()
+    }
+
+    fn vmlinux(&self) -> Option<&Path> {
+        self.vmlinux.as_deref()
+    }
+}
+
+/// Load the types in a `--types-from` file: a raw BTF blob like
+/// `/sys/kernel/btf/vmlinux` or pahole's `--btf_encode_detached` output, an
+/// ELF with a `.BTF` section like an object `pahole -J` encoded, or the JSON
+/// `bpftool -j btf dump file` prints.
+pub fn load(path: &Path) -> anyhow::Result<Box<dyn TypeSource>> {
+    let file = std::fs::File::open(path)?;
+    let data = unsafe { Mmap::map(&file) }?;
+    let btf = if data.starts_with(&[0x9f, 0xeb]) || data.starts_with(&[0xeb, 0x9f]) {
+        Btf::parse(&data)
+    } else if kimage::is_elf(&data) {
+        let elf = object::File::parse(&*data)?;
+        let Some(section) = elf.section_by_name(".BTF") else {
+            anyhow::bail!("{} is an ELF without a .BTF section", path.display());
+        };
+        Btf::parse(section.data()?)
+    } else if data.trim_ascii_start().starts_with(b"{") {
+        Btf::from_json(&serde_json::from_slice(&data)?)
+    } else {
+        anyhow::bail!(
+            "{} is neither BTF, an ELF with a .BTF section, nor a bpftool JSON dump",
+            path.display()
+        );
+    };
+    btf.map(|btf| Box::new(btf) as Box<dyn TypeSource>)
+        .map_err(|e| e.context(format!("failed to load types from {}", path.display())))
+}
+
+/// A type in a BTF type section. Modifiers only keep the type they modify.
+enum BtfKind {
+    Void,
+    Int {
+        size: usize,
+    },
+    Float {
+        size: usize,
+    },
+    Pointer(u32),
+    Array {
+        element: u32,
+        len: usize,
+    },
+    Struct {
+        size: usize,
+        members: Vec<BtfMember>,
+    },
+    Union {
+        size: usize,
+        members: Vec<BtfMember>,
+    },
+    Enum {
+        size: usize,
+    },
+    Forward {
+        union: bool,
+    },
+    Typedef(u32),
+    Qualifier(&'static str, u32),
+    FunctionProto {
+        ret: u32,
+        params: Vec<u32>,
+        variadic: bool,
+    },
+    // functions, variables, data sections, tags and anything newer, which
+    // struct members never refer to
+    Other,
+}
+
+struct BtfMember {
+    name: String,
+    ty: u32,
+    bit_offset: usize,
+    bitfield_size: usize,
+}
+
+struct BtfType {
+    name: String,
+    kind: BtfKind,
+}
+
+/// The types of a BTF blob, by id, with type 0 the implicit `void`.
+pub struct Btf {
+    types: Vec<BtfType>,
+    by_name: HashMap<String, u32>,
+    kind: &'static str,
+}
+
+/// How deeply anonymous structs and unions are expanded into their parent.
+const MAX_NESTING: usize = 8;
+
+impl Btf {
+    /// Parse a raw BTF blob, of either byte order. Split BTF, like a
+    /// module's, refers to the vmlinux's types and isn't supported.
+    fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        let big_endian = data.starts_with(&[0xeb, 0x9f]);
+        let u32_at = |offset: usize| -> anyhow::Result<u32> {
+            let Some(bytes) = data.get(offset..offset + 4) else {
+                anyhow::bail!("truncated BTF at offset {offset:#x}");
+            };
+            let bytes = bytes.try_into().unwrap();
+            Ok(match big_endian {
+                true => u32::from_be_bytes(bytes),
+                false => u32::from_le_bytes(bytes),
+            })
+        };
+        let header_len = u32_at(4)? as usize;
+        let (type_off, type_len) = (u32_at(8)? as usize, u32_at(12)? as usize);
+        let (str_off, str_len) = (u32_at(16)? as usize, u32_at(20)? as usize);
+        let strings = data
+            .get(header_len + str_off..header_len + str_off + str_len)
+            .ok_or_else(|| anyhow::anyhow!("BTF string section is out of bounds"))?;
+        let string = |offset: u32| -> String {
+            let bytes = strings.get(offset as usize..).unwrap_or_default();
+            let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+            String::from_utf8_lossy(&bytes[..end]).into_owned()
+        };
+
+        let mut types = vec![BtfType {
+            name: String::new(),
+            kind: BtfKind::Void,
+        }];
+        let mut pos = header_len + type_off;
+        let end = pos + type_len;
+        while pos < end {
+            let name = string(u32_at(pos)?);
+            let info = u32_at(pos + 4)?;
+            let size_or_type = u32_at(pos + 8)?;
+            pos += 12;
+            let vlen = (info & 0xffff) as usize;
+            let kind_flag = info >> 31 == 1;
+            let kind = match (info >> 24) & 0x1f {
+                1 => {
+                    pos += 4;
+                    BtfKind::Int {
+                        size: size_or_type as usize,
+                    }
+                }
+                2 => BtfKind::Pointer(size_or_type),
+                3 => {
+                    let array = BtfKind::Array {
+                        element: u32_at(pos)?,
+                        len: u32_at(pos + 8)? as usize,
+                    };
+                    pos += 12;
+                    array
+                }
+                kind @ (4 | 5) => {
+                    let mut members = Vec::with_capacity(vlen);
+                    for _ in 0..vlen {
+                        let offset = u32_at(pos + 8)?;
+                        let (bit_offset, bitfield_size) = match kind_flag {
+                            true => (offset & 0xff_ffff, offset >> 24),
+                            false => (offset, 0),
+                        };
+                        members.push(BtfMember {
+                            name: string(u32_at(pos)?),
+                            ty: u32_at(pos + 4)?,
+                            bit_offset: bit_offset as usize,
+                            bitfield_size: bitfield_size as usize,
+                        });
+                        pos += 12;
+                    }
+                    let size = size_or_type as usize;
+                    match kind {
+                        4 => BtfKind::Struct { size, members },
+                        _ => BtfKind::Union { size, members },
+                    }
+                }
+                6 => {
+                    pos += 8 * vlen;
+                    BtfKind::Enum {
+                        size: size_or_type as usize,
+                    }
+                }
+                7 => BtfKind::Forward { union: kind_flag },
+                8 => BtfKind::Typedef(size_or_type),
+                9 => BtfKind::Qualifier("volatile", size_or_type),
+                10 => BtfKind::Qualifier("const", size_or_type),
+                11 => BtfKind::Qualifier("restrict", size_or_type),
+                13 => {
+                    let mut params = Vec::with_capacity(vlen);
+                    let mut variadic = false;
+                    for _ in 0..vlen {
+                        let (name_off, ty) = (u32_at(pos)?, u32_at(pos + 4)?);
+                        if name_off == 0 && ty == 0 {
+                            variadic = true;
+                        } else {
+                            params.push(ty);
+                        }
+                        pos += 8;
+                    }
+                    BtfKind::FunctionProto {
+                        ret: size_or_type,
+                        params,
+                        variadic,
+                    }
+                }
+                14 | 17 => {
+                    pos += 4;
+                    BtfKind::Other
+                }
+                15 => {
+                    pos += 12 * vlen;
+                    BtfKind::Other
+                }
+                16 => BtfKind::Float {
+                    size: size_or_type as usize,
+                },
+                18 => BtfKind::Qualifier("", size_or_type),
+                19 => {
+                    pos += 12 * vlen;
+                    BtfKind::Enum {
+                        size: size_or_type as usize,
+                    }
+                }
+                12 => BtfKind::Other,
+                kind => anyhow::bail!("unknown BTF kind {kind} at offset {:#x}", pos - 12),
+            };
+            types.push(BtfType { name, kind });
+        }
+        Ok(Btf::new(types, "BTF"))
+    }
+
+    /// Read the types from `bpftool -j btf dump file`, whose ids are in
+    /// order from 1 and whose anonymous types are named `(anon)`.
+    fn from_json(json: &Value) -> anyhow::Result<Self> {
+        let Some(entries) = json.get("types").and_then(Value::as_array) else {
+            anyhow::bail!("no \"types\" array, not a bpftool JSON dump");
+        };
+        let number = |value: &Value, key: &str| -> usize {
+            value.get(key).and_then(Value::as_u64).unwrap_or(0) as usize
+        };
+        let id = |value: &Value, key: &str| number(value, key) as u32;
+        let name = |value: &Value| -> String {
+            match value.get("name").and_then(Value::as_str) {
+                Some("(anon)") | None => String::new(),
+                Some(name) => name.to_string(),
+            }
+        };
+
+        let mut types = vec![BtfType {
+            name: String::new(),
+            kind: BtfKind::Void,
+        }];
+        for entry in entries {
+            if number(entry, "id") != types.len() {
+                anyhow::bail!("type ids aren't consecutive at id {}", number(entry, "id"));
+            }
+            let size = number(entry, "size");
+            let kind = match entry.get("kind").and_then(Value::as_str).unwrap_or("") {
+                "INT" => BtfKind::Int { size },
+                "FLOAT" => BtfKind::Float { size },
+                "PTR" => BtfKind::Pointer(id(entry, "type_id")),
+                "ARRAY" => BtfKind::Array {
+                    element: id(entry, "type_id"),
+                    len: number(entry, "nr_elems"),
+                },
+                kind @ ("STRUCT" | "UNION") => {
+                    let members = entry
+                        .get("members")
+                        .and_then(Value::as_array)
+                        .map(Vec::as_slice)
+                        .unwrap_or_default()
+                        .iter()
+                        .map(|member| BtfMember {
+                            name: name(member),
+                            ty: id(member, "type_id"),
+                            bit_offset: number(member, "bits_offset"),
+                            bitfield_size: number(member, "bitfield_size"),
+                        })
+                        .collect();
+                    match kind {
+                        "STRUCT" => BtfKind::Struct { size, members },
+                        _ => BtfKind::Union { size, members },
+                    }
+                }
+                "ENUM" | "ENUM64" => BtfKind::Enum { size },
+                "FWD" => BtfKind::Forward {
+                    union: entry.get("fwd_kind").and_then(Value::as_str) == Some("union"),
+                },
+                "TYPEDEF" => BtfKind::Typedef(id(entry, "type_id")),
+                "VOLATILE" => BtfKind::Qualifier("volatile", id(entry, "type_id")),
+                "CONST" => BtfKind::Qualifier("const", id(entry, "type_id")),
+                "RESTRICT" => BtfKind::Qualifier("restrict", id(entry, "type_id")),
+                "TYPE_TAG" => BtfKind::Qualifier("", id(entry, "type_id")),
+                "FUNC_PROTO" => {
+                    let params: Vec<&Value> = entry
+                        .get("params")
+                        .and_then(Value::as_array)
+                        .map(|params| params.iter().collect())
+                        .unwrap_or_default();
+                    let variadic = params
+                        .last()
+                        .is_some_and(|param| name(param).is_empty() && id(param, "type_id") == 0);
+                    BtfKind::FunctionProto {
+                        ret: id(entry, "ret_type_id"),
+                        params: params[..params.len() - usize::from(variadic)]
+                            .iter()
+                            .map(|param| id(param, "type_id"))
+                            .collect(),
+                        variadic,
+                    }
+                }
+                _ => BtfKind::Other,
+            };
+            types.push(BtfType {
+                name: name(entry),
+                kind,
+            });
+        }
+        Ok(Btf::new(types, "bpftool JSON"))
+    }
+
+    fn new(types: Vec<BtfType>, kind: &'static str) -> Self {
+        let mut by_name = HashMap::new();
+        for (id, ty) in types.iter().enumerate() {
+            if let BtfKind::Struct { .. } = ty.kind {
+                if !ty.name.is_empty() {
+                    by_name.entry(ty.name.clone()).or_insert(id as u32);
+                }
+            }
+        }
+        Btf {
+            types,
+            by_name,
+            kind,
+        }
+    }
+
+    fn get(&self, id: u32) -> &BtfType {
+        self.types.get(id as usize).unwrap_or(&self.types[0])
+    }
+
+    /// The size of a type in bytes, pointers being the size of `long`.
+    fn size_of(&self, id: u32, depth: usize) -> usize {
+        if depth > 32 {
+            return 0;
+        }
+        match self.get(id).kind {
+            BtfKind::Int { size }
+            | BtfKind::Float { size }
+            | BtfKind::Struct { size, .. }
+            | BtfKind::Union { size, .. }
+            | BtfKind::Enum { size } => size,
+            BtfKind::Pointer(_) => self.pointer_size(),
+            BtfKind::Array { element, len } => self.size_of(element, depth + 1) * len,
+            BtfKind::Typedef(ty) | BtfKind::Qualifier(_, ty) => self.size_of(ty, depth + 1),
+            _ => 0,
+        }
+    }
+
+    fn pointer_size(&self) -> usize {
+        self.types
+            .iter()
+            .find_map(|ty| match ty.kind {
+                BtfKind::Int { size } if ty.name == "long unsigned int" => Some(size),
+                _ => None,
+            })
+            .unwrap_or(8)
+    }
+
+    /// Declare `declarator` with a type in C syntax, e.g. `void (*fn)(void *)`
+    /// for a pointer to a function prototype and `fn`.
+    fn declare(&self, id: u32, declarator: &str, depth: usize) -> String {
+        let base = |base: String| match declarator.is_empty() {
+            true => base,
+            false => format!("{base} {declarator}"),
+        };
+        let ty = self.get(id);
+        let tag = |keyword: &str| match ty.name.is_empty() {
+            true => format!("{keyword} {{...}}"),
+            false => format!("{keyword} {}", ty.name),
+        };
+        if depth > 32 {
+            return base("void".to_string());
+        }
+        match &ty.kind {
+            BtfKind::Void | BtfKind::Other => base("void".to_string()),
+            BtfKind::Int { .. } | BtfKind::Float { .. } | BtfKind::Typedef(_) => {
+                base(ty.name.clone())
+            }
+            BtfKind::Struct { .. } => base(tag("struct")),
+            BtfKind::Union { .. } => base(tag("union")),
+            BtfKind::Enum { .. } => base(tag("enum")),
+            BtfKind::Forward { union } => base(tag(if *union { "union" } else { "struct" })),
+            BtfKind::Pointer(target) => {
+                let declarator = match self.get(*target).kind {
+                    BtfKind::Array { .. } | BtfKind::FunctionProto { .. } => {
+                        format!("(*{declarator})")
+                    }
+                    _ => format!("*{declarator}"),
+                };
+                self.declare(*target, &declarator, depth + 1)
+            }
+            BtfKind::Array { element, len } => {
+                self.declare(*element, &format!("{declarator}[{len}]"), depth + 1)
+            }
+            BtfKind::Qualifier(qualifier, target) => {
+                let inner = self.declare(*target, declarator, depth + 1);
+                match qualifier.is_empty() {
+                    true => inner,
+                    false => format!("{qualifier} {inner}"),
+                }
+            }
+            BtfKind::FunctionProto {
+                ret,
+                params,
+                variadic,
+            } => {
+                let mut params: Vec<String> = params
+                    .iter()
+                    .map(|&param| self.declare(param, "", depth + 1))
+                    .collect();
+                if *variadic {
+                    params.push("...".to_string());
+                }
+                if params.is_empty() {
+                    params.push("void".to_string());
+                }
+                self.declare(
+                    *ret,
+                    &format!("{declarator}({})", params.join(", ")),
+                    depth + 1,
+                )
+            }
+        }
+    }
+
+    /// Write the members of a struct or union at `offset` bytes into the
+    /// outermost struct, expanding anonymous structs and unions in place.
+    fn write_members(&self, out: &mut String, members: &[BtfMember], offset: usize, depth: usize) {
+        let indent = "    ".repeat(depth);
+        for member in members {
+            let member_offset = offset + member.bit_offset / 8;
+            let ty = self.get(member.ty);
+            let nested = match &ty.kind {
+                BtfKind::Struct { members, .. } if ty.name.is_empty() => Some(("struct", members)),
+                BtfKind::Union { members, .. } if ty.name.is_empty() => Some(("union", members)),
+                _ => None,
+            };
+            if let Some((keyword, nested)) = nested.filter(|_| depth < MAX_NESTING) {
+                out.push_str(&format!("{indent}{keyword} {{\n"));
+                self.write_members(out, nested, member_offset, depth + 1);
+                match member.name.is_empty() {
+                    true => out.push_str(&format!("{indent}}};\n")),
+                    false => out.push_str(&format!("{indent}}} {};\n", member.name)),
+                }
+                continue;
+            }
+
+            let mut decl = self.declare(member.ty, &member.name, 0);
+            if member.bitfield_size > 0 {
+                decl.push_str(&format!(":{}", member.bitfield_size));
+            }
+            let size = self.size_of(member.ty, 0);
+            out.push_str(&format!(
+                "{indent}{:<44}\t/* {size:>4} | {member_offset:>4} */\n",
+                format!("{decl};")
+            ));
+        }
+    }
+}
+
+impl TypeSource for Btf {
+    fn sizes(&self) -> anyhow::Result<HashMap<String, usize>> {
+        Ok(self
+            .by_name
+            .iter()
+            .map(|(name, &id)| (name.clone(), self.size_of(id, 0)))
+            .collect())
+    }
+
+    fn layout(&self, name: &str) -> anyhow::Result<String> {
+        let Some(&id) = self.by_name.get(name) else {
+            anyhow::bail!("struct '{name}' is not in the {}", self.kind);
+        };
+        let BtfKind::Struct { size, members } = &self.get(id).kind else {
+            unreachable!("only structs are indexed by name");
+        };
+        let mut out = format!("struct {name} {{\n");
+        self.write_members(&mut out, members, 0, 1);
+        out.push_str(&format!("\n    /* total size: {size} */\n}};"));
+        Ok(out)
+    }
+
+    fn kind(&self) -> &'static str {
+        self.kind
+    }
+}
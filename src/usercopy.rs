@@ -0,0 +1,603 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use tree_sitter as ts;
+use tree_sitter_c as ts_c;
+
+use crate::layout::Layout;
+use crate::output;
+use crate::pointee;
+use crate::query;
+use crate::scanner;
+
+// copy user memory into their first argument
+const COPY_FROM_USER: &[&str] = &["copy_from_user", "__copy_from_user"];
+// return a kernel copy of user memory
+const MEMDUP_USER: &[&str] = &["memdup_user", "memdup_user_nul", "vmemdup_user"];
+// copy their second argument out to user memory
+const COPY_TO_USER: &[&str] = &["copy_to_user", "__copy_to_user"];
+// store the value of their first argument in user memory
+const PUT_USER: &[&str] = &["put_user", "__put_user"];
+
+// set by `--track-user-writes` and `--track-user-reads`
+static TRACK_WRITES: AtomicBool = AtomicBool::new(false);
+static TRACK_READS: AtomicBool = AtomicBool::new(false);
+
+/// The part of an object a write lands in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// The object itself, written from its start.
+    Object,
+    Field(String),
+}
+
+/// How data moves between the object and user memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Access {
+    /// Copied in, with the length argument as written.
+    CopyIn(String),
+    /// A field is set to point at a kernel copy of user memory.
+    Buffer,
+    /// Copied out, with the length argument as written.
+    CopyOut(String),
+    /// A single value stored to user memory with `put_user`.
+    Put,
+}
+
+/// A call copying between user memory and one of the selected structs.
+struct UserCopy {
+    path: PathBuf,
+    line: usize,
+    function: Option<String>,
+    call: String,
+    target: Target,
+    access: Access,
+}
+
+lazy_static! {
+    static ref CALL_QUERY: ts::Query = {
+        let names: Vec<&str> = COPY_FROM_USER
+            .iter()
+            .chain(MEMDUP_USER)
+            .chain(COPY_TO_USER)
+            .chain(PUT_USER)
+            .chain(&["memcpy"])
+            .copied()
+            .collect();
+        let query = format!(
+            "(call_expression
+                function: (identifier) @call.function
+                (#match? @call.function \"^({})$\")
+                arguments: (argument_list) @call.args
+            ) @call",
+            names.join("|")
+        );
+        ts::Query::new(ts_c::language(), &query).unwrap()
+    };
+    // user writes and reads by struct
+    static ref WRITES: Mutex<BTreeMap<String, Vec<UserCopy>>> = Mutex::new(BTreeMap::new());
+    static ref READS: Mutex<BTreeMap<String, Vec<UserCopy>>> = Mutex::new(BTreeMap::new());
+}
+
+pub fn enable_writes() {
+    TRACK_WRITES.store(true, Ordering::SeqCst);
+}
+
+pub fn writes_enabled() -> bool {
+    TRACK_WRITES.load(Ordering::SeqCst)
+}
+
+pub fn enable_reads() {
+    TRACK_READS.store(true, Ordering::SeqCst);
+}
+
+pub fn reads_enabled() -> bool {
+    TRACK_READS.load(Ordering::SeqCst)
+}
+
+/// The copies found in a file, by direction.
+struct Copies {
+    writes: Vec<(String, UserCopy)>,
+    reads: Vec<(String, UserCopy)>,
+}
+
+/// A call of interest in a file, along with the function it is made in.
+struct Call<'tree> {
+    node: ts::Node<'tree>,
+    name: &'tree str,
+    args: ts::Node<'tree>,
+    function: Option<ts::Node<'tree>>,
+}
+
+/// Find the copies between user memory and the structs `selected` accepts in
+/// a parsed file, of whichever directions are tracked. Writes are
+/// `copy_from_user` into an object or one of its fields, a field set to the
+/// result of `memdup_user`, and a `memcpy` from a buffer filled from user
+/// memory earlier in the same function. Reads are `copy_to_user` from an
+/// object or field and `put_user` of a field.
+pub fn find<'tree>(
+    root: ts::Node<'tree>,
+    content: &'tree [u8],
+    path: &Path,
+    selected: impl Fn(&str) -> bool,
+) {
+    let Copies { writes, reads } = copies_in(root, content, path, selected);
+    for (tracked, found, sites) in [
+        (writes_enabled(), writes, &*WRITES),
+        (reads_enabled(), reads, &*READS),
+    ] {
+        if !tracked || found.is_empty() {
+            continue;
+        }
+        let mut sites = sites.lock().unwrap();
+        for (struct_name, site) in found {
+            sites.entry(struct_name).or_default().push(site);
+        }
+    }
+}
+
+/// The copies of both directions [`find`] records, along with the struct
+/// each copies to or from.
+fn copies_in<'tree>(
+    root: ts::Node<'tree>,
+    content: &'tree [u8],
+    path: &Path,
+    selected: impl Fn(&str) -> bool,
+) -> Copies {
+    let mut calls = vec![];
+    for [call, function, args] in query::captures(
+        &CALL_QUERY,
+        ["call", "call.function", "call.args"],
+        root,
+        content,
+    ) {
+        calls.push(Call {
+            node: call,
+            name: function.utf8_text(content).unwrap_or(""),
+            args,
+            function: scanner::enclosing_function(call),
+        });
+    }
+
+    // the variables each function fills with user memory, which a memcpy
+    // may then copy on into an object
+    let mut user_buffers: HashSet<(Option<usize>, &str)> = HashSet::new();
+    for call in &calls {
+        let buffer = if COPY_FROM_USER.contains(&call.name) {
+            query::argument(call.args, 0).and_then(|dst| buffer_variable(dst, content))
+        } else if MEMDUP_USER.contains(&call.name) {
+            assigned_variable(call.node, content)
+        } else {
+            None
+        };
+        if let Some(buffer) = buffer {
+            user_buffers.insert((call.function.map(|f| f.start_byte()), buffer));
+        }
+    }
+
+    let mut writes = vec![];
+    let mut reads = vec![];
+    for call in &calls {
+        let (copy, found) = if COPY_FROM_USER.contains(&call.name) {
+            (copy_into(call, content), &mut writes)
+        } else if MEMDUP_USER.contains(&call.name) {
+            let copy = assigned_field(call.node, content)
+                .map(|(struct_name, field)| (struct_name, Target::Field(field), Access::Buffer));
+            (copy, &mut writes)
+        } else if COPY_TO_USER.contains(&call.name) {
+            (copy_out_of(call, content), &mut reads)
+        } else if PUT_USER.contains(&call.name) {
+            let copy = query::argument(call.args, 0)
+                .and_then(|value| field_of(value, content))
+                .map(|(struct_name, field)| (struct_name, Target::Field(field), Access::Put));
+            (copy, &mut reads)
+        } else {
+            // memcpy, only from a buffer holding user memory
+            let function = call.function.map(|f| f.start_byte());
+            let copy = query::argument(call.args, 1)
+                .and_then(|src| buffer_variable(src, content))
+                .filter(|&src| user_buffers.contains(&(function, src)))
+                .and_then(|_| copy_into(call, content));
+            (copy, &mut writes)
+        };
+
+        let Some((struct_name, target, access)) = copy else {
+            continue;
+        };
+        if !selected(struct_name) {
+            continue;
+        }
+        // a stored memdup_user result is shown with what it is stored in
+        let shown = match access {
+            Access::Buffer => call.node.parent().unwrap_or(call.node),
+            _ => call.node,
+        };
+        found.push((
+            struct_name.to_string(),
+            UserCopy {
+                path: path.to_path_buf(),
+                line: call.node.start_position().row + 1,
+                function: call
+                    .function
+                    .and_then(|def| scanner::function_name(def, content)),
+                call: output::one_line(&content[shown.byte_range()]),
+                target,
+                access,
+            },
+        ));
+    }
+
+    Copies { writes, reads }
+}
+
+/// Where a `copy_from_user` or `memcpy` call copies to, with its length.
+fn copy_into<'a>(call: &Call<'_>, content: &'a [u8]) -> Option<(&'a str, Target, Access)> {
+    let (struct_name, target) = object_part(query::argument(call.args, 0)?, content)?;
+    Some((struct_name, target, Access::CopyIn(length(call, content))))
+}
+
+/// Where a `copy_to_user` call copies from, with its length.
+fn copy_out_of<'a>(call: &Call<'_>, content: &'a [u8]) -> Option<(&'a str, Target, Access)> {
+    let (struct_name, target) = object_part(query::argument(call.args, 1)?, content)?;
+    Some((struct_name, target, Access::CopyOut(length(call, content))))
+}
+
+/// The length argument of a copy, as written.
+fn length(call: &Call<'_>, content: &[u8]) -> String {
+    query::argument(call.args, 2)
+        .map(|len| output::one_line(&content[len.byte_range()]))
+        .unwrap_or_default()
+}
+
+/// The struct and part of it a copy's source or destination refers to: a
+/// pointer to the object, or a field of it with or without its address
+/// taken.
+pub(crate) fn object_part<'a>(ptr: ts::Node<'_>, content: &'a [u8]) -> Option<(&'a str, Target)> {
+    if let Some(struct_name) = pointee::struct_pointee(ptr, content) {
+        return Some((struct_name, Target::Object));
+    }
+
+    let mut field = ptr;
+    while matches!(field.kind(), "parenthesized_expression" | "cast_expression") {
+        field = field
+            .child_by_field_name("value")
+            .or_else(|| field.named_child(0))?;
+    }
+    if field.kind() == "pointer_expression"
+        && field
+            .child_by_field_name("operator")?
+            .utf8_text(content)
+            .ok()?
+            == "&"
+    {
+        field = field.child_by_field_name("argument")?;
+    }
+    field_of(field, content).map(|(struct_name, field)| (struct_name, Target::Field(field)))
+}
+
+/// The struct and member a `p->field` or `s.field` expression accesses.
+pub(crate) fn field_of<'a>(expr: ts::Node<'_>, content: &'a [u8]) -> Option<(&'a str, String)> {
+    if expr.kind() != "field_expression" {
+        return None;
+    }
+    let levels = match expr
+        .child_by_field_name("operator")?
+        .utf8_text(content)
+        .ok()?
+    {
+        "->" => 1,
+        _ => 0,
+    };
+    let (struct_name, resolved) = pointee::resolve(expr.child_by_field_name("argument")?, content)?;
+    if resolved != levels {
+        return None;
+    }
+    let field = expr.child_by_field_name("field")?.utf8_text(content).ok()?;
+    Some((struct_name, field.to_string()))
+}
+
+/// The variable a buffer argument refers to, `buf` for `buf`, `&buf` or
+/// `(void *)buf`.
+fn buffer_variable<'a>(arg: ts::Node<'_>, content: &'a [u8]) -> Option<&'a str> {
+    let arg = match arg.kind() {
+        "pointer_expression" => arg.child_by_field_name("argument")?,
+        _ => arg,
+    };
+    pointee::variable(arg, content)
+}
+
+/// The variable a call's result is assigned to or initializes.
+fn assigned_variable<'a>(call: ts::Node<'_>, content: &'a [u8]) -> Option<&'a str> {
+    let parent = call.parent()?;
+    match parent.kind() {
+        "assignment_expression" => pointee::variable(parent.child_by_field_name("left")?, content),
+        "init_declarator" => {
+            let declarator = parent.child_by_field_name("declarator")?;
+            let (name, _) = query::declared_identifiers(declarator).into_iter().next()?;
+            name.utf8_text(content).ok()
+        }
+        _ => None,
+    }
+}
+
+/// The struct and member a call's result is assigned to.
+fn assigned_field<'a>(call: ts::Node<'_>, content: &'a [u8]) -> Option<(&'a str, String)> {
+    let parent = call
+        .parent()
+        .filter(|p| p.kind() == "assignment_expression")?;
+    field_of(parent.child_by_field_name("left")?, content)
+}
+
+/// A tracked copy as the database records it.
+pub(crate) struct Row {
+    pub struct_name: String,
+    /// The path as it would have been printed.
+    pub path: String,
+    pub line: usize,
+    pub function: Option<String>,
+    pub call: String,
+    /// The member copied to or from, None for the whole object.
+    pub field: Option<String>,
+    /// `copy_in`, `buffer`, `copy_out` or `put`.
+    pub access: &'static str,
+    /// The length argument as written, None for `buffer` and `put`.
+    pub length: Option<String>,
+}
+
+/// The user-controlled writes found, for the database.
+pub(crate) fn write_rows() -> Vec<Row> {
+    rows(&WRITES.lock().unwrap())
+}
+
+/// The copies out to user memory found, for the database.
+pub(crate) fn read_rows() -> Vec<Row> {
+    rows(&READS.lock().unwrap())
+}
+
+fn rows(sites_by_struct: &BTreeMap<String, Vec<UserCopy>>) -> Vec<Row> {
+    let mut rows = vec![];
+    for (struct_name, sites) in sites_by_struct {
+        for site in sites {
+            let (access, length) = match &site.access {
+                Access::CopyIn(len) => ("copy_in", Some(len.clone())),
+                Access::Buffer => ("buffer", None),
+                Access::CopyOut(len) => ("copy_out", Some(len.clone())),
+                Access::Put => ("put", None),
+            };
+            rows.push(Row {
+                struct_name: struct_name.clone(),
+                path: output::display_path(&site.path),
+                line: site.line,
+                function: site.function.clone(),
+                call: site.call.clone(),
+                field: match &site.target {
+                    Target::Object => None,
+                    Target::Field(name) => Some(name.clone()),
+                },
+                access,
+                length,
+            });
+        }
+    }
+    rows.sort_by(|a, b| (&a.path, a.line).cmp(&(&b.path, b.line)));
+    rows
+}
+
+/// Describe the bytes a write lands in, with the member's offsets when the
+/// struct's layout has it.
+pub(crate) fn describe(target: &Target, layout: Option<&Layout>) -> String {
+    match target {
+        Target::Object => "the object from offset 0".to_string(),
+        Target::Field(name) => {
+            let member =
+                layout.and_then(|layout| layout.members.iter().find(|member| &member.name == name));
+            match member {
+                Some(member) if member.size > 0 => format!(
+                    "{name} (bytes {}-{})",
+                    member.offset,
+                    member.offset + member.size - 1
+                ),
+                Some(member) => format!("{name} (offset {})", member.offset),
+                None => name.clone(),
+            }
+        }
+    }
+}
+
+/// Print a section listing the user-controlled writes found into each
+/// struct, `layout` looking up the struct's members for their offsets.
+pub fn print_writes(layout: impl Fn(&str) -> Option<Layout>) {
+    print_sections("User-controlled writes to", &WRITES.lock().unwrap(), layout);
+}
+
+/// Print a section listing the copies out to user memory found from each
+/// struct, the candidates for leaking its contents. Copies of a whole object
+/// note the padding they include, which is left uninitialized unless the
+/// object was zeroed.
+pub fn print_reads(layout: impl Fn(&str) -> Option<Layout>) {
+    print_sections("Copies to user memory from", &READS.lock().unwrap(), layout);
+}
+
+fn print_sections(
+    title: &str,
+    sites_by_struct: &BTreeMap<String, Vec<UserCopy>>,
+    layout: impl Fn(&str) -> Option<Layout>,
+) {
+    for (struct_name, sites) in sites_by_struct {
+        println!("======== {title}: struct {struct_name} ========\n");
+        let layout = layout(struct_name);
+        let mut sites: Vec<&UserCopy> = sites.iter().collect();
+        sites.sort_by(|a, b| (&a.path, a.line).cmp(&(&b.path, b.line)));
+
+        for site in sites {
+            let target = describe(&site.target, layout.as_ref());
+            let effect = match &site.access {
+                Access::CopyIn(len) => format!("copies {len} bytes into {target}"),
+                Access::Buffer => format!("points {target} at a copy of user memory"),
+                Access::CopyOut(len) => {
+                    let holes: Vec<String> = match (&site.target, &layout) {
+                        (Target::Object, Some(layout)) => layout
+                            .holes()
+                            .iter()
+                            .map(|hole| format!("{}-{}", hole.start, hole.end - 1))
+                            .collect(),
+                        _ => vec![],
+                    };
+                    match holes.is_empty() {
+                        true => format!("copies {len} bytes out of {target}"),
+                        false => format!(
+                            "copies {len} bytes out of {target}, which has padding at bytes {}",
+                            holes.join(", ")
+                        ),
+                    }
+                }
+                Access::Put => format!("copies {target} out"),
+            };
+            let function = site
+                .function
+                .as_deref()
+                .map(|function| format!(" in {function}"))
+                .unwrap_or_default();
+            println!(
+                "{}:{}{function}: {}, {effect}",
+                output::display_path(&site.path),
+                site.line,
+                site.call
+            );
+        }
+        println!();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "
+long foo_get(struct foo *p, struct bar *q, void __user *arg, char *buf, int x)
+{
+	copy_to_user(arg, p, sizeof(*p));
+	copy_to_user(arg, &p->a, sizeof(p->a));
+	copy_to_user(arg, p->name, 16);
+	put_user(p->a, (int __user *)arg);
+	copy_to_user(arg, q, sizeof(*q));
+	copy_to_user(arg, buf, 16);
+	put_user(x, (int __user *)arg);
+	put_user(q->a, (int __user *)arg);
+	return copy_from_user(p, arg, sizeof(*p));
+}
+";
+
+    // the struct, line, target and access of a copy
+    type Found = (String, usize, Target, Access);
+
+    /// The copies of `struct foo` in `content`.
+    fn copies_of_foo(content: &str) -> Copies {
+        let mut parser = ts::Parser::new();
+        parser.set_language(ts_c::language()).unwrap();
+        let tree = parser.parse(content, None).unwrap();
+        copies_in(
+            tree.root_node(),
+            content.as_bytes(),
+            Path::new("foo.c"),
+            |name| name == "foo",
+        )
+    }
+
+    /// The writes and reads of `struct foo` in `content`.
+    fn copies(content: &str) -> (Vec<Found>, Vec<Found>) {
+        let Copies { writes, reads } = copies_of_foo(content);
+        let flatten = |found: Vec<(String, UserCopy)>| {
+            found
+                .into_iter()
+                .map(|(struct_name, copy)| (struct_name, copy.line, copy.target, copy.access))
+                .collect()
+        };
+        (flatten(writes), flatten(reads))
+    }
+
+    fn foo(line: usize, target: Target, access: Access) -> Found {
+        ("foo".to_string(), line, target, access)
+    }
+
+    #[test]
+    fn copies_out_of_objects_and_fields() {
+        let (_, reads) = copies(SOURCE);
+        let field = |name: &str| Target::Field(name.to_string());
+        let out = |len: &str| Access::CopyOut(len.to_string());
+        assert_eq!(
+            reads,
+            [
+                foo(4, Target::Object, out("sizeof(*p)")),
+                foo(5, field("a"), out("sizeof(p->a)")),
+                foo(6, field("name"), out("16")),
+                foo(7, field("a"), Access::Put),
+            ]
+        );
+    }
+
+    #[test]
+    fn unresolved_sources_are_not_reads() {
+        // struct bar isn't selected, buf and x aren't a struct at all
+        let (_, reads) = copies(SOURCE);
+        assert!(reads.iter().all(|(_, line, _, _)| *line < 8), "{reads:?}");
+
+        let (writes, reads) =
+            copies("void f(void __user *arg, long v) { copy_to_user(arg, &v, sizeof(v)); }");
+        assert!(writes.is_empty() && reads.is_empty());
+    }
+
+    #[test]
+    fn reads_are_kept_apart_from_writes() {
+        let (writes, reads) = copies(SOURCE);
+        assert_eq!(
+            writes,
+            [foo(
+                12,
+                Target::Object,
+                Access::CopyIn("sizeof(*p)".to_string())
+            )]
+        );
+        assert!(reads
+            .iter()
+            .all(|(_, _, _, access)| matches!(access, Access::CopyOut(_) | Access::Put)));
+    }
+
+    #[test]
+    fn rows_of_reads() {
+        let Copies { reads, .. } = copies_of_foo(SOURCE);
+        let mut by_struct = BTreeMap::new();
+        for (struct_name, copy) in reads {
+            by_struct
+                .entry(struct_name)
+                .or_insert_with(Vec::new)
+                .push(copy);
+        }
+        let rows: Vec<(usize, Option<String>, &str, Option<String>)> = rows(&by_struct)
+            .into_iter()
+            .map(|row| (row.line, row.field, row.access, row.length))
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                (4, None, "copy_out", Some("sizeof(*p)".to_string())),
+                (
+                    5,
+                    Some("a".to_string()),
+                    "copy_out",
+                    Some("sizeof(p->a)".to_string())
+                ),
+                (
+                    6,
+                    Some("name".to_string()),
+                    "copy_out",
+                    Some("16".to_string())
+                ),
+                (7, Some("a".to_string()), "put", None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,50 @@
+// each test binary uses its own subset of these
+#![allow(dead_code)]
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// The directory of the fixture sources and `types.json`, the structs
+/// they allocate in bpftool's JSON format.
+pub fn fixtures() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+}
+
+/// Run `kheap_sift scan` with the fixture types and `args`, from the fixture
+/// directory.
+pub fn scan(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_kheap_sift"))
+        .current_dir(fixtures())
+        .args(["scan", "--types-from", "types.json"])
+        .args(args)
+        .output()
+        .expect("kheap_sift runs")
+}
+
+/// The stdout of a scan which must succeed, with the fixture directory left
+/// out of the paths so it compares equal wherever the tree is checked out.
+pub fn scan_stdout(args: &[&str]) -> String {
+    let output = scan(args);
+    assert!(
+        output.status.success(),
+        "scan {args:?} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    // the paths printed are canonical
+    let prefix = format!("{}/", fixtures().canonicalize().unwrap().display());
+    String::from_utf8(output.stdout)
+        .expect("the output is UTF-8")
+        .replace(&prefix, "")
+}
+
+/// Compare the scan of `source` against the `.expected` file next to it.
+pub fn assert_golden(source: &str) {
+    let actual = scan_stdout(&["--all-structs", source]);
+    let expected_path = fixtures().join(source).with_extension("expected");
+    let expected = std::fs::read_to_string(&expected_path)
+        .unwrap_or_else(|e| panic!("reading {}: {e}", expected_path.display()));
+    assert_eq!(
+        actual, expected,
+        "output of {source} differs from its .expected"
+    );
+}
@@ -0,0 +1,100 @@
+//! Golden output of the excerpt printed for each site: the function's
+//! preamble up to its opening brace, the lines holding the captures and its
+//! exit path, with `...` between the gaps.
+
+mod common;
+
+use std::path::Path;
+
+use kheap_sift::{DwarfSource, ScanConfig, Scanner};
+
+#[test]
+fn multi_line_declarator_with_attributes() {
+    common::assert_golden("excerpt/declarator.c");
+}
+
+#[test]
+fn brace_on_the_declarator_line() {
+    common::assert_golden("excerpt/brace_on_declarator.c");
+}
+
+#[test]
+fn captures_out_of_textual_order() {
+    // kmalloc_trace takes the flags before the size, which is captured first
+    common::assert_golden("excerpt/out_of_order.c");
+}
+
+#[test]
+fn one_line_body() {
+    common::assert_golden("excerpt/one_line.c");
+}
+
+#[test]
+fn exit_path_after_goto_cleanup() {
+    common::assert_golden("excerpt/goto_cleanup.c");
+}
+
+#[test]
+fn void_function_without_return() {
+    common::assert_golden("excerpt/void_no_return.c");
+}
+
+#[test]
+fn syscall_define_without_a_declarator() {
+    // the body parses as a block of its own, with no brace of a definition
+    // to start the excerpt at, only the matched lines are shown
+    common::assert_golden("excerpt/syscall_define.c");
+}
+
+#[test]
+fn syscall_define_warns_in_verbose_mode() {
+    let output = common::scan(&["--all-structs", "--verbose", "excerpt/syscall_define.c"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("in function: foo_create()\n"));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("warning: no opening brace in function at ")
+            && stderr.contains("excerpt/syscall_define.c:2\n"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn crlf_matches_lf() {
+    // written out at test time, git could normalize a CRLF fixture
+    let lf = std::fs::read_to_string(common::fixtures().join("excerpt/long_function.c")).unwrap();
+    let dir = std::env::temp_dir().join(format!("kheap_sift-crlf-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let crlf_path = dir.join("long_function.c");
+    std::fs::write(&crlf_path, lf.replace('\n', "\r\n")).unwrap();
+    let crlf_path = crlf_path.canonicalize().unwrap();
+
+    let expected = common::scan_stdout(&["--all-structs", "excerpt/long_function.c"]);
+    let crlf = common::scan_stdout(&["--all-structs", crlf_path.to_str().unwrap()]);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(expected.contains("excerpt/long_function.c:1 "));
+    assert!(expected.contains("\tp = kzalloc(sizeof(*p),\n\t\t    GFP_KERNEL);\n"));
+    assert_eq!(
+        crlf.replace(crlf_path.to_str().unwrap(), "excerpt/long_function.c"),
+        expected
+    );
+}
+
+#[test]
+fn crlf_site_lines_match_lf() {
+    let config = ScanConfig::new(
+        DwarfSource::TypesFrom(common::fixtures().join("types.json")),
+        vec![],
+    );
+    let scanner = Scanner::new(config).unwrap();
+    let path = Path::new("long_function.c");
+    let lf = std::fs::read_to_string(common::fixtures().join("excerpt/long_function.c")).unwrap();
+    let line = |content: &str| -> Vec<usize> {
+        let matches = scanner.scan_source(path, content.as_bytes()).unwrap();
+        matches.iter().map(|site| site.line).collect()
+    };
+    assert_eq!(line(&lf), [22]);
+    assert_eq!(line(&lf.replace('\n', "\r\n")), [22]);
+}
@@ -0,0 +1,33 @@
+struct foo *foo_alloc(void)
+{
+	struct foo *p;
+
+	p = kzalloc(sizeof(*p), GFP_KERNEL);
+	return p;
+}
+
+long foo_set(struct foo *p, void __user *arg)
+{
+	if (copy_from_user(p, arg, sizeof(*p)))
+		return -EFAULT;
+	return 0;
+}
+
+long foo_set_name(struct foo *p, void __user *arg, size_t len)
+{
+	return copy_from_user(p->name, arg, len);
+}
+
+long foo_get(struct foo *p, void __user *arg)
+{
+	if (copy_to_user(arg, p, sizeof(*p)))
+		return -EFAULT;
+	return put_user(p->a, (int __user *)arg);
+}
+
+void foo_reset(struct foo *p, const char *name)
+{
+	memset(p, 0, sizeof(*p));
+	memcpy(p->name, name, 32);
+	memmove(&p->b, name, n);
+}
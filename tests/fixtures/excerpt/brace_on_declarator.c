@@ -0,0 +1,12 @@
+int bar_setup(struct device *dev,
+	      unsigned int count) {
+	struct bar *b;
+	int ret;
+
+	ret = check(dev);
+	if (ret)
+		return ret;
+	b = kzalloc(sizeof(struct bar), GFP_KERNEL);
+	dev->priv = b;
+	return 0;
+}
@@ -0,0 +1,18 @@
+static struct foo *foo_create(int a,
+			      int b,
+			      const char *name)
+	__must_hold(&foo_lock)
+	__attribute__((warn_unused_result))
+{
+	struct foo *p;
+
+	if (a < 0)
+		return NULL;
+	p = kmalloc(sizeof(*p), GFP_KERNEL);
+	if (!p)
+		return NULL;
+	p->a = a;
+	p->b = b;
+	strscpy(p->name, name, sizeof(p->name));
+	return p;
+}
@@ -0,0 +1,20 @@
+int foo_open(struct inode *inode, struct file *file)
+{
+	struct foo *p;
+	int err;
+
+	p = kmalloc(sizeof(*p), GFP_KERNEL_ACCOUNT);
+	if (!p)
+		return -ENOMEM;
+	err = foo_register(p);
+	if (err)
+		goto out_free;
+	file->private_data = p;
+	return 0;
+
+out_free:
+	kfree(p);
+	goto out;
+out:
+	return err;
+}
@@ -0,0 +1,28 @@
+static int foo_probe(struct platform_device *pdev)
+{
+	struct device *dev = &pdev->dev;
+	struct foo *p;
+	int irq, ret;
+
+	irq = platform_get_irq(pdev, 0);
+	if (irq < 0)
+		return irq;
+
+	ret = devm_request_irq(dev, irq, foo_irq, 0, "foo", NULL);
+	if (ret)
+		return ret;
+
+	ret = foo_hw_reset(dev);
+	if (ret) {
+		dev_err(dev, "reset failed: %d\n", ret);
+		return ret;
+	}
+
+	/* everything above is set up, the state is allocated last */
+	p = kzalloc(sizeof(*p),
+		    GFP_KERNEL);
+	if (!p)
+		return -ENOMEM;
+	platform_set_drvdata(pdev, p);
+	return 0;
+}
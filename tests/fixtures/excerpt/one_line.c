@@ -0,0 +1 @@
+struct foo *foo_new(void) { struct foo *p; p = kzalloc(sizeof(*p), GFP_KERNEL); return p; }
@@ -0,0 +1,16 @@
+static struct foo *foo_from_cache(int idx)
+{
+	struct foo *p;
+	int unused;
+
+	unused = idx * 2;
+	p = kmalloc_trace(kmalloc_caches[idx],
+			  GFP_KERNEL | __GFP_ZERO,
+			  sizeof(*p));
+	if (!p)
+		goto fail;
+	init_foo(p);
+	return p;
+fail:
+	return NULL;
+}
@@ -0,0 +1,9 @@
+SYSCALL_DEFINE3(foo_create, int, fd, unsigned int, flags, size_t, len)
+{
+	struct foo *p;
+
+	p = kzalloc(sizeof(*p), GFP_KERNEL);
+	if (!p)
+		return -ENOMEM;
+	return foo_install(p, fd);
+}
@@ -0,0 +1,10 @@
+void bar_init(struct device *dev)
+{
+	struct bar *b;
+
+	b = kmalloc(sizeof(*b), GFP_ATOMIC);
+	if (b) {
+		memset(b->buf, 0, sizeof(b->buf));
+		dev->priv = b;
+	}
+}
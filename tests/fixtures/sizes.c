@@ -0,0 +1,31 @@
+struct foo *foo_by_type(void)
+{
+	struct foo *p;
+
+	p = kmalloc(sizeof(struct foo), GFP_KERNEL);
+	return p;
+}
+
+struct foo *foo_by_deref(void)
+{
+	struct foo *p;
+
+	p = kmalloc(sizeof(*p), GFP_KERNEL);
+	return p;
+}
+
+struct foo *foo_with_tail(size_t n)
+{
+	struct foo *p;
+
+	p = kmalloc(sizeof(*p) + n, GFP_KERNEL);
+	return p;
+}
+
+struct foo *foo_array(size_t n)
+{
+	struct foo *p;
+
+	p = kcalloc(n, sizeof(*p), GFP_KERNEL);
+	return p;
+}
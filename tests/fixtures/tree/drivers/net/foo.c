@@ -0,0 +1,20 @@
+#include <linux/foo.h>
+
+static struct foo *foo_alloc(gfp_t gfp)
+{
+	struct foo *p;
+
+	p = kzalloc(sizeof(*p), gfp);
+	return p;
+}
+
+int foo_ioctl(struct file *file, unsigned int cmd, unsigned long arg)
+{
+	struct bar *b;
+
+	b = kmalloc(sizeof(struct bar), GFP_KERNEL_ACCOUNT);
+	if (!b)
+		return -ENOMEM;
+	kfree(b);
+	return 0;
+}
@@ -0,0 +1,5 @@
+/* nothing allocated here */
+int baz(void)
+{
+	return 0;
+}
@@ -0,0 +1,13 @@
+struct foo {
+	int a;
+	int b;
+	char name[16];
+};
+
+static inline struct foo *foo_get(void)
+{
+	struct foo *f;
+
+	f = kmalloc(sizeof(*f), GFP_NOWAIT);
+	return f;
+}
@@ -0,0 +1,7 @@
+#include <linux/foo.h>
+
+int bar_create(struct bar **out)
+{
+	*out = kcalloc(1, sizeof(struct bar), GFP_ATOMIC);
+	return *out ? 0 : -ENOMEM;
+}
@@ -0,0 +1,51 @@
+//! The `info` subcommand over the fixture types and tree.
+
+mod common;
+
+use std::process::Command;
+
+fn info(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_kheap_sift"))
+        .current_dir(common::fixtures())
+        .args(["info", "--types-from", "types.json"])
+        .args(args)
+        .output()
+        .expect("kheap_sift runs");
+    assert!(
+        output.status.success(),
+        "info {args:?} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).expect("the output is UTF-8")
+}
+
+#[test]
+fn counts_allocation_sites_in_a_tree() {
+    let bar = info(&["bar", "--source", "tree"]);
+    assert!(bar.starts_with("struct bar\nSize:               96 bytes\n"));
+    assert!(bar.contains("\nAllocation sites:   2\n"), "{bar}");
+    // the count is of the named struct only, and never prints the sites
+    let foo = info(&["foo", "--source", "tree"]);
+    assert!(foo.contains("\nAllocation sites:   2\n"), "{foo}");
+    assert!(!foo.contains("Found allocation site"));
+    let single = info(&["foo", "--source", "tree/include/linux/foo.h"]);
+    assert!(single.contains("\nAllocation sites:   1\n"), "{single}");
+}
+
+#[test]
+fn no_count_without_a_source() {
+    let foo = info(&["foo"]);
+    assert!(!foo.contains("Allocation sites:"));
+    assert!(foo.contains("struct foo {\n"));
+}
+
+#[test]
+fn unknown_struct_is_an_error() {
+    let output = Command::new(env!("CARGO_BIN_EXE_kheap_sift"))
+        .current_dir(common::fixtures())
+        .args(["info", "--types-from", "types.json", "nosuch"])
+        .output()
+        .expect("kheap_sift runs");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("struct 'nosuch' was not found"));
+}
@@ -0,0 +1,93 @@
+//! The bounds the scan pipeline keeps on memory and on concurrency, checked
+//! through the counters `--stats` prints.
+
+mod common;
+
+use std::path::Path;
+
+// large enough that holding every file at once would stand out
+const FILES: usize = 12;
+const FILE_SIZE: usize = 2 << 20;
+
+/// Write `files` sources of about `size` bytes each into `dir`, each with one
+/// site after a long comment.
+fn write_tree(dir: &Path, files: usize, size: usize) {
+    std::fs::create_dir_all(dir).unwrap();
+    let padding = format!("/*\n{}*/\n", " * padding\n".repeat(size / 11));
+    for i in 0..files {
+        let source = format!(
+            "{padding}\nstruct foo *big{i}_alloc(void)\n{{\n\tstruct foo *p;\n\n\
+             \tp = kmalloc(sizeof(*p), GFP_KERNEL);\n\treturn p;\n}}\n"
+        );
+        std::fs::write(dir.join(format!("big{i}.c")), source).unwrap();
+    }
+}
+
+/// The number on the `--stats` line starting with `label`.
+fn stat(stderr: &str, label: &str) -> usize {
+    let line = stderr
+        .lines()
+        .find(|line| line.starts_with(label))
+        .unwrap_or_else(|| panic!("no {label:?} line in {stderr}"));
+    line[label.len()..]
+        .split_whitespace()
+        .next()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or_else(|| panic!("no count on {line:?}"))
+}
+
+#[test]
+fn buffered_files_stay_within_the_limit() {
+    let dir = std::env::temp_dir().join(format!("kheap_sift-large-{}", std::process::id()));
+    write_tree(&dir, FILES, FILE_SIZE);
+
+    let output = common::scan(&[
+        "--all-structs",
+        "--threads",
+        "4",
+        "--max-buffered-files",
+        "3",
+        "--stats",
+        dir.to_str().unwrap(),
+    ]);
+    std::fs::remove_dir_all(&dir).unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "scan failed: {stderr}");
+
+    // every file is still scanned, only fewer are held at once
+    assert_eq!(stat(&stderr, "Files scanned:"), FILES);
+    assert_eq!(stat(&stderr, "Allocation sites reported:"), FILES);
+    let peak_files = stat(&stderr, "Peak buffered files:");
+    assert!((1..=3).contains(&peak_files), "{peak_files} files buffered");
+    let peak_bytes = stat(&stderr, "Peak buffered bytes:");
+    assert!(peak_bytes >= FILE_SIZE, "{peak_bytes} bytes buffered");
+    assert!(peak_bytes < 4 * FILE_SIZE, "{peak_bytes} bytes buffered");
+}
+
+#[test]
+fn workers_stay_within_the_thread_count() {
+    let dir = std::env::temp_dir().join(format!("kheap_sift-workers-{}", std::process::id()));
+    write_tree(&dir, 48, 64 << 10);
+
+    // more readers than parsers, so files queue up for the workers
+    let output = common::scan(&[
+        "--all-structs",
+        "--io-threads",
+        "8",
+        "--parse-threads",
+        "2",
+        "--stats",
+        dir.to_str().unwrap(),
+    ]);
+    std::fs::remove_dir_all(&dir).unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    // a worker over the limit trips the assertion in debug builds, failing
+    // its file
+    assert!(output.status.success(), "scan failed: {stderr}");
+    assert!(!stderr.contains("workers running, limit is"), "{stderr}");
+
+    assert_eq!(stat(&stderr, "Files scanned:"), 48);
+    assert_eq!(stat(&stderr, "Allocation sites reported:"), 48);
+    let peak = stat(&stderr, "Peak concurrent workers:");
+    assert!((1..=2).contains(&peak), "{peak} workers at once");
+}
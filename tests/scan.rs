@@ -0,0 +1,161 @@
+//! Scans of the fixture kernel tree under `tests/fixtures/tree`, and of
+//! single files from it.
+
+mod common;
+
+use std::path::Path;
+
+use kheap_sift::{DwarfSource, ScanConfig, Scanner};
+
+/// The `path:line` of every site in a scan's output, sorted as the files are
+/// scanned concurrently. The indented sites of the reports after the scan
+/// are left out.
+fn sites(stdout: &str) -> Vec<String> {
+    let mut sites: Vec<String> = stdout
+        .lines()
+        .filter(|line| !line.starts_with(char::is_whitespace))
+        .filter_map(|line| {
+            let (location, status) = line.split_once(" (")?;
+            let (_, number) = location.rsplit_once(':')?;
+            (status.ends_with(')') && number.parse::<usize>().is_ok()).then(|| location.to_string())
+        })
+        .collect();
+    sites.sort();
+    sites
+}
+
+#[test]
+fn scans_the_fixture_tree() {
+    let stdout = common::scan_stdout(&["--all-structs", "--relative-paths", "tree"]);
+    assert_eq!(
+        sites(&stdout),
+        [
+            "drivers/net/foo.c:11",
+            "drivers/net/foo.c:3",
+            "include/linux/foo.h:7",
+            "net/core/bar.c:3",
+        ]
+    );
+    assert!(stdout.contains("======== Found allocation site for: struct bar ========"));
+    assert!(stdout.contains("GFP: GFP_ATOMIC (atomic)"));
+    // bar is allocated both with and without __GFP_ACCOUNT
+    assert!(stdout.contains(
+        "kmalloc-96:\n    net/core/bar.c:5 (GFP_ATOMIC)\n\
+         kmalloc-cg-96:\n    drivers/net/foo.c:15 (GFP_KERNEL_ACCOUNT)\n"
+    ));
+}
+
+#[test]
+fn selects_structs_by_size() {
+    // foo is 24 bytes and bar 96
+    let stdout = common::scan_stdout(&["--min-size", "64", "--relative-paths", "tree"]);
+    assert_eq!(sites(&stdout), ["drivers/net/foo.c:11", "net/core/bar.c:3"]);
+}
+
+#[test]
+fn include_and_exclude_globs() {
+    let stdout = common::scan_stdout(&[
+        "--all-structs",
+        "--relative-paths",
+        "--include",
+        "drivers/**",
+        "--include",
+        "include/**",
+        "--exclude",
+        "**/*.h",
+        "tree",
+    ]);
+    assert_eq!(
+        sites(&stdout),
+        ["drivers/net/foo.c:11", "drivers/net/foo.c:3"]
+    );
+}
+
+/// The functions of the sites `--size-expr regex` keeps in `sizes.c`.
+fn sized_functions(regex: &str) -> Vec<String> {
+    common::scan_stdout(&["--all-structs", "--size-expr", regex, "sizes.c"])
+        .lines()
+        .filter_map(|line| line.strip_prefix("in function: "))
+        .map(str::to_string)
+        .collect()
+}
+
+#[test]
+fn size_expression_filter() {
+    assert_eq!(
+        sized_functions(r"^sizeof\(struct \w+\)$"),
+        ["foo_by_type()"]
+    );
+    assert_eq!(sized_functions(r"^sizeof\(\*\w+\)$"), ["foo_by_deref()"]);
+    assert_eq!(sized_functions(r"\+"), ["foo_with_tail()"]);
+    // array allocators are matched as COUNT * SIZE
+    assert_eq!(sized_functions(r"^n \* sizeof"), ["foo_array()"]);
+    assert_eq!(
+        sized_functions("sizeof"),
+        [
+            "foo_by_type()",
+            "foo_by_deref()",
+            "foo_with_tail()",
+            "foo_array()",
+        ]
+    );
+}
+
+#[test]
+fn scans_a_single_source_file() {
+    let stdout = common::scan_stdout(&["--all-structs", "tree/drivers/net/foo.c"]);
+    assert_eq!(
+        sites(&stdout),
+        ["tree/drivers/net/foo.c:11", "tree/drivers/net/foo.c:3"]
+    );
+    assert!(stdout.contains("in function: foo_alloc()"));
+    assert!(stdout.contains("in function: foo_ioctl()"));
+}
+
+#[test]
+fn scans_a_single_header() {
+    let stdout = common::scan_stdout(&["--all-structs", "tree/include/linux/foo.h"]);
+    assert_eq!(sites(&stdout), ["tree/include/linux/foo.h:7"]);
+}
+
+#[test]
+fn file_without_sites() {
+    let stdout = common::scan_stdout(&["--all-structs", "tree/fs/baz.c"]);
+    assert_eq!(stdout, "");
+}
+
+#[test]
+fn non_source_file_has_nothing_to_scan() {
+    let stdout = common::scan_stdout(&["--all-structs", "notes.txt"]);
+    assert_eq!(
+        stdout,
+        "Exiting, no files to process: notes.txt is not a .c or .h file, or was filtered out\n"
+    );
+}
+
+#[test]
+fn missing_source_path_is_rejected() {
+    let output = common::scan(&["--all-structs", "tree/does-not-exist"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is not a directory or file"));
+}
+
+#[test]
+fn library_matches_name_their_function() {
+    let config = ScanConfig::new(
+        DwarfSource::TypesFrom(common::fixtures().join("types.json")),
+        vec![],
+    );
+    let scanner = Scanner::new(config).unwrap();
+    let functions = |source: &str| -> Vec<String> {
+        let content = std::fs::read(common::fixtures().join(source)).unwrap();
+        let matches = scanner.scan_source(Path::new(source), &content).unwrap();
+        matches.into_iter().map(|site| site.function).collect()
+    };
+    assert_eq!(
+        functions("tree/drivers/net/foo.c"),
+        ["foo_alloc", "foo_ioctl"]
+    );
+    assert_eq!(functions("tree/net/core/bar.c"), ["bar_create"]);
+    assert_eq!(functions("excerpt/syscall_define.c"), ["foo_create"]);
+}
@@ -0,0 +1,110 @@
+//! `--format sqlite` runs over the fixtures, read back with SQL.
+
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use rusqlite::Connection;
+
+/// Scan `copies.c` into a fresh database with `args`, returning its path.
+fn scan_into_db(name: &str, args: &[&str]) -> PathBuf {
+    let db = std::env::temp_dir().join(format!("kheap_sift-{name}-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&db);
+    let mut all = vec!["--all-structs", "--format", "sqlite", "--output"];
+    all.push(db.to_str().unwrap());
+    all.extend_from_slice(args);
+    all.push("copies.c");
+    let output = common::scan(&all);
+    assert!(
+        output.status.success(),
+        "scan failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    db
+}
+
+/// The rows of `sql`, each column as text, NULL as "NULL".
+fn rows(db: &Path, sql: &str) -> Vec<Vec<String>> {
+    let db = Connection::open(db).unwrap();
+    let mut statement = db.prepare(sql).unwrap();
+    let columns = statement.column_count();
+    statement
+        .query_map([], |row| {
+            (0..columns)
+                .map(|idx| {
+                    Ok(match row.get_ref(idx)? {
+                        rusqlite::types::ValueRef::Null => "NULL".to_string(),
+                        rusqlite::types::ValueRef::Integer(n) => n.to_string(),
+                        value => value.as_str()?.to_string(),
+                    })
+                })
+                .collect()
+        })
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap()
+}
+
+#[test]
+fn user_copies_are_recorded() {
+    let db = scan_into_db("copies", &["--track-user-writes", "--track-user-reads"]);
+    let columns = "struct_name, line, function, field, access, length";
+    let writes = rows(
+        &db,
+        &format!("SELECT {columns} FROM user_writes ORDER BY line"),
+    );
+    let reads = rows(
+        &db,
+        &format!("SELECT {columns} FROM user_reads ORDER BY line"),
+    );
+    std::fs::remove_file(&db).unwrap();
+
+    assert_eq!(
+        writes,
+        [
+            ["foo", "11", "foo_set", "NULL", "copy_in", "sizeof(*p)"],
+            ["foo", "18", "foo_set_name", "name", "copy_in", "len"],
+        ]
+    );
+    assert_eq!(
+        reads,
+        [
+            ["foo", "23", "foo_get", "NULL", "copy_out", "sizeof(*p)"],
+            ["foo", "25", "foo_get", "a", "put", "NULL"],
+        ]
+    );
+}
+
+#[test]
+fn untracked_copies_are_not_recorded() {
+    let db = scan_into_db("untracked", &["--track-user-reads"]);
+    let writes = rows(&db, "SELECT count(*) FROM user_writes");
+    let reads = rows(&db, "SELECT count(*) FROM user_reads");
+    let sites = rows(&db, "SELECT line, function FROM sites");
+    std::fs::remove_file(&db).unwrap();
+
+    assert_eq!(writes, [["0"]]);
+    assert_eq!(reads, [["2"]]);
+    assert_eq!(sites, [["5", "foo_alloc"]]);
+}
+
+#[test]
+fn bulk_writes_are_recorded_with_their_bound() {
+    let db = scan_into_db("bulk", &["--track-bulk-writes"]);
+    let writes = rows(
+        &db,
+        "SELECT line, op, field, size, bound, exceeds_by FROM bulk_writes ORDER BY line",
+    );
+    let version = rows(&db, "SELECT version FROM schema_version");
+    std::fs::remove_file(&db).unwrap();
+
+    assert_eq!(
+        writes,
+        [
+            ["30", "memset", "NULL", "sizeof(*p)", "exact", "NULL"],
+            ["31", "memcpy", "name", "32", "exceeds", "16"],
+            ["32", "memmove", "b", "n", "may_exceed", "NULL"],
+        ]
+    );
+    assert_eq!(version, [["6"]]);
+}